@@ -5,9 +5,12 @@
 extern crate futures;
 
 use futures::future::Future;
+use futures::stream::Stream;
 
 pub type BoxFuture<T, E> = Box<Future<Item = T, Error = E> + Send>;
 
+pub type BoxStream<T, E> = Box<Stream<Item = T, Error = E> + Send>;
+
 pub trait Boxable {
   fn to_boxed(self) -> Box<Self>;
 }
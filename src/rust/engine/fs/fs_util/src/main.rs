@@ -218,6 +218,9 @@ fn execute(top_match: clap::ArgMatches) -> Result<(), ExitError> {
             )?)
             .map_err(|e| format!("Error expanding globs: {}", e.description()))
             .and_then(move |paths| {
+              for path in posix_fs.skipped_special_files() {
+                eprintln!("Skipped special file {:?}", path);
+              }
               Snapshot::from_path_stats(
                 store.clone(),
                 Arc::new(FileSaver {
@@ -227,7 +230,7 @@ fn execute(top_match: clap::ArgMatches) -> Result<(), ExitError> {
                 paths,
               )
             })
-            .map(|snapshot| snapshot.digest.unwrap())
+            .map(|snapshot| snapshot.digest().unwrap())
             .wait()?;
           Ok(println!("{} {}", digest.0, digest.1))
         }
@@ -344,11 +347,10 @@ fn materialize_directory(
         .map(|file_node| {
           let store = store.clone();
           let path = destination.join(file_node.get_name());
-          future::result(Fingerprint::from_hex_string(
-            file_node.get_digest().get_hash(),
-          )).map_err(|e| e.into())
-            .and_then(move |fingerprint| {
-              materialize_file(store, path, fingerprint)
+          future::result(fs::Digest::from_bazel_digest(file_node.get_digest()))
+            .map_err(|e| e.into())
+            .and_then(move |digest| {
+              materialize_file(store, path, digest.0)
             })
         })
         .collect::<Vec<_>>();
@@ -358,11 +360,10 @@ fn materialize_directory(
         .map(|directory_node| {
           let store = store.clone();
           let path = destination.join(directory_node.get_name());
-          future::result(Fingerprint::from_hex_string(
-            directory_node.get_digest().get_hash(),
-          )).map_err(|e| e.into())
-            .and_then(move |fingerprint| {
-              materialize_directory(store, path, fingerprint)
+          future::result(fs::Digest::from_bazel_digest(directory_node.get_digest()))
+            .map_err(|e| e.into())
+            .and_then(move |digest| {
+              materialize_directory(store, path, digest.0)
             })
         })
         .collect::<Vec<_>>();
@@ -0,0 +1,232 @@
+// Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use boxfuture::{Boxable, BoxFuture};
+use futures::{future, Future};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+
+use {Digest, File, GetFileDigest, PathStat, ResettablePool, Store};
+
+// Git's modes for regular and executable files. Anything else (symlinks, submodules) can't be
+// represented in a Snapshot.
+const REGULAR_FILE_MODE: &str = "100644";
+const EXECUTABLE_FILE_MODE: &str = "100755";
+
+///
+/// The files of a git tree object (or anything which resolves to one, such as a commit or a
+/// branch), read directly from the object database without touching the working directory.
+///
+/// The PathStats of the tree can be passed to `Snapshot::from_path_stats` along with the GitTree
+/// itself as the GetFileDigest, to snapshot the tree as of that commit.
+///
+/// This shells out to the `git` binary, which must be on the PATH.
+///
+pub struct GitTree {
+  repo: PathBuf,
+  store: Arc<Store>,
+  pool: Arc<ResettablePool>,
+  // Blob ids of the files in the tree, by path.
+  blobs: HashMap<PathBuf, String>,
+  path_stats: Vec<PathStat>,
+}
+
+impl GitTree {
+  ///
+  /// Lists the files of the given treeish in the given repository. This blocks while git runs.
+  ///
+  pub fn new<P: AsRef<Path>>(
+    repo: P,
+    treeish: &str,
+    store: Arc<Store>,
+    pool: Arc<ResettablePool>,
+  ) -> Result<GitTree, String> {
+    let repo = repo.as_ref().to_owned();
+    let listing = run_git(&repo, &["ls-tree", "-r", "-z", "--full-tree", treeish])?;
+
+    let mut blobs = HashMap::new();
+    let mut path_stats = Vec::new();
+    for entry in listing.split(|&b| b == 0).filter(|entry| !entry.is_empty()) {
+      let entry = String::from_utf8(entry.to_vec()).map_err(|e| {
+        format!("Could not decode git tree entry as UTF8: {:?}", e)
+      })?;
+      // Each entry is "<mode> <type> <object>\t<path>".
+      let (metadata, path) = match entry.find('\t') {
+        Some(index) => (&entry[..index], &entry[index + 1..]),
+        None => return Err(format!("Malformed git tree entry: {:?}", entry)),
+      };
+      let fields = metadata.split(' ').collect::<Vec<_>>();
+      if fields.len() != 3 {
+        return Err(format!("Malformed git tree entry: {:?}", entry));
+      }
+      let is_executable = match fields[0] {
+        REGULAR_FILE_MODE => false,
+        EXECUTABLE_FILE_MODE => true,
+        mode => {
+          return Err(format!(
+            "Unsupported entry {:?} with mode {} in git tree {}",
+            path,
+            mode,
+            treeish
+          ))
+        }
+      };
+      let path = PathBuf::from(path);
+      blobs.insert(path.clone(), fields[2].to_owned());
+      path_stats.push(PathStat::file(
+        path.clone(),
+        File {
+          path: path,
+          is_executable: is_executable,
+        },
+      ));
+    }
+
+    Ok(GitTree {
+      repo: repo,
+      store: store,
+      pool: pool,
+      blobs: blobs,
+      path_stats: path_stats,
+    })
+  }
+
+  pub fn path_stats(&self) -> Vec<PathStat> {
+    self.path_stats.clone()
+  }
+}
+
+impl GetFileDigest<String> for GitTree {
+  fn digest(&self, file: &File) -> BoxFuture<Digest, String> {
+    let blob = match self.blobs.get(&file.path) {
+      Some(blob) => blob.clone(),
+      None => {
+        return future::err(format!("File {:?} is not in the git tree", file.path)).to_boxed()
+      }
+    };
+    let repo = self.repo.clone();
+    let store = self.store.clone();
+    self
+      .pool
+      .spawn_fn(move || run_git(&repo, &["cat-file", "blob", blob.as_str()]))
+      .and_then(move |content| store.store_file_bytes(content))
+      .to_boxed()
+  }
+}
+
+fn run_git(repo: &Path, args: &[&str]) -> Result<Vec<u8>, String> {
+  let output = Command::new("git")
+    .arg("-C")
+    .arg(repo)
+    .args(args)
+    .output()
+    .map_err(|e| format!("Error running git {:?}: {}", args, e))?;
+  if output.status.success() {
+    Ok(output.stdout)
+  } else {
+    Err(format!(
+      "git {:?} failed: {}",
+      args,
+      String::from_utf8_lossy(&output.stderr)
+    ))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempdir;
+  extern crate testutil;
+
+  use futures::Future;
+  use self::testutil::make_file;
+  use std;
+  use std::io::Read;
+  use std::path::Path;
+  use std::process::Command;
+  use std::sync::Arc;
+  use super::GitTree;
+  use super::super::{File, PathStat, ResettablePool, Snapshot, Store};
+
+  fn git(repo: &Path, args: &[&str]) {
+    let status = Command::new("git")
+      .arg("-C")
+      .arg(repo)
+      .args(&["-c", "user.name=test", "-c", "user.email=test@example.com"])
+      .args(args)
+      .status()
+      .unwrap();
+    assert!(status.success(), "git {:?} failed", args);
+  }
+
+  #[test]
+  fn snapshot_of_commit_ignores_working_directory() {
+    let repo = tempdir::TempDir::new("repo").unwrap();
+    let store_dir = tempdir::TempDir::new("store").unwrap();
+    let pool = Arc::new(ResettablePool::new("test-pool-".to_string()));
+    let store = Arc::new(Store::new(store_dir.path(), pool.clone()).unwrap());
+
+    git(repo.path(), &["init", "-q"]);
+    make_file(&repo.path().join("roland"), b"European Burmese", 0o600);
+    std::fs::create_dir(repo.path().join("cats")).unwrap();
+    make_file(&repo.path().join("cats").join("run"), b"#!/bin/sh", 0o700);
+    git(repo.path(), &["add", "."]);
+    git(repo.path(), &["commit", "-q", "-m", "Cats"]);
+    make_file(&repo.path().join("roland"), b"Modified", 0o600);
+    make_file(&repo.path().join("untracked"), b"", 0o600);
+
+    let tree = Arc::new(
+      GitTree::new(repo.path(), "HEAD", store.clone(), pool).unwrap(),
+    );
+    let mut path_stats = tree.path_stats();
+    path_stats.sort_by(|a, b| a.path().cmp(b.path()));
+    assert_eq!(
+      path_stats,
+      vec![
+        PathStat::file(
+          "cats/run".into(),
+          File {
+            path: "cats/run".into(),
+            is_executable: true,
+          },
+        ),
+        PathStat::file(
+          "roland".into(),
+          File {
+            path: "roland".into(),
+            is_executable: false,
+          },
+        ),
+      ]
+    );
+
+    let snapshot = Snapshot::from_path_stats(store.clone(), tree, path_stats)
+      .wait()
+      .unwrap();
+    let destination = tempdir::TempDir::new("materialized").unwrap();
+    snapshot
+      .materialize(store, destination.path().to_owned())
+      .wait()
+      .unwrap();
+    let mut contents = Vec::new();
+    std::fs::File::open(destination.path().join("roland"))
+      .unwrap()
+      .read_to_end(&mut contents)
+      .unwrap();
+    assert_eq!(contents, b"European Burmese".to_vec());
+  }
+
+  #[test]
+  fn unknown_treeish() {
+    let repo = tempdir::TempDir::new("repo").unwrap();
+    let store_dir = tempdir::TempDir::new("store").unwrap();
+    let pool = Arc::new(ResettablePool::new("test-pool-".to_string()));
+    let store = Arc::new(Store::new(store_dir.path(), pool.clone()).unwrap());
+    git(repo.path(), &["init", "-q"]);
+
+    GitTree::new(repo.path(), "no-such-branch", store, pool)
+      .err()
+      .expect("Want error");
+  }
+}
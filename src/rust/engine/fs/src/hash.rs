@@ -12,7 +12,11 @@ use hex;
 
 const FINGERPRINT_SIZE: usize = 32;
 
-#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+///
+/// A SHA-256 fingerprint. Fingerprints are ordered lexicographically by their bytes, which is also
+/// the order of their hex representations.
+///
+#[derive(Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Fingerprint(pub [u8; FINGERPRINT_SIZE]);
 
 impl Fingerprint {
@@ -263,4 +267,23 @@ mod fingerprint_tests {
       hex.to_lowercase()
     )
   }
+
+  #[test]
+  fn ordering_matches_hex() {
+    let mut hexes = vec![
+      "ff23456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+      "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+      "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdee",
+    ];
+    let mut fingerprints = hexes
+      .iter()
+      .map(|hex| Fingerprint::from_hex_string(hex).unwrap())
+      .collect::<Vec<_>>();
+    hexes.sort();
+    fingerprints.sort();
+    assert_eq!(
+      fingerprints.iter().map(|f| f.to_hex()).collect::<Vec<_>>(),
+      hexes
+    );
+  }
 }
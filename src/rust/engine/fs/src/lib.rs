@@ -1,34 +1,56 @@
 // Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
+mod git;
+pub use git::GitTree;
 mod hash;
 pub use hash::Fingerprint;
+mod multihash;
+pub use multihash::{AuxiliaryHasher, MultiHashDigester};
 mod snapshot;
-pub use snapshot::{GetFileDigest, Snapshot};
+pub use snapshot::{ContentClassifier, GetFileDigest, MaterializePlan, MergeStrategy, Snapshot,
+                   SnapshotBuilder, SnapshotInterner, SnapshotPatch, UnreadableFilePolicy};
 mod store;
-pub use store::{Digest, Store};
+pub use store::{Cursor, Digest, Durability, EnvInfo, EvictCallback, FileWriter, Store,
+                StoreEnvironment, StoreOptions, StoreReader, DATABASES_PER_STORE,
+                DEFAULT_MAX_DIRECTORY_DEPTH, MAX_BUFFERED_STORE_SIZE};
 mod pool;
 pub use pool::ResettablePool;
+mod sparse;
+mod storing;
+pub use storing::StoringDigester;
+mod lru;
+mod timer;
+mod verify;
+pub use verify::ChangeDetectingDigester;
 
 extern crate bazel_protos;
 extern crate boxfuture;
 extern crate digest;
+extern crate filetime;
 extern crate futures;
 extern crate futures_cpupool;
 extern crate glob;
+extern crate grpcio;
 extern crate hex;
 extern crate ignore;
 extern crate itertools;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
 extern crate lmdb;
+extern crate lmdb_sys;
 extern crate ordermap;
 extern crate protobuf;
+extern crate regex;
+#[macro_use]
+extern crate serde_json;
 extern crate sha2;
 extern crate tar;
 extern crate tempdir;
 
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
+use std::ffi::OsString;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Component, Path, PathBuf};
 use std::sync::{Arc, Mutex};
@@ -37,13 +59,14 @@ use std::io::{self, Read};
 use std::cmp::min;
 
 use futures::future::{self, Future};
+use futures::stream::{self, Stream};
 use futures_cpupool::CpuFuture;
-use glob::Pattern;
+use glob::{MatchOptions, Pattern};
 use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use ordermap::OrderMap;
 use tempdir::TempDir;
 
-use boxfuture::{Boxable, BoxFuture};
+use boxfuture::{Boxable, BoxFuture, BoxStream};
 use hash::WriterHasher;
 
 
@@ -302,11 +325,61 @@ impl PathGlob {
 #[derive(Debug)]
 pub struct PathGlobs {
   include: Vec<PathGlob>,
+  // The filespec which each of `include` was parsed from, so that errors can name it.
+  include_specs: Vec<String>,
   exclude: Arc<Gitignore>,
+  // The filespecs that `exclude` was built from, since a Gitignore cannot be inspected.
+  exclude_specs: Vec<String>,
+  // Filespecs which must each match at least one path, along with the PathGlobs they parse to.
+  required: Vec<(String, Vec<PathGlob>)>,
+  // If set, only files with one of these extensions (without their leading dots) are matched.
+  extensions: Option<HashSet<OsString>>,
+  // If set, expansion fails once it has matched more than this many paths.
+  max_entries: Option<usize>,
+  // If unset, wildcards do not match names starting with a dot. See `create_with_options`.
+  include_hidden: bool,
 }
 
 impl PathGlobs {
   pub fn create(include: &[String], exclude: &[String]) -> Result<PathGlobs, String> {
+    PathGlobs::create_requiring_matches(include, exclude, &[])
+  }
+
+  ///
+  /// As for `create`, but explicit about whether hidden files and directories (those whose names
+  /// start with a dot) are matched. `create` matches them, as though `include_hidden` were set.
+  ///
+  /// Unless `include_hidden` is set, wildcards (including `**`) never match a name starting with a
+  /// dot, and a hidden directory is not even listed unless a component which starts with a literal
+  /// dot (as in `.git/*`, `**/.hidden` or `.*`) names it, so large hidden trees cost nothing to
+  /// skip.
+  ///
+  pub fn create_with_options(
+    include: &[String],
+    exclude: &[String],
+    include_hidden: bool,
+  ) -> Result<PathGlobs, String> {
+    let mut path_globs = PathGlobs::create(include, exclude)?;
+    path_globs.include_hidden = include_hidden;
+    Ok(path_globs)
+  }
+
+  ///
+  /// As for `create`, but additionally includes the `must_match` filespecs, each of which must
+  /// match at least one path: if any of them match nothing, `expand` will fail with an error that
+  /// lists them. This catches typos in filespecs which would otherwise silently match nothing.
+  ///
+  /// Include and must_match filespecs which are empty, absolute, or use syntax which globs do not
+  /// support (negation with `!`, or brace alternatives) are rejected with an error naming the
+  /// filespec and its index, rather than silently matching nothing (or something unintended).
+  ///
+  pub fn create_requiring_matches(
+    include: &[String],
+    exclude: &[String],
+    must_match: &[String],
+  ) -> Result<PathGlobs, String> {
+    validate_filespecs("include", include)?;
+    validate_filespecs("must_match", must_match)?;
     let ignore_for_exclude = if exclude.is_empty() {
       EMPTY_IGNORE.clone()
     } else {
@@ -314,25 +387,184 @@ impl PathGlobs {
         format!("Could not parse glob excludes {:?}: {:?}", exclude, e)
       })?)
     };
+    let mut include_globs = Vec::new();
+    let mut include_specs = Vec::new();
+    for filespec in include {
+      let path_globs = PathGlob::create(&[filespec.clone()])?;
+      include_specs.extend(path_globs.iter().map(|_| filespec.clone()));
+      include_globs.extend(path_globs);
+    }
+    let mut required = Vec::new();
+    for filespec in must_match {
+      let path_globs = PathGlob::create(&[filespec.clone()])?;
+      include_specs.extend(path_globs.iter().map(|_| filespec.clone()));
+      include_globs.extend(path_globs.iter().cloned());
+      required.push((filespec.clone(), path_globs));
+    }
     Ok(PathGlobs {
-      include: PathGlob::create(include)?,
+      include: include_globs,
+      include_specs: include_specs,
       exclude: ignore_for_exclude,
+      exclude_specs: exclude.to_vec(),
+      required: required,
+      extensions: None,
+      max_entries: None,
+      include_hidden: true,
     })
   }
 
+  ///
+  /// Matches the files beneath each of the given root globs (which match directories, as in
+  /// `src/*/main`) which have any of the given extensions (with or without a leading dot).
+  ///
+  /// This is equivalent to a `<root>/**/*.<ext>` filespec for each pair of root and extension, but
+  /// each directory beneath the roots is listed once, and each file in it is matched by looking up
+  /// its extension, rather than by testing it against one pattern per extension.
+  ///
+  /// A file's extension is what follows the last dot in its name, so extensions which themselves
+  /// contain a dot (such as `tar.gz`) could never match, and are rejected.
+  ///
+  pub fn by_extensions(root_globs: &[String], exts: &[&str]) -> Result<PathGlobs, String> {
+    let mut extensions = HashSet::new();
+    for ext in exts {
+      let ext = ext.trim_left_matches('.');
+      if ext.is_empty() || ext.contains('/') || ext.contains('.') {
+        return Err(format!("Invalid file extension: {:?}", ext));
+      }
+      extensions.insert(OsString::from(ext));
+    }
+    let filespecs = root_globs
+      .iter()
+      .map(|root| {
+        let root = root.trim_right_matches('/');
+        if root.is_empty() {
+          "**/*".to_owned()
+        } else {
+          format!("{}/**/*", root)
+        }
+      })
+      .collect::<Vec<_>>();
+    let mut path_globs = PathGlobs::create(&filespecs, &[])?;
+    path_globs.extensions = Some(extensions);
+    Ok(path_globs)
+  }
+
   pub fn from_globs(include: Vec<PathGlob>) -> PathGlobs {
+    // There are no filespecs to name, so each glob names itself.
+    let include_specs = include
+      .iter()
+      .map(|path_glob| format!("{:?}", path_glob))
+      .collect();
     PathGlobs {
       include: include,
+      include_specs: include_specs,
       exclude: EMPTY_IGNORE.clone(),
+      exclude_specs: vec![],
+      required: vec![],
+      extensions: None,
+      max_entries: None,
+      include_hidden: true,
     }
   }
+
+  ///
+  /// Makes `expand` fail, naming the filespec being expanded and the limit, as soon as more than
+  /// `max_entries` paths have been matched (or, with None, never). This bounds the time and memory
+  /// spent on a glob (such as a misplaced `**`) which matches far more than was intended.
+  ///
+  pub fn with_max_entries(mut self, max_entries: Option<usize>) -> PathGlobs {
+    self.max_entries = max_entries;
+    self
+  }
+
+  ///
+  /// Returns a deterministic encoding of these PathGlobs, for use in cache keys. Includes are
+  /// encoded in their parsed form, so PathGlobs which differ only in the order or repetition of
+  /// their include filespecs, or in redundant components (such as `./` or repeated `**`), are
+  /// encoded identically.
+  ///
+  /// Excludes are gitignore patterns, in which a later pattern (such as a `!` negation) overrides
+  /// an earlier one, so they are encoded in order. Only the earlier copies of a repeated exclude
+  /// are dropped, since the last copy is the one which takes effect.
+  ///
+  pub fn canonical_string(&self) -> String {
+    let include = self
+      .include
+      .iter()
+      .map(|path_glob| format!("{:?}", path_glob))
+      .collect::<BTreeSet<_>>();
+    let mut seen = HashSet::new();
+    let mut exclude = self
+      .exclude_specs
+      .iter()
+      .rev()
+      .filter(|spec| seen.insert(*spec))
+      .collect::<Vec<_>>();
+    exclude.reverse();
+    let required = self
+      .required
+      .iter()
+      .map(|&(_, ref path_globs)| format!("{:?}", path_globs))
+      .collect::<BTreeSet<_>>();
+    let extensions = self.extensions.as_ref().map(|extensions| {
+      extensions.iter().collect::<BTreeSet<_>>()
+    });
+    format!(
+      "include={:?};exclude={:?};required={:?};extensions={:?};max_entries={:?};\
+       include_hidden={}",
+      include,
+      exclude,
+      required,
+      extensions,
+      self.max_entries,
+      self.include_hidden
+    )
+  }
+}
+
+fn validate_filespecs(kind: &str, filespecs: &[String]) -> Result<(), String> {
+  for (index, filespec) in filespecs.iter().enumerate() {
+    let problem = if Path::new(filespec)
+      .components()
+      .all(|component| component == Component::CurDir)
+    {
+      Some("it is empty")
+    } else if filespec.starts_with('/') {
+      Some("absolute paths are not supported")
+    } else if filespec.starts_with('!') {
+      Some("negation is only supported in excludes")
+    } else if filespec.contains('{') || filespec.contains('}') {
+      Some("brace alternatives are not supported")
+    } else {
+      None
+    };
+    if let Some(problem) = problem {
+      return Err(format!(
+        "Invalid {} filespec {:?} at index {}: {}",
+        kind,
+        filespec,
+        index,
+        problem
+      ));
+    }
+    PathGlob::create(&[filespec.clone()]).map_err(|e| {
+      format!(
+        "Invalid {} filespec {:?} at index {}: {}",
+        kind,
+        filespec,
+        index,
+        e
+      )
+    })?;
+  }
+  Ok(())
 }
 
 #[derive(Debug)]
 struct PathGlobsExpansion<T: Sized> {
   context: T,
-  // Globs that have yet to be expanded, in order.
-  todo: Vec<PathGlob>,
+  // Globs that have yet to be expanded, in order, with the filespecs they were derived from.
+  todo: Vec<(PathGlob, Arc<String>)>,
   // Paths to exclude.
   exclude: Arc<Gitignore>,
   // Globs that have already been expanded.
@@ -361,6 +593,25 @@ fn is_ignored(ignore: &Gitignore, stat: &Stat) -> bool {
   }
 }
 
+///
+/// What to do when scanning a directory which contains special files (such as FIFOs, sockets and
+/// device files), which cannot be represented as a PathStat.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SpecialFilePolicy {
+  // Leave special files out of directory listings (and so out of expansions), recording them in
+  // `PosixFS::skipped_special_files`.
+  Skip,
+  // Fail to scan any directory which contains a special file.
+  ErrorOnSpecial,
+}
+
+impl Default for SpecialFilePolicy {
+  fn default() -> SpecialFilePolicy {
+    SpecialFilePolicy::Skip
+  }
+}
+
 ///
 /// All Stats consumed or return by this type are relative to the root.
 ///
@@ -368,6 +619,9 @@ pub struct PosixFS {
   root: Dir,
   pool: Arc<ResettablePool>,
   ignore: Gitignore,
+  special_files: SpecialFilePolicy,
+  // Special files which have been left out of scans under SpecialFilePolicy::Skip.
+  skipped_special_files: Arc<Mutex<Vec<PathBuf>>>,
 }
 
 impl PosixFS {
@@ -375,6 +629,20 @@ impl PosixFS {
     root: P,
     pool: Arc<ResettablePool>,
     ignore_patterns: Vec<String>,
+  ) -> Result<PosixFS, String> {
+    PosixFS::new_with_special_file_policy(
+      root,
+      pool,
+      ignore_patterns,
+      SpecialFilePolicy::default(),
+    )
+  }
+
+  pub fn new_with_special_file_policy<P: AsRef<Path>>(
+    root: P,
+    pool: Arc<ResettablePool>,
+    ignore_patterns: Vec<String>,
+    special_files: SpecialFilePolicy,
   ) -> Result<PosixFS, String> {
     let root: &Path = root.as_ref();
     let canonical_root = root
@@ -406,26 +674,54 @@ impl PosixFS {
       root: canonical_root,
       pool: pool,
       ignore: ignore,
+      special_files: special_files,
+      skipped_special_files: Arc::new(Mutex::new(Vec::new())),
     })
   }
 
-  fn scandir_sync(root: PathBuf, dir_relative_to_root: Dir) -> Result<Vec<Stat>, io::Error> {
+  ///
+  /// Returns the paths (relative to the root) of the special files which scans have skipped so
+  /// far, in sorted order, so that callers can warn about them.
+  ///
+  pub fn skipped_special_files(&self) -> Vec<PathBuf> {
+    let mut skipped = self.skipped_special_files.lock().unwrap().clone();
+    skipped.sort();
+    skipped.dedup();
+    skipped
+  }
+
+  ///
+  /// Returns the Stats in the given directory, and the special files which were skipped.
+  ///
+  fn scandir_sync(
+    root: PathBuf,
+    dir_relative_to_root: Dir,
+    special_files: SpecialFilePolicy,
+  ) -> Result<(Vec<Stat>, Vec<PathBuf>), io::Error> {
     let dir_abs = root.join(&dir_relative_to_root.0);
-    let mut stats: Vec<Stat> = dir_abs
-      .read_dir()?
-      .map(|readdir| {
-        let dir_entry = readdir?;
-        let get_metadata = || std::fs::metadata(dir_abs.join(dir_entry.file_name()));
-        PosixFS::stat_internal(
-          dir_relative_to_root.0.join(dir_entry.file_name()),
-          dir_entry.file_type()?,
-          &dir_abs,
-          get_metadata,
-        )
-      })
-      .collect::<Result<Vec<_>, io::Error>>()?;
+    let mut stats: Vec<Stat> = Vec::new();
+    let mut skipped = Vec::new();
+    for readdir in dir_abs.read_dir()? {
+      let dir_entry = readdir?;
+      let path = dir_relative_to_root.0.join(dir_entry.file_name());
+      let file_type = dir_entry.file_type()?;
+      // Special files are only ever listed, never opened, so a FIFO cannot block the scan.
+      if !(file_type.is_dir() || file_type.is_file() || file_type.is_symlink()) &&
+        special_files == SpecialFilePolicy::Skip
+      {
+        skipped.push(path);
+        continue;
+      }
+      let get_metadata = || std::fs::metadata(dir_abs.join(dir_entry.file_name()));
+      stats.push(PosixFS::stat_internal(
+        path,
+        file_type,
+        &dir_abs,
+        get_metadata,
+      )?);
+    }
     stats.sort_by(|s1, s2| s1.path().cmp(s2.path()));
-    Ok(stats)
+    Ok((stats, skipped))
   }
 
   pub fn is_ignored(&self, stat: &Stat) -> bool {
@@ -447,6 +743,48 @@ impl PosixFS {
       .to_boxed()
   }
 
+  ///
+  /// As for `read_file`, but only files of up to `MAX_BUFFERED_READ_SIZE` bytes are read into
+  /// memory at once: larger files are read as a stream of chunks of at most `READ_CHUNK_SIZE`
+  /// bytes, so that they can be hashed or copied incrementally.
+  ///
+  /// A streamed file's length is taken when it is opened, and exactly that many bytes are read,
+  /// so a file which is truncated while it is being streamed fails with `UnexpectedEof`.
+  ///
+  pub fn read_file_stream(&self, file: &File) -> BoxFuture<FileContentStream, io::Error> {
+    let path = file.path.clone();
+    let path_abs = self.root.0.join(&file.path);
+    let pool = self.pool.clone();
+    self
+      .pool
+      .spawn_fn(move || -> Result<_, io::Error> {
+        let mut f = std::fs::File::open(&path_abs)?;
+        let len = f.metadata()?.len();
+        if len <= MAX_BUFFERED_READ_SIZE {
+          let mut content = Vec::with_capacity(len as usize);
+          f.read_to_end(&mut content)?;
+          return Ok(FileContentStream::Buffered(FileContent { path, content }));
+        }
+        let chunks = stream::unfold((f, len), move |(mut f, remaining)| {
+          if remaining == 0 {
+            return None;
+          }
+          Some(pool.spawn_fn(move || -> Result<_, io::Error> {
+            let mut chunk = vec![0; min(remaining, READ_CHUNK_SIZE as u64) as usize];
+            f.read_exact(&mut chunk)?;
+            let remaining = remaining - chunk.len() as u64;
+            Ok((chunk, (f, remaining)))
+          }))
+        });
+        Ok(FileContentStream::Chunked {
+          path: path,
+          len: len,
+          chunks: Box::new(chunks),
+        })
+      })
+      .to_boxed()
+  }
+
   pub fn read_link(&self, link: &Link) -> BoxFuture<PathBuf, io::Error> {
     let link_parent = link.0.parent().map(|p| p.to_owned());
     let link_abs = self.root.0.join(link.0.as_path()).to_owned();
@@ -541,9 +879,16 @@ impl PosixFS {
   pub fn scandir(&self, dir: &Dir) -> BoxFuture<Vec<Stat>, io::Error> {
     let dir = dir.to_owned();
     let root = self.root.0.clone();
+    let special_files = self.special_files;
+    let skipped_special_files = self.skipped_special_files.clone();
     self
       .pool
-      .spawn_fn(move || PosixFS::scandir_sync(root, dir))
+      .spawn_fn(move || {
+        PosixFS::scandir_sync(root, dir, special_files).map(|(stats, skipped)| {
+          skipped_special_files.lock().unwrap().extend(skipped);
+          stats
+        })
+      })
       .to_boxed()
   }
 }
@@ -617,10 +962,18 @@ pub trait VFS<E: Send + Sync + 'static>: Clone + Send + Sync + 'static {
     symbolic_path: PathBuf,
     wildcard: Pattern,
     exclude: &Arc<Gitignore>,
+    include_hidden: bool,
   ) -> BoxFuture<Vec<PathStat>, E> {
     // List the directory.
     let context = self.clone();
     let exclude = exclude.clone();
+    // Unless hidden names are included, they must be matched by a literal leading dot. Since Dirs
+    // are only listed once matched, this also prunes hidden directories from `**`.
+    let match_options = MatchOptions {
+      case_sensitive: true,
+      require_literal_separator: false,
+      require_literal_leading_dot: !include_hidden,
+    };
 
     self
       .scandir(canonical_dir)
@@ -634,7 +987,9 @@ pub trait VFS<E: Send + Sync + 'static>: Clone + Send + Sync + 'static {
               stat
                 .path()
                 .file_name()
-                .map(|file_name| wildcard.matches_path(Path::new(file_name)))
+                .map(|file_name| {
+                  wildcard.matches_path_with(Path::new(file_name), &match_options)
+                })
                 .unwrap_or(false)
             })
             .filter_map(|stat| {
@@ -677,15 +1032,177 @@ pub trait VFS<E: Send + Sync + 'static>: Clone + Send + Sync + 'static {
   ///
   /// Recursively expands PathGlobs into PathStats while applying excludes.
   ///
+  /// Each PathStat is returned at most once, even if it was matched by multiple (overlapping)
+  /// globs, in the order in which it was first matched. Callers need not deduplicate the result
+  /// before passing it to (for example) `Snapshot::from_path_stats`.
+  ///
+  /// Fails if any of the PathGlobs' required filespecs did not match any paths.
+  ///
   fn expand(&self, path_globs: PathGlobs) -> BoxFuture<Vec<PathStat>, E> {
-    if path_globs.include.is_empty() {
+    let PathGlobs {
+      include,
+      include_specs,
+      exclude,
+      required,
+      extensions,
+      max_entries,
+      include_hidden,
+      ..
+    } = path_globs;
+    let expanded = self.expand_globs(
+      include,
+      include_specs,
+      exclude.clone(),
+      max_entries,
+      include_hidden,
+    );
+    let expanded = match extensions {
+      None => expanded,
+      Some(extensions) => {
+        expanded
+          .map(move |path_stats| {
+            path_stats
+              .into_iter()
+              .filter(|path_stat| match path_stat {
+                &PathStat::File { ref path, .. } => {
+                  path
+                    .extension()
+                    .map(|ext| extensions.contains(ext))
+                    .unwrap_or(false)
+                }
+                &PathStat::Dir { .. } => false,
+              })
+              .collect()
+          })
+          .to_boxed()
+      }
+    };
+    if required.is_empty() {
+      return expanded;
+    }
+
+    // Expand each required filespec independently, to determine whether it matched anything.
+    let context = self.clone();
+    let checks = future::join_all(
+      required
+        .into_iter()
+        .map(|(filespec, globs)| {
+          let specs = vec![filespec.clone(); globs.len()];
+          context
+            .expand_globs(globs, specs, exclude.clone(), max_entries, include_hidden)
+            .map(move |path_stats| (filespec, path_stats.is_empty()))
+        })
+        .collect::<Vec<_>>(),
+    );
+    expanded
+      .join(checks)
+      .and_then(|(path_stats, checks)| {
+        let unmatched = checks
+          .into_iter()
+          .filter(|&(_, is_empty)| is_empty)
+          .map(|(filespec, _)| filespec)
+          .collect::<Vec<_>>();
+        if unmatched.is_empty() {
+          Ok(path_stats)
+        } else {
+          Err(Self::mk_error(&format!(
+            "Globs did not match any files: {:?}",
+            unmatched
+          )))
+        }
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Expands each of the given PathGlobs, and returns only the PathStats which all of them matched,
+  /// in the order in which the first PathGlobs matched them. Each PathGlobs applies its own
+  /// excludes and required filespecs.
+  ///
+  /// This allows independently defined sets of globs (for example, "all rust files" and "everything
+  /// under src") to be combined without writing a single pattern which expresses both.
+  ///
+  fn expand_all_of(&self, path_globs: Vec<PathGlobs>) -> BoxFuture<Vec<PathStat>, E> {
+    if path_globs.is_empty() {
+      return future::ok(vec![]).to_boxed();
+    }
+    future::join_all(
+      path_globs
+        .into_iter()
+        .map(|pg| self.expand(pg))
+        .collect::<Vec<_>>(),
+    ).map(|mut expansions| {
+      let first = expansions.remove(0);
+      let rest = expansions
+        .into_iter()
+        .map(|path_stats| path_stats.into_iter().collect::<HashSet<_>>())
+        .collect::<Vec<_>>();
+      first
+        .into_iter()
+        .filter(|path_stat| rest.iter().all(|others| others.contains(path_stat)))
+        .collect()
+    })
+      .to_boxed()
+  }
+
+  ///
+  /// Reports which files beneath the root are not matched by the given PathGlobs, which helps to
+  /// check that a spec captures everything it was intended to. Files which are ignored by this VFS
+  /// are not considered to be present.
+  ///
+  fn coverage(&self, path_globs: PathGlobs) -> BoxFuture<CoverageReport, E> {
+    let everything = PathGlobs::create(&["**".to_owned()], &[]).expect("`**` is a valid glob.");
+    self
+      .expand(everything)
+      .join(self.expand(path_globs))
+      .map(|(all, matched)| {
+        let matched = matched
+          .into_iter()
+          .map(|path_stat| path_stat.path().to_owned())
+          .collect::<HashSet<_>>();
+        let mut unmatched = all
+          .into_iter()
+          .filter_map(|path_stat| match path_stat {
+            PathStat::File { path, .. } => {
+              if matched.contains(&path) {
+                None
+              } else {
+                Some(path)
+              }
+            }
+            PathStat::Dir { .. } => None,
+          })
+          .collect::<Vec<_>>();
+        unmatched.sort();
+        CoverageReport { unmatched: unmatched }
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Expands the given PathGlobs, failing if more than `max_entries` (if set) paths are matched,
+  /// with an error naming the filespec (from the parallel `include_specs`) whose glob exceeded the
+  /// limit. Unless `include_hidden` is set, wildcards do not match names starting with a dot.
+  ///
+  fn expand_globs(
+    &self,
+    include: Vec<PathGlob>,
+    include_specs: Vec<String>,
+    exclude: Arc<Gitignore>,
+    max_entries: Option<usize>,
+    include_hidden: bool,
+  ) -> BoxFuture<Vec<PathStat>, E> {
+    if include.is_empty() {
       return future::ok(vec![]).to_boxed();
     }
 
     let init = PathGlobsExpansion {
       context: self.clone(),
-      todo: path_globs.include,
-      exclude: path_globs.exclude,
+      todo: include
+        .into_iter()
+        .zip(include_specs.into_iter().map(Arc::new))
+        .collect(),
+      exclude: exclude,
       completed: HashSet::default(),
       outputs: OrderMap::default(),
     };
@@ -697,24 +1214,40 @@ pub trait VFS<E: Send + Sync + 'static>: Clone + Send + Sync + 'static {
         expansion
           .todo
           .drain(..)
-          .map(|path_glob| context.expand_single(path_glob, exclude))
+          .map(|(path_glob, filespec)| {
+            context.expand_single(path_glob, exclude, include_hidden).map(
+              move |expanded| (filespec, expanded),
+            )
+          })
           .collect::<Vec<_>>()
       });
-      round.map(move |paths_and_globs| {
+      round.and_then(move |paths_and_globs| {
         // Collect distinct new PathStats and PathGlobs
-        for (paths, globs) in paths_and_globs.into_iter() {
+        for (filespec, (paths, globs)) in paths_and_globs.into_iter() {
           expansion.outputs.extend(paths.into_iter().map(|p| (p, ())));
+          if let Some(max_entries) = max_entries {
+            if expansion.outputs.len() > max_entries {
+              return Err(Self::mk_error(&format!(
+                "Expanding filespec {:?} matched more than the limit of {} entries",
+                filespec,
+                max_entries
+              )));
+            }
+          }
           let completed = &mut expansion.completed;
-          expansion.todo.extend(globs.into_iter().filter(|pg| {
-            completed.insert(pg.clone())
-          }));
+          expansion.todo.extend(
+            globs
+              .into_iter()
+              .filter(|pg| completed.insert(pg.clone()))
+              .map(|pg| (pg, filespec.clone())),
+          );
         }
 
         // If there were any new PathGlobs, continue the expansion.
         if expansion.todo.is_empty() {
-          future::Loop::Break(expansion)
+          Ok(future::Loop::Break(expansion))
         } else {
-          future::Loop::Continue(expansion)
+          Ok(future::Loop::Continue(expansion))
         }
       })
     }).map(|expansion| {
@@ -737,16 +1270,17 @@ pub trait VFS<E: Send + Sync + 'static>: Clone + Send + Sync + 'static {
     &self,
     path_glob: PathGlob,
     exclude: &Arc<Gitignore>,
+    include_hidden: bool,
   ) -> BoxFuture<(Vec<PathStat>, Vec<PathGlob>), E> {
     match path_glob {
       PathGlob::Wildcard { canonical_dir, symbolic_path, wildcard } =>
         // Filter directory listing to return PathStats, with no continuation.
-        self.directory_listing(canonical_dir, symbolic_path, wildcard, exclude)
+        self.directory_listing(canonical_dir, symbolic_path, wildcard, exclude, include_hidden)
           .map(|path_stats| (path_stats, vec![]))
           .to_boxed(),
       PathGlob::DirWildcard { canonical_dir, symbolic_path, wildcard, remainder } =>
         // Filter directory listing and request additional PathGlobs for matched Dirs.
-        self.directory_listing(canonical_dir, symbolic_path, wildcard, exclude)
+        self.directory_listing(canonical_dir, symbolic_path, wildcard, exclude, include_hidden)
           .and_then(move |path_stats| {
             path_stats.into_iter()
               .filter_map(|ps| match ps {
@@ -771,6 +1305,17 @@ pub trait VFS<E: Send + Sync + 'static>: Clone + Send + Sync + 'static {
   }
 }
 
+///
+/// The result of `VFS::coverage`.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CoverageReport {
+  ///
+  /// The paths of files which were present but not matched, sorted.
+  ///
+  pub unmatched: Vec<PathBuf>,
+}
+
 pub struct FileContent {
   pub path: PathBuf,
   pub content: Vec<u8>,
@@ -795,6 +1340,56 @@ impl fmt::Debug for FileContent {
   }
 }
 
+///
+/// The largest file which `PosixFS::read_file_stream` reads into memory at once.
+///
+pub const MAX_BUFFERED_READ_SIZE: u64 = 1024 * 1024;
+
+///
+/// The largest chunk in which `PosixFS::read_file_stream` streams a larger file.
+///
+pub const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+///
+/// The contents of a file read by `PosixFS::read_file_stream`: either buffered whole, for a small
+/// file, or a stream of the chunks of a large file, which has the given length in bytes.
+///
+pub enum FileContentStream {
+  Buffered(FileContent),
+  Chunked {
+    path: PathBuf,
+    len: u64,
+    chunks: BoxStream<Vec<u8>, io::Error>,
+  },
+}
+
+impl FileContentStream {
+  pub fn path(&self) -> &Path {
+    match self {
+      &FileContentStream::Buffered(ref content) => &content.path,
+      &FileContentStream::Chunked { ref path, .. } => path,
+    }
+  }
+
+  ///
+  /// Reads the whole of a chunked file into memory, or returns a buffered one unchanged.
+  ///
+  pub fn into_file_content(self) -> BoxFuture<FileContent, io::Error> {
+    match self {
+      FileContentStream::Buffered(content) => future::ok(content).to_boxed(),
+      FileContentStream::Chunked { path, len, chunks } => {
+        chunks
+          .fold(Vec::with_capacity(len as usize), |mut content, chunk| {
+            content.extend_from_slice(&chunk);
+            future::ok::<_, io::Error>(content)
+          })
+          .map(move |content| FileContent { path, content })
+          .to_boxed()
+      }
+    }
+  }
+}
+
 // Like std::fs::create_dir_all, except handles concurrent calls among multiple
 // threads or processes. Originally lifted from rustc.
 pub fn safe_create_dir_all_ioerror(path: &Path) -> Result<(), io::Error> {
@@ -828,6 +1423,23 @@ fn safe_create_tmpdir_in(base_dir: &Path, prefix: &str) -> Result<TempDir, Strin
   })?)
 }
 
+///
+/// Which modification times the entries of a Snapshot's tar archive record.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArchiveTimestamps {
+  ///
+  /// Every entry records the Unix epoch (with normalized ownership and permissions), so that
+  /// archives of the same paths and contents are byte-identical whenever they are created.
+  ///
+  Epoch,
+  ///
+  /// Entries record the modification times (and ownership and permissions) of the files on disk.
+  /// Archives, and so their fingerprints, then differ whenever any of those differ.
+  ///
+  Recorded,
+}
+
 ///
 /// A facade for the snapshot directory, which lives under the pants workdir.
 ///
@@ -902,9 +1514,13 @@ impl Snapshots {
     dest: W,
     paths: &Vec<PathStat>,
     relative_to: &Dir,
+    timestamps: ArchiveTimestamps,
   ) -> Result<W, String> {
     let mut tar_builder = tar::Builder::new(dest);
-    tar_builder.mode(tar::HeaderMode::Deterministic);
+    tar_builder.mode(match timestamps {
+      ArchiveTimestamps::Epoch => tar::HeaderMode::Deterministic,
+      ArchiveTimestamps::Recorded => tar::HeaderMode::Complete,
+    });
     for path_stat in paths {
       // Append the PathStat using the symbolic name and underlying stat.
       let append_res = match path_stat {
@@ -938,6 +1554,7 @@ impl Snapshots {
     dest: &Path,
     paths: &Vec<PathStat>,
     relative_to: &Dir,
+    timestamps: ArchiveTimestamps,
   ) -> Result<Fingerprint, String> {
     // Wrap buffering around a fingerprinted stream above a File.
     let stream = io::BufWriter::new(WriterHasher::new(fs::File::create(dest).map_err(|e| {
@@ -946,7 +1563,7 @@ impl Snapshots {
 
     // Then append the tar to the stream, and retrieve the Fingerprint to flush all writers.
     Ok(
-      Snapshots::tar_create(stream, paths, relative_to)?
+      Snapshots::tar_create(stream, paths, relative_to, timestamps)?
         .into_inner()
         .map_err(|e| {
           format!("Failed to flush to {:?}: {:?}", dest, e.error())
@@ -993,9 +1610,22 @@ impl Snapshots {
   }
 
   ///
-  /// Creates a Snapshot for the given paths under the given VFS.
+  /// Creates a Snapshot for the given paths under the given VFS. The archive records the Unix epoch
+  /// as every entry's modification time, so that it is reproducible.
   ///
   pub fn create(&self, fs: &PosixFS, paths: Vec<PathStat>) -> CpuFuture<Snapshot, String> {
+    self.create_with_timestamps(fs, paths, ArchiveTimestamps::Epoch)
+  }
+
+  ///
+  /// As for `create`, but with the given choice of modification times for the archive's entries.
+  ///
+  pub fn create_with_timestamps(
+    &self,
+    fs: &PosixFS,
+    paths: Vec<PathStat>,
+    timestamps: ArchiveTimestamps,
+  ) -> CpuFuture<Snapshot, String> {
     let dest_dir = self.snapshot_path().to_owned();
     let root = fs.root.clone();
     let temp_path = self.next_temp_path().expect(
@@ -1004,7 +1634,8 @@ impl Snapshots {
 
     fs.pool.spawn_fn(move || {
       // Write the tar deterministically to a temporary file while fingerprinting.
-      let fingerprint = Snapshots::tar_create_fingerprinted(temp_path.as_path(), &paths, &root)?;
+      let fingerprint =
+        Snapshots::tar_create_fingerprinted(temp_path.as_path(), &paths, &root, timestamps)?;
 
       // Rename to the final path if it does not already exist.
       Snapshots::finalize(
@@ -1025,12 +1656,12 @@ impl Snapshots {
 
     // Zip the in-memory Snapshot to the on disk representation, validating as we go.
     let mut files_content = Vec::new();
-    for (entry_res, path_stat) in archive.entries()?.zip(snapshot.path_stats.into_iter()) {
+    for (entry_res, path_stat) in archive.entries()?.zip(snapshot.path_stats().iter()) {
       let mut entry = entry_res?;
       if entry.header().entry_type() == tar::EntryType::file() {
         let path = match path_stat {
-          PathStat::File { path, .. } => path,
-          PathStat::Dir { .. } => panic!("Snapshot contents changed after storage."),
+          &PathStat::File { ref path, .. } => path.clone(),
+          &PathStat::Dir { .. } => panic!("Snapshot contents changed after storage."),
         };
         let mut content = Vec::new();
         io::Read::read_to_end(&mut entry, &mut content)?;
@@ -1063,9 +1694,12 @@ mod posixfs_test {
   extern crate tempdir;
   extern crate testutil;
 
-  use super::{Dir, File, Link, PosixFS, Stat, ResettablePool};
-  use futures::Future;
-  use self::testutil::make_file;
+  use super::{ArchiveTimestamps, CoverageReport, Dir, File, FileContentStream, Link, PathGlobs,
+              PathStat, PosixFS, Snapshots, Stat, ResettablePool, SpecialFilePolicy, VFS,
+              MAX_BUFFERED_READ_SIZE, READ_CHUNK_SIZE};
+  use filetime::{self, FileTime};
+  use futures::{Future, Stream};
+  use self::testutil::{make_file, owned_string_vec};
   use std;
   use std::path::{Path, PathBuf};
   use std::sync::Arc;
@@ -1104,6 +1738,47 @@ mod posixfs_test {
     assert_eq!(file_content.content, content);
   }
 
+  #[test]
+  fn read_file_stream_chunks_large_files() {
+    let dir = tempdir::TempDir::new("posixfs").unwrap();
+    let small = "cute".as_bytes().to_vec();
+    let large = (0..MAX_BUFFERED_READ_SIZE + 1)
+      .map(|i| i as u8)
+      .collect::<Vec<_>>();
+    make_file(&dir.path().join("marmosets"), &small, 0o600);
+    make_file(&dir.path().join("gorillas"), &large, 0o600);
+    let fs = new_posixfs(&dir.path());
+    let read = |name: &str| {
+      fs.read_file_stream(&File {
+        path: PathBuf::from(name),
+        is_executable: false,
+      }).wait()
+        .unwrap()
+    };
+
+    match read("marmosets") {
+      FileContentStream::Buffered(file_content) => {
+        assert_eq!(file_content.path, PathBuf::from("marmosets"));
+        assert_eq!(file_content.content, small);
+      }
+      FileContentStream::Chunked { .. } => panic!("Small file should have been buffered"),
+    }
+    match read("gorillas") {
+      FileContentStream::Chunked { path, len, chunks } => {
+        assert_eq!(path, PathBuf::from("gorillas"));
+        assert_eq!(len, large.len() as u64);
+        let chunks = chunks.collect().wait().unwrap();
+        assert!(chunks.iter().all(|chunk| chunk.len() <= READ_CHUNK_SIZE));
+        assert_eq!(chunks.concat(), large);
+      }
+      FileContentStream::Buffered(_) => panic!("Large file should have been streamed"),
+    }
+    assert_eq!(
+      read("gorillas").into_file_content().wait().unwrap().content,
+      large
+    );
+  }
+
   #[test]
   fn read_file_missing() {
     let dir = tempdir::TempDir::new("posixfs").unwrap();
@@ -1258,6 +1933,396 @@ mod posixfs_test {
       .expect_err("Want error");
   }
 
+  #[test]
+  fn scandir_special_files() {
+    let dir = tempdir::TempDir::new("posixfs").unwrap();
+    make_file(&dir.path().join("marmoset"), &[], 0o600);
+    let status = std::process::Command::new("mkfifo")
+      .arg(dir.path().join("pipe"))
+      .status()
+      .unwrap();
+    assert!(status.success());
+
+    let skipping_fs = Arc::new(new_posixfs(&dir.path()));
+    let marmoset = PathStat::file(
+      PathBuf::from("marmoset"),
+      File {
+        path: PathBuf::from("marmoset"),
+        is_executable: false,
+      },
+    );
+    assert_eq!(
+      skipping_fs
+        .expand(PathGlobs::create(&["*".to_owned()], &[]).unwrap())
+        .wait()
+        .unwrap(),
+      vec![marmoset]
+    );
+    assert_eq!(
+      skipping_fs.skipped_special_files(),
+      vec![PathBuf::from("pipe")]
+    );
+
+    let erroring_fs = PosixFS::new_with_special_file_policy(
+      dir.path(),
+      Arc::new(ResettablePool::new("test-pool-".to_string())),
+      vec![],
+      SpecialFilePolicy::ErrorOnSpecial,
+    ).unwrap();
+    erroring_fs
+      .scandir(&Dir(PathBuf::from(".")))
+      .wait()
+      .expect_err("Want error");
+    assert_eq!(erroring_fs.skipped_special_files(), Vec::<PathBuf>::new());
+  }
+
+  #[test]
+  fn expand_required_filespec_matched() {
+    let dir = tempdir::TempDir::new("posixfs").unwrap();
+    make_file(&dir.path().join("marmosets"), &[], 0o600);
+    let posix_fs = Arc::new(new_posixfs(&dir.path()));
+    let path_globs =
+      PathGlobs::create_requiring_matches(&[], &[], &["marmosets".to_owned()]).unwrap();
+    assert_eq!(
+      posix_fs.expand(path_globs).wait().unwrap(),
+      vec![
+        PathStat::file(
+          PathBuf::from("marmosets"),
+          File {
+            path: PathBuf::from("marmosets"),
+            is_executable: false,
+          }
+        ),
+      ]
+    );
+  }
+
+  #[test]
+  fn expand_required_filespec_unmatched() {
+    let dir = tempdir::TempDir::new("posixfs").unwrap();
+    make_file(&dir.path().join("marmosets"), &[], 0o600);
+    let posix_fs = Arc::new(new_posixfs(&dir.path()));
+    let path_globs = PathGlobs::create_requiring_matches(
+      &["*".to_owned()],
+      &[],
+      &["marmosets".to_owned(), "lemurs/*".to_owned()],
+    ).unwrap();
+    let err = posix_fs.expand(path_globs).wait().expect_err("Want error");
+    let message = format!("{}", err);
+    assert!(message.contains("\"lemurs/*\""), message);
+    assert!(!message.contains("\"marmosets\""), message);
+  }
+
+  #[test]
+  fn expand_overlapping_globs_is_distinct() {
+    let dir = tempdir::TempDir::new("posixfs").unwrap();
+    std::fs::create_dir(dir.path().join("src")).unwrap();
+    make_file(&dir.path().join("src").join("lemurs.rs"), &[], 0o600);
+    let posix_fs = Arc::new(new_posixfs(&dir.path()));
+    let path_globs = PathGlobs::create(
+      &["**/*.rs".to_owned(), "src/**/*".to_owned(), "src/lemurs.rs".to_owned()],
+      &[],
+    ).unwrap();
+    assert_eq!(
+      posix_fs.expand(path_globs).wait().unwrap(),
+      vec![
+        PathStat::file(
+          PathBuf::from("src/lemurs.rs"),
+          File {
+            path: PathBuf::from("src/lemurs.rs"),
+            is_executable: false,
+          }
+        ),
+      ]
+    );
+  }
+
+  #[test]
+  fn expand_all_of_intersects() {
+    let dir = tempdir::TempDir::new("posixfs").unwrap();
+    make_file(&dir.path().join("marmosets.rs"), &[], 0o600);
+    make_file(&dir.path().join("marmosets.py"), &[], 0o600);
+    std::fs::create_dir(dir.path().join("src")).unwrap();
+    make_file(&dir.path().join("src").join("lemurs.rs"), &[], 0o600);
+    make_file(&dir.path().join("src").join("lemurs.py"), &[], 0o600);
+    let posix_fs = Arc::new(new_posixfs(&dir.path()));
+    let rust_files = PathGlobs::create(&["**/*.rs".to_owned()], &[]).unwrap();
+    let under_src = PathGlobs::create(&["src/**/*".to_owned()], &[]).unwrap();
+    assert_eq!(
+      posix_fs
+        .expand_all_of(vec![rust_files, under_src])
+        .wait()
+        .unwrap(),
+      vec![
+        PathStat::file(
+          PathBuf::from("src/lemurs.rs"),
+          File {
+            path: PathBuf::from("src/lemurs.rs"),
+            is_executable: false,
+          }
+        ),
+      ]
+    );
+  }
+
+  #[test]
+  fn canonical_string_ignores_order_and_redundancy() {
+    let canonical = |include: &[&str], exclude: &[&str]| {
+      PathGlobs::create(&owned_string_vec(include), &owned_string_vec(exclude))
+        .unwrap()
+        .canonical_string()
+    };
+    assert_eq!(
+      canonical(&["src/**/*.rs", "*.toml"], &["target"]),
+      canonical(&["./*.toml", "src/**/**/*.rs", "*.toml"], &["target"])
+    );
+    assert!(
+      canonical(&["*.toml"], &["target"]) != canonical(&["*.toml"], &["target", "*.lock"])
+    );
+    assert!(canonical(&["*.toml"], &[]) != canonical(&["*.rs"], &[]));
+  }
+
+  #[test]
+  fn canonical_string_preserves_exclude_order() {
+    let canonical = |exclude: &[&str]| {
+      PathGlobs::create(&owned_string_vec(&["**"]), &owned_string_vec(exclude))
+        .unwrap()
+        .canonical_string()
+    };
+    // A negation only re-includes what an earlier pattern excluded.
+    assert!(canonical(&["*.rs", "!main.rs"]) != canonical(&["!main.rs", "*.rs"]));
+    assert_eq!(
+      canonical(&["*.rs", "!main.rs", "*.rs"]),
+      canonical(&["!main.rs", "*.rs"])
+    );
+  }
+
+  #[test]
+  fn create_rejects_invalid_filespecs() {
+    fn error(include: &[&str]) -> String {
+      PathGlobs::create(&owned_string_vec(include), &[])
+        .err()
+        .expect("Want error")
+    }
+    assert_eq!(
+      error(&["*.rs", ""]),
+      "Invalid include filespec \"\" at index 1: it is empty"
+    );
+    assert_eq!(
+      error(&["./"]),
+      "Invalid include filespec \"./\" at index 0: it is empty"
+    );
+    assert_eq!(
+      error(&["src/*.rs", "/etc/passwd"]),
+      "Invalid include filespec \"/etc/passwd\" at index 1: absolute paths are not supported"
+    );
+    assert_eq!(
+      error(&["!target"]),
+      "Invalid include filespec \"!target\" at index 0: negation is only supported in excludes"
+    );
+    assert_eq!(
+      error(&["*.{rs,py}"]),
+      "Invalid include filespec \"*.{rs,py}\" at index 0: brace alternatives are not supported"
+    );
+    assert!(error(&["*.rs", "src/a**b"]).starts_with(
+      "Invalid include filespec \"src/a**b\" at index 1: ",
+    ));
+    assert!(error(&["../outside"]).starts_with(
+      "Invalid include filespec \"../outside\" at index 0: ",
+    ));
+    assert!(PathGlobs::create(&owned_string_vec(&["./*.rs", "src/**"]), &[]).is_ok());
+  }
+
+  #[test]
+  fn expand_fails_beyond_max_entries() {
+    let dir = tempdir::TempDir::new("posixfs").unwrap();
+    make_file(&dir.path().join("marmosets"), &[], 0o600);
+    std::fs::create_dir(dir.path().join("src")).unwrap();
+    make_file(&dir.path().join("src").join("lemurs"), &[], 0o600);
+    let posix_fs = Arc::new(new_posixfs(&dir.path()));
+    let everything = |max_entries| {
+      PathGlobs::create(&["**".to_owned()], &[])
+        .unwrap()
+        .with_max_entries(max_entries)
+    };
+
+    assert_eq!(posix_fs.expand(everything(Some(3))).wait().unwrap().len(), 3);
+    assert_eq!(posix_fs.expand(everything(None)).wait().unwrap().len(), 3);
+    let err = posix_fs
+      .expand(everything(Some(2)))
+      .wait()
+      .expect_err("Want error")
+      .to_string();
+    assert!(
+      err.contains("Expanding filespec \"**\" matched more than the limit of 2 entries"),
+      "Unexpected error: {}",
+      err
+    );
+
+    // The error names the filespec whose expansion exceeded the limit.
+    let err = posix_fs
+      .expand(
+        PathGlobs::create(&owned_string_vec(&["marmosets", "src/**"]), &[])
+          .unwrap()
+          .with_max_entries(Some(1)),
+      )
+      .wait()
+      .expect_err("Want error")
+      .to_string();
+    assert!(
+      err.contains("Expanding filespec \"src/**\" matched more than the limit of 1 entries"),
+      "Unexpected error: {}",
+      err
+    );
+  }
+
+  #[test]
+  fn expand_excludes_hidden_paths_unless_included() {
+    let dir = tempdir::TempDir::new("posixfs").unwrap();
+    std::fs::create_dir_all(dir.path().join(".git")).unwrap();
+    std::fs::create_dir_all(dir.path().join("src").join(".cache").join(".nested")).unwrap();
+    make_file(&dir.path().join(".git").join("config"), &[], 0o600);
+    make_file(&dir.path().join(".hidden"), &[], 0o600);
+    make_file(&dir.path().join("marmosets"), &[], 0o600);
+    make_file(&dir.path().join("src").join("lemurs"), &[], 0o600);
+    make_file(
+      &dir.path().join("src").join(".cache").join(".nested").join("tamarins"),
+      &[],
+      0o600,
+    );
+    let posix_fs = Arc::new(new_posixfs(&dir.path()));
+    let expand = |path_globs: PathGlobs| {
+      let mut paths = posix_fs
+        .expand(path_globs)
+        .wait()
+        .unwrap()
+        .into_iter()
+        .map(|path_stat| path_stat.path().to_owned())
+        .collect::<Vec<_>>();
+      paths.sort();
+      paths
+    };
+    let globs = |include: &[&str], include_hidden: bool| {
+      PathGlobs::create_with_options(&owned_string_vec(include), &[], include_hidden).unwrap()
+    };
+    let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+    assert_eq!(
+      expand(globs(&["**"], false)),
+      paths(&["marmosets", "src", "src/lemurs"])
+    );
+    assert_eq!(
+      expand(globs(&["**"], true)),
+      paths(&[
+        ".git",
+        ".git/config",
+        ".hidden",
+        "marmosets",
+        "src",
+        "src/.cache",
+        "src/.cache/.nested",
+        "src/.cache/.nested/tamarins",
+        "src/lemurs",
+      ])
+    );
+    assert_eq!(
+      expand(globs(&["**"], true)),
+      expand(PathGlobs::create(&owned_string_vec(&["**"]), &[]).unwrap())
+    );
+    // Components with a literal leading dot still match hidden names, but nothing beneath them is
+    // matched by wildcards alone.
+    assert_eq!(
+      expand(globs(&[".git/*", ".hidden", "**/.cache"], false)),
+      paths(&[".git/config", ".hidden", "src/.cache"])
+    );
+    assert_eq!(expand(globs(&["src/.cache/**"], false)), paths(&[]));
+  }
+
+  #[test]
+  fn expand_by_extensions() {
+    let dir = tempdir::TempDir::new("posixfs").unwrap();
+    make_file(&dir.path().join("marmosets.rs"), &[], 0o600);
+    std::fs::create_dir_all(dir.path().join("src").join("nested.py")).unwrap();
+    make_file(&dir.path().join("src").join("lemurs.rs"), &[], 0o600);
+    make_file(&dir.path().join("src").join("lemurs.txt"), &[], 0o600);
+    make_file(
+      &dir.path().join("src").join("nested.py").join("tamarins.py"),
+      &[],
+      0o600,
+    );
+    let posix_fs = Arc::new(new_posixfs(&dir.path()));
+    let path_globs = PathGlobs::by_extensions(&["src".to_owned()], &["rs", ".py"]).unwrap();
+    let mut paths = posix_fs
+      .expand(path_globs)
+      .wait()
+      .unwrap()
+      .into_iter()
+      .map(|path_stat| path_stat.path().to_owned())
+      .collect::<Vec<_>>();
+    paths.sort();
+    assert_eq!(
+      paths,
+      vec![
+        PathBuf::from("src/lemurs.rs"),
+        PathBuf::from("src/nested.py/tamarins.py"),
+      ]
+    );
+
+    PathGlobs::by_extensions(&["src".to_owned()], &["."]).expect_err("Want error");
+    assert_eq!(
+      PathGlobs::by_extensions(&["src".to_owned()], &["rs", ".tar.gz"]).err(),
+      Some("Invalid file extension: \"tar.gz\"".to_owned())
+    );
+  }
+
+  #[test]
+  fn coverage_lists_unmatched_files() {
+    let dir = tempdir::TempDir::new("posixfs").unwrap();
+    make_file(&dir.path().join("marmosets.rs"), &[], 0o600);
+    make_file(&dir.path().join("marmosets.py"), &[], 0o600);
+    std::fs::create_dir_all(dir.path().join("src").join("empty")).unwrap();
+    make_file(&dir.path().join("src").join("lemurs.rs"), &[], 0o600);
+    make_file(&dir.path().join("src").join("lemurs.py"), &[], 0o600);
+    let posix_fs = Arc::new(new_posixfs(&dir.path()));
+    let rust_files = PathGlobs::create(&["**/*.rs".to_owned()], &[]).unwrap();
+    assert_eq!(
+      posix_fs.coverage(rust_files).wait().unwrap(),
+      CoverageReport {
+        unmatched: vec![PathBuf::from("marmosets.py"), PathBuf::from("src/lemurs.py")],
+      }
+    );
+  }
+
+  #[test]
+  fn tar_create_is_reproducible_with_epoch_timestamps() {
+    let dir = tempdir::TempDir::new("posixfs").unwrap();
+    make_file(&dir.path().join("roland"), b"European Burmese", 0o600);
+    let paths = vec![
+      PathStat::file(
+        PathBuf::from("roland"),
+        File {
+          path: PathBuf::from("roland"),
+          is_executable: false,
+        },
+      ),
+    ];
+    let root = Dir(dir.path().to_owned());
+    let archive_at = |seconds: u64, timestamps: ArchiveTimestamps| {
+      let mtime = FileTime::from_seconds_since_1970(seconds, 0);
+      filetime::set_file_times(dir.path().join("roland"), mtime, mtime).unwrap();
+      Snapshots::tar_create(Vec::new(), &paths, &root, timestamps).unwrap()
+    };
+
+    assert_eq!(
+      archive_at(1_000_000_000, ArchiveTimestamps::Epoch),
+      archive_at(1_200_000_000, ArchiveTimestamps::Epoch)
+    );
+    assert!(
+      archive_at(1_000_000_000, ArchiveTimestamps::Recorded) !=
+        archive_at(1_200_000_000, ArchiveTimestamps::Recorded)
+    );
+  }
+
   fn assert_only_file_is_executable(path: &Path, want_is_executable: bool) {
     let fs = new_posixfs(path);
     let stats = fs.scandir(&Dir(PathBuf::from("."))).wait().unwrap();
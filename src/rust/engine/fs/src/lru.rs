@@ -0,0 +1,108 @@
+// Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+///
+/// A map holding at most `capacity` entries, which evicts the least recently used entry when it
+/// would otherwise exceed that. A capacity of zero holds nothing.
+///
+pub struct LruCache<K: Clone + Eq + Hash, V> {
+  capacity: usize,
+  // Incremented on each access, so that smaller values were used less recently.
+  tick: u64,
+  entries: HashMap<K, (V, u64)>,
+  recency: BTreeMap<u64, K>,
+}
+
+impl<K: Clone + Eq + Hash, V> LruCache<K, V> {
+  pub fn new(capacity: usize) -> LruCache<K, V> {
+    LruCache {
+      capacity: capacity,
+      tick: 0,
+      entries: HashMap::new(),
+      recency: BTreeMap::new(),
+    }
+  }
+
+  pub fn get(&mut self, key: &K) -> Option<&V> {
+    self.tick += 1;
+    let tick = self.tick;
+    match self.entries.get_mut(key) {
+      Some(&mut (ref value, ref mut last_used)) => {
+        self.recency.remove(last_used);
+        self.recency.insert(tick, key.clone());
+        *last_used = tick;
+        Some(value)
+      }
+      None => None,
+    }
+  }
+
+  pub fn insert(&mut self, key: K, value: V) {
+    if self.capacity == 0 {
+      return;
+    }
+    self.tick += 1;
+    if let Some((_, last_used)) = self.entries.insert(key.clone(), (value, self.tick)) {
+      self.recency.remove(&last_used);
+    }
+    self.recency.insert(self.tick, key);
+    while self.entries.len() > self.capacity {
+      let oldest = match self.recency.keys().next() {
+        Some(&tick) => tick,
+        None => break,
+      };
+      if let Some(key) = self.recency.remove(&oldest) {
+        self.entries.remove(&key);
+      }
+    }
+  }
+
+  pub fn remove(&mut self, key: &K) {
+    if let Some((_, last_used)) = self.entries.remove(key) {
+      self.recency.remove(&last_used);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::LruCache;
+
+  #[test]
+  fn evicts_least_recently_used() {
+    let mut cache = LruCache::new(2);
+    cache.insert("roland", 1);
+    cache.insert("european", 2);
+    assert_eq!(cache.get(&"roland"), Some(&1));
+    cache.insert("burmese", 3);
+    assert_eq!(cache.get(&"european"), None);
+    assert_eq!(cache.get(&"roland"), Some(&1));
+    assert_eq!(cache.get(&"burmese"), Some(&3));
+  }
+
+  #[test]
+  fn reinserting_replaces_value() {
+    let mut cache = LruCache::new(2);
+    cache.insert("roland", 1);
+    cache.insert("roland", 2);
+    assert_eq!(cache.get(&"roland"), Some(&2));
+  }
+
+  #[test]
+  fn remove() {
+    let mut cache = LruCache::new(2);
+    cache.insert("roland", 1);
+    cache.remove(&"roland");
+    assert_eq!(cache.get(&"roland"), None);
+  }
+
+  #[test]
+  fn zero_capacity_holds_nothing() {
+    let mut cache = LruCache::new(0);
+    cache.insert("roland", 1);
+    assert_eq!(cache.get(&"roland"), None);
+  }
+}
@@ -0,0 +1,149 @@
+// Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use boxfuture::{Boxable, BoxFuture};
+use futures::Future;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use {Digest, File, GetFileDigest, PosixFS, Store};
+use hash::WriterHasher;
+
+///
+/// A hash function to compute alongside a file's Digest, such as one needed to interoperate with
+/// another system.
+///
+pub type AuxiliaryHasher = Arc<Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+///
+/// A GetFileDigest which stores files read from a PosixFS, and which additionally computes a set of
+/// named auxiliary hashes of each file from the same read. The Store only uses the file's Digest;
+/// the auxiliary hashes are kept for the caller, and can be retrieved with `auxiliary_hashes`.
+///
+pub struct MultiHashDigester {
+  store: Arc<Store>,
+  posix_fs: Arc<PosixFS>,
+  hashers: Arc<Vec<(String, AuxiliaryHasher)>>,
+  // Auxiliary hashes of the files digested so far, by name, keyed by the files' paths.
+  auxiliary: Arc<Mutex<HashMap<PathBuf, BTreeMap<String, Vec<u8>>>>>,
+}
+
+impl MultiHashDigester {
+  pub fn new(
+    store: Arc<Store>,
+    posix_fs: Arc<PosixFS>,
+    hashers: Vec<(String, AuxiliaryHasher)>,
+  ) -> MultiHashDigester {
+    MultiHashDigester {
+      store: store,
+      posix_fs: posix_fs,
+      hashers: Arc::new(hashers),
+      auxiliary: Arc::new(Mutex::new(HashMap::new())),
+    }
+  }
+
+  ///
+  /// Returns the auxiliary hashes of the file at the given path (relative to the PosixFS' root), by
+  /// name, or None if that file has not been digested.
+  ///
+  pub fn auxiliary_hashes(&self, path: &Path) -> Option<BTreeMap<String, Vec<u8>>> {
+    self.auxiliary.lock().unwrap().get(path).cloned()
+  }
+}
+
+impl GetFileDigest<String> for MultiHashDigester {
+  fn digest(&self, file: &File) -> BoxFuture<Digest, String> {
+    let path = file.path.clone();
+    let pool = self.posix_fs.pool.clone();
+    let hashers = self.hashers.clone();
+    let auxiliary = self.auxiliary.clone();
+    let store = self.store.clone();
+    self
+      .posix_fs
+      .read_file(file)
+      .map_err(move |e| format!("Error reading file {:?}: {}", path, e))
+      .and_then(move |file_content| {
+        pool.spawn_fn(move || {
+          let mut hasher = WriterHasher::new(io::sink());
+          hasher.write_all(&file_content.content).map_err(|e| {
+            format!("Error hashing file {:?}: {}", file_content.path, e)
+          })?;
+          let digest = Digest(hasher.finish(), file_content.content.len());
+          let hashes = hashers
+            .iter()
+            .map(|&(ref name, ref hasher)| (name.clone(), hasher(&file_content.content)))
+            .collect();
+          auxiliary.lock().unwrap().insert(
+            file_content.path,
+            hashes,
+          );
+          Ok((file_content.content, digest))
+        })
+      })
+      .and_then(move |(content, digest)| {
+        store.store_file_bytes_with_digest(content, digest)
+      })
+      .to_boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempdir;
+  extern crate testutil;
+
+  use digest::{Digest as DigestTrait, FixedOutput};
+  use futures::Future;
+  use self::testutil::make_file;
+  use sha2::Sha512;
+  use std::path::PathBuf;
+  use std::sync::Arc;
+  use super::{AuxiliaryHasher, MultiHashDigester};
+  use super::super::{File, GetFileDigest, PosixFS, ResettablePool, Store};
+
+  #[test]
+  fn computes_auxiliary_hashes_alongside_digest() {
+    let dir = tempdir::TempDir::new("root").unwrap();
+    let store_dir = tempdir::TempDir::new("store").unwrap();
+    let pool = Arc::new(ResettablePool::new("test-pool-".to_string()));
+    let store = Arc::new(Store::new(store_dir.path(), pool.clone()).unwrap());
+    let posix_fs = Arc::new(PosixFS::new(dir.path(), pool, vec![]).unwrap());
+    make_file(&dir.path().join("roland"), b"European Burmese", 0o600);
+
+    let sha512: AuxiliaryHasher = Arc::new(|bytes: &[u8]| {
+      let mut hasher = Sha512::default();
+      hasher.input(bytes);
+      hasher.fixed_result().to_vec()
+    });
+    let length: AuxiliaryHasher = Arc::new(|bytes: &[u8]| vec![bytes.len() as u8]);
+    let digester = MultiHashDigester::new(
+      store.clone(),
+      posix_fs,
+      vec![("sha512".to_owned(), sha512.clone()), ("length".to_owned(), length)],
+    );
+
+    let path = PathBuf::from("roland");
+    assert_eq!(digester.auxiliary_hashes(&path), None);
+    let digest = digester
+      .digest(&File {
+        path: path.clone(),
+        is_executable: false,
+      })
+      .wait()
+      .unwrap();
+    assert_eq!(
+      digest,
+      store
+        .store_file_bytes(b"European Burmese".to_vec())
+        .wait()
+        .unwrap()
+    );
+
+    let hashes = digester.auxiliary_hashes(&path).unwrap();
+    assert_eq!(hashes.len(), 2);
+    assert_eq!(hashes["sha512"], sha512(b"European Burmese"));
+    assert_eq!(hashes["length"], vec![16]);
+  }
+}
@@ -2,16 +2,40 @@
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
 use bazel_protos;
-use boxfuture::{Boxable, BoxFuture};
-use futures::Future;
-use futures::future::join_all;
+use bazel_protos::remote_execution_grpc::ContentAddressableStorageClient;
+use digest::{Digest as DigestTrait, FixedOutput};
+use filetime::{self, FileTime};
+use boxfuture::{Boxable, BoxFuture, BoxStream};
+use futures::{stream, Future, Stream};
+use futures::future::{self, join_all};
+use grpcio;
 use itertools::Itertools;
-use {Digest, File, PathStat, Store};
-use hash::Fingerprint;
-use protobuf;
-use std::ffi::OsString;
+use {safe_create_dir_all, safe_create_tmpdir_in, Digest, Dir, File, FileContent, Link, PathGlobs,
+     PathStat, PosixFS, Stat, Store, StoreReader, StoringDigester, VFS};
+use hash::{Fingerprint, WriterHasher};
+use hex;
+use pool::ResettablePool;
+use protobuf::{self, Message};
+use regex::Regex;
+use serde_json;
+use sha2::Sha256;
+use tar;
+use tempdir::TempDir;
+use std::cmp::{max, min, Ordering};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::collections::btree_map::Entry;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
-use std::sync::Arc;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+// The version of the encoding used by `Snapshot::tree_key`, which prefixes each key. This must be
+// changed if the encoding or its hash function ever changes.
+const TREE_KEY_VERSION: &str = "tree-v1";
 
 #[derive(Clone, PartialEq)]
 pub struct Snapshot {
@@ -23,15 +47,697 @@ pub struct Snapshot {
   pub path_stats: Vec<PathStat>,
 }
 
+///
+/// The changes that materializing a Snapshot would make to a destination directory, as computed by
+/// `Snapshot::plan_materialize`. All paths are relative to the destination, and sorted.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MaterializePlan {
+  ///
+  /// Files which do not yet exist in the destination.
+  ///
+  pub create: Vec<PathBuf>,
+  ///
+  /// Files which already exist in the destination, and would be replaced.
+  ///
+  pub overwrite: Vec<PathBuf>,
+  ///
+  /// Files in the destination which are not in the Snapshot. `materialize` leaves these alone, so
+  /// they only need deleting to make the destination match the Snapshot exactly.
+  ///
+  pub delete: Vec<PathBuf>,
+  ///
+  /// The total size of the files which would be created or overwritten.
+  ///
+  pub bytes_to_write: u64,
+}
+
+///
+/// The changes which turn a destination directory containing one Snapshot into one containing
+/// another, as computed by `Snapshot::patch_against`. All paths are relative to the destination,
+/// and sorted.
+///
+#[derive(Clone)]
+pub struct SnapshotPatch {
+  store: Arc<Store>,
+  ///
+  /// Files which are only in the new Snapshot, along with their Digests and executable bits.
+  ///
+  pub add: Vec<(PathBuf, Digest, bool)>,
+  ///
+  /// Files which are in both Snapshots, but whose contents or executable bits differ, along with
+  /// their new Digests and executable bits.
+  ///
+  pub update: Vec<(PathBuf, Digest, bool)>,
+  ///
+  /// Files which are only in the old Snapshot.
+  ///
+  pub delete: Vec<PathBuf>,
+  ///
+  /// Directories which are only in the new Snapshot.
+  ///
+  pub create_dirs: Vec<PathBuf>,
+  ///
+  /// Directories which are only in the old Snapshot.
+  ///
+  pub remove_dirs: Vec<PathBuf>,
+}
+
+impl SnapshotPatch {
+  ///
+  /// Applies this patch to a destination directory which contains the old Snapshot (for example,
+  /// because it was materialized there), so that it contains the new Snapshot. Only the files which
+  /// differ are written or deleted.
+  ///
+  /// Removed directories which still contain something which was not part of the old Snapshot are
+  /// left in place, along with their contents.
+  ///
+  pub fn apply_to(self, destination: PathBuf) -> BoxFuture<(), String> {
+    let SnapshotPatch {
+      store,
+      add,
+      update,
+      delete,
+      create_dirs,
+      mut remove_dirs,
+    } = self;
+    store
+      .read_batch(move |reader| {
+        for path in delete {
+          let dest = destination.join(&path);
+          match fs::remove_file(&dest) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Error deleting {:?}: {}", dest, e)),
+          }
+        }
+        // Children before their parents.
+        remove_dirs.reverse();
+        for path in remove_dirs {
+          let dest = destination.join(&path);
+          let is_empty = match fs::read_dir(&dest) {
+            Ok(mut entries) => entries.next().is_none(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("Error listing {:?}: {}", dest, e)),
+          };
+          if is_empty {
+            fs::remove_dir(&dest).map_err(|e| {
+              format!("Error removing directory {:?}: {}", dest, e)
+            })?;
+          }
+        }
+        for path in create_dirs {
+          safe_create_dir_all(&destination.join(path))?;
+        }
+        for (path, digest, is_executable) in add.into_iter().chain(update.into_iter()) {
+          let dest = destination.join(&path);
+          reader
+            .load_file_bytes_with(digest.0, |bytes| {
+              write_file(&dest, bytes, is_executable)
+            })?
+            .ok_or_else(|| format!("File with fingerprint {} not found", digest.0))??;
+        }
+        Ok(())
+      })
+      .to_boxed()
+  }
+}
+
 pub trait GetFileDigest<Error> {
   fn digest(&self, file: &File) -> BoxFuture<Digest, Error>;
 }
 
+///
+/// Computes a small tag describing the type of a file's content (such as "text" or "elf") from its
+/// first bytes, for `Snapshot::classify`.
+///
+pub trait ContentClassifier {
+  ///
+  /// Identifies this classifier: tags are recorded in the Store by file Digest and by this name, so
+  /// it should change whenever the classifier's rules do.
+  ///
+  fn name(&self) -> &str;
+
+  ///
+  /// The number of leading bytes of each file which `classify` is given (or fewer, for shorter
+  /// files).
+  ///
+  fn prefix_len(&self) -> usize;
+
+  fn classify(&self, prefix: &[u8]) -> String;
+}
+
+///
+/// How `Snapshot::from_path_stats_with_policy` treats files which could not be digested (for
+/// example, because they could not be read).
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnreadableFilePolicy {
+  ///
+  /// Leave the files out of the Snapshot, and return them alongside it.
+  ///
+  Skip,
+  ///
+  /// Fail once every file has been attempted, with an error listing all of the failures.
+  ///
+  ErrorWithAll,
+}
+
+///
+/// How `Snapshot::merge_with_provenance` resolves a path at which more than one of the merged
+/// Snapshots has a file, with differing contents or executable bits. Identical files never
+/// conflict.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MergeStrategy {
+  ///
+  /// Fail the merge, with an error naming the path and the labels of the conflicting Snapshots.
+  ///
+  Fail,
+  ///
+  /// Keep the file from the earliest of the Snapshots.
+  ///
+  FirstWins,
+  ///
+  /// Keep the file from the latest of the Snapshots.
+  ///
+  LastWins,
+}
+
+///
+/// Deduplicates equal Snapshots held in memory, for long-running processes in which many
+/// Snapshots (and so their Digests and PathStats) are identical. See `Snapshot::intern`.
+///
+/// Only weak references are held, so an interned Snapshot is freed as usual once no handle to it
+/// remains.
+///
+#[derive(Default)]
+pub struct SnapshotInterner {
+  // Interned Snapshots, keyed by their fingerprints. Snapshots with equal fingerprints may still
+  // differ in their PathStats, and so be distinct.
+  snapshots: Mutex<HashMap<Fingerprint, Vec<Weak<Snapshot>>>>,
+}
+
+impl SnapshotInterner {
+  pub fn new() -> SnapshotInterner {
+    SnapshotInterner::default()
+  }
+
+  ///
+  /// Forgets Snapshots which have been freed. Interning does this for Snapshots with the same
+  /// fingerprint as it goes, so this only needs calling to reclaim the space of other fingerprints.
+  ///
+  pub fn purge(&self) {
+    let mut snapshots = self.snapshots.lock().unwrap();
+    for candidates in snapshots.values_mut() {
+      candidates.retain(|candidate| candidate.upgrade().is_some());
+    }
+    snapshots.retain(|_, candidates| !candidates.is_empty());
+  }
+
+  fn intern(&self, snapshot: Snapshot) -> Arc<Snapshot> {
+    let mut snapshots = self.snapshots.lock().unwrap();
+    let candidates = snapshots.entry(snapshot.fingerprint).or_insert_with(
+      Vec::new,
+    );
+    candidates.retain(|candidate| candidate.upgrade().is_some());
+    for candidate in candidates.iter() {
+      if let Some(candidate) = candidate.upgrade() {
+        if *candidate == snapshot {
+          return candidate;
+        }
+      }
+    }
+    let interned = Arc::new(snapshot);
+    candidates.push(Arc::downgrade(&interned));
+    interned
+  }
+}
+
+///
+/// Builds a Snapshot from files and empty directories which are added one at a time, for producers
+/// which do not know all of their paths upfront. Entries may be added in any order. File contents
+/// are held in memory until `finish` stores them.
+///
+#[derive(Default)]
+pub struct SnapshotBuilder {
+  files: Vec<(PathBuf, Vec<u8>, bool)>,
+  empty_dirs: Vec<PathBuf>,
+}
+
+impl SnapshotBuilder {
+  pub fn new() -> SnapshotBuilder {
+    SnapshotBuilder::default()
+  }
+
+  pub fn add_file(&mut self, path: PathBuf, bytes: Vec<u8>, is_executable: bool) {
+    self.files.push((path, bytes, is_executable));
+  }
+
+  pub fn add_empty_dir(&mut self, path: PathBuf) {
+    self.empty_dirs.push(path);
+  }
+
+  ///
+  /// Stores the added files, and returns a Snapshot of them and of the added empty directories.
+  ///
+  /// Fails if a path was added more than once, or if an entry was added beneath a file or beneath
+  /// an empty directory.
+  ///
+  pub fn finish(self, store: Arc<Store>) -> BoxFuture<Snapshot, String> {
+    let SnapshotBuilder { files, empty_dirs } = self;
+
+    let mut added = BTreeSet::new();
+    for path in files
+      .iter()
+      .map(|&(ref path, _, _)| path.as_path())
+      .chain(empty_dirs.iter().map(|path| path.as_path()))
+    {
+      if !added.insert(path) {
+        return future::err(format!("{:?} was added to the Snapshot more than once", path))
+          .to_boxed();
+      }
+    }
+    for path in &added {
+      let mut current = path.parent();
+      while let Some(parent) = current {
+        if added.contains(&parent) {
+          return future::err(format!(
+            "{:?} cannot be added to the Snapshot beneath {:?}, which is not a directory",
+            path,
+            parent
+          )).to_boxed();
+        }
+        current = parent.parent();
+      }
+    }
+
+    let stored = join_all(
+      files
+        .into_iter()
+        .map(|(path, bytes, is_executable)| {
+          store.store_file_bytes(bytes).map(move |digest| {
+            (path, digest, is_executable)
+          })
+        })
+        .collect::<Vec<_>>(),
+    );
+    stored
+      .and_then(move |files| {
+        let mut digests = HashMap::new();
+        let mut path_stats = Vec::new();
+        for (path, digest, is_executable) in files {
+          digests.insert(path.clone(), digest);
+          path_stats.push(PathStat::file(
+            path.clone(),
+            File {
+              path: path,
+              is_executable: is_executable,
+            },
+          ));
+        }
+        for path in empty_dirs {
+          path_stats.push(PathStat::dir(path.clone(), Dir(path)));
+        }
+        Snapshot::from_path_stats(store, Arc::new(KnownDigests(digests)), path_stats)
+      })
+      .to_boxed()
+  }
+}
+
+// A GetFileDigest for files whose Digests have already been computed.
+struct KnownDigests(HashMap<PathBuf, Digest>);
+
+impl GetFileDigest<String> for KnownDigests {
+  fn digest(&self, file: &File) -> BoxFuture<Digest, String> {
+    match self.0.get(&file.path) {
+      Some(digest) => future::ok(digest.clone()).to_boxed(),
+      None => future::err(format!("No digest was computed for {:?}", file.path)).to_boxed(),
+    }
+  }
+}
+
+// A read-only VFS over the paths of a Snapshot's files, so that PathGlobs can be matched against
+// them without materializing the Snapshot.
+#[derive(Clone)]
+struct ManifestVFS {
+  // The entries of each directory, keyed by the directory's path.
+  listings: Arc<HashMap<PathBuf, Vec<Stat>>>,
+}
+
+impl ManifestVFS {
+  fn new(files: &[(PathBuf, bool)]) -> ManifestVFS {
+    let mut listings: HashMap<PathBuf, BTreeMap<PathBuf, Stat>> = HashMap::new();
+    for &(ref path, is_executable) in files {
+      let mut stat = Stat::File(File {
+        path: path.clone(),
+        is_executable: is_executable,
+      });
+      let mut current = path.clone();
+      loop {
+        let parent = match current.parent() {
+          Some(parent) => parent.to_owned(),
+          None => break,
+        };
+        listings.entry(parent.clone()).or_insert_with(BTreeMap::new).insert(
+          current,
+          stat,
+        );
+        stat = Stat::Dir(Dir(parent.clone()));
+        current = parent;
+      }
+    }
+    ManifestVFS {
+      listings: Arc::new(
+        listings
+          .into_iter()
+          .map(|(dir, entries)| (dir, entries.into_iter().map(|(_, stat)| stat).collect()))
+          .collect(),
+      ),
+    }
+  }
+}
+
+impl VFS<String> for ManifestVFS {
+  fn read_link(&self, link: Link) -> BoxFuture<PathBuf, String> {
+    future::err(format!("Snapshots do not contain links, but got {:?}", link)).to_boxed()
+  }
+
+  fn scandir(&self, dir: Dir) -> BoxFuture<Vec<Stat>, String> {
+    future::ok(self.listings.get(&dir.0).cloned().unwrap_or_else(Vec::new)).to_boxed()
+  }
+
+  fn is_ignored(&self, _stat: &Stat) -> bool {
+    false
+  }
+
+  fn mk_error(msg: &str) -> String {
+    msg.to_owned()
+  }
+}
+
 impl Snapshot {
   pub fn from_path_stats<GFD: GetFileDigest<Error> + Sized, Error: fmt::Debug + 'static + Send>(
+    store: Arc<Store>,
+    file_digester: Arc<GFD>,
+    path_stats: Vec<PathStat>,
+  ) -> BoxFuture<Snapshot, String> {
+    // Paths which were not relative and normalized could escape the root of the Snapshot when
+    // it was materialized. Child paths are derived by stripping components, so it is sufficient to
+    // check the paths we were given.
+    for path_stat in &path_stats {
+      if let Err(err) = tree_components(path_stat.path(), store.max_directory_depth()) {
+        return future::err(err).to_boxed();
+      }
+    }
+    // Entries which would collide in the tree would otherwise silently turn a file into a
+    // directory, so anything other than an exact repetition is rejected.
+    let mut path_stats = path_stats;
+    path_stats.sort_by(|a, b| a.path().cmp(b.path()));
+    path_stats.dedup();
+    if let Err(err) = check_distinct_entries(&path_stats) {
+      return future::err(err).to_boxed();
+    }
+    Snapshot::from_path_stats_with_cache(
+      store,
+      file_digester,
+      path_stats,
+      Arc::new(Mutex::new(HashMap::new())),
+    )
+  }
+
+  ///
+  /// As for `from_path_stats`, but the Snapshot's PathStats are ordered by the given comparator
+  /// (such as a case-insensitive one) rather than by their paths' bytes. Entries which compare as
+  /// equal keep their bytewise order.
+  ///
+  /// The comparator only affects `path_stats`: the Snapshot's Directories always list their entries
+  /// sorted bytewise by name, as the remote execution API requires for them to be canonical, so the
+  /// Snapshot's Digest does not depend on the comparator.
+  ///
+  pub fn from_path_stats_sorted_by<
+    GFD: GetFileDigest<Error> + Sized,
+    Error: fmt::Debug + 'static + Send,
+    F: Fn(&PathStat, &PathStat) -> Ordering + Send + 'static,
+  >(
+    store: Arc<Store>,
+    file_digester: Arc<GFD>,
+    path_stats: Vec<PathStat>,
+    compare: F,
+  ) -> BoxFuture<Snapshot, String> {
+    Snapshot::from_path_stats(store, file_digester, path_stats)
+      .map(move |mut snapshot| {
+        snapshot.path_stats.sort_by(|a, b| compare(a, b));
+        snapshot
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// As for `from_path_stats`, but renames each component of the PathStats' paths with the given
+  /// transform, which is passed the (untransformed) path of the directory containing the component,
+  /// and the component's name. The Snapshot's PathStats have the transformed paths.
+  ///
+  /// Fails if the transform produces a name which is not a single normal path component, or maps
+  /// two different entries in a directory to the same name.
+  ///
+  pub fn from_path_stats_with_name_transform<
+    GFD: GetFileDigest<Error> + Sized,
+    Error: fmt::Debug + 'static + Send,
+    F: Fn(&Path, &OsStr) -> OsString,
+  >(
+    store: Arc<Store>,
+    file_digester: Arc<GFD>,
+    path_stats: Vec<PathStat>,
+    name_transform: F,
+  ) -> BoxFuture<Snapshot, String> {
+    match rename_path_stats(path_stats, &name_transform) {
+      Ok(path_stats) => Snapshot::from_path_stats(store, file_digester, path_stats),
+      Err(err) => future::err(err).to_boxed(),
+    }
+  }
+
+  ///
+  /// As for `from_path_stats`, but rather than failing on names which are not valid UTF8, records
+  /// their UTF8-lossy forms in the Snapshot, and their exact bytes in the Store, from which
+  /// `materialize_with_raw_names` restores them. The Snapshot's PathStats have the lossy paths.
+  ///
+  /// The Snapshot's Directories are still valid for use elsewhere (such as in a remote CAS), which
+  /// will see only the lossy names. Because the raw names are keyed by the Directory containing
+  /// them, this fails if a Directory which differs only in its raw names has already been recorded.
+  ///
+  pub fn from_path_stats_preserving_names<
+    GFD: GetFileDigest<Error> + Sized,
+    Error: fmt::Debug + 'static + Send,
+  >(
+    store: Arc<Store>,
+    file_digester: Arc<GFD>,
+    path_stats: Vec<PathStat>,
+  ) -> BoxFuture<Snapshot, String> {
+    // The raw bytes of each name which is not valid UTF8, by lossy parent path and lossy name.
+    let mut raw_names = BTreeMap::new();
+    for path_stat in &path_stats {
+      let mut lossy_parent = PathBuf::new();
+      for component in path_stat.path().components() {
+        let name = component.as_os_str();
+        let lossy = name.to_string_lossy().into_owned();
+        if name.to_str().is_none() {
+          raw_names.insert(
+            (lossy_parent.clone(), lossy.clone()),
+            name.as_bytes().to_vec(),
+          );
+        }
+        lossy_parent.push(lossy);
+      }
+    }
+    let record_store = store.clone();
+    Snapshot::from_path_stats_with_name_transform(
+      store,
+      file_digester,
+      path_stats,
+      |_, name| OsString::from(name.to_string_lossy().into_owned()),
+    ).and_then(move |snapshot| {
+      if raw_names.is_empty() {
+        return future::ok(snapshot).to_boxed();
+      }
+      let root = match snapshot.directory_fingerprint() {
+        Ok(fingerprint) => fingerprint,
+        Err(e) => return future::err(e).to_boxed(),
+      };
+      let names_store = record_store.clone();
+      record_store
+        .read_batch(move |reader| {
+          let directories = directory_fingerprints(reader, root)?
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+          raw_names
+            .into_iter()
+            .map(|((parent, name), raw)| {
+              let fingerprint = directories.get(&parent).ok_or_else(|| {
+                format!("Directory {:?} not found in Snapshot {}", parent, root)
+              })?;
+              Ok((*fingerprint, name, raw))
+            })
+            .collect::<Result<Vec<_>, String>>()
+        })
+        .and_then(move |names| names_store.record_raw_names(names))
+        .map(move |()| snapshot)
+        .to_boxed()
+    })
+      .to_boxed()
+  }
+
+  ///
+  /// As for `from_path_stats`, but rather than failing on the first file which cannot be digested,
+  /// attempts every file and then applies the given policy to those which failed. The failures are
+  /// returned as the PathStat's path and the error, sorted by path.
+  ///
+  /// With `UnreadableFilePolicy::Skip`, directories whose only files failed are kept, but empty.
+  ///
+  pub fn from_path_stats_with_policy<
+    GFD: GetFileDigest<Error> + Sized,
+    Error: fmt::Debug + 'static + Send,
+  >(
+    store: Arc<Store>,
+    file_digester: Arc<GFD>,
+    path_stats: Vec<PathStat>,
+    policy: UnreadableFilePolicy,
+  ) -> BoxFuture<(Snapshot, Vec<(PathBuf, String)>), String> {
+    let digests = path_stats
+      .iter()
+      .filter_map(|path_stat| match path_stat {
+        &PathStat::File { ref path, ref stat } => {
+          let path = path.clone();
+          let file_path = stat.path.clone();
+          Some(
+            file_digester
+              .digest(stat)
+              .then(move |res| Ok((path, file_path, res.map_err(|e| format!("{:?}", e)))))
+              .to_boxed(),
+          )
+        }
+        &PathStat::Dir { .. } => None,
+      })
+      .collect::<Vec<BoxFuture<(PathBuf, PathBuf, Result<Digest, String>), String>>>();
+    join_all(digests)
+      .and_then(move |results| {
+        let mut known = HashMap::new();
+        let mut failures = Vec::new();
+        for (path, file_path, res) in results {
+          match res {
+            Ok(digest) => {
+              known.insert(file_path, digest);
+            }
+            Err(err) => failures.push((path, err)),
+          }
+        }
+        failures.sort();
+        if policy == UnreadableFilePolicy::ErrorWithAll && !failures.is_empty() {
+          let details = failures
+            .iter()
+            .map(|&(ref path, ref err)| format!("  {:?}: {}", path, err))
+            .collect::<Vec<_>>();
+          return future::err(format!(
+            "Failed to digest {} files:\n{}",
+            failures.len(),
+            details.join("\n")
+          )).to_boxed();
+        }
+        let failed = failures
+          .iter()
+          .map(|&(ref path, _)| path.clone())
+          .collect::<HashSet<_>>();
+        let path_stats = path_stats
+          .into_iter()
+          .filter(|path_stat| match path_stat {
+            &PathStat::File { ref path, .. } => !failed.contains(path),
+            &PathStat::Dir { .. } => true,
+          })
+          .collect();
+        Snapshot::from_path_stats(store, Arc::new(KnownDigests(known)), path_stats)
+          .map(move |snapshot| (snapshot, failures))
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// As for `from_path_stats`, but then checks (with `verify_complete`) that everything the
+  /// Snapshot references is in the Store, so that the Snapshot is guaranteed to be materializable.
+  ///
+  pub fn from_path_stats_verified<
+    GFD: GetFileDigest<Error> + Sized,
+    Error: fmt::Debug + 'static + Send,
+  >(
+    store: Arc<Store>,
+    file_digester: Arc<GFD>,
+    path_stats: Vec<PathStat>,
+  ) -> BoxFuture<Snapshot, String> {
+    let verify_store = store.clone();
+    Snapshot::from_path_stats(store, file_digester, path_stats)
+      .and_then(move |snapshot| snapshot.verify_complete(verify_store))
+      .to_boxed()
+  }
+
+  ///
+  /// Returns this Snapshot if its root Directory, and every Directory and file beneath it, is
+  /// present in the Store with the size its Digest claims, or fails naming the first which is not.
+  ///
+  /// A GetFileDigest which computes a file's Digest without storing it, or a Store which expired
+  /// something in the meantime, could otherwise produce a Snapshot which cannot be materialized.
+  ///
+  pub fn verify_complete(self, store: Arc<Store>) -> BoxFuture<Snapshot, String> {
+    let root = match self.digest {
+      Some(ref digest) => digest.clone(),
+      None => return future::err(format!("Snapshot {:?} has no digest", self)).to_boxed(),
+    };
+    store
+      .read_batch(move |reader| {
+        check_blob("Directory", Path::new(""), &root, || {
+          reader.load_directory_proto_bytes(root.0).map(|maybe_bytes| {
+            maybe_bytes.map(|bytes| bytes.len())
+          })
+        })?;
+        walk_directories(reader, root.0, PathBuf::new(), &mut |path, directory| {
+          for directory_node in directory.get_directories() {
+            let digest = Digest::from_bazel_digest(directory_node.get_digest())?;
+            check_blob(
+              "Directory",
+              &path.join(directory_node.get_name()),
+              &digest,
+              || {
+                reader.load_directory_proto_bytes(digest.0).map(|maybe_bytes| {
+                  maybe_bytes.map(|bytes| bytes.len())
+                })
+              },
+            )?;
+          }
+          for file_node in directory.get_files() {
+            let digest = Digest::from_bazel_digest(file_node.get_digest())?;
+            check_blob("File", &path.join(file_node.get_name()), &digest, || {
+              reader.load_file_bytes_with(digest.0, |bytes| bytes.len())
+            })?;
+          }
+          Ok(())
+        })
+      })
+      .map(move |()| self)
+      .to_boxed()
+  }
+
+  ///
+  /// As for `from_path_stats`, but consults (and populates) a cache of the Digests of Directories
+  /// which have already been recorded, keyed by their children. Identical directories in different
+  /// parts of the tree (such as vendored copies) are then only recorded once.
+  ///
+  fn from_path_stats_with_cache<
+    GFD: GetFileDigest<Error> + Sized,
+    Error: fmt::Debug + 'static + Send,
+  >(
     store: Arc<Store>,
     file_digester: Arc<GFD>,
     mut path_stats: Vec<PathStat>,
+    recorded: Arc<Mutex<HashMap<ChildSet, Digest>>>,
   ) -> BoxFuture<Snapshot, String> {
     let mut file_futures: Vec<BoxFuture<bazel_protos::remote_execution::FileNode, String>> =
       Vec::new();
@@ -74,14 +780,16 @@ impl Snapshot {
           PathStat::Dir { .. } => {
             // Because there are no children of this Dir, it must be empty.
             dir_futures.push(
-              store
-                .record_directory(&bazel_protos::remote_execution::Directory::new())
-                .map(move |digest| {
-                  let mut directory_node = bazel_protos::remote_execution::DirectoryNode::new();
-                  directory_node.set_name(osstring_as_utf8(first_component).unwrap());
-                  directory_node.set_digest(digest.into());
-                  directory_node
-                })
+              record_directory_cached(
+                &store,
+                &recorded,
+                bazel_protos::remote_execution::Directory::new(),
+              ).and_then(move |digest| {
+                let mut directory_node = bazel_protos::remote_execution::DirectoryNode::new();
+                directory_node.set_name(osstring_as_utf8(first_component)?);
+                directory_node.set_digest(digest.into());
+                Ok(directory_node)
+              })
                 .to_boxed(),
             );
           }
@@ -89,14 +797,15 @@ impl Snapshot {
       } else {
         dir_futures.push(
           // TODO: Memoize this in the graph
-          Snapshot::from_path_stats(
+          Snapshot::from_path_stats_with_cache(
             store.clone(),
             file_digester.clone(),
             paths_of_child_dir(path_group),
+            recorded.clone(),
           ).and_then(move |snapshot| {
             let mut dir_node = bazel_protos::remote_execution::DirectoryNode::new();
             dir_node.set_name(osstring_as_utf8(first_component)?);
-            dir_node.set_digest(snapshot.digest.unwrap().into());
+            dir_node.set_digest(snapshot.digest().unwrap().into());
             Ok(dir_node)
           })
             .to_boxed(),
@@ -109,7 +818,7 @@ impl Snapshot {
         let mut directory = bazel_protos::remote_execution::Directory::new();
         directory.set_directories(protobuf::RepeatedField::from_vec(dirs));
         directory.set_files(protobuf::RepeatedField::from_vec(files));
-        store.record_directory(&directory).map(move |digest| {
+        record_directory_cached(&store, &recorded, directory).map(move |digest| {
           Snapshot {
             fingerprint: digest.0,
             digest: Some(digest),
@@ -119,164 +828,4385 @@ impl Snapshot {
       })
       .to_boxed()
   }
-}
-
-impl fmt::Debug for Snapshot {
-  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(
-      f,
-      "Snapshot({}, digest={:?}, entries={})",
-      self.fingerprint.to_hex(),
-      self.digest,
-      self.path_stats.len()
-    )
-  }
-}
 
-fn paths_of_child_dir(paths: Vec<PathStat>) -> Vec<PathStat> {
-  paths
-    .into_iter()
-    .filter_map(|s| {
-      if s.path().components().count() == 1 {
-        return None;
+  ///
+  /// Merges the given labeled Snapshots into one containing all of their files and empty
+  /// directories, resolving conflicting files according to `strategy`. Alongside the merged
+  /// Snapshot, returns the label of the input which each of its files and empty directories came
+  /// from: for a conflict, the winner's, and otherwise that of the earliest input containing it.
+  ///
+  /// Only Directories are read. A path which is a file in one input but a directory in another
+  /// cannot be merged, whatever the strategy.
+  ///
+  pub fn merge_with_provenance(
+    store: Arc<Store>,
+    labeled: Vec<(String, Snapshot)>,
+    strategy: MergeStrategy,
+  ) -> BoxFuture<(Snapshot, HashMap<PathBuf, String>), String> {
+    let mut roots = Vec::with_capacity(labeled.len());
+    for &(_, ref snapshot) in &labeled {
+      match snapshot.directory_fingerprint() {
+        Ok(fingerprint) => roots.push(fingerprint),
+        Err(e) => return future::err(e).to_boxed(),
       }
-      Some(match s {
-        PathStat::File { path, stat } => {
-          PathStat::File {
-            path: path.iter().skip(1).collect(),
-            stat: stat,
+    }
+    let tree_store = store.clone();
+    store
+      .read_batch(move |reader| {
+        // Each path's entry, along with the index of the input it came from.
+        let mut files: BTreeMap<PathBuf, (bazel_protos::remote_execution::FileNode, usize)> =
+          BTreeMap::new();
+        let mut empty_dirs: BTreeMap<PathBuf, usize> = BTreeMap::new();
+        for (index, root) in roots.into_iter().enumerate() {
+          let mut input_files = Vec::new();
+          walk_directories(reader, root, PathBuf::new(), &mut |path, directory| {
+            for file_node in directory.get_files() {
+              input_files.push((path.join(file_node.get_name()), file_node.clone()));
+            }
+            if directory.get_files().is_empty() && directory.get_directories().is_empty() &&
+              path != Path::new("")
+            {
+              empty_dirs.entry(path.to_owned()).or_insert(index);
+            }
+            Ok(())
+          })?;
+          for (path, file_node) in input_files {
+            let replace = match files.get(&path) {
+              None => true,
+              Some(&(ref existing, existing_index)) => {
+                if existing.get_digest() == file_node.get_digest() &&
+                  existing.get_is_executable() == file_node.get_is_executable()
+                {
+                  false
+                } else {
+                  match strategy {
+                    MergeStrategy::Fail => {
+                      return Err(format!(
+                        "Conflicting files at {:?} in {:?} and {:?}",
+                        path,
+                        labeled[existing_index].0,
+                        labeled[index].0
+                      ))
+                    }
+                    MergeStrategy::FirstWins => false,
+                    MergeStrategy::LastWins => true,
+                  }
+                }
+              }
+            };
+            if replace {
+              files.insert(path, (file_node, index));
+            }
           }
         }
-        PathStat::Dir { path, stat } => {
-          PathStat::Dir {
-            path: path.iter().skip(1).collect(),
-            stat: stat,
+
+        // Directories which were empty in some input, but contain something in the merge, are no
+        // longer empty directories.
+        let mut occupied = HashSet::new();
+        for path in files.keys().chain(empty_dirs.keys()) {
+          let mut current = path.parent();
+          loop {
+            let parent = match current {
+              Some(parent) => parent,
+              None => break,
+            };
+            occupied.insert(parent.to_owned());
+            current = parent.parent();
           }
         }
-      })
-    })
-    .collect()
-}
-
-fn osstring_as_utf8(path: OsString) -> Result<String, String> {
-  path.into_string().map_err(|p| {
-    format!("{:?}'s file_name is not representable in UTF8", p)
-  })
-}
+        let empty_dirs = empty_dirs
+          .into_iter()
+          .filter(|&(ref path, _)| !occupied.contains(path))
+          .collect::<BTreeMap<_, _>>();
 
-#[cfg(test)]
-mod tests {
-  extern crate testutil;
-  extern crate tempdir;
+        // Keep the PathStats of the winning files, and the first PathStat for each other path.
+        let mut path_stats = BTreeMap::new();
+        for (index, &(_, ref snapshot)) in labeled.iter().enumerate() {
+          for path_stat in &snapshot.path_stats {
+            let is_winner = match *path_stat {
+              PathStat::File { ref path, .. } => {
+                files.get(path).map(|&(_, winner)| winner == index).unwrap_or(
+                  false,
+                )
+              }
+              PathStat::Dir { .. } => true,
+            };
+            if is_winner && !path_stats.contains_key(path_stat.path()) {
+              path_stats.insert(path_stat.path().to_owned(), path_stat.clone());
+            }
+          }
+        }
 
-  use boxfuture::{BoxFuture, Boxable};
-  use futures::future::Future;
-  use tempdir::TempDir;
-  use self::testutil::make_file;
+        let mut provenance = HashMap::new();
+        for (path, &(_, index)) in &files {
+          provenance.insert(path.clone(), labeled[index].0.clone());
+        }
+        for (path, &index) in &empty_dirs {
+          provenance.insert(path.clone(), labeled[index].0.clone());
+        }
+        Ok((
+          files
+            .into_iter()
+            .map(|(path, (file_node, _))| (path, file_node))
+            .collect::<Vec<_>>(),
+          empty_dirs.into_iter().map(|(path, _)| path).collect(),
+          path_stats.into_iter().map(|(_, path_stat)| path_stat).collect(),
+          provenance,
+        ))
+      })
+      .and_then(move |(files, empty_dirs, path_stats, provenance)| {
+        Snapshot::from_tree(tree_store, files, empty_dirs, path_stats)
+          .map(move |snapshot| (snapshot, provenance))
+      })
+      .to_boxed()
+  }
 
-  use super::super::{Digest, File, Fingerprint, GetFileDigest, PathGlobs, PathStat, PosixFS,
-                     ResettablePool, Snapshot, Store, VFS};
+  ///
+  /// Creates a Snapshot containing several independent roots, each of whose PathStats will be
+  /// placed beneath its prefix. The PathStats' underlying Stats must all be relative to the
+  /// file_digester's root.
+  ///
+  /// All roots are built concurrently as one tree, which avoids recording and then re-reading
+  /// intermediate per-root Snapshots in order to combine them.
+  ///
+  pub fn from_roots<GFD: GetFileDigest<Error> + Sized, Error: fmt::Debug + 'static + Send>(
+    store: Arc<Store>,
+    file_digester: Arc<GFD>,
+    roots: Vec<(PathBuf, Vec<PathStat>)>,
+  ) -> BoxFuture<Snapshot, String> {
+    let mut path_stats = roots
+      .into_iter()
+      .flat_map(|(prefix, path_stats)| {
+        path_stats.into_iter().map(move |path_stat| match path_stat {
+          PathStat::File { path, stat } => PathStat::File {
+            path: prefix.join(path),
+            stat: stat,
+          },
+          PathStat::Dir { path, stat } => PathStat::Dir {
+            path: prefix.join(path),
+            stat: stat,
+          },
+        })
+      })
+      .collect::<Vec<_>>();
+    path_stats.sort_by(|a, b| a.path().cmp(b.path()));
+    for pair in path_stats.windows(2) {
+      if pair[0].path() == pair[1].path() {
+        return future::err(format!("Roots overlap at path {:?}", pair[0].path())).to_boxed();
+      }
+    }
+    Snapshot::from_path_stats(store, file_digester, path_stats)
+  }
 
-  use std;
-  use std::error::Error;
-  use std::path::PathBuf;
-  use std::sync::Arc;
+  ///
+  /// The Digest of this Snapshot's root Directory, if it has one. Only Snapshots which were
+  /// created as archives (rather than as Directories in a Store) lack one.
+  ///
+  /// Prefer this (and `path_stats`) to accessing the fields directly, which will eventually become
+  /// private.
+  ///
+  pub fn digest(&self) -> Option<Digest> {
+    self.digest.clone()
+  }
 
-  const STR: &str = "European Burmese";
+  pub fn path_stats(&self) -> &[PathStat] {
+    &self.path_stats
+  }
 
-  fn setup() -> (Arc<Store>, TempDir, Arc<PosixFS>, Arc<FileSaver>) {
-    let pool = Arc::new(ResettablePool::new("test-pool-".to_string()));
-    let store = Arc::new(
-      Store::new(TempDir::new("lmdb_store").unwrap(), pool.clone()).unwrap(),
-    );
-    let dir = TempDir::new("root").unwrap();
-    let posix_fs = Arc::new(PosixFS::new(dir.path(), pool, vec![]).unwrap());
-    let digester = Arc::new(FileSaver(store.clone(), posix_fs.clone()));
-    (store, dir, posix_fs, digester)
+  ///
+  /// Returns a shared handle to this Snapshot, which is the same handle as for any equal Snapshot
+  /// still interned in `interner`, so that holders of equal Snapshots share one copy of their
+  /// Digest and PathStats rather than each allocating their own.
+  ///
+  pub fn intern(self, interner: &SnapshotInterner) -> Arc<Snapshot> {
+    interner.intern(self)
   }
 
-  #[test]
-  fn snapshot_one_file() {
-    let (store, dir, posix_fs, digester) = setup();
+  fn directory_fingerprint(&self) -> Result<Fingerprint, String> {
+    self.digest().map(|digest| digest.0).ok_or_else(|| {
+      format!("Snapshot {:?} has no Directory digest", self)
+    })
+  }
 
-    let file_name = PathBuf::from("roland");
-    make_file(&dir.path().join(&file_name), STR.as_bytes(), 0o600);
+  ///
+  /// Computes a checksum of the files in this Snapshot which depends only on their (normalized,
+  /// '/'-separated) relative paths and their content Digests, and not on the way in which the tree
+  /// of Directory protos happens to be encoded.
+  ///
+  /// Empty directories and executable bits do not contribute to the checksum.
+  ///
+  pub fn content_set_digest(self, store: Arc<Store>) -> BoxFuture<Fingerprint, String> {
+    let root = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    store
+      .read_batch(move |reader| {
+        let mut files = Vec::new();
+        walk_files(reader, root, PathBuf::new(), &mut files)?;
+        let mut entries = files
+          .into_iter()
+          .map(|(path, file_node)| {
+            let normalized = path
+              .components()
+              .map(|c| c.as_os_str().to_string_lossy().into_owned())
+              .collect::<Vec<_>>()
+              .join("/");
+            let digest = file_node.get_digest();
+            (normalized, digest.get_hash().to_owned(), digest.get_size_bytes())
+          })
+          .collect::<Vec<_>>();
+        entries.sort();
 
-    let path_stats = expand_all_sorted(posix_fs);
-    // TODO: Inline when only used once
-    let fingerprint = Fingerprint::from_hex_string(
-      "63949aa823baf765eff07b946050d76ec0033144c785a94d3ebd82baa931cd16",
-    ).unwrap();
-    assert_eq!(
-      Snapshot::from_path_stats(store, digester, path_stats.clone())
-        .wait()
-        .unwrap(),
-      Snapshot {
-        fingerprint: fingerprint,
-        digest: Some(Digest(fingerprint, 80)),
-        path_stats: path_stats,
-      }
-    );
+        let mut hasher = WriterHasher::new(io::sink());
+        for (path, hash, size) in entries {
+          write!(hasher, "{}\0{}\0{}\n", path, hash, size).map_err(|e| {
+            format!("Error hashing content set: {}", e)
+          })?;
+        }
+        Ok(hasher.finish())
+      })
+      .to_boxed()
   }
 
-  #[test]
-  fn snapshot_recursive_directories() {
+  ///
+  /// Computes an identifier for this Snapshot's tree which depends only on its (normalized,
+  /// '/'-separated) relative paths, executable bits, empty directories and file contents, and not
+  /// on the hash function used for Digests. Unlike the Snapshot's Digest, it remains stable if the
+  /// Store's hash function is changed, which makes it suitable for logs and caches which must
+  /// survive such a migration.
+  ///
+  /// Since files are hashed with a fixed function independently of their Digests, their contents
+  /// are read, which makes this much more expensive than `content_set_digest`.
+  ///
+  pub fn tree_key(self, store: Arc<Store>) -> BoxFuture<String, String> {
+    let root = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    store
+      .read_batch(move |reader| {
+        let normalize = |path: &Path| {
+          path
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+            .join("/")
+        };
+        let mut files = Vec::new();
+        let mut entries = Vec::new();
+        walk_directories(reader, root, PathBuf::new(), &mut |path, directory| {
+          let is_root = path.components().next().is_none();
+          let is_empty = directory.get_files().is_empty() && directory.get_directories().is_empty();
+          if !is_root && is_empty {
+            entries.push(format!("d\0{}\n", normalize(path)));
+          }
+          for file_node in directory.get_files() {
+            files.push((path.join(file_node.get_name()), file_node.clone()));
+          }
+          Ok(())
+        })?;
+        for (path, file_node) in files {
+          let fingerprint = Digest::from_bazel_digest(file_node.get_digest())?.0;
+          let content_hash = reader
+            .load_file_bytes_with(fingerprint, |bytes| {
+              let mut hasher = Sha256::default();
+              hasher.input(bytes);
+              hex::encode(&hasher.fixed_result()[..])
+            })?
+            .ok_or_else(|| format!("File with fingerprint {} not found", fingerprint))?;
+          entries.push(format!(
+            "f\0{}\0{}\0{}\n",
+            normalize(&path),
+            if file_node.get_is_executable() {
+              "x"
+            } else {
+              "-"
+            },
+            content_hash
+          ));
+        }
+        entries.sort();
+
+        let mut hasher = Sha256::default();
+        for entry in entries {
+          hasher.input(entry.as_bytes());
+        }
+        Ok(format!(
+          "{}:{}",
+          TREE_KEY_VERSION,
+          hex::encode(&hasher.fixed_result()[..])
+        ))
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Returns the distinct Digests of the files in this Snapshot, without regard to their paths.
+  ///
+  pub fn file_digests(self, store: Arc<Store>) -> BoxFuture<HashSet<Digest>, String> {
+    let root = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    store
+      .read_batch(move |reader| {
+        let mut digests = HashSet::new();
+        walk_directories(reader, root, PathBuf::new(), &mut |_, directory| {
+          for file_node in directory.get_files() {
+            digests.insert(Digest::from_bazel_digest(file_node.get_digest())?);
+          }
+          Ok(())
+        })?;
+        Ok(digests)
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Returns the Digests of the blobs reachable from this Snapshot (its files, and its Directories
+  /// including the root) which the given remote CAS does not have, sorted, so that only those need
+  /// to be uploaded. The remote is asked with as few FindMissingBlobs requests as possible, each
+  /// checking at most `batch_size` distinct Digests.
+  ///
+  pub fn missing_on_remote(
+    self,
+    store: Arc<Store>,
+    cas: Arc<ContentAddressableStorageClient>,
+    batch_size: usize,
+  ) -> BoxFuture<Vec<Digest>, String> {
+    self.missing_on_remote_impl(store, cas, batch_size, true)
+  }
+
+  ///
+  /// As for `missing_on_remote`, but only considers this Snapshot's Directories (including the
+  /// root), so that only the Directory protos which the remote lacks need to be uploaded.
+  ///
+  pub fn missing_directories_on_remote(
+    self,
+    store: Arc<Store>,
+    cas: Arc<ContentAddressableStorageClient>,
+    batch_size: usize,
+  ) -> BoxFuture<Vec<Digest>, String> {
+    self.missing_on_remote_impl(store, cas, batch_size, false)
+  }
+
+  fn missing_on_remote_impl(
+    self,
+    store: Arc<Store>,
+    cas: Arc<ContentAddressableStorageClient>,
+    batch_size: usize,
+    include_files: bool,
+  ) -> BoxFuture<Vec<Digest>, String> {
+    let root = match self.digest() {
+      Some(digest) => digest,
+      None => return future::err(format!("Snapshot {:?} has no Directory digest", self)).to_boxed(),
+    };
+    let pool = store.pool();
+    store
+      .read_batch(move |reader| {
+        let mut digests = HashSet::new();
+        let fingerprint = root.0;
+        digests.insert(root);
+        walk_directories(reader, fingerprint, PathBuf::new(), &mut |_, directory| {
+          if include_files {
+            for file_node in directory.get_files() {
+              digests.insert(Digest::from_bazel_digest(file_node.get_digest())?);
+            }
+          }
+          for directory_node in directory.get_directories() {
+            digests.insert(Digest::from_bazel_digest(directory_node.get_digest())?);
+          }
+          Ok(())
+        })?;
+        Ok(digests)
+      })
+      .and_then(move |digests| find_missing_blobs(&pool, cas, digests, batch_size))
+      .to_boxed()
+  }
+
+  ///
+  /// Fetches this Snapshot's tree of Directories from the given remote CAS (with GetTree), and
+  /// verifies it before it is trusted: starting from the root, the Directory which each
+  /// DirectoryNode names must be in the tree, with the Digest that the DirectoryNode claims (as
+  /// recomputed from the Directory's encoding). Fails on the first mismatch, naming its path.
+  ///
+  /// Once the tree is verified, its Directories are recorded in the given Store.
+  ///
+  pub fn verify_against(
+    self,
+    store: Arc<Store>,
+    cas: Arc<ContentAddressableStorageClient>,
+  ) -> BoxFuture<(), String> {
+    let root = match self.digest() {
+      Some(digest) => digest,
+      None => return future::err(format!("Snapshot {:?} has no Directory digest", self)).to_boxed(),
+    };
+    store
+      .pool()
+      .spawn_fn(move || -> Result<_, String> {
+        let fetch_err = |err| format!("Error fetching tree {:?}: {}", root, err);
+        let mut directories = HashMap::new();
+        let mut page_token = String::new();
+        loop {
+          let mut request = bazel_protos::remote_execution::GetTreeRequest::new();
+          request.set_root_digest(root.clone().into());
+          request.set_page_token(page_token);
+          let mut response = cas.get_tree(request).map_err(
+            |err| fetch_err(grpc_error(err)),
+          )?;
+          for directory in response.take_directories().into_iter() {
+            let bytes = directory.write_to_bytes().map_err(
+              |err| fetch_err(format!("{:?}", err)),
+            )?;
+            let mut hasher = Sha256::default();
+            hasher.input(&bytes);
+            let fingerprint = Fingerprint::from_bytes_unsafe(&hasher.fixed_result());
+            directories.insert(Digest(fingerprint, bytes.len()), directory);
+          }
+          page_token = response.take_next_page_token();
+          if page_token.is_empty() {
+            break;
+          }
+        }
+
+        let mut verified = HashMap::new();
+        let mut todo = vec![(PathBuf::new(), root.clone())];
+        while let Some((path, digest)) = todo.pop() {
+          if verified.contains_key(&digest) {
+            continue;
+          }
+          let directory = match directories.remove(&digest) {
+            Some(directory) => directory,
+            None => {
+              return Err(format!(
+                "Remote tree {:?} is inconsistent: it has no Directory with the digest {:?} \
+                 claimed for {:?}",
+                root,
+                digest,
+                path
+              ))
+            }
+          };
+          for directory_node in directory.get_directories().iter().rev() {
+            todo.push((
+              path.join(directory_node.get_name()),
+              Digest::from_bazel_digest(directory_node.get_digest())?,
+            ));
+          }
+          verified.insert(digest, directory);
+        }
+        Ok(verified.into_iter().map(|(_, directory)| directory).collect::<Vec<_>>())
+      })
+      .and_then(move |verified| {
+        join_all(
+          verified
+            .iter()
+            .map(|directory| store.record_directory(directory))
+            .collect::<Vec<_>>(),
+        ).map(|_| ())
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Returns the path, Digest, and executable bit of each file in this Snapshot, sorted by path.
+  /// This only reads Directories, so no file contents are loaded (or need to be present).
+  ///
+  /// Manifests are cached by the Store if `StoreOptions::manifest_cache_size` is set, since a
+  /// Snapshot's Digest determines its manifest.
+  ///
+  pub fn manifest(self, store: Arc<Store>) -> BoxFuture<Vec<(PathBuf, Digest, bool)>, String> {
+    let root = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    if let Some(manifest) = store.cached_manifest(root) {
+      return future::ok((*manifest).clone()).to_boxed();
+    }
+    let cache_store = store.clone();
+    store
+      .read_batch(move |reader| {
+        let mut files = Vec::new();
+        walk_files(reader, root, PathBuf::new(), &mut files)?;
+        let mut manifest = files
+          .into_iter()
+          .map(|(path, file_node)| {
+            let digest = Digest::from_bazel_digest(file_node.get_digest())?;
+            Ok((path, digest, file_node.get_is_executable()))
+          })
+          .collect::<Result<Vec<_>, String>>()?;
+        manifest.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(manifest)
+      })
+      .map(move |manifest| {
+        cache_store.cache_manifest(root, &manifest);
+        manifest
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Returns this Snapshot's tree as nested JSON, for APIs which render it as a tree view. Each
+  /// directory is an object with its "name", "digest", "size_bytes" and "children" (directories
+  /// before files, each sorted by name), and each file an object with its "name", "digest",
+  /// "size_bytes" and "is_executable". The root directory's name is empty. As with `manifest`, only
+  /// Directories are read, so no file contents are included (or need to be present).
+  ///
+  pub fn to_tree_json(self, store: Arc<Store>) -> BoxFuture<serde_json::Value, String> {
+    let digest = match self.digest {
+      Some(digest) => digest,
+      None => return future::err(format!("Snapshot {:?} has no Digest", self)).to_boxed(),
+    };
+    store
+      .read_batch(move |reader| directory_json(reader, "", digest, 0))
+      .to_boxed()
+  }
+
+  ///
+  /// Returns the content type of each file in this Snapshot, by path, as computed by `classifier`.
+  ///
+  /// Types are recorded in the Store by file Digest, so each distinct content is only read and
+  /// classified once, by any Snapshot: later calls (and later steps) reuse the recorded types
+  /// without reading the files again.
+  ///
+  pub fn classify<C: ContentClassifier + Send + Sync + 'static>(
+    self,
+    store: Arc<Store>,
+    classifier: Arc<C>,
+  ) -> BoxFuture<HashMap<PathBuf, String>, String> {
+    let root = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    let record_store = store.clone();
+    let name = classifier.name().to_owned();
+    store
+      .read_batch(move |reader| {
+        let mut files = Vec::new();
+        walk_files(reader, root, PathBuf::new(), &mut files)?;
+        let mut known: HashMap<Fingerprint, String> = HashMap::new();
+        let mut classified = Vec::new();
+        let mut content_types = HashMap::new();
+        for (path, file_node) in files {
+          let fingerprint = Digest::from_bazel_digest(file_node.get_digest())?.0;
+          let known_type = known.get(&fingerprint).cloned();
+          let content_type = match known_type {
+            Some(content_type) => content_type,
+            None => {
+              let content_type = match reader.load_content_type(fingerprint, classifier.name())? {
+                Some(content_type) => content_type,
+                None => {
+                  let content_type = reader
+                    .load_file_bytes_with(fingerprint, |bytes| {
+                      classifier.classify(&bytes[..min(bytes.len(), classifier.prefix_len())])
+                    })?
+                    .ok_or_else(|| format!("File with fingerprint {} not found", fingerprint))?;
+                  classified.push((fingerprint, content_type.clone()));
+                  content_type
+                }
+              };
+              known.insert(fingerprint, content_type.clone());
+              content_type
+            }
+          };
+          content_types.insert(path, content_type);
+        }
+        Ok((content_types, classified))
+      })
+      .and_then(move |(content_types, classified)| {
+        if classified.is_empty() {
+          return future::ok(content_types).to_boxed();
+        }
+        record_store
+          .record_content_types(name, classified)
+          .map(move |()| content_types)
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Returns each Digest which is the content of more than one file in this Snapshot, along with
+  /// the sorted paths of those files. Groups are sorted by Digest. As with `manifest`, only
+  /// Directories are read.
+  ///
+  pub fn duplicate_groups(
+    self,
+    store: Arc<Store>,
+  ) -> BoxFuture<Vec<(Digest, Vec<PathBuf>)>, String> {
+    let root = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    store
+      .read_batch(move |reader| {
+        let mut files = Vec::new();
+        walk_files(reader, root, PathBuf::new(), &mut files)?;
+        let mut groups: BTreeMap<Digest, Vec<PathBuf>> = BTreeMap::new();
+        for (path, file_node) in files {
+          let digest = Digest::from_bazel_digest(file_node.get_digest())?;
+          groups.entry(digest).or_insert_with(Vec::new).push(path);
+        }
+        Ok(
+          groups
+            .into_iter()
+            .filter(|&(_, ref paths)| paths.len() > 1)
+            .map(|(digest, mut paths)| {
+              paths.sort();
+              (digest, paths)
+            })
+            .collect(),
+        )
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Writes the contents of this Snapshot beneath the destination directory, which will be created
+  /// if it does not already exist. Existing files at the same paths are overwritten.
+  ///
+  /// A destination which does not already exist only appears once it is complete: the Snapshot is
+  /// written into a temporary sibling directory, which is renamed into place on success and removed
+  /// on failure, so that a crash never leaves a partial destination behind. Since the temporary
+  /// directory is a sibling, the rename never crosses devices. An existing destination is always
+  /// written in place.
+  ///
+  pub fn materialize(self, store: Arc<Store>, destination: PathBuf) -> BoxFuture<(), String> {
+    if fs::symlink_metadata(&destination).is_ok() {
+      return self.materialize_with(store, destination, unchanged_path);
+    }
+    let (name, parent) = match (destination.file_name(), destination.parent()) {
+      (Some(name), Some(parent)) => (name.to_owned(), parent.to_owned()),
+      // Destinations like `..` cannot be renamed into place.
+      _ => return self.materialize_with(store, destination, unchanged_path),
+    };
+    let staging = match safe_create_tmpdir_in(
+      if parent.as_os_str().is_empty() {
+        Path::new(".")
+      } else {
+        parent.as_path()
+      },
+      &format!(".{}.materialize", name.to_string_lossy()),
+    ) {
+      Ok(staging) => staging,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    let staging_path = staging.path().to_owned();
+    self
+      .materialize_with(store, staging_path, unchanged_path)
+      .and_then(move |()| {
+        // On failure, dropping the TempDir removes the partially written Snapshot.
+        let staged = staging.into_path();
+        fs::rename(&staged, &destination).map_err(|e| {
+          let _ = fs::remove_dir_all(&staged);
+          format!(
+            "Error renaming {:?} into place at {:?}: {}",
+            staged,
+            destination,
+            e
+          )
+        })
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Materializes this Snapshot into a temporary directory beneath `tmp`, snapshots the result
+  /// again, and fails unless the two Snapshots have the same Digest. A mismatch means that some
+  /// part of the tree (such as a mode, an empty directory, or a name) was not faithfully written
+  /// or read back.
+  ///
+  pub fn verify_roundtrip(self, store: Arc<Store>, tmp: PathBuf) -> BoxFuture<(), String> {
+    let expected = match self.digest {
+      Some(ref digest) => digest.clone(),
+      None => return future::err(format!("Snapshot {:?} has no digest", self)).to_boxed(),
+    };
+    let workdir = match safe_create_tmpdir_in(&tmp, ".roundtrip") {
+      Ok(workdir) => workdir,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    let root = workdir.path().join("tree");
+    let snapshot_store = store.clone();
+    self
+      .materialize(store, root.clone())
+      .and_then(move |()| -> Result<_, String> {
+        let posix_fs = Arc::new(PosixFS::new(&root, snapshot_store.pool(), vec![])?);
+        let path_globs = PathGlobs::create(&["**".to_owned()], &[])?;
+        Ok((posix_fs, path_globs))
+      })
+      .and_then(|(posix_fs, path_globs)| {
+        posix_fs
+          .expand(path_globs)
+          .map_err(|e| format!("Error expanding materialized Snapshot: {}", e))
+          .map(move |path_stats| (posix_fs, path_stats))
+      })
+      .and_then(move |(posix_fs, path_stats)| {
+        let digester = Arc::new(StoringDigester::new(snapshot_store.clone(), posix_fs));
+        Snapshot::from_path_stats(snapshot_store, digester, path_stats)
+      })
+      .and_then(move |roundtripped| {
+        // The materialized tree is only removed once it has been snapshotted.
+        drop(workdir);
+        if roundtripped.digest.as_ref() == Some(&expected) {
+          Ok(())
+        } else {
+          Err(format!(
+            "Snapshot {:?} did not round trip: materializing and re-snapshotting it gave {:?}",
+            expected,
+            roundtripped.digest
+          ))
+        }
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// As for `materialize`, but writes up to `concurrency` files (or one, if `concurrency` is zero)
+  /// at a time on the Store's pool. All directories are created before any files are written, so
+  /// that concurrent writes never race to create a shared parent.
+  ///
+  pub fn materialize_concurrently(
+    self,
+    store: Arc<Store>,
+    destination: PathBuf,
+    concurrency: usize,
+  ) -> BoxFuture<(), String> {
+    let root = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    let write_store = store.clone();
+    store
+      .read_batch(move |reader| {
+        let mut files = Vec::new();
+        let mut directories = BTreeSet::new();
+        walk_directories(reader, root, PathBuf::new(), &mut |path, directory| {
+          directories.insert(destination.join(path));
+          for file_node in directory.get_files() {
+            files.push((destination.join(path).join(file_node.get_name()), file_node.clone()));
+          }
+          Ok(())
+        })?;
+        // Sorted, so parents are created before their children.
+        for directory in directories {
+          safe_create_dir_all(&directory)?;
+        }
+        files
+          .into_iter()
+          .map(|(dest, file_node)| {
+            let fingerprint = Digest::from_bazel_digest(file_node.get_digest())?.0;
+            Ok((dest, fingerprint, file_node.get_is_executable()))
+          })
+          .collect::<Result<Vec<_>, String>>()
+      })
+      .and_then(move |files| {
+        stream::iter_ok::<_, String>(files)
+          .map(move |(dest, fingerprint, is_executable)| {
+            write_store
+              .load_file_bytes_with(fingerprint, move |bytes| {
+                write_file(&dest, bytes, is_executable)
+              })
+              .and_then(move |maybe_written| {
+                maybe_written.unwrap_or_else(|| {
+                  Err(format!("File with fingerprint {} not found", fingerprint))
+                })
+              })
+          })
+          .buffer_unordered(max(concurrency, 1))
+          .for_each(|()| Ok(()))
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Streams the contents of this Snapshot's files, in path order, loading them only as they are
+  /// consumed. At most `buffer` files (or one, if `buffer` is zero) are loaded ahead of the
+  /// consumer, which bounds memory use when the consumer is slower than the Store.
+  ///
+  pub fn contents_stream(self, store: Arc<Store>, buffer: usize) -> BoxStream<FileContent, String> {
+    let load_store = store.clone();
+    Box::new(
+      self
+        .manifest(store)
+        .map(move |manifest| {
+          let files = manifest
+            .into_iter()
+            .map(|(path, digest, _)| (path, digest.0))
+            .collect::<Vec<_>>();
+          stream::iter_ok::<_, String>(files)
+            .map(move |(path, fingerprint)| {
+              load_store.load_file_bytes(fingerprint).and_then(
+                move |maybe_bytes| {
+                  maybe_bytes
+                    .map(|content| {
+                      FileContent {
+                        path: path,
+                        content: content,
+                      }
+                    })
+                    .ok_or_else(|| format!("File with fingerprint {} not found", fingerprint))
+                },
+              )
+            })
+            .buffered(max(buffer, 1))
+        })
+        .flatten_stream(),
+    )
+  }
+
+  ///
+  /// Loads the contents of this Snapshot's files whose sizes are at most `max_file_size` bytes,
+  /// sorted by path. Larger files are not loaded: their paths are returned separately, sorted, so
+  /// that the caller can tell what was omitted (and treat it as an error if need be).
+  ///
+  pub fn contents_up_to_size(
+    self,
+    store: Arc<Store>,
+    max_file_size: usize,
+  ) -> BoxFuture<(Vec<FileContent>, Vec<PathBuf>), String> {
+    let root = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    store
+      .read_batch(move |reader| {
+        let mut files = Vec::new();
+        walk_files(reader, root, PathBuf::new(), &mut files)?;
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut contents = Vec::new();
+        let mut skipped = Vec::new();
+        for (path, file_node) in files {
+          let digest = Digest::from_bazel_digest(file_node.get_digest())?;
+          if digest.1 > max_file_size {
+            skipped.push(path);
+            continue;
+          }
+          let content = reader
+            .load_file_bytes_with(digest.0, |bytes| bytes.to_vec())?
+            .ok_or_else(|| format!("File with fingerprint {} not found", digest.0))?;
+          contents.push(FileContent {
+            path: path,
+            content: content,
+          });
+        }
+        Ok((contents, skipped))
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Loads the contents of the files at the given paths, in the order they were given, reading only
+  /// the Directories along those paths rather than walking the whole Snapshot. Fails, listing every
+  /// path which was missing, if any of them is not a file in this Snapshot.
+  ///
+  pub fn contents_of(
+    self,
+    store: Arc<Store>,
+    paths: Vec<PathBuf>,
+  ) -> BoxFuture<Vec<FileContent>, String> {
+    let root = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    store
+      .read_batch(move |reader| {
+        let mut contents = Vec::new();
+        let mut missing = Vec::new();
+        for path in paths {
+          let file_node = match find_file(reader, root, &path)? {
+            Some(file_node) => file_node,
+            None => {
+              missing.push(path);
+              continue;
+            }
+          };
+          let fingerprint = Digest::from_bazel_digest(file_node.get_digest())?.0;
+          let content = reader
+            .load_file_bytes_with(fingerprint, |bytes| bytes.to_vec())?
+            .ok_or_else(|| format!("File with fingerprint {} not found", fingerprint))?;
+          contents.push(FileContent {
+            path: path,
+            content: content,
+          });
+        }
+        if !missing.is_empty() {
+          return Err(format!("Files not found in Snapshot: {:?}", missing));
+        }
+        Ok(contents)
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Returns the 1-based numbers of the lines of each of this Snapshot's files which match the
+  /// given pattern, for the files with at least one matching line, sorted by path. If `globs` are
+  /// given, only the files which they match are searched.
+  ///
+  /// Files are searched one at a time, in place in the Store, so no more than one file's contents
+  /// are ever held in memory. Lines which are not valid UTF8 are searched in their lossy form.
+  ///
+  pub fn grep(
+    self,
+    store: Arc<Store>,
+    pattern: Regex,
+    globs: Option<PathGlobs>,
+  ) -> BoxFuture<Vec<(PathBuf, Vec<usize>)>, String> {
+    let load_store = store.clone();
+    let pattern = Arc::new(pattern);
+    self
+      .manifest(store)
+      .and_then(move |manifest| {
+        let globs = match globs {
+          Some(globs) => globs,
+          None => return future::ok(manifest).to_boxed(),
+        };
+        let files = manifest
+          .iter()
+          .map(|&(ref path, _, is_executable)| (path.clone(), is_executable))
+          .collect::<Vec<_>>();
+        ManifestVFS::new(&files)
+          .expand(globs)
+          .map(move |path_stats| {
+            let matched = path_stats
+              .into_iter()
+              .map(|path_stat| path_stat.path().to_owned())
+              .collect::<HashSet<_>>();
+            manifest
+              .into_iter()
+              .filter(|&(ref path, _, _)| matched.contains(path))
+              .collect::<Vec<_>>()
+          })
+          .to_boxed()
+      })
+      .and_then(move |manifest| {
+        stream::iter_ok::<_, String>(manifest)
+          .and_then(move |(path, digest, _)| {
+            let pattern = pattern.clone();
+            load_store
+              .load_file_bytes_with(digest.0, move |bytes| matching_lines(&pattern, bytes))
+              .and_then(move |maybe_lines| {
+                maybe_lines
+                  .map(|lines| (path, lines))
+                  .ok_or_else(|| format!("File with fingerprint {} not found", digest.0))
+              })
+          })
+          .filter(|&(_, ref lines)| !lines.is_empty())
+          .collect()
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Computes what `materialize` would change beneath `destination`, without writing anything. The
+  /// destination is only inspected with stat calls and directory listings; file contents are not
+  /// read or compared.
+  ///
+  /// Fails if a directory in the destination occupies the path of a file in the Snapshot, since
+  /// `materialize` would fail to write that file.
+  ///
+  pub fn plan_materialize(
+    self,
+    store: Arc<Store>,
+    destination: PathBuf,
+  ) -> BoxFuture<MaterializePlan, String> {
+    let root = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    store
+      .read_batch(move |reader| {
+        let mut files = Vec::new();
+        walk_files(reader, root, PathBuf::new(), &mut files)?;
+
+        let mut plan = MaterializePlan::default();
+        let mut snapshot_paths = HashSet::new();
+        for (path, file_node) in files {
+          let dest = destination.join(&path);
+          match fs::symlink_metadata(&dest) {
+            Ok(ref metadata) if metadata.is_dir() => {
+              return Err(format!(
+                "Cannot materialize file {:?}: {:?} is a directory",
+                path,
+                dest
+              ));
+            }
+            Ok(_) => plan.overwrite.push(path.clone()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => plan.create.push(path.clone()),
+            Err(e) => return Err(format!("Error inspecting {:?}: {}", dest, e)),
+          }
+          plan.bytes_to_write += file_node.get_digest().get_size_bytes() as u64;
+          snapshot_paths.insert(path);
+        }
+
+        let mut existing = Vec::new();
+        list_files(
+          &destination,
+          PathBuf::new(),
+          &mut existing,
+          reader.max_directory_depth(),
+        )?;
+        plan.delete = existing
+          .into_iter()
+          .filter(|path| !snapshot_paths.contains(path))
+          .collect();
+
+        plan.create.sort();
+        plan.overwrite.sort();
+        plan.delete.sort();
+        Ok(plan)
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Computes the changes which turn a directory containing `other` (the old Snapshot) into one
+  /// containing this Snapshot. Only Directories are read. The resulting patch can then be applied
+  /// with `SnapshotPatch::apply_to`, which moves only the difference rather than materializing all
+  /// of this Snapshot.
+  ///
+  pub fn patch_against(
+    self,
+    other: Snapshot,
+    store: Arc<Store>,
+  ) -> BoxFuture<SnapshotPatch, String> {
+    let new_root = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    let old_root = match other.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    let patch_store = store.clone();
+    store
+      .read_batch(move |reader| {
+        let (new_files, new_dirs) = tree_entries(reader, new_root)?;
+        let (mut old_files, old_dirs) = tree_entries(reader, old_root)?;
+
+        let mut add = Vec::new();
+        let mut update = Vec::new();
+        for (path, (digest, is_executable)) in new_files {
+          match old_files.remove(&path) {
+            None => add.push((path, digest, is_executable)),
+            Some(old) => {
+              if old != (digest.clone(), is_executable) {
+                update.push((path, digest, is_executable));
+              }
+            }
+          }
+        }
+        Ok(SnapshotPatch {
+          store: patch_store,
+          add: add,
+          update: update,
+          delete: old_files.into_iter().map(|(path, _)| path).collect(),
+          create_dirs: new_dirs.difference(&old_dirs).cloned().collect(),
+          remove_dirs: old_dirs.difference(&new_dirs).cloned().collect(),
+        })
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Records the current modification times of this Snapshot's files, as found beneath `root`
+  /// (which should be the directory that the Snapshot was created from), so that
+  /// `materialize_with_mtimes` can restore them. Modification times do not affect any Digest.
+  ///
+  /// This should be called immediately after creating the Snapshot: a file which has been modified
+  /// since then will have its new modification time recorded.
+  ///
+  pub fn capture_mtimes(self, store: Arc<Store>, root: PathBuf) -> BoxFuture<(), String> {
+    let root_fingerprint = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    let record_store = store.clone();
+    store
+      .read_batch(move |reader| {
+        let mut files = Vec::new();
+        walk_files(reader, root_fingerprint, PathBuf::new(), &mut files)?;
+        files
+          .into_iter()
+          .map(|(path, file_node)| {
+            let fingerprint = Digest::from_bazel_digest(file_node.get_digest())?.0;
+            let metadata = fs::metadata(root.join(&path)).map_err(|e| {
+              format!("Error reading mtime of {:?}: {}", path, e)
+            })?;
+            let mtime = FileTime::from_last_modification_time(&metadata);
+            Ok((path, fingerprint, mtime))
+          })
+          .collect::<Result<Vec<_>, String>>()
+      })
+      .and_then(move |mtimes| record_store.record_mtimes(mtimes))
+      .to_boxed()
+  }
+
+  ///
+  /// As for `materialize`, but additionally restores the modification times of any files whose
+  /// modification times were recorded by `capture_mtimes`. Other files keep the time at which they
+  /// were written.
+  ///
+  pub fn materialize_with_mtimes(
+    self,
+    store: Arc<Store>,
+    destination: PathBuf,
+  ) -> BoxFuture<(), String> {
+    let root = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    let mtime_store = store.clone();
+    self
+      .materialize(store, destination.clone())
+      .and_then(move |()| {
+        mtime_store.read_batch(move |reader| {
+          let mut files = Vec::new();
+          walk_files(reader, root, PathBuf::new(), &mut files)?;
+          for (path, file_node) in files {
+            let fingerprint = Digest::from_bazel_digest(file_node.get_digest())?.0;
+            if let Some(mtime) = reader.load_mtime(&path, fingerprint)? {
+              let dest = destination.join(&path);
+              let atime = fs::metadata(&dest)
+                .map(|metadata| FileTime::from_last_access_time(&metadata))
+                .unwrap_or(mtime);
+              filetime::set_file_times(&dest, atime, mtime).map_err(|e| {
+                format!("Error setting mtime of {:?}: {}", dest, e)
+              })?;
+            }
+          }
+          Ok(())
+        })
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Records the current permission bits of this Snapshot's directories (including its root), as
+  /// found beneath `root`, so that `materialize_with_directory_modes` can restore them. As with
+  /// `capture_mtimes`, this should be called immediately after creating the Snapshot, and does not
+  /// affect any Digest.
+  ///
+  pub fn capture_directory_modes(
+    self,
+    store: Arc<Store>,
+    root: PathBuf,
+  ) -> BoxFuture<(), String> {
+    let root_fingerprint = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    let record_store = store.clone();
+    store
+      .read_batch(move |reader| {
+        directory_fingerprints(reader, root_fingerprint)?
+          .into_iter()
+          .map(|(path, fingerprint)| {
+            let metadata = fs::metadata(root.join(&path)).map_err(|e| {
+              format!("Error reading mode of {:?}: {}", path, e)
+            })?;
+            Ok((path, fingerprint, metadata.permissions().mode() & 0o7777))
+          })
+          .collect::<Result<Vec<_>, String>>()
+      })
+      .and_then(move |modes| record_store.record_directory_modes(modes))
+      .to_boxed()
+  }
+
+  ///
+  /// As for `materialize`, but additionally restores the permission bits of any directories whose
+  /// modes were recorded by `capture_directory_modes`. Modes are applied once all files have been
+  /// written, and to subdirectories before their parents, so that restrictive modes do not prevent
+  /// the Snapshot from being written.
+  ///
+  pub fn materialize_with_directory_modes(
+    self,
+    store: Arc<Store>,
+    destination: PathBuf,
+  ) -> BoxFuture<(), String> {
+    let root = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    let mode_store = store.clone();
+    self
+      .materialize(store, destination.clone())
+      .and_then(move |()| {
+        mode_store.read_batch(move |reader| {
+          let mut directories = directory_fingerprints(reader, root)?;
+          directories.sort_by(|a, b| b.0.cmp(&a.0));
+          for (path, fingerprint) in directories {
+            if let Some(mode) = reader.load_directory_mode(&path, fingerprint)? {
+              let dest = destination.join(&path);
+              fs::set_permissions(&dest, fs::Permissions::from_mode(mode))
+                .map_err(|e| format!("Error setting mode of {:?}: {}", dest, e))?;
+            }
+          }
+          Ok(())
+        })
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// As for `materialize`, but restores the exact names recorded by
+  /// `from_path_stats_preserving_names` in place of their UTF8-lossy forms. Names without a record
+  /// are written as they appear in the Snapshot.
+  ///
+  pub fn materialize_with_raw_names(
+    self,
+    store: Arc<Store>,
+    destination: PathBuf,
+  ) -> BoxFuture<(), String> {
+    let root = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    let materialize_store = store.clone();
+    store
+      .read_batch(move |reader| {
+        // The raw name of each renamed entry, by its lossy path.
+        let mut raw_names = HashMap::new();
+        for (path, fingerprint) in directory_fingerprints(reader, root)? {
+          let directory = reader.load_directory_proto(fingerprint)?.ok_or_else(|| {
+            format!("Directory with fingerprint {} not found", fingerprint)
+          })?;
+          let names = directory
+            .get_files()
+            .iter()
+            .map(|file_node| file_node.get_name())
+            .chain(directory.get_directories().iter().map(|directory_node| {
+              directory_node.get_name()
+            }));
+          for name in names {
+            if let Some(raw) = reader.load_raw_name(fingerprint, name)? {
+              raw_names.insert(path.join(name), OsStr::from_bytes(&raw).to_owned());
+            }
+          }
+        }
+        Ok(raw_names)
+      })
+      .and_then(move |raw_names: HashMap<PathBuf, OsString>| {
+        self.materialize_with(materialize_store, destination, move |path: &Path| {
+          let mut lossy_path = PathBuf::new();
+          let mut raw_path = PathBuf::new();
+          for component in path.components() {
+            lossy_path.push(component.as_os_str());
+            match raw_names.get(&lossy_path) {
+              Some(raw) => raw_path.push(raw),
+              None => raw_path.push(component.as_os_str()),
+            }
+          }
+          Some(raw_path)
+        })
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// As for `materialize`, but passes the relative path of each file (and of each empty directory)
+  /// through the transform before writing it. Entries for which the transform returns None are
+  /// skipped, and the returned paths must be relative and must not traverse outside of the
+  /// destination.
+  ///
+  pub fn materialize_with<F>(
+    self,
+    store: Arc<Store>,
+    destination: PathBuf,
+    transform: F,
+  ) -> BoxFuture<(), String>
+  where
+    F: Fn(&Path) -> Option<PathBuf> + Send + 'static,
+  {
+    let root = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    store
+      .read_batch(move |reader| {
+        let mut files = Vec::new();
+        let mut empty_dirs = Vec::new();
+        walk_directories(reader, root, PathBuf::new(), &mut |path, directory| {
+          if directory.get_files().is_empty() && directory.get_directories().is_empty() {
+            empty_dirs.push(path.to_owned());
+          }
+          for file_node in directory.get_files() {
+            files.push((path.join(file_node.get_name()), file_node.clone()));
+          }
+          Ok(())
+        })?;
+
+        safe_create_dir_all(&destination)?;
+        for path in empty_dirs {
+          if path.components().next().is_none() {
+            // The root is the destination itself.
+            continue;
+          }
+          if let Some(relative) = transform_relative(&transform, &path)? {
+            safe_create_dir_all(&destination.join(relative))?;
+          }
+        }
+        for (path, file_node) in files {
+          let dest = match transform_relative(&transform, &path)? {
+            Some(relative) => destination.join(relative),
+            None => continue,
+          };
+          if let Some(parent) = dest.parent() {
+            safe_create_dir_all(parent)?;
+          }
+          let fingerprint = Digest::from_bazel_digest(file_node.get_digest())?.0;
+          let is_executable = file_node.get_is_executable();
+          reader
+            .load_file_bytes_with(fingerprint, |bytes| {
+              write_file(&dest, bytes, is_executable)
+            })?
+            .ok_or_else(|| {
+              format!("File with fingerprint {} not found", fingerprint)
+            })??;
+        }
+        Ok(())
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Returns a Snapshot containing only the files of this Snapshot whose relative paths are exactly
+  /// present in `allowed`. Directories which are left empty are pruned.
+  ///
+  /// Unlike globs, the allowed set is matched literally, which makes it suitable for an externally
+  /// supplied allowlist.
+  ///
+  pub fn restrict_to(
+    self,
+    store: Arc<Store>,
+    allowed: HashSet<PathBuf>,
+  ) -> BoxFuture<Snapshot, String> {
+    let root = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    let path_stats = self.path_stats;
+    store
+      .read_batch(move |reader| {
+        let mut files = Vec::new();
+        walk_files(reader, root, PathBuf::new(), &mut files)?;
+        Ok(files)
+      })
+      .and_then(move |files| {
+        let files = files
+          .into_iter()
+          .filter(|&(ref path, _)| allowed.contains(path))
+          .collect::<Vec<_>>();
+        let path_stats = retain_path_stats(path_stats, &allowed);
+        Snapshot::from_tree(store, files, vec![], path_stats)
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Partitions the files and empty directories of this Snapshot into `n` standalone Snapshots, by
+  /// a stable hash of their paths, so that each of `n` workers can materialize its own shard. Every
+  /// file is in exactly one shard, and merging the shards gives back this Snapshot; a shard may be
+  /// empty.
+  ///
+  /// Only Directories are read; file contents are not re-stored.
+  ///
+  pub fn shard(self, store: Arc<Store>, n: usize) -> BoxFuture<Vec<Snapshot>, String> {
+    if n == 0 {
+      return future::err("Cannot shard a Snapshot into 0 shards".to_owned()).to_boxed();
+    }
+    let root = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    let path_stats = self.path_stats;
+    store
+      .read_batch(move |reader| {
+        let mut files = Vec::new();
+        let mut empty_dirs = Vec::new();
+        walk_directories(reader, root, PathBuf::new(), &mut |path, directory| {
+          for file_node in directory.get_files() {
+            files.push((path.join(file_node.get_name()), file_node.clone()));
+          }
+          if directory.get_files().is_empty() && directory.get_directories().is_empty() &&
+            path != Path::new("")
+          {
+            empty_dirs.push(path.to_owned());
+          }
+          Ok(())
+        })?;
+        Ok((files, empty_dirs))
+      })
+      .and_then(move |(files, empty_dirs)| {
+        let mut shards = (0..n).map(|_| (Vec::new(), Vec::new())).collect::<Vec<_>>();
+        for (path, file_node) in files {
+          shards[shard_index(&path, n)].0.push((path, file_node));
+        }
+        for path in empty_dirs {
+          shards[shard_index(&path, n)].1.push(path);
+        }
+        join_all(
+          shards
+            .into_iter()
+            .map(|(files, empty_dirs)| {
+              let entries = files
+                .iter()
+                .map(|&(ref path, _)| path.clone())
+                .chain(empty_dirs.iter().cloned())
+                .collect::<HashSet<_>>();
+              let path_stats = shard_path_stats(&path_stats, &entries);
+              Snapshot::from_tree(store.clone(), files, empty_dirs, path_stats)
+            })
+            .collect::<Vec<_>>(),
+        )
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Returns a Snapshot with the same contents as this one, but with the executable bit of each
+  /// file replaced by the result of calling `f` with its path and current executable bit.
+  ///
+  /// File contents are neither read nor re-stored: only the Directories containing changed files
+  /// are recorded again.
+  ///
+  pub fn with_modes<F: Fn(&Path, bool) -> bool + Send + 'static>(
+    self,
+    store: Arc<Store>,
+    f: F,
+  ) -> BoxFuture<Snapshot, String> {
+    let root = match self.directory_fingerprint() {
+      Ok(fingerprint) => fingerprint,
+      Err(e) => return future::err(e).to_boxed(),
+    };
+    let path_stats = self.path_stats;
+    store
+      .read_batch(move |reader| {
+        let mut files = Vec::new();
+        let mut empty_dirs = Vec::new();
+        walk_directories(reader, root, PathBuf::new(), &mut |path, directory| {
+          for file_node in directory.get_files() {
+            files.push((path.join(file_node.get_name()), file_node.clone()));
+          }
+          if directory.get_files().is_empty() && directory.get_directories().is_empty() &&
+            path != Path::new("")
+          {
+            empty_dirs.push(path.to_owned());
+          }
+          Ok(())
+        })?;
+        Ok((files, empty_dirs))
+      })
+      .and_then(move |(files, empty_dirs)| {
+        let files = files
+          .into_iter()
+          .map(|(path, mut file_node)| {
+            let is_executable = f(&path, file_node.get_is_executable());
+            file_node.set_is_executable(is_executable);
+            (path, file_node)
+          })
+          .collect::<Vec<_>>();
+        let modes = files
+          .iter()
+          .map(|&(ref path, ref file_node)| {
+            (path.clone(), file_node.get_is_executable())
+          })
+          .collect::<HashMap<_, _>>();
+        let path_stats = path_stats
+          .into_iter()
+          .map(|path_stat| match path_stat {
+            PathStat::File { path, stat } => {
+              let is_executable = modes.get(&path).cloned().unwrap_or(stat.is_executable);
+              PathStat::File {
+                path: path,
+                stat: File {
+                  path: stat.path,
+                  is_executable: is_executable,
+                },
+              }
+            }
+            dir => dir,
+          })
+          .collect();
+        Snapshot::from_tree(store, files, empty_dirs, path_stats)
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Creates a Snapshot from the entries of a tar archive, storing each file's contents as it is
+  /// read from the stream, without extracting the archive to disk. Directory entries become
+  /// (possibly empty) directories, and files' executable bits are taken from their modes.
+  ///
+  /// The archive is read synchronously when the returned future is first polled. Entries other
+  /// than regular files and directories (such as symlinks) are rejected.
+  ///
+  pub fn from_tar_stream<R: Read + Send + 'static>(
+    store: Arc<Store>,
+    reader: R,
+  ) -> BoxFuture<Snapshot, String> {
+    let read_store = store.clone();
+    future::lazy(move || read_tar_entries(&read_store, reader))
+      .and_then(move |(file_futures, dirs)| {
+        join_all(file_futures).and_then(move |files| {
+          let mut all_dirs = dirs.iter().cloned().collect::<BTreeSet<_>>();
+          for &(ref path, _) in &files {
+            let mut current = path.parent();
+            while let Some(parent) = current {
+              if parent != Path::new("") {
+                all_dirs.insert(parent.to_owned());
+              }
+              current = parent.parent();
+            }
+          }
+          let mut path_stats = all_dirs
+            .into_iter()
+            .map(|path| PathStat::dir(path.clone(), Dir(path)))
+            .chain(files.iter().map(|&(ref path, ref file_node)| {
+              PathStat::file(
+                path.clone(),
+                File {
+                  path: path.clone(),
+                  is_executable: file_node.get_is_executable(),
+                },
+              )
+            }))
+            .collect::<Vec<_>>();
+          path_stats.sort_by(|a, b| a.path().cmp(b.path()));
+          Snapshot::from_tree(store, files, dirs, path_stats)
+        })
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Creates a Snapshot containing a single file at the given path, whose content is read from the
+  /// given reader (such as a pipe). The content is spooled to a temporary file as it is read and
+  /// then moved into the Store with `Store::promote_file`, so it is never buffered in memory.
+  ///
+  /// As with `from_tar_stream`, the reader is consumed synchronously when the returned future is
+  /// first polled.
+  ///
+  pub fn of_reader<R: Read + Send + 'static>(
+    store: Arc<Store>,
+    name: PathBuf,
+    mut reader: R,
+    is_executable: bool,
+  ) -> BoxFuture<Snapshot, String> {
+    let spool_store = store.clone();
+    future::lazy(move || {
+      let spool_dir = TempDir::new("snapshot_of_reader").map_err(|e| {
+        format!("Error creating spool directory for {:?}: {}", name, e)
+      })?;
+      let spool_path = spool_dir.path().join("content");
+      {
+        let mut spool = fs::File::create(&spool_path).map_err(|e| {
+          format!("Error creating spool file {:?}: {}", spool_path, e)
+        })?;
+        io::copy(&mut reader, &mut spool).map_err(|e| {
+          format!("Error spooling content of {:?}: {}", name, e)
+        })?;
+      }
+      Ok::<_, String>((spool_dir, spool_path, name))
+    }).and_then(move |(spool_dir, spool_path, name)| {
+        spool_store.promote_file(&spool_path).map(move |digest| {
+          // The spool directory is only removed once its content has been moved into the Store.
+          drop(spool_dir);
+          (name, digest)
+        })
+      })
+      .and_then(move |(name, digest)| {
+        let mut digests = HashMap::new();
+        digests.insert(name.clone(), digest);
+        let path_stats = vec![
+          PathStat::file(
+            name.clone(),
+            File {
+              path: name,
+              is_executable: is_executable,
+            },
+          ),
+        ];
+        Snapshot::from_path_stats(store, Arc::new(KnownDigests(digests)), path_stats)
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Records the Directory protos for a tree containing the given files (which will be renamed to
+  /// the file names of their paths) and empty directories, and wraps them in a Snapshot with the
+  /// given PathStats.
+  ///
+  fn from_tree(
+    store: Arc<Store>,
+    files: Vec<(PathBuf, bazel_protos::remote_execution::FileNode)>,
+    empty_dirs: Vec<PathBuf>,
+    path_stats: Vec<PathStat>,
+  ) -> BoxFuture<Snapshot, String> {
+    let mut root = BTreeMap::new();
+    let entries = files
+      .into_iter()
+      .map(|(path, file_node)| (path, TreeEntry::File(file_node)))
+      .chain(empty_dirs.into_iter().map(|path| {
+        (path, TreeEntry::Directory(BTreeMap::new()))
+      }));
+    for (path, entry) in entries {
+      let components = match tree_components(&path, store.max_directory_depth()) {
+        Ok(components) => components,
+        Err(e) => return future::err(e).to_boxed(),
+      };
+      if let Err(e) = insert_tree_entry(&mut root, &components, entry, &path) {
+        return future::err(e).to_boxed();
+      }
+    }
+    record_tree(store, root)
+      .map(move |digest| {
+        Snapshot {
+          fingerprint: digest.0,
+          digest: Some(digest),
+          path_stats: path_stats,
+        }
+      })
+      .to_boxed()
+  }
+}
+
+impl fmt::Debug for Snapshot {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "Snapshot({}, digest={:?}, entries={})",
+      self.fingerprint.to_hex(),
+      self.digest,
+      self.path_stats.len()
+    )
+  }
+}
+
+// The identity transform for `Snapshot::materialize_with`.
+fn unchanged_path(path: &Path) -> Option<PathBuf> {
+  Some(path.to_owned())
+}
+
+///
+/// Reads the entries of a tar archive, returning futures for the storing of each file (paired with
+/// its path), and the paths of its directory entries.
+///
+fn read_tar_entries<R: Read>(
+  store: &Arc<Store>,
+  reader: R,
+) -> Result<
+  (
+    Vec<BoxFuture<(PathBuf, bazel_protos::remote_execution::FileNode), String>>,
+    Vec<PathBuf>,
+  ),
+  String,
+> {
+  let mut archive = tar::Archive::new(reader);
+  let entries = archive.entries().map_err(
+    |e| format!("Error reading tar archive: {}", e),
+  )?;
+  let mut file_futures = Vec::new();
+  let mut dirs = Vec::new();
+  for entry in entries {
+    let mut entry = entry.map_err(|e| format!("Error reading tar entry: {}", e))?;
+    let raw_path = entry
+      .path()
+      .map_err(|e| format!("Error reading tar entry path: {}", e))?
+      .into_owned();
+    // Archives commonly prefix their entries with "./", which is not part of the path.
+    let path = raw_path
+      .components()
+      .filter(|component| component != &Component::CurDir)
+      .collect::<PathBuf>();
+    tree_components(&path, store.max_directory_depth())?;
+
+    let entry_type = entry.header().entry_type();
+    if entry_type.is_dir() {
+      dirs.push(path);
+    } else if entry_type.is_file() {
+      let mode = entry.header().mode().map_err(|e| {
+        format!("Error reading mode of tar entry {:?}: {}", raw_path, e)
+      })?;
+      let mut content = Vec::new();
+      entry.read_to_end(&mut content).map_err(|e| {
+        format!("Error reading tar entry {:?}: {}", raw_path, e)
+      })?;
+      file_futures.push(
+        store
+          .store_file_bytes(content)
+          .map(move |digest| {
+            let mut file_node = bazel_protos::remote_execution::FileNode::new();
+            file_node.set_digest(digest.into());
+            file_node.set_is_executable(mode & 0o100 == 0o100);
+            (path, file_node)
+          })
+          .to_boxed(),
+      );
+    } else {
+      return Err(format!(
+        "Unsupported tar entry {:?} of type {:?}",
+        raw_path,
+        entry_type
+      ));
+    }
+  }
+  Ok((file_futures, dirs))
+}
+
+///
+/// The children of a Directory, as (name, hash, size, is_executable) tuples, where is_executable is
+/// None for child Directories. Two Directories with equal ChildSets have equal Digests.
+///
+type ChildSet = Vec<(String, String, i64, Option<bool>)>;
+
+fn child_set(directory: &bazel_protos::remote_execution::Directory) -> ChildSet {
+  directory
+    .get_files()
+    .iter()
+    .map(|file_node| {
+      (
+        file_node.get_name().to_owned(),
+        file_node.get_digest().get_hash().to_owned(),
+        file_node.get_digest().get_size_bytes(),
+        Some(file_node.get_is_executable()),
+      )
+    })
+    .chain(directory.get_directories().iter().map(|directory_node| {
+      (
+        directory_node.get_name().to_owned(),
+        directory_node.get_digest().get_hash().to_owned(),
+        directory_node.get_digest().get_size_bytes(),
+        None,
+      )
+    }))
+    .collect()
+}
+
+fn record_directory_cached(
+  store: &Arc<Store>,
+  recorded: &Arc<Mutex<HashMap<ChildSet, Digest>>>,
+  directory: bazel_protos::remote_execution::Directory,
+) -> BoxFuture<Digest, String> {
+  let children = child_set(&directory);
+  if let Some(digest) = recorded.lock().unwrap().get(&children) {
+    return future::ok(digest.clone()).to_boxed();
+  }
+  let recorded = recorded.clone();
+  store
+    .record_directory(&directory)
+    .map(move |digest| {
+      recorded.lock().unwrap().insert(children, digest.clone());
+      digest
+    })
+    .to_boxed()
+}
+
+///
+/// Asks the remote CAS which of the given Digests it lacks, in FindMissingBlobs requests of at most
+/// `batch_size` Digests each (sent one after another, on the pool), and returns the missing ones
+/// sorted.
+///
+fn find_missing_blobs(
+  pool: &ResettablePool,
+  cas: Arc<ContentAddressableStorageClient>,
+  digests: HashSet<Digest>,
+  batch_size: usize,
+) -> BoxFuture<Vec<Digest>, String> {
+  if batch_size == 0 {
+    return future::err("FindMissingBlobs batch size must be at least 1".to_owned()).to_boxed();
+  }
+  let mut digests = digests.into_iter().collect::<Vec<_>>();
+  digests.sort();
+  pool
+    .spawn_fn(move || {
+      let mut missing = Vec::new();
+      for batch in digests.chunks(batch_size) {
+        let mut request = bazel_protos::remote_execution::FindMissingBlobsRequest::new();
+        request.set_blob_digests(protobuf::RepeatedField::from_vec(
+          batch.iter().map(|digest| digest.clone().into()).collect(),
+        ));
+        let response = cas.find_missing_blobs(request).map_err(|err| {
+          format!("Error finding missing blobs: {}", grpc_error(err))
+        })?;
+        for digest in response.get_missing_blob_digests() {
+          missing.push(Digest::from_bazel_digest(digest)?);
+        }
+      }
+      missing.sort();
+      Ok(missing)
+    })
+    .to_boxed()
+}
+
+fn grpc_error(err: grpcio::Error) -> String {
+  match err {
+    grpcio::Error::RpcFailure(status) => {
+      format!(
+        "{:?}: {}",
+        status.status,
+        status.details.unwrap_or_else(|| "[no message]".to_owned())
+      )
+    }
+    err => format!("{}", err),
+  }
+}
+
+///
+/// Recursively visits the Directory with the given Fingerprint and all Directories beneath it,
+/// parents before children, passing each to the visitor along with its path (which is prefixed
+/// with the given prefix).
+///
+fn walk_directories(
+  reader: &StoreReader,
+  fingerprint: Fingerprint,
+  prefix: PathBuf,
+  visit: &mut FnMut(&Path, &bazel_protos::remote_execution::Directory) -> Result<(), String>,
+) -> Result<(), String> {
+  let max_depth = reader.max_directory_depth();
+  if prefix.components().count() > max_depth {
+    return Err(nesting_error(max_depth));
+  }
+  let directory = reader.load_directory_proto(fingerprint)?.ok_or_else(|| {
+    format!("Directory with fingerprint {} not found", fingerprint)
+  })?;
+  visit(&prefix, &directory)?;
+  let children = reader.child_directory_digests(fingerprint, &directory)?;
+  for (directory_node, child) in directory.get_directories().iter().zip(children.iter()) {
+    walk_directories(
+      reader,
+      child.0,
+      prefix.join(directory_node.get_name()),
+      &mut *visit,
+    )?;
+  }
+  Ok(())
+}
+
+///
+/// Fails for `Snapshot::verify_complete` unless the given lookup finds the blob of the given kind
+/// at the given path, with the size its Digest claims.
+///
+fn check_blob<F: FnOnce() -> Result<Option<usize>, String>>(
+  kind: &str,
+  path: &Path,
+  digest: &Digest,
+  lookup: F,
+) -> Result<(), String> {
+  match lookup()? {
+    Some(size) if size == digest.1 => Ok(()),
+    Some(size) => Err(format!(
+      "{} {:?} with digest {:?} is incomplete: the Store holds {} bytes for it",
+      kind,
+      path,
+      digest,
+      size
+    )),
+    None => Err(format!(
+      "{} {:?} with digest {:?} is missing from the Store",
+      kind,
+      path,
+      digest
+    )),
+  }
+}
+
+///
+/// Recursively collects the paths and fingerprints of the Directory with the given Fingerprint
+/// (whose path is empty) and of all Directories beneath it.
+///
+fn directory_fingerprints(
+  reader: &StoreReader,
+  root: Fingerprint,
+) -> Result<Vec<(PathBuf, Fingerprint)>, String> {
+  let mut directories = vec![(PathBuf::new(), root)];
+  walk_directories(reader, root, PathBuf::new(), &mut |path, directory| {
+    for directory_node in directory.get_directories() {
+      directories.push((
+        path.join(directory_node.get_name()),
+        Digest::from_bazel_digest(directory_node.get_digest())?.0,
+      ));
+    }
+    Ok(())
+  })?;
+  Ok(directories)
+}
+
+///
+/// Renders the Directory with the given Digest, which is nested `depth` Directories beneath the
+/// root, and everything beneath it as JSON for `Snapshot::to_tree_json`.
+///
+fn directory_json(
+  reader: &StoreReader,
+  name: &str,
+  digest: Digest,
+  depth: usize,
+) -> Result<serde_json::Value, String> {
+  let max_depth = reader.max_directory_depth();
+  if depth > max_depth {
+    return Err(nesting_error(max_depth));
+  }
+  let directory = reader.load_directory_proto(digest.0)?.ok_or_else(|| {
+    format!("Directory with fingerprint {} not found", digest.0)
+  })?;
+  let mut children = Vec::new();
+  for directory_node in directory.get_directories() {
+    children.push(directory_json(
+      reader,
+      directory_node.get_name(),
+      Digest::from_bazel_digest(directory_node.get_digest())?,
+      depth + 1,
+    )?);
+  }
+  for file_node in directory.get_files() {
+    let file_digest = Digest::from_bazel_digest(file_node.get_digest())?;
+    children.push(json!({
+      "name": file_node.get_name(),
+      "digest": file_digest.0.to_hex(),
+      "size_bytes": file_digest.1,
+      "is_executable": file_node.get_is_executable()
+    }));
+  }
+  Ok(json!({
+    "name": name,
+    "digest": digest.0.to_hex(),
+    "size_bytes": digest.1,
+    "children": children
+  }))
+}
+
+///
+/// The 1-based numbers of the lines of `bytes` which match `pattern`. A trailing newline does not
+/// begin another line.
+///
+fn matching_lines(pattern: &Regex, bytes: &[u8]) -> Vec<usize> {
+  if bytes.is_empty() {
+    return vec![];
+  }
+  let bytes = if bytes.ends_with(b"\n") {
+    &bytes[..bytes.len() - 1]
+  } else {
+    bytes
+  };
+  bytes
+    .split(|&byte| byte == b'\n')
+    .enumerate()
+    .filter(|&(_, line)| pattern.is_match(&String::from_utf8_lossy(line)))
+    .map(|(index, _)| index + 1)
+    .collect()
+}
+
+///
+/// Finds the FileNode at the given path beneath the Directory with the given Fingerprint, loading
+/// only the Directories along that path. Returns None if there is no file at the path.
+///
+fn find_file(
+  reader: &StoreReader,
+  root: Fingerprint,
+  path: &Path,
+) -> Result<Option<bazel_protos::remote_execution::FileNode>, String> {
+  let mut components = tree_components(path, reader.max_directory_depth())?;
+  let file_name = components.pop().unwrap();
+  let mut fingerprint = root;
+  for component in components {
+    let directory = reader.load_directory_proto(fingerprint)?.ok_or_else(|| {
+      format!("Directory with fingerprint {} not found", fingerprint)
+    })?;
+    let child = match directory.get_directories().iter().find(|directory_node| {
+      directory_node.get_name() == component
+    }) {
+      Some(directory_node) => Digest::from_bazel_digest(directory_node.get_digest())?.0,
+      None => return Ok(None),
+    };
+    fingerprint = child;
+  }
+  let directory = reader.load_directory_proto(fingerprint)?.ok_or_else(|| {
+    format!("Directory with fingerprint {} not found", fingerprint)
+  })?;
+  Ok(
+    directory
+      .get_files()
+      .iter()
+      .find(|file_node| file_node.get_name() == file_name)
+      .cloned(),
+  )
+}
+
+///
+/// Recursively collects the FileNodes beneath the Directory with the given Fingerprint, paired with
+/// their paths (which are prefixed with the given prefix).
+///
+fn walk_files(
+  reader: &StoreReader,
+  fingerprint: Fingerprint,
+  prefix: PathBuf,
+  files: &mut Vec<(PathBuf, bazel_protos::remote_execution::FileNode)>,
+) -> Result<(), String> {
+  walk_directories(reader, fingerprint, prefix, &mut |path, directory| {
+    for file_node in directory.get_files() {
+      files.push((path.join(file_node.get_name()), file_node.clone()));
+    }
+    Ok(())
+  })
+}
+
+///
+/// Recursively collects the Digests and executable bits of the files beneath the Directory with the
+/// given Fingerprint, and the paths of the Directories beneath it (not including itself), by path.
+///
+fn tree_entries(
+  reader: &StoreReader,
+  root: Fingerprint,
+) -> Result<(BTreeMap<PathBuf, (Digest, bool)>, BTreeSet<PathBuf>), String> {
+  let mut files = BTreeMap::new();
+  let mut directories = BTreeSet::new();
+  walk_directories(reader, root, PathBuf::new(), &mut |path, directory| {
+    if path.components().next().is_some() {
+      directories.insert(path.to_owned());
+    }
+    for file_node in directory.get_files() {
+      files.insert(
+        path.join(file_node.get_name()),
+        (
+          Digest::from_bazel_digest(file_node.get_digest())?,
+          file_node.get_is_executable(),
+        ),
+      );
+    }
+    Ok(())
+  })?;
+  Ok((files, directories))
+}
+
+///
+/// Recursively collects the paths (prefixed with the given prefix) of everything other than
+/// directories beneath `root`, which may nest directories at most `max_depth` deep. A root which
+/// does not exist contains nothing.
+///
+fn list_files(
+  root: &Path,
+  prefix: PathBuf,
+  paths: &mut Vec<PathBuf>,
+  max_depth: usize,
+) -> Result<(), String> {
+  if prefix.components().count() > max_depth {
+    return Err(nesting_error(max_depth));
+  }
+  let entries = match fs::read_dir(root.join(&prefix)) {
+    Ok(entries) => entries,
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+    Err(e) => return Err(format!("Error listing {:?}: {}", root.join(&prefix), e)),
+  };
+  for entry in entries {
+    let entry = entry.map_err(|e| {
+      format!("Error listing {:?}: {}", root.join(&prefix), e)
+    })?;
+    let path = prefix.join(entry.file_name());
+    let file_type = entry.file_type().map_err(|e| {
+      format!("Error inspecting {:?}: {}", root.join(&path), e)
+    })?;
+    if file_type.is_dir() {
+      list_files(root, path, paths, max_depth)?;
+    } else {
+      paths.push(path);
+    }
+  }
+  Ok(())
+}
+
+///
+/// Applies a name transform (see `Snapshot::from_path_stats_with_name_transform`) to each
+/// component of the paths of the given PathStats.
+///
+fn rename_path_stats<F: Fn(&Path, &OsStr) -> OsString>(
+  path_stats: Vec<PathStat>,
+  transform: &F,
+) -> Result<Vec<PathStat>, String> {
+  // The untransformed path of the entry which was renamed to each transformed path.
+  let mut renamed: BTreeMap<PathBuf, PathBuf> = BTreeMap::new();
+  path_stats
+    .into_iter()
+    .map(|path_stat| {
+      let mut original = PathBuf::new();
+      let mut new_path = PathBuf::new();
+      for component in path_stat.path().components() {
+        let name = component.as_os_str();
+        let new_name = transform(&original, name);
+        {
+          let mut new_components = Path::new(&new_name).components();
+          match (new_components.next(), new_components.next()) {
+            (Some(Component::Normal(_)), None) => {}
+            _ => {
+              return Err(format!(
+                "Name transform renamed {:?} to {:?}, which is not a single path component",
+                original.join(name),
+                new_name
+              ))
+            }
+          }
+        }
+        original.push(name);
+        new_path.push(&new_name);
+        match renamed.entry(new_path.clone()) {
+          Entry::Occupied(entry) => {
+            if entry.get() != &original {
+              return Err(format!(
+                "Name transform renamed both {:?} and {:?} to {:?}",
+                entry.get(),
+                original,
+                new_path
+              ));
+            }
+          }
+          Entry::Vacant(entry) => {
+            entry.insert(original.clone());
+          }
+        }
+      }
+      Ok(match path_stat {
+        PathStat::File { stat, .. } => PathStat::file(new_path, stat),
+        PathStat::Dir { stat, .. } => PathStat::dir(new_path, stat),
+      })
+    })
+    .collect()
+}
+
+///
+/// Returns every proper ancestor of the given paths (including the empty path, for the root).
+///
+fn ancestors_of(paths: &HashSet<PathBuf>) -> HashSet<PathBuf> {
+  let mut ancestors = HashSet::new();
+  for path in paths {
+    let mut current = path.parent();
+    while let Some(parent) = current {
+      ancestors.insert(parent.to_owned());
+      current = parent.parent();
+    }
+  }
+  ancestors
+}
+
+///
+/// Filters PathStats to the files whose paths are in `allowed`, and the directories which contain
+/// them.
+///
+fn retain_path_stats(path_stats: Vec<PathStat>, allowed: &HashSet<PathBuf>) -> Vec<PathStat> {
+  let ancestors = ancestors_of(allowed);
+  path_stats
+    .into_iter()
+    .filter(|path_stat| match path_stat {
+      &PathStat::File { ref path, .. } => allowed.contains(path),
+      &PathStat::Dir { ref path, .. } => ancestors.contains(path),
+    })
+    .collect()
+}
+
+///
+/// The shard of `n` (see `Snapshot::shard`) which the entry at the given path belongs to. This
+/// must not change between releases, since workers may use different builds.
+///
+fn shard_index(path: &Path, n: usize) -> usize {
+  let mut hasher = Sha256::default();
+  hasher.input(path.as_os_str().as_bytes());
+  let hash = hasher.fixed_result();
+  let prefix = hash[..8].iter().fold(0u64, |acc, &byte| (acc << 8) | byte as u64);
+  (prefix % n as u64) as usize
+}
+
+///
+/// Keeps the PathStats of the given files and empty directories, and of the directories which
+/// contain them, for a shard.
+///
+fn shard_path_stats(path_stats: &[PathStat], entries: &HashSet<PathBuf>) -> Vec<PathStat> {
+  let ancestors = ancestors_of(entries);
+  path_stats
+    .iter()
+    .filter(|path_stat| match *path_stat {
+      &PathStat::File { ref path, .. } => entries.contains(path),
+      &PathStat::Dir { ref path, .. } => entries.contains(path) || ancestors.contains(path),
+    })
+    .cloned()
+    .collect()
+}
+
+///
+/// An in-memory tree of entries, which can be recorded as Directory protos by `record_tree`.
+///
+enum TreeEntry {
+  File(bazel_protos::remote_execution::FileNode),
+  Directory(BTreeMap<String, TreeEntry>),
+}
+
+fn nesting_error(max_depth: usize) -> String {
+  format!("Directory nesting exceeds limit {}", max_depth)
+}
+
+fn tree_components(path: &Path, max_depth: usize) -> Result<Vec<String>, String> {
+  let components = path
+    .components()
+    .map(|component| match component {
+      Component::Normal(name) => osstring_as_utf8(name.to_owned()),
+      _ => Err(format!(
+        "Path {:?} must be relative, and may not contain . or .. components",
+        path
+      )),
+    })
+    .collect::<Result<Vec<_>, _>>()?;
+  if components.is_empty() {
+    return Err(format!("Path {:?} was empty", path));
+  }
+  // A path of N components is nested in N - 1 directories beneath the root.
+  if components.len() > max_depth + 1 {
+    return Err(nesting_error(max_depth));
+  }
+  Ok(components)
+}
+
+///
+/// Fails if, in the given PathStats (which must be sorted by path), the same path appears with
+/// different stats (such as both a file and a directory), or anything appears beneath a file.
+///
+fn check_distinct_entries(sorted: &[PathStat]) -> Result<(), String> {
+  for pair in sorted.windows(2) {
+    if pair[0].path() == pair[1].path() {
+      return Err(format!(
+        "Path {:?} was given more than once, with different stats: {:?} and {:?}",
+        pair[0].path(),
+        pair[0],
+        pair[1]
+      ));
+    }
+  }
+  let files = sorted
+    .iter()
+    .filter_map(|path_stat| match path_stat {
+      &PathStat::File { ref path, .. } => Some(path.as_path()),
+      &PathStat::Dir { .. } => None,
+    })
+    .collect::<HashSet<_>>();
+  for path_stat in sorted {
+    let mut ancestor = path_stat.path().parent();
+    while let Some(parent) = ancestor {
+      if files.contains(parent) {
+        return Err(format!(
+          "Path {:?} is beneath the file {:?}",
+          path_stat.path(),
+          parent
+        ));
+      }
+      ancestor = parent.parent();
+    }
+  }
+  Ok(())
+}
+
+fn insert_tree_entry(
+  directory: &mut BTreeMap<String, TreeEntry>,
+  components: &[String],
+  entry: TreeEntry,
+  path: &Path,
+) -> Result<(), String> {
+  let (name, rest) = components.split_first().expect(
+    "Tree paths must have at least one component.",
+  );
+  if rest.is_empty() {
+    return match directory.entry(name.clone()) {
+      Entry::Vacant(vacant) => {
+        vacant.insert(entry);
+        Ok(())
+      }
+      Entry::Occupied(occupied) => {
+        match (occupied.get(), &entry) {
+          // An empty directory which already exists (possibly with contents) is a no-op.
+          (&TreeEntry::Directory(_), &TreeEntry::Directory(ref new)) if new.is_empty() => Ok(()),
+          _ => Err(format!("Multiple entries for path {:?}", path)),
+        }
+      }
+    };
+  }
+  let child = directory.entry(name.clone()).or_insert_with(|| {
+    TreeEntry::Directory(BTreeMap::new())
+  });
+  match *child {
+    TreeEntry::Directory(ref mut children) => insert_tree_entry(children, rest, entry, path),
+    TreeEntry::File(_) => Err(format!(
+      "Path {:?} is beneath {:?}, which is a file",
+      path,
+      name
+    )),
+  }
+}
+
+///
+/// Records Directory protos for the given tree, children before parents, and returns the Digest of
+/// its root. Entries are recorded in name order, so the resulting protos are canonical.
+///
+fn record_tree(
+  store: Arc<Store>,
+  entries: BTreeMap<String, TreeEntry>,
+) -> BoxFuture<Digest, String> {
+  let mut files = Vec::new();
+  let mut dir_futures = Vec::new();
+  for (name, entry) in entries {
+    match entry {
+      TreeEntry::File(mut file_node) => {
+        file_node.set_name(name);
+        files.push(file_node);
+      }
+      TreeEntry::Directory(children) => {
+        dir_futures.push(
+          record_tree(store.clone(), children)
+            .map(move |digest| {
+              let mut directory_node = bazel_protos::remote_execution::DirectoryNode::new();
+              directory_node.set_name(name);
+              directory_node.set_digest(digest.into());
+              directory_node
+            })
+            .to_boxed(),
+        );
+      }
+    }
+  }
+  join_all(dir_futures)
+    .and_then(move |dirs| {
+      let mut directory = bazel_protos::remote_execution::Directory::new();
+      directory.set_directories(protobuf::RepeatedField::from_vec(dirs));
+      directory.set_files(protobuf::RepeatedField::from_vec(files));
+      store.record_directory(&directory)
+    })
+    .to_boxed()
+}
+
+fn transform_relative<F: Fn(&Path) -> Option<PathBuf>>(
+  transform: &F,
+  path: &Path,
+) -> Result<Option<PathBuf>, String> {
+  match transform(path) {
+    Some(transformed) => {
+      let escapes = transformed.components().any(|c| match c {
+        Component::Normal(_) |
+        Component::CurDir => false,
+        Component::Prefix(..) |
+        Component::RootDir |
+        Component::ParentDir => true,
+      });
+      if escapes {
+        Err(format!(
+          "Path {:?} was transformed to {:?}, which is not within the destination",
+          path,
+          transformed
+        ))
+      } else {
+        Ok(Some(transformed))
+      }
+    }
+    None => Ok(None),
+  }
+}
+
+fn write_file(path: &Path, bytes: &[u8], is_executable: bool) -> Result<(), String> {
+  let mode = if is_executable { 0o755 } else { 0o644 };
+  fs::File::create(path)
+    .and_then(|mut f| {
+      f.write_all(bytes)?;
+      f.set_permissions(fs::Permissions::from_mode(mode))
+    })
+    .map_err(|e| format!("Error writing file {:?}: {}", path, e))
+}
+
+fn paths_of_child_dir(paths: Vec<PathStat>) -> Vec<PathStat> {
+  paths
+    .into_iter()
+    .filter_map(|s| {
+      if s.path().components().count() == 1 {
+        return None;
+      }
+      Some(match s {
+        PathStat::File { path, stat } => {
+          PathStat::File {
+            path: path.iter().skip(1).collect(),
+            stat: stat,
+          }
+        }
+        PathStat::Dir { path, stat } => {
+          PathStat::Dir {
+            path: path.iter().skip(1).collect(),
+            stat: stat,
+          }
+        }
+      })
+    })
+    .collect()
+}
+
+fn osstring_as_utf8(path: OsString) -> Result<String, String> {
+  path.into_string().map_err(|p| {
+    format!("{:?}'s file_name is not representable in UTF8", p)
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate testutil;
+  extern crate tempdir;
+
+  use bazel_protos;
+  use bazel_protos::remote_execution;
+  use bazel_protos::remote_execution_grpc::{create_content_addressable_storage,
+                                            ContentAddressableStorage,
+                                            ContentAddressableStorageClient};
+  use boxfuture::{BoxFuture, Boxable};
+  use futures::{Future, Stream};
+  use grpcio;
+  use tempdir::TempDir;
+  use self::testutil::{assert_contents_eq, assert_lines_eq, make_file};
+
+  use super::{walk_directories, ContentClassifier};
+  use super::super::{Digest, Dir, File, Fingerprint, GetFileDigest, MaterializePlan,
+                     MergeStrategy, PathGlobs, PathStat, PosixFS, ResettablePool, Snapshot,
+                     SnapshotBuilder, SnapshotInterner, Store, StoreOptions, UnreadableFilePolicy,
+                     VFS};
+
+  use filetime::{self, FileTime};
+  use regex::Regex;
+  use std;
+  use std::collections::{HashMap, HashSet};
+  use std::error::Error;
+  use std::ffi::{OsStr, OsString};
+  use std::io::Read;
+  use tar;
+  use std::os::unix::ffi::OsStrExt;
+  use std::os::unix::fs::PermissionsExt;
+  use std::path::{Component, Path, PathBuf};
+  use std::sync::{Arc, Mutex};
+  use std::sync::atomic::{self, AtomicUsize};
+
+  const STR: &str = "European Burmese";
+
+  fn setup() -> (Arc<Store>, TempDir, Arc<PosixFS>, Arc<FileSaver>) {
+    let pool = Arc::new(ResettablePool::new("test-pool-".to_string()));
+    let store = Arc::new(
+      Store::new(TempDir::new("lmdb_store").unwrap(), pool.clone()).unwrap(),
+    );
+    let dir = TempDir::new("root").unwrap();
+    let posix_fs = Arc::new(PosixFS::new(dir.path(), pool, vec![]).unwrap());
+    let digester = Arc::new(FileSaver(store.clone(), posix_fs.clone()));
+    (store, dir, posix_fs, digester)
+  }
+
+  // Creates the given files (with their contents and modes) and empty directories beneath root,
+  // along with any missing parent directories.
+  fn write_tree(root: &Path, files: &[(&str, &[u8], u32)], empty_dirs: &[&str]) {
+    for &(path, contents, mode) in files {
+      let path = root.join(path);
+      std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+      make_file(&path, contents, mode);
+    }
+    for empty_dir in empty_dirs {
+      std::fs::create_dir_all(root.join(empty_dir)).unwrap();
+    }
+  }
+
+  // Snapshots a fresh root (into a fresh Store) once write_tree has populated it.
+  fn snapshot_from_disk(
+    files: &[(&str, &[u8], u32)],
+    empty_dirs: &[&str],
+  ) -> (Arc<Store>, TempDir, Snapshot) {
+    let (store, _, _, _) = setup();
+    let (dir, snapshot) = snapshot_from_disk_into(&store, files, empty_dirs);
+    (store, dir, snapshot)
+  }
+
+  // As snapshot_from_disk, but into the given Store, so that the Snapshot can be compared with
+  // others in it.
+  fn snapshot_from_disk_into(
+    store: &Arc<Store>,
+    files: &[(&str, &[u8], u32)],
+    empty_dirs: &[&str],
+  ) -> (TempDir, Snapshot) {
+    let (_, dir, posix_fs, _) = setup();
+    write_tree(dir.path(), files, empty_dirs);
+    let digester = Arc::new(FileSaver(store.clone(), posix_fs.clone()));
+    let snapshot = Snapshot::from_path_stats(store.clone(), digester, expand_all_sorted(posix_fs))
+      .wait()
+      .unwrap();
+    (dir, snapshot)
+  }
+
+  // A Snapshot of "cats/roland" (containing STR), "small" and the empty "treats".
+  fn cats_snapshot(store: &Arc<Store>) -> Snapshot {
+    let mut builder = SnapshotBuilder::new();
+    builder.add_file(PathBuf::from("cats/roland"), STR.as_bytes().to_vec(), false);
+    builder.add_file(PathBuf::from("small"), b"meow".to_vec(), false);
+    builder.add_file(PathBuf::from("treats"), b"".to_vec(), false);
+    builder.finish(store.clone()).wait().unwrap()
+  }
+
+  #[test]
+  fn snapshot_one_file() {
+    let (store, dir, posix_fs, digester) = setup();
+
+    let file_name = PathBuf::from("roland");
+    make_file(&dir.path().join(&file_name), STR.as_bytes(), 0o600);
+
+    let path_stats = expand_all_sorted(posix_fs);
+    // TODO: Inline when only used once
+    let fingerprint = Fingerprint::from_hex_string(
+      "63949aa823baf765eff07b946050d76ec0033144c785a94d3ebd82baa931cd16",
+    ).unwrap();
+    let snapshot = Snapshot::from_path_stats(store.clone(), digester, path_stats.clone())
+      .wait()
+      .unwrap();
+    assert_snapshot_eq(
+      &store,
+      &snapshot,
+      &Snapshot {
+        fingerprint: fingerprint,
+        digest: Some(Digest(fingerprint, 80)),
+        path_stats: path_stats,
+      },
+    );
+  }
+
+  #[test]
+  fn snapshot_recursive_directories() {
+    let (store, dir, posix_fs, digester) = setup();
+
+    let cats = PathBuf::from("cats");
+    let roland = cats.join("roland");
+    std::fs::create_dir_all(&dir.path().join(cats)).unwrap();
+    make_file(&dir.path().join(&roland), STR.as_bytes(), 0o600);
+
+    let path_stats = expand_all_sorted(posix_fs);
+    // TODO: Inline when only used once
+    let fingerprint = Fingerprint::from_hex_string(
+      "8b1a7ea04eaa2527b35683edac088bc826117b53b7ec6601740b55e20bce3deb",
+    ).unwrap();
+    let snapshot = Snapshot::from_path_stats(store.clone(), digester, path_stats.clone())
+      .wait()
+      .unwrap();
+    assert_snapshot_eq(
+      &store,
+      &snapshot,
+      &Snapshot {
+        fingerprint: fingerprint,
+        digest: Some(Digest(fingerprint, 78)),
+        path_stats: path_stats,
+      },
+    );
+  }
+
+  #[test]
+  fn snapshot_recursive_directories_including_empty() {
+    let (store, dir, posix_fs, digester) = setup();
+
+    let cats = PathBuf::from("cats");
+    let roland = cats.join("roland");
+    let dogs = PathBuf::from("dogs");
+    let llamas = PathBuf::from("llamas");
+    std::fs::create_dir_all(&dir.path().join(&cats)).unwrap();
+    std::fs::create_dir_all(&dir.path().join(&dogs)).unwrap();
+    std::fs::create_dir_all(&dir.path().join(&llamas)).unwrap();
+    make_file(&dir.path().join(&roland), STR.as_bytes(), 0o600);
+
+    let sorted_path_stats = expand_all_sorted(posix_fs);
+    let mut unsorted_path_stats = sorted_path_stats.clone();
+    unsorted_path_stats.reverse();
+    // TODO: Inline when only used once
+    let fingerprint = Fingerprint::from_hex_string(
+      "fbff703bdaac62accf2ea5083bcfed89292073bf710ef9ad14d9298c637e777b",
+    ).unwrap();
+    let snapshot = Snapshot::from_path_stats(store.clone(), digester, unsorted_path_stats)
+      .wait()
+      .unwrap();
+    assert_snapshot_eq(
+      &store,
+      &snapshot,
+      &Snapshot {
+        fingerprint: fingerprint,
+        digest: Some(Digest(fingerprint, 232)),
+        path_stats: sorted_path_stats,
+      },
+    );
+  }
+
+  #[test]
+  fn content_set_digest_ignores_directory_layout() {
+    let roland = [("cats/roland", STR.as_bytes(), 0o600)];
+    let (store, _dir, without_dogs) = snapshot_from_disk(&roland, &[]);
+    let (dogs_store, _dogs_dir, with_dogs) = snapshot_from_disk(&roland, &["dogs"]);
+
+    assert_ne!(without_dogs.digest(), with_dogs.digest());
+    assert_eq!(
+      without_dogs.content_set_digest(store).wait(),
+      with_dogs.content_set_digest(dogs_store).wait()
+    );
+  }
+
+  #[test]
+  fn file_digests_are_distinct() {
+    let (store, _dir, snapshot) = snapshot_from_disk(
+      &[
+        ("cats/roland", STR.as_bytes(), 0o600),
+        ("roland", STR.as_bytes(), 0o700),
+        ("treats", &b""[..], 0o600),
+      ],
+      &[],
+    );
+
+    let str_digest = store.store_file_bytes(STR.as_bytes().to_vec()).wait().unwrap();
+    let empty_digest = store.store_file_bytes(vec![]).wait().unwrap();
+    let mut expected = HashSet::new();
+    expected.insert(str_digest);
+    expected.insert(empty_digest);
+    assert_eq!(snapshot.file_digests(store).wait(), Ok(expected));
+  }
+
+  #[test]
+  fn manifest_lists_files_without_their_contents() {
+    let (store, _dir, snapshot) = snapshot_from_disk(
+      &[("cats/roland", STR.as_bytes(), 0o700), ("treats", &b""[..], 0o600)],
+      &[],
+    );
+
+    let str_digest = store.store_file_bytes(STR.as_bytes().to_vec()).wait().unwrap();
+    let empty_digest = store.store_file_bytes(vec![]).wait().unwrap();
+    assert_eq!(
+      snapshot.manifest(store).wait(),
+      Ok(vec![
+        (PathBuf::from("cats/roland"), str_digest, true),
+        (PathBuf::from("treats"), empty_digest, false),
+      ])
+    );
+  }
+
+  #[test]
+  fn to_tree_json_nests_directories() {
+    let (store, _dir, snapshot) = snapshot_from_disk(
+      &[("cats/roland", STR.as_bytes(), 0o700), ("treats", &b""[..], 0o600)],
+      &[],
+    );
+
+    let str_digest = store.store_file_bytes(STR.as_bytes().to_vec()).wait().unwrap();
+    let tree = snapshot.clone().to_tree_json(store).wait().unwrap();
+    let root_digest = snapshot.digest.unwrap();
+    assert_eq!(tree["name"], "");
+    assert_eq!(tree["digest"], root_digest.0.to_hex());
+    assert_eq!(tree["size_bytes"], root_digest.1);
+    let children = tree["children"].as_array().unwrap();
+    assert_eq!(children.len(), 2);
+    assert_eq!(children[0]["name"], "cats");
+    assert_eq!(
+      children[0]["children"],
+      json!([{
+        "name": "roland",
+        "digest": str_digest.0.to_hex(),
+        "size_bytes": 16,
+        "is_executable": true
+      }])
+    );
+    assert_eq!(children[1]["name"], "treats");
+    assert_eq!(children[1]["size_bytes"], 0);
+    assert_eq!(children[1]["is_executable"], false);
+  }
+
+  #[test]
+  fn preserving_names_round_trips_non_utf8_names() {
+    let (store, dir, _, digester) = setup();
+
+    let raw_dir = OsStr::from_bytes(b"cats\xff").to_owned();
+    let raw_file = OsStr::from_bytes(b"rol\xe9and").to_owned();
+    std::fs::create_dir(dir.path().join(&raw_dir)).unwrap();
+    make_file(
+      &dir.path().join(&raw_dir).join(&raw_file),
+      STR.as_bytes(),
+      0o600,
+    );
+    make_file(&dir.path().join("treats"), b"", 0o600);
+    // Globs only match UTF8 names, so the PathStats are listed by hand.
+    let raw_path = Path::new(&raw_dir).join(&raw_file);
+    let path_stats = vec![
+      PathStat::dir(PathBuf::from(&raw_dir), Dir(PathBuf::from(&raw_dir))),
+      PathStat::file(
+        raw_path.clone(),
+        File {
+          path: raw_path,
+          is_executable: false,
+        },
+      ),
+      PathStat::file(
+        PathBuf::from("treats"),
+        File {
+          path: PathBuf::from("treats"),
+          is_executable: false,
+        },
+      ),
+    ];
+
+    Snapshot::from_path_stats(store.clone(), digester.clone(), path_stats.clone())
+      .wait()
+      .expect_err("Want error");
+    let snapshot = Snapshot::from_path_stats_preserving_names(store.clone(), digester, path_stats)
+      .wait()
+      .unwrap();
+
+    let lossy_path = PathBuf::from("cats\u{FFFD}/rol\u{FFFD}and");
+    assert_eq!(
+      snapshot.clone().manifest(store.clone()).wait().unwrap()[0].0,
+      lossy_path
+    );
+
+    let lossy_destination = TempDir::new("lossy").unwrap();
+    snapshot
+      .clone()
+      .materialize(store.clone(), lossy_destination.path().to_owned())
+      .wait()
+      .unwrap();
+    assert!(lossy_destination.path().join(&lossy_path).is_file());
+
+    let raw_destination = TempDir::new("raw").unwrap();
+    snapshot
+      .materialize_with_raw_names(store, raw_destination.path().to_owned())
+      .wait()
+      .unwrap();
+    let mut contents = String::new();
+    std::fs::File::open(raw_destination.path().join(&raw_dir).join(&raw_file))
+      .unwrap()
+      .read_to_string(&mut contents)
+      .unwrap();
+    assert_eq!(contents, STR);
+    assert!(raw_destination.path().join("treats").is_file());
+  }
+
+  #[test]
+  fn contents_up_to_size_skips_large_files() {
+    let (store, _, _, _) = setup();
+
+    let snapshot = cats_snapshot(&store);
+
+    let (contents, skipped) = snapshot.contents_up_to_size(store, 4).wait().unwrap();
+    let contents = contents
+      .into_iter()
+      .map(|file_content| (file_content.path, file_content.content))
+      .collect::<Vec<_>>();
+    assert_contents_eq(
+      &contents,
+      &[
+        (PathBuf::from("small"), &b"meow"[..]),
+        (PathBuf::from("treats"), &b""[..]),
+      ],
+    );
+    assert_eq!(skipped, vec![PathBuf::from("cats/roland")]);
+  }
+
+  #[test]
+  fn merge_with_provenance_labels_each_path() {
+    let (store, _, _, _) = setup();
+
+    let snapshot = |files: &[(&str, &str)], empty_dirs: &[&str]| {
+      let mut builder = SnapshotBuilder::new();
+      for &(path, content) in files {
+        builder.add_file(PathBuf::from(path), content.as_bytes().to_vec(), false);
+      }
+      for &path in empty_dirs {
+        builder.add_empty_dir(PathBuf::from(path));
+      }
+      builder.finish(store.clone()).wait().unwrap()
+    };
+    let labeled = vec![
+      (
+        "base".to_string(),
+        snapshot(&[("roland", STR), ("cats/tabby", "")], &["empty", "toys"]),
+      ),
+      (
+        "overlay".to_string(),
+        snapshot(&[("roland", "Siamese"), ("treats", ""), ("toys/ball", "")], &[]),
+      ),
+    ];
+    let merge = |strategy: MergeStrategy| {
+      Snapshot::merge_with_provenance(store.clone(), labeled.clone(), strategy).wait()
+    };
+
+    let (merged, provenance) = merge(MergeStrategy::LastWins).unwrap();
+    let label = |path: &str| provenance.get(Path::new(path)).map(|label| label.as_str());
+    assert_eq!(label("roland"), Some("overlay"));
+    assert_eq!(label("cats/tabby"), Some("base"));
+    assert_eq!(label("empty"), Some("base"));
+    assert_eq!(label("toys"), None);
+    assert_eq!(label("toys/ball"), Some("overlay"));
+    assert_eq!(provenance.len(), 5);
+    let siamese = store.store_file_bytes(b"Siamese".to_vec()).wait().unwrap();
+    let manifest = merged.manifest(store.clone()).wait().unwrap();
+    assert_eq!(
+      manifest
+        .iter()
+        .map(|&(ref path, _, _)| path.to_str().unwrap())
+        .collect::<Vec<_>>(),
+      vec!["cats/tabby", "roland", "toys/ball", "treats"]
+    );
+    assert_eq!(manifest[1].1, siamese);
+
+    let (_, provenance) = merge(MergeStrategy::FirstWins).unwrap();
+    assert_eq!(provenance.get(Path::new("roland")).unwrap(), "base");
+
+    assert_eq!(
+      merge(MergeStrategy::Fail).err(),
+      Some(
+        "Conflicting files at \"roland\" in \"base\" and \"overlay\"".to_string(),
+      )
+    );
+  }
+
+  #[test]
+  fn intern_shares_equal_snapshots() {
+    let (store, _, _, _) = setup();
+
+    let snapshot = |content: &str| {
+      let mut builder = SnapshotBuilder::new();
+      builder.add_file(PathBuf::from("roland"), content.as_bytes().to_vec(), false);
+      builder.finish(store.clone()).wait().unwrap()
+    };
+    let interner = SnapshotInterner::new();
+    let first = snapshot(STR).intern(&interner);
+    let second = snapshot(STR).intern(&interner);
+    let other = snapshot("Siamese").intern(&interner);
+    assert!(Arc::ptr_eq(&first, &second));
+    assert!(!Arc::ptr_eq(&first, &other));
+
+    // Once every handle is dropped, the Snapshot is freed rather than kept by the interner.
+    let weak = Arc::downgrade(&first);
+    drop(first);
+    drop(second);
+    assert!(weak.upgrade().is_none());
+    interner.purge();
+    assert_eq!(interner.snapshots.lock().unwrap().len(), 1);
+  }
+
+  // Tags files by their first byte, counting how many files it has been asked to classify.
+  struct FirstByteClassifier(AtomicUsize);
+
+  impl ContentClassifier for FirstByteClassifier {
+    fn name(&self) -> &str {
+      "first-byte"
+    }
+
+    fn prefix_len(&self) -> usize {
+      1
+    }
+
+    fn classify(&self, prefix: &[u8]) -> String {
+      self.0.fetch_add(1, atomic::Ordering::SeqCst);
+      String::from_utf8_lossy(prefix).into_owned()
+    }
+  }
+
+  #[test]
+  fn classify_reuses_recorded_types() {
+    let (store, _, _, _) = setup();
+
+    let snapshot = cats_snapshot(&store);
+    let classifier = Arc::new(FirstByteClassifier(AtomicUsize::new(0)));
+
+    let mut expected = HashMap::new();
+    expected.insert(PathBuf::from("cats/roland"), "E".to_string());
+    expected.insert(PathBuf::from("small"), "m".to_string());
+    expected.insert(PathBuf::from("treats"), "".to_string());
+    assert_eq!(
+      snapshot
+        .clone()
+        .classify(store.clone(), classifier.clone())
+        .wait(),
+      Ok(expected.clone())
+    );
+    assert_eq!(classifier.0.load(atomic::Ordering::SeqCst), 3);
+
+    assert_eq!(
+      snapshot.classify(store.clone(), classifier.clone()).wait(),
+      Ok(expected)
+    );
+    assert_eq!(classifier.0.load(atomic::Ordering::SeqCst), 3);
+
+    // Identical files are only classified once, even in different Snapshots.
+    let mut builder = SnapshotBuilder::new();
+    builder.add_file(PathBuf::from("roland"), STR.as_bytes().to_vec(), false);
+    let other = builder.finish(store.clone()).wait().unwrap();
+    let mut expected = HashMap::new();
+    expected.insert(PathBuf::from("roland"), "E".to_string());
+    assert_eq!(other.classify(store, classifier.clone()).wait(), Ok(expected));
+    assert_eq!(classifier.0.load(atomic::Ordering::SeqCst), 3);
+  }
+
+  // A remote CAS which holds the blobs with the given fingerprints, and records the number of
+  // Digests in each FindMissingBlobs request it receives. Its GetTree ignores the requested root.
+  #[derive(Clone)]
+  struct MockCas {
+    present: Arc<HashSet<Fingerprint>>,
+    batch_sizes: Arc<Mutex<Vec<usize>>>,
+    // The Directories returned by GetTree, one per page.
+    tree: Arc<Vec<remote_execution::Directory>>,
+  }
+
+  impl MockCas {
+    fn serve(
+      present: HashSet<Fingerprint>,
+      tree: Vec<remote_execution::Directory>,
+    ) -> (MockCas, grpcio::Server, Arc<ContentAddressableStorageClient>) {
+      let cas = MockCas {
+        present: Arc::new(present),
+        batch_sizes: Arc::new(Mutex::new(vec![])),
+        tree: Arc::new(tree),
+      };
+      let env = Arc::new(grpcio::Environment::new(1));
+      let mut server = grpcio::ServerBuilder::new(env.clone())
+        .register_service(create_content_addressable_storage(cas.clone()))
+        .bind("localhost", 0)
+        .build()
+        .unwrap();
+      server.start();
+      let address = {
+        let bind_addr = server.bind_addrs().first().unwrap();
+        format!("{}:{}", bind_addr.0, bind_addr.1)
+      };
+      let client = ContentAddressableStorageClient::new(
+        grpcio::ChannelBuilder::new(env).connect(&address),
+      );
+      (cas, server, Arc::new(client))
+    }
+  }
+
+  impl ContentAddressableStorage for MockCas {
+    fn find_missing_blobs(
+      &self,
+      _: grpcio::RpcContext,
+      req: remote_execution::FindMissingBlobsRequest,
+      sink: grpcio::UnarySink<remote_execution::FindMissingBlobsResponse>,
+    ) {
+      self.batch_sizes.lock().unwrap().push(
+        req.get_blob_digests().len(),
+      );
+      let mut response = remote_execution::FindMissingBlobsResponse::new();
+      for digest in req.get_blob_digests() {
+        let fingerprint = Fingerprint::from_hex_string(digest.get_hash()).unwrap();
+        if !self.present.contains(&fingerprint) {
+          response.mut_missing_blob_digests().push(digest.clone());
+        }
+      }
+      sink.success(response);
+    }
+
+    fn batch_update_blobs(
+      &self,
+      _: grpcio::RpcContext,
+      _: remote_execution::BatchUpdateBlobsRequest,
+      sink: grpcio::UnarySink<remote_execution::BatchUpdateBlobsResponse>,
+    ) {
+      sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Unimplemented,
+        None,
+      ));
+    }
+
+    fn get_tree(
+      &self,
+      _: grpcio::RpcContext,
+      req: remote_execution::GetTreeRequest,
+      sink: grpcio::UnarySink<remote_execution::GetTreeResponse>,
+    ) {
+      let page = req.get_page_token().parse::<usize>().unwrap_or(0);
+      let mut response = remote_execution::GetTreeResponse::new();
+      if let Some(directory) = self.tree.get(page) {
+        response.mut_directories().push(directory.clone());
+      }
+      if page + 1 < self.tree.len() {
+        response.set_next_page_token((page + 1).to_string());
+      }
+      sink.success(response);
+    }
+  }
+
+  #[test]
+  fn missing_on_remote_batches_find_missing_blobs() {
+    let (store, _, _, _) = setup();
+    let snapshot = cats_snapshot(&store);
+    let root = snapshot.digest().unwrap();
+
+    // The three files, the root, and "cats".
+    let (empty_cas, _empty_server, empty_client) = MockCas::serve(HashSet::new(), vec![]);
+    let everything = snapshot
+      .clone()
+      .missing_on_remote(store.clone(), empty_client.clone(), 2)
+      .wait()
+      .unwrap();
+    assert_eq!(everything.len(), 5);
+    assert!(everything.contains(&root));
+    assert_eq!(*empty_cas.batch_sizes.lock().unwrap(), vec![2, 2, 1]);
+
+    let str_digest = store.store_file_bytes(STR.as_bytes().to_vec()).wait().unwrap();
+    let present = vec![str_digest.0, root.0].into_iter().collect();
+    let (cas, _server, client) = MockCas::serve(present, vec![]);
+    let expected = everything
+      .into_iter()
+      .filter(|digest| *digest != str_digest && *digest != root)
+      .collect::<Vec<_>>();
+    assert_eq!(
+      snapshot
+        .clone()
+        .missing_on_remote(store.clone(), client, 5)
+        .wait(),
+      Ok(expected)
+    );
+    assert_eq!(*cas.batch_sizes.lock().unwrap(), vec![5]);
+
+    assert_eq!(
+      snapshot.missing_on_remote(store, empty_client, 0).wait(),
+      Err("FindMissingBlobs batch size must be at least 1".to_owned())
+    );
+  }
+
+  #[test]
+  fn missing_directories_on_remote_skips_files() {
+    let (store, _, _, _) = setup();
+    let snapshot = cats_snapshot(&store);
+    let root = snapshot.digest().unwrap();
+    let str_digest = store.store_file_bytes(STR.as_bytes().to_vec()).wait().unwrap();
+
+    let present = vec![root.0].into_iter().collect();
+    let (cas, _server, client) = MockCas::serve(present, vec![]);
+    let missing = snapshot
+      .missing_directories_on_remote(store.clone(), client, 10)
+      .wait()
+      .unwrap();
+    // Only "cats" is missing: the root is present, and files are not asked about.
+    assert_eq!(missing.len(), 1);
+    assert!(missing[0] != root && missing[0] != str_digest);
+    assert_eq!(
+      store.load_directory_proto(missing[0].0).wait().unwrap().map(|directory| {
+        directory.get_files()[0].get_name().to_owned()
+      }),
+      Some("roland".to_owned())
+    );
+    assert_eq!(*cas.batch_sizes.lock().unwrap(), vec![2]);
+  }
+
+  #[test]
+  fn verify_against_checks_remote_tree() {
+    let (store, _, _, _) = setup();
+    let snapshot = cats_snapshot(&store);
+    let root = store
+      .load_directory_proto(snapshot.digest().unwrap().0)
+      .wait()
+      .unwrap()
+      .unwrap();
+    let cats_digest = Digest::from_bazel_digest(root.get_directories()[0].get_digest()).unwrap();
+    let cats = store.load_directory_proto(cats_digest.0).wait().unwrap().unwrap();
+
+    let (local, _, _, _) = setup();
+    let (_, _server, client) = MockCas::serve(HashSet::new(), vec![cats.clone(), root.clone()]);
+    assert_eq!(
+      snapshot.clone().verify_against(local.clone(), client).wait(),
+      Ok(())
+    );
+    assert_eq!(
+      local.load_directory_proto(cats_digest.0).wait(),
+      Ok(Some(cats.clone()))
+    );
+
+    let mut tampered = cats;
+    tampered.mut_files()[0].set_is_executable(true);
+    let (_, _server, client) = MockCas::serve(HashSet::new(), vec![root, tampered]);
+    let err = snapshot
+      .clone()
+      .verify_against(store.clone(), client)
+      .wait()
+      .expect_err("Want error");
+    assert!(err.ends_with(&format!("claimed for {:?}", PathBuf::from("cats"))), err);
+
+    let (_, _server, client) = MockCas::serve(HashSet::new(), vec![]);
+    let err = snapshot
+      .verify_against(store, client)
+      .wait()
+      .expect_err("Want error");
+    assert!(err.ends_with(&format!("claimed for {:?}", PathBuf::new())), err);
+  }
+
+  #[test]
+  fn grep_reports_matching_lines() {
+    let (store, _, _, _) = setup();
+
+    let mut builder = SnapshotBuilder::new();
+    builder.add_file(
+      PathBuf::from("src/cats.rs"),
+      b"fn roland() {}\n// European Burmese\nfn main() {}\n".to_vec(),
+      false,
+    );
+    builder.add_file(PathBuf::from("src/treats.txt"), b"fn\n".to_vec(), false);
+    builder.add_file(PathBuf::from("README"), b"Nothing to see".to_vec(), false);
+    let snapshot = builder.finish(store.clone()).wait().unwrap();
+    let pattern = Regex::new("^fn").unwrap();
+
+    assert_eq!(
+      snapshot
+        .clone()
+        .grep(store.clone(), pattern.clone(), None)
+        .wait(),
+      Ok(vec![
+        (PathBuf::from("src/cats.rs"), vec![1, 3]),
+        (PathBuf::from("src/treats.txt"), vec![1]),
+      ])
+    );
+    let globs = PathGlobs::create(&["src/*.rs".to_owned()], &[]).unwrap();
+    assert_eq!(
+      snapshot.grep(store, pattern, Some(globs)).wait(),
+      Ok(vec![(PathBuf::from("src/cats.rs"), vec![1, 3])])
+    );
+  }
+
+  #[test]
+  fn contents_of_loads_requested_files() {
+    let (store, _, _, _) = setup();
+
+    let snapshot = cats_snapshot(&store);
+
+    let contents = snapshot
+      .clone()
+      .contents_of(
+        store.clone(),
+        vec![PathBuf::from("treats"), PathBuf::from("cats/roland")],
+      )
+      .wait()
+      .unwrap()
+      .into_iter()
+      .map(|file_content| (file_content.path, file_content.content))
+      .collect::<Vec<_>>();
+    assert_contents_eq(
+      &contents,
+      &[
+        (PathBuf::from("treats"), &b""[..]),
+        (PathBuf::from("cats/roland"), STR.as_bytes()),
+      ],
+    );
+
+    assert_eq!(
+      snapshot
+        .contents_of(
+          store,
+          vec![
+            PathBuf::from("small"),
+            PathBuf::from("cats"),
+            PathBuf::from("dogs/fido"),
+          ],
+        )
+        .wait()
+        .err(),
+      Some(
+        "Files not found in Snapshot: [\"cats\", \"dogs/fido\"]".to_owned(),
+      )
+    );
+  }
+
+  #[test]
+  fn of_reader_snapshots_single_file() {
+    let (store, _, _, _) = setup();
+
+    let snapshot = Snapshot::of_reader(
+      store.clone(),
+      PathBuf::from("cats/roland"),
+      std::io::Cursor::new(STR.as_bytes().to_vec()),
+      true,
+    ).wait()
+      .unwrap();
+
+    let mut builder = SnapshotBuilder::new();
+    builder.add_file(PathBuf::from("cats/roland"), STR.as_bytes().to_vec(), true);
+    assert_snapshot_eq(&store, &snapshot, &builder.finish(store.clone()).wait().unwrap());
+    assert_eq!(
+      snapshot
+        .contents_of(store, vec![PathBuf::from("cats/roland")])
+        .wait()
+        .unwrap()
+        .into_iter()
+        .map(|file_content| file_content.content)
+        .collect::<Vec<_>>(),
+      vec![STR.as_bytes().to_vec()]
+    );
+  }
+
+  #[test]
+  fn tree_key_is_pinned() {
+    let (store, _, _, _) = setup();
+
+    let mut builder = SnapshotBuilder::new();
+    builder.add_file(PathBuf::from("cats/roland"), STR.as_bytes().to_vec(), false);
+    builder.add_file(PathBuf::from("treats"), b"".to_vec(), true);
+    builder.add_empty_dir(PathBuf::from("empty"));
+    let snapshot = builder.finish(store.clone()).wait().unwrap();
+
+    // The key must not change as long as the tree does not, so it is compared against a literal.
+    assert_eq!(
+      snapshot.clone().tree_key(store.clone()).wait(),
+      Ok(
+        "tree-v1:b1146b1ffd10bd13479cf936700aa74a95538a878ec8cb9300ee21aa5bc122f9".to_owned(),
+      )
+    );
+
+    let mut builder = SnapshotBuilder::new();
+    builder.add_file(PathBuf::from("cats/roland"), STR.as_bytes().to_vec(), false);
+    builder.add_file(PathBuf::from("treats"), b"".to_vec(), false);
+    builder.add_empty_dir(PathBuf::from("empty"));
+    let not_executable = builder.finish(store.clone()).wait().unwrap();
+    assert_ne!(
+      not_executable.tree_key(store.clone()).wait().unwrap(),
+      snapshot.tree_key(store).wait().unwrap()
+    );
+  }
+
+  #[test]
+  fn from_path_stats_sorted_by_orders_only_path_stats() {
+    let (store, dir, posix_fs, digester) = setup();
+    for name in &["B", "a", "C"] {
+      make_file(&dir.path().join(name), STR.as_bytes(), 0o600);
+    }
+    let path_stats = expand_all_sorted(posix_fs);
+
+    let bytewise = Snapshot::from_path_stats(store.clone(), digester.clone(), path_stats.clone())
+      .wait()
+      .unwrap();
+    let case_insensitive = Snapshot::from_path_stats_sorted_by(
+      store,
+      digester,
+      path_stats,
+      |a, b| {
+        let lower = |path_stat: &PathStat| path_stat.path().to_string_lossy().to_lowercase();
+        lower(a).cmp(&lower(b))
+      },
+    ).wait()
+      .unwrap();
+
+    assert_eq!(case_insensitive.digest(), bytewise.digest());
+    assert_eq!(
+      case_insensitive
+        .path_stats()
+        .iter()
+        .map(|path_stat| path_stat.path().to_owned())
+        .collect::<Vec<_>>(),
+      vec![PathBuf::from("a"), PathBuf::from("B"), PathBuf::from("C")]
+    );
+    assert_eq!(
+      bytewise
+        .path_stats()
+        .iter()
+        .map(|path_stat| path_stat.path().to_owned())
+        .collect::<Vec<_>>(),
+      vec![PathBuf::from("B"), PathBuf::from("C"), PathBuf::from("a")]
+    );
+  }
+
+  #[test]
+  fn from_path_stats_rejects_colliding_entries() {
+    let (store, _, _, digester) = setup();
+    let file = |path: &str| {
+      PathStat::file(
+        PathBuf::from(path),
+        File {
+          path: PathBuf::from(path),
+          is_executable: false,
+        },
+      )
+    };
+    let dir = |path: &str| PathStat::dir(PathBuf::from(path), Dir(PathBuf::from(path)));
+
+    for path_stats in vec![
+      vec![file("roland"), dir("roland")],
+      vec![file("roland"), file("roland/treats")],
+      vec![dir("cats"), file("cats/roland"), dir("cats/roland/treats")],
+    ] {
+      Snapshot::from_path_stats(store.clone(), digester.clone(), path_stats)
+        .wait()
+        .expect_err("Want error");
+    }
+  }
+
+  #[test]
+  fn from_path_stats_verified_requires_all_blobs() {
+    let (store, dir, posix_fs, digester) = setup();
+    write_tree(dir.path(), &[("cats/roland", STR.as_bytes(), 0o600)], &[]);
+    let path_stats = expand_all_sorted(posix_fs);
+
+    let snapshot = Snapshot::from_path_stats_verified(store.clone(), digester, path_stats.clone())
+      .wait()
+      .unwrap();
+    assert_snapshot_eq(
+      &store,
+      &snapshot.clone().verify_complete(store.clone()).wait().unwrap(),
+      &snapshot,
+    );
+
+    // The Digest of "Unstored Burmese", which is never stored.
+    let unstored = Digest(
+      Fingerprint::from_hex_string(
+        "a4a82fcd7bda9d714f10392425983c624fd63e68d0d4a09752ab80b97bbeaf95",
+      ).unwrap(),
+      16,
+    );
+    let mut digests = HashMap::new();
+    digests.insert(PathBuf::from("cats/roland"), unstored.clone());
+    assert_eq!(
+      Snapshot::from_path_stats_verified(
+        store,
+        Arc::new(super::KnownDigests(digests)),
+        path_stats,
+      ).wait(),
+      Err(format!(
+        "File \"cats/roland\" with digest {:?} is missing from the Store",
+        unstored
+      ))
+    );
+  }
+
+  #[test]
+  fn from_path_stats_rejects_deep_nesting() {
+    let (store, _, _, digester) = setup();
+    let path = (0..10000).map(|_| "d").collect::<Vec<_>>().join("/");
+    let path_stats = vec![
+      PathStat::file(
+        PathBuf::from(&path),
+        File {
+          path: PathBuf::from(&path),
+          is_executable: false,
+        },
+      ),
+    ];
+    let err = Snapshot::from_path_stats(store, digester, path_stats)
+      .wait()
+      .expect_err("Want error");
+    assert!(
+      err.contains("Directory nesting exceeds limit 512"),
+      "Unexpected error: {}",
+      err
+    );
+  }
+
+  #[test]
+  fn walking_respects_configured_max_directory_depth() {
+    let pool = Arc::new(ResettablePool::new("test-pool-".to_string()));
+    let store = Arc::new(
+      Store::new_with_options(
+        TempDir::new("lmdb_store").unwrap(),
+        pool,
+        StoreOptions {
+          max_directory_depth: Some(3),
+          ..StoreOptions::default()
+        },
+      ).unwrap(),
+    );
+
+    // Record a chain of directories nested 5 deep, bypassing from_path_stats.
+    let mut digest = store
+      .record_directory(&bazel_protos::remote_execution::Directory::new())
+      .wait()
+      .unwrap();
+    for _ in 0..5 {
+      let mut node = bazel_protos::remote_execution::DirectoryNode::new();
+      node.set_name("d".to_owned());
+      node.set_digest(digest.into());
+      let mut directory = bazel_protos::remote_execution::Directory::new();
+      directory.mut_directories().push(node);
+      digest = store.record_directory(&directory).wait().unwrap();
+    }
+    let snapshot = Snapshot {
+      fingerprint: digest.0,
+      digest: Some(digest),
+      path_stats: vec![],
+    };
+
+    assert_eq!(
+      snapshot.manifest(store).wait(),
+      Err("Directory nesting exceeds limit 3".to_owned())
+    );
+  }
+
+  ///
+  /// Feeds adversarial, pseudo-randomly generated PathStats (deeply nested, with many empty
+  /// directories, near-duplicate names, repetitions and arbitrary orderings) to `from_path_stats`,
+  /// and checks that it either fails cleanly or produces canonical Directories containing exactly
+  /// the given files. The generator is seeded, so failures are reproducible.
+  ///
+  #[test]
+  fn from_path_stats_fuzz() {
+    let (store, _, _, _) = setup();
+    let empty_digest = store.store_file_bytes(vec![]).wait().unwrap();
+    let names = ["a", "b", "a.b", "A", "ab", " a", "a ", "\u{e9}", "e\u{301}", "-"];
+
+    // A linear congruential generator, since the tree deliberately has no randomness dependency.
+    let mut state: u64 = 0x5eed;
+    let mut next = |bound: usize| {
+      state = state
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+      ((state >> 33) as usize) % bound
+    };
+
+    let (mut successes, mut failures) = (0, 0);
+    for _ in 0..200 {
+      let mut path_stats = Vec::new();
+      let mut digests = HashMap::new();
+      for _ in 0..next(12) {
+        let mut path = PathBuf::new();
+        for _ in 0..(1 + next(6)) {
+          path.push(names[next(names.len())]);
+        }
+        if next(3) == 0 {
+          path_stats.push(PathStat::dir(path.clone(), Dir(path)));
+        } else {
+          digests.insert(path.clone(), empty_digest.clone());
+          path_stats.push(PathStat::file(
+            path.clone(),
+            File {
+              path: path,
+              is_executable: next(2) == 0,
+            },
+          ));
+        }
+        if next(4) == 0 {
+          // Repeat an entry exactly.
+          let repeated = path_stats[next(path_stats.len())].clone();
+          path_stats.push(repeated);
+        }
+      }
+      // Shuffle.
+      for i in (1..path_stats.len()).rev() {
+        let j = next(i + 1);
+        path_stats.swap(i, j);
+      }
+
+      let expected_files = path_stats
+        .iter()
+        .filter_map(|path_stat| match path_stat {
+          &PathStat::File { ref path, .. } => Some(path.clone()),
+          &PathStat::Dir { .. } => None,
+        })
+        .collect::<HashSet<_>>();
+      let snapshot = match Snapshot::from_path_stats(
+        store.clone(),
+        Arc::new(super::KnownDigests(digests)),
+        path_stats.clone(),
+      ).wait()
+      {
+        Ok(snapshot) => snapshot,
+        Err(_) => {
+          failures += 1;
+          continue;
+        }
+      };
+      successes += 1;
+
+      let root = snapshot.digest().unwrap().0;
+      store
+        .read_batch(move |reader| {
+          walk_directories(reader, root, PathBuf::new(), &mut |path, directory| {
+            let files = directory
+              .get_files()
+              .iter()
+              .map(|file_node| file_node.get_name())
+              .collect::<Vec<_>>();
+            let dirs = directory
+              .get_directories()
+              .iter()
+              .map(|directory_node| directory_node.get_name())
+              .collect::<Vec<_>>();
+            for names in &[&files, &dirs] {
+              assert!(
+                names.windows(2).all(|pair| pair[0] < pair[1]),
+                "Names in {:?} were not sorted and distinct: {:?}",
+                path,
+                names
+              );
+            }
+            assert!(
+              files.iter().all(|name| !dirs.contains(name)),
+              "{:?} had a file and directory with the same name",
+              path
+            );
+            Ok(())
+          })
+        })
+        .wait()
+        .unwrap();
+      let actual_files = snapshot
+        .manifest(store.clone())
+        .wait()
+        .unwrap()
+        .into_iter()
+        .map(|(path, _, _)| path)
+        .collect::<HashSet<_>>();
+      assert_eq!(actual_files, expected_files, "For {:?}", path_stats);
+    }
+    // Both outcomes should be well represented, or the generator is not exercising much.
+    assert!(successes > 20 && failures > 20, "{} ok, {} failed", successes, failures);
+  }
+
+  #[test]
+  fn duplicate_groups_lists_shared_contents() {
+    let (store, _, _, _) = setup();
+
+    let mut builder = SnapshotBuilder::new();
+    builder.add_file(PathBuf::from("cats/roland"), STR.as_bytes().to_vec(), false);
+    builder.add_file(PathBuf::from("cats/susannah"), b"".to_vec(), false);
+    builder.add_file(PathBuf::from("roland"), STR.as_bytes().to_vec(), true);
+    builder.add_file(PathBuf::from("treats"), b"treats".to_vec(), false);
+    builder.add_file(PathBuf::from("alias"), STR.as_bytes().to_vec(), false);
+    let snapshot = builder.finish(store.clone()).wait().unwrap();
+
+    let str_digest = store.store_file_bytes(STR.as_bytes().to_vec()).wait().unwrap();
+    assert_eq!(
+      snapshot.duplicate_groups(store).wait(),
+      Ok(vec![
+        (
+          str_digest,
+          vec![
+            PathBuf::from("alias"),
+            PathBuf::from("cats/roland"),
+            PathBuf::from("roland"),
+          ],
+        ),
+      ])
+    );
+  }
+
+  #[test]
+  fn content_set_digest_depends_on_paths() {
+    let (store, dir, posix_fs, digester) = setup();
+
+    make_file(&dir.path().join("roland"), STR.as_bytes(), 0o600);
+    let roland = Snapshot::from_path_stats(
+      store.clone(),
+      digester.clone(),
+      expand_all_sorted(posix_fs.clone()),
+    ).wait()
+      .unwrap();
+
+    std::fs::rename(dir.path().join("roland"), dir.path().join("susannah")).unwrap();
+    let susannah = Snapshot::from_path_stats(store.clone(), digester, expand_all_sorted(posix_fs))
+      .wait()
+      .unwrap();
+
+    assert_ne!(
+      roland.content_set_digest(store.clone()).wait().unwrap(),
+      susannah.content_set_digest(store).wait().unwrap()
+    );
+  }
+
+  #[test]
+  fn materialize_recursive_directories_including_empty() {
+    let (store, _dir, snapshot) = snapshot_from_disk(
+      &[("cats/roland", STR.as_bytes(), 0o600), ("treats", STR.as_bytes(), 0o700)],
+      &["dogs"],
+    );
+
+    let destination = TempDir::new("destination").unwrap();
+    let out = destination.path().join("out");
+    snapshot.materialize(store, out.clone()).wait().unwrap();
+
+    assert_eq!(read_file(&out.join("cats").join("roland")), STR.as_bytes());
+    assert_eq!(read_file(&out.join("treats")), STR.as_bytes());
+    assert!(out.join("dogs").is_dir());
+    assert!(!is_executable(&out.join("cats").join("roland")));
+    assert!(is_executable(&out.join("treats")));
+  }
+
+  #[test]
+  fn verify_roundtrip_rematerializes_identically() {
+    let (store, _dir, snapshot) = snapshot_from_disk(
+      &[("cats/roland", STR.as_bytes(), 0o600), ("treats", STR.as_bytes(), 0o700)],
+      &["dogs"],
+    );
+
+    let tmp = TempDir::new("roundtrip").unwrap();
+    assert_eq!(
+      snapshot
+        .verify_roundtrip(store, tmp.path().to_owned())
+        .wait(),
+      Ok(())
+    );
+    // The materialized tree is cleaned up.
+    assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 0);
+  }
+
+  #[test]
+  fn materialize_only_creates_complete_destinations() {
+    let (store, _, _, _) = setup();
+
+    let snapshot = cats_snapshot(&store);
+    let destination = TempDir::new("destination").unwrap();
+    snapshot
+      .materialize(store.clone(), destination.path().join("out"))
+      .wait()
+      .unwrap();
+    assert_eq!(
+      read_file(&destination.path().join("out").join("cats").join("roland")),
+      STR.as_bytes()
+    );
+
+    // A Snapshot whose file content is missing fails partway through, leaving nothing behind.
+    let missing = Digest(Fingerprint::from_hex_string(
+      "0000000000000000000000000000000000000000000000000000000000000000",
+    ).unwrap(), 7);
+    let mut directory = bazel_protos::remote_execution::Directory::new();
+    directory.mut_files().push({
+      let mut file = bazel_protos::remote_execution::FileNode::new();
+      file.set_name("missing".to_owned());
+      file.set_digest(missing.into());
+      file
+    });
+    let digest = store.record_directory(&directory).wait().unwrap();
+    let broken = Snapshot {
+      fingerprint: digest.0,
+      digest: Some(digest),
+      path_stats: vec![],
+    };
+    broken
+      .materialize(store, destination.path().join("broken"))
+      .wait()
+      .expect_err("Want error");
+    let entries = std::fs::read_dir(destination.path())
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name())
+      .collect::<Vec<_>>();
+    assert_eq!(entries, vec![OsString::from("out")]);
+  }
+
+  #[test]
+  fn materialize_concurrently_writes_all_files() {
+    let (store, _, _, _) = setup();
+
+    let mut builder = SnapshotBuilder::new();
+    for i in 0..20 {
+      builder.add_file(
+        PathBuf::from(format!("cats/{}/roland", i % 4)).join(i.to_string()),
+        format!("{} {}", STR, i).into_bytes(),
+        i % 2 == 0,
+      );
+    }
+    builder.add_empty_dir(PathBuf::from("dogs"));
+    let snapshot = builder.finish(store.clone()).wait().unwrap();
+
+    for &concurrency in &[0, 3] {
+      let destination = TempDir::new("destination").unwrap();
+      let out = destination.path().join("out");
+      snapshot
+        .clone()
+        .materialize_concurrently(store.clone(), out.clone(), concurrency)
+        .wait()
+        .unwrap();
+      for i in 0..20 {
+        let path = out.join(format!("cats/{}/roland", i % 4)).join(i.to_string());
+        assert_eq!(read_file(&path), format!("{} {}", STR, i).into_bytes());
+        assert_eq!(is_executable(&path), i % 2 == 0);
+      }
+      assert!(out.join("dogs").is_dir());
+    }
+  }
+
+  #[test]
+  fn contents_stream_yields_files_in_order() {
+    let (store, _dir, snapshot) = snapshot_from_disk(
+      &[("cats/roland", STR.as_bytes(), 0o600), ("treats", &b"meow"[..], 0o600)],
+      &[],
+    );
+
+    let contents = snapshot
+      .contents_stream(store, 1)
+      .map(|file_content| (file_content.path, file_content.content))
+      .collect()
+      .wait()
+      .unwrap();
+    assert_contents_eq(
+      &contents,
+      &[
+        (PathBuf::from("cats/roland"), STR.as_bytes()),
+        (PathBuf::from("treats"), &b"meow"[..]),
+      ],
+    );
+  }
+
+  #[test]
+  fn plan_materialize_lists_changes_without_writing() {
+    let (store, _dir, snapshot) = snapshot_from_disk(
+      &[("cats/roland", STR.as_bytes(), 0o600), ("treats", STR.as_bytes(), 0o600)],
+      &[],
+    );
+
+    let destination = TempDir::new("destination").unwrap();
+    make_file(&destination.path().join("treats"), b"stale", 0o600);
+    std::fs::create_dir_all(destination.path().join("dogs")).unwrap();
+    make_file(&destination.path().join("dogs").join("fido"), b"", 0o600);
+
+    let plan = snapshot
+      .plan_materialize(store, destination.path().to_owned())
+      .wait()
+      .unwrap();
+    assert_eq!(
+      plan,
+      MaterializePlan {
+        create: vec![PathBuf::from("cats/roland")],
+        overwrite: vec![PathBuf::from("treats")],
+        delete: vec![PathBuf::from("dogs/fido")],
+        bytes_to_write: 2 * STR.len() as u64,
+      }
+    );
+    assert!(!destination.path().join("cats").exists());
+    assert_eq!(read_file(&destination.path().join("treats")), b"stale".to_vec());
+  }
+
+  #[test]
+  fn patch_against_applies_only_changes() {
+    let (store, _, _, _) = setup();
+
+    let mut builder = SnapshotBuilder::new();
+    builder.add_file(PathBuf::from("cats/roland"), STR.as_bytes().to_vec(), false);
+    builder.add_file(PathBuf::from("treats"), b"stale".to_vec(), false);
+    builder.add_file(PathBuf::from("run"), b"#!/bin/sh".to_vec(), false);
+    builder.add_file(PathBuf::from("dogs/fido"), b"woof".to_vec(), false);
+    builder.add_file(PathBuf::from("leash"), b"".to_vec(), false);
+    let old = builder.finish(store.clone()).wait().unwrap();
+
+    let mut builder = SnapshotBuilder::new();
+    builder.add_file(PathBuf::from("cats/roland"), STR.as_bytes().to_vec(), false);
+    builder.add_file(PathBuf::from("treats"), b"fresh".to_vec(), false);
+    builder.add_file(PathBuf::from("run"), b"#!/bin/sh".to_vec(), true);
+    builder.add_file(PathBuf::from("leash/red"), b"".to_vec(), false);
+    builder.add_empty_dir(PathBuf::from("birds"));
+    let new = builder.finish(store.clone()).wait().unwrap();
+
+    let patch = new
+      .clone()
+      .patch_against(old.clone(), store.clone())
+      .wait()
+      .unwrap();
+    let fresh_digest = store.store_file_bytes(b"fresh".to_vec()).wait().unwrap();
+    let run_digest = store.store_file_bytes(b"#!/bin/sh".to_vec()).wait().unwrap();
+    let empty_digest = store.store_file_bytes(vec![]).wait().unwrap();
+    assert_eq!(
+      patch.add,
+      vec![(PathBuf::from("leash/red"), empty_digest, false)]
+    );
+    assert_eq!(
+      patch.update,
+      vec![
+        (PathBuf::from("run"), run_digest, true),
+        (PathBuf::from("treats"), fresh_digest, false),
+      ]
+    );
+    assert_eq!(
+      patch.delete,
+      vec![PathBuf::from("dogs/fido"), PathBuf::from("leash")]
+    );
+    assert_eq!(
+      patch.create_dirs,
+      vec![PathBuf::from("birds"), PathBuf::from("leash")]
+    );
+    assert_eq!(patch.remove_dirs, vec![PathBuf::from("dogs")]);
+
+    let destination = TempDir::new("destination").unwrap();
+    old
+      .materialize(store.clone(), destination.path().to_owned())
+      .wait()
+      .unwrap();
+    patch.apply_to(destination.path().to_owned()).wait().unwrap();
+
+    let expected = TempDir::new("expected").unwrap();
+    new
+      .materialize(store, expected.path().to_owned())
+      .wait()
+      .unwrap();
+    let files_of = |root: &Path| {
+      let mut paths = Vec::new();
+      super::list_files(root, PathBuf::new(), &mut paths, 16).unwrap();
+      paths.sort();
+      paths
+        .into_iter()
+        .map(|path| {
+          let content = read_file(&root.join(&path));
+          (path, content)
+        })
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(files_of(destination.path()), files_of(expected.path()));
+    assert!(is_executable(&destination.path().join("run")));
+    assert!(destination.path().join("birds").is_dir());
+    assert!(!destination.path().join("dogs").exists());
+  }
+
+  #[test]
+  fn plan_materialize_rejects_directory_in_place_of_file() {
+    let (store, dir, posix_fs, digester) = setup();
+
+    make_file(&dir.path().join("roland"), STR.as_bytes(), 0o600);
+    let snapshot = Snapshot::from_path_stats(store.clone(), digester, expand_all_sorted(posix_fs))
+      .wait()
+      .unwrap();
+
+    let destination = TempDir::new("destination").unwrap();
+    std::fs::create_dir_all(destination.path().join("roland")).unwrap();
+    snapshot
+      .plan_materialize(store, destination.path().to_owned())
+      .wait()
+      .expect_err("Want error");
+  }
+
+  #[test]
+  fn materialize_with_transforms_and_skips_paths() {
+    let (store, _dir, snapshot) = snapshot_from_disk(
+      &[("cats/roland", STR.as_bytes(), 0o600), ("cats/susannah", STR.as_bytes(), 0o600)],
+      &[],
+    );
+
+    let destination = TempDir::new("destination").unwrap();
+    snapshot
+      .materialize_with(store, destination.path().to_owned(), |path| {
+        if path.ends_with("susannah") {
+          None
+        } else {
+          path.strip_prefix("cats").ok().map(|p| p.to_owned())
+        }
+      })
+      .wait()
+      .unwrap();
+
+    assert_eq!(read_file(&destination.path().join("roland")), STR.as_bytes());
+    assert!(!destination.path().join("susannah").exists());
+    assert!(!destination.path().join("cats").exists());
+  }
+
+  #[test]
+  fn materialize_with_rejects_escaping_transform() {
+    let (store, dir, posix_fs, digester) = setup();
+
+    make_file(&dir.path().join("roland"), STR.as_bytes(), 0o600);
+    let snapshot = Snapshot::from_path_stats(store.clone(), digester, expand_all_sorted(posix_fs))
+      .wait()
+      .unwrap();
+
+    let destination = TempDir::new("destination").unwrap();
+    snapshot
+      .materialize_with(store, destination.path().to_owned(), |path| {
+        Some(Path::new("..").join(path))
+      })
+      .wait()
+      .expect_err("Want error");
+  }
+
+  #[test]
+  fn restrict_to_allowed_paths() {
+    let (store, _dir, snapshot) = snapshot_from_disk(
+      &[
+        ("cats/roland", STR.as_bytes(), 0o600),
+        ("cats/susannah", STR.as_bytes(), 0o600),
+        ("treats", STR.as_bytes(), 0o700),
+      ],
+      &["dogs"],
+    );
+
+    let mut allowed = HashSet::new();
+    allowed.insert(PathBuf::from("cats/roland"));
+    allowed.insert(PathBuf::from("treats"));
+    allowed.insert(PathBuf::from("not_in_the_snapshot"));
+    let restricted = snapshot.restrict_to(store.clone(), allowed).wait().unwrap();
+
+    let (_expected_dir, expected) = snapshot_from_disk_into(
+      &store,
+      &[("cats/roland", STR.as_bytes(), 0o600), ("treats", STR.as_bytes(), 0o700)],
+      &[],
+    );
+    assert_snapshot_eq(&store, &restricted, &expected);
+  }
+
+  #[test]
+  fn shards_partition_files() {
+    let (store, _dir, snapshot) = snapshot_from_disk(
+      &[
+        ("cats/roland", &b"roland"[..], 0o600),
+        ("cats/susannah", &b"susannah"[..], 0o600),
+        ("cats/tabby", &b"tabby"[..], 0o600),
+        ("cats/tom", &b"tom"[..], 0o600),
+        ("treats", STR.as_bytes(), 0o700),
+      ],
+      &["dogs"],
+    );
+    let files = snapshot.clone().manifest(store.clone()).wait().unwrap();
+
+    let shards = snapshot.clone().shard(store.clone(), 3).wait().unwrap();
+    assert_eq!(shards.len(), 3);
+    let mut sharded_files = Vec::new();
+    for shard in &shards {
+      sharded_files.extend(shard.clone().manifest(store.clone()).wait().unwrap());
+    }
+    sharded_files.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(sharded_files, files);
+
+    let labeled = shards
+      .into_iter()
+      .enumerate()
+      .map(|(index, shard)| (format!("shard {}", index), shard))
+      .collect();
+    let (merged, _) = Snapshot::merge_with_provenance(store.clone(), labeled, MergeStrategy::Fail)
+      .wait()
+      .unwrap();
+    assert_eq!(merged.digest(), snapshot.digest());
+
+    let shards = snapshot.clone().shard(store.clone(), 1).wait().unwrap();
+    assert_eq!(
+      shards.iter().map(|shard| shard.digest()).collect::<Vec<_>>(),
+      vec![snapshot.digest()]
+    );
+    assert!(snapshot.shard(store, 0).wait().is_err());
+  }
+
+  #[test]
+  fn identical_directories_have_identical_digests() {
     let (store, dir, posix_fs, digester) = setup();
 
+    for vendored in &["first", "second"] {
+      let path = dir.path().join(vendored).join("cats");
+      std::fs::create_dir_all(&path).unwrap();
+      make_file(&path.join("roland"), STR.as_bytes(), 0o600);
+    }
+
+    let snapshot = Snapshot::from_path_stats(
+      store.clone(),
+      digester,
+      expand_all_sorted(posix_fs),
+    ).wait()
+      .unwrap();
+    let root = store
+      .load_directory_proto(snapshot.digest().unwrap().0)
+      .wait()
+      .unwrap()
+      .unwrap();
+    let children = root.get_directories();
+    assert_eq!(children.len(), 2);
+    assert_eq!(children[0].get_digest(), children[1].get_digest());
+  }
+
+  #[test]
+  fn materialize_with_mtimes_restores_captured_mtimes() {
+    let (store, dir, snapshot) = snapshot_from_disk(
+      &[("cats/roland", STR.as_bytes(), 0o600), ("treats", STR.as_bytes(), 0o600)],
+      &[],
+    );
     let cats = PathBuf::from("cats");
-    let roland = cats.join("roland");
-    std::fs::create_dir_all(&dir.path().join(cats)).unwrap();
-    make_file(&dir.path().join(&roland), STR.as_bytes(), 0o600);
+    let roland_mtime = FileTime::from_seconds_since_1970(1_000_000_000, 0);
+    let treats_mtime = FileTime::from_seconds_since_1970(1_200_000_000, 500);
+    for &(ref path, mtime) in &[
+      (cats.join("roland"), roland_mtime),
+      (PathBuf::from("treats"), treats_mtime),
+    ]
+    {
+      filetime::set_file_times(dir.path().join(path), mtime, mtime).unwrap();
+    }
 
-    let path_stats = expand_all_sorted(posix_fs);
-    // TODO: Inline when only used once
-    let fingerprint = Fingerprint::from_hex_string(
-      "8b1a7ea04eaa2527b35683edac088bc826117b53b7ec6601740b55e20bce3deb",
-    ).unwrap();
+    snapshot
+      .clone()
+      .capture_mtimes(store.clone(), dir.path().to_owned())
+      .wait()
+      .unwrap();
+
+    let destination = TempDir::new("destination").unwrap();
+    snapshot
+      .materialize_with_mtimes(store, destination.path().to_owned())
+      .wait()
+      .unwrap();
+    let mtime_of = |path: PathBuf| {
+      FileTime::from_last_modification_time(&std::fs::metadata(path).unwrap())
+    };
     assert_eq!(
-      Snapshot::from_path_stats(store, digester, path_stats.clone())
-        .wait()
-        .unwrap(),
-      Snapshot {
-        fingerprint: fingerprint,
-        digest: Some(Digest(fingerprint, 78)),
-        path_stats: path_stats,
-      }
+      mtime_of(destination.path().join(cats.join("roland"))),
+      roland_mtime
     );
+    assert_eq!(mtime_of(destination.path().join("treats")), treats_mtime);
   }
 
   #[test]
-  fn snapshot_recursive_directories_including_empty() {
+  fn materialize_with_directory_modes_restores_captured_modes() {
+    let (store, dir, posix_fs, digester) = setup();
+
+    let secrets = PathBuf::from("cats").join("secrets");
+    std::fs::create_dir_all(&dir.path().join(&secrets)).unwrap();
+    make_file(&dir.path().join(secrets.join("roland")), STR.as_bytes(), 0o600);
+    set_mode(&dir.path().join("cats"), 0o750);
+    // Not writable, so the file inside it can only be written before the mode is restored.
+    set_mode(&dir.path().join(&secrets), 0o500);
+
+    let snapshot = Snapshot::from_path_stats(store.clone(), digester, expand_all_sorted(posix_fs))
+      .wait()
+      .unwrap();
+    snapshot
+      .clone()
+      .capture_directory_modes(store.clone(), dir.path().to_owned())
+      .wait()
+      .unwrap();
+
+    let destination = TempDir::new("destination").unwrap();
+    snapshot
+      .materialize_with_directory_modes(store, destination.path().to_owned())
+      .wait()
+      .unwrap();
+    assert_eq!(mode_of(&destination.path().join("cats")), 0o750);
+    assert_eq!(mode_of(&destination.path().join(&secrets)), 0o500);
+    assert_eq!(
+      read_file(&destination.path().join(secrets.join("roland"))),
+      STR.as_bytes().to_vec()
+    );
+
+    // Allow the TempDirs to be cleaned up.
+    set_mode(&dir.path().join(&secrets), 0o700);
+    set_mode(&destination.path().join(&secrets), 0o700);
+  }
+
+  #[test]
+  fn snapshot_builder_matches_from_path_stats() {
+    let (store, _dir, expected) = snapshot_from_disk(
+      &[("cats/roland", STR.as_bytes(), 0o700), ("treats", STR.as_bytes(), 0o600)],
+      &["dogs"],
+    );
+
+    let mut builder = SnapshotBuilder::new();
+    builder.add_file(PathBuf::from("treats"), STR.as_bytes().to_vec(), false);
+    builder.add_empty_dir(PathBuf::from("dogs"));
+    builder.add_file(PathBuf::from("cats/roland"), STR.as_bytes().to_vec(), true);
+    let snapshot = builder.finish(store).wait().unwrap();
+    assert_eq!(snapshot.digest(), expected.digest());
+  }
+
+  #[test]
+  fn snapshot_builder_rejects_conflicting_entries() {
+    let (store, _dir, _, _) = setup();
+
+    let mut duplicate = SnapshotBuilder::new();
+    duplicate.add_file(PathBuf::from("roland"), vec![], false);
+    duplicate.add_empty_dir(PathBuf::from("roland"));
+    duplicate.finish(store.clone()).wait().expect_err("Want error");
+
+    let mut beneath_file = SnapshotBuilder::new();
+    beneath_file.add_file(PathBuf::from("roland"), vec![], false);
+    beneath_file.add_file(PathBuf::from("roland/treats"), vec![], false);
+    beneath_file.finish(store).wait().expect_err("Want error");
+  }
+
+  #[test]
+  fn from_tar_stream() {
+    let mut builder = tar::Builder::new(Vec::new());
+    append_tar_entry(&mut builder, "./cats/", tar::EntryType::Directory, 0o755, &[]);
+    append_tar_entry(
+      &mut builder,
+      "./cats/roland",
+      tar::EntryType::Regular,
+      0o755,
+      STR.as_bytes(),
+    );
+    append_tar_entry(&mut builder, "dogs/", tar::EntryType::Directory, 0o755, &[]);
+    append_tar_entry(
+      &mut builder,
+      "treats",
+      tar::EntryType::Regular,
+      0o644,
+      STR.as_bytes(),
+    );
+    let archive = builder.into_inner().unwrap();
+
+    let (store, _dir, _, _) = setup();
+    let snapshot = Snapshot::from_tar_stream(store.clone(), std::io::Cursor::new(archive))
+      .wait()
+      .unwrap();
+
+    let (_expected_dir, expected) = snapshot_from_disk_into(
+      &store,
+      &[("cats/roland", STR.as_bytes(), 0o700), ("treats", STR.as_bytes(), 0o600)],
+      &["dogs"],
+    );
+    assert_snapshot_eq(&store, &snapshot, &expected);
+  }
+
+  #[test]
+  fn from_tar_stream_rejects_symlinks() {
+    let mut builder = tar::Builder::new(Vec::new());
+    append_tar_entry(&mut builder, "roland", tar::EntryType::Symlink, 0o755, &[]);
+    let archive = builder.into_inner().unwrap();
+
+    let (store, _dir, _, _) = setup();
+    Snapshot::from_tar_stream(store, std::io::Cursor::new(archive))
+      .wait()
+      .expect_err("Want error");
+  }
+
+  #[test]
+  fn with_modes_sets_executable_bits() {
+    let (store, _dir, snapshot) = snapshot_from_disk(
+      &[("cats/roland", STR.as_bytes(), 0o600), ("treats", STR.as_bytes(), 0o700)],
+      &["dogs"],
+    );
+    let modified = snapshot
+      .with_modes(store.clone(), |path, is_executable| {
+        path.starts_with("cats") || !is_executable
+      })
+      .wait()
+      .unwrap();
+
+    let (_expected_dir, expected) = snapshot_from_disk_into(
+      &store,
+      &[("cats/roland", STR.as_bytes(), 0o700), ("treats", STR.as_bytes(), 0o600)],
+      &["dogs"],
+    );
+    assert_snapshot_eq(&store, &modified, &expected);
+  }
+
+  #[test]
+  fn from_roots_places_roots_beneath_prefixes() {
+    let (store, dir, posix_fs, digester) = setup();
+    write_tree(dir.path(), &[("cats/roland", STR.as_bytes(), 0o600)], &[]);
+    let path_stats = expand_all_sorted(posix_fs);
+
+    let snapshot = Snapshot::from_roots(
+      store,
+      digester,
+      vec![
+        (PathBuf::from("first"), path_stats.clone()),
+        (PathBuf::from("second").join("nested"), path_stats),
+      ],
+    ).wait()
+      .unwrap();
+
+    let (_, _expected_dir, expected) = snapshot_from_disk(
+      &[
+        ("first/cats/roland", STR.as_bytes(), 0o600),
+        ("second/nested/cats/roland", STR.as_bytes(), 0o600),
+      ],
+      &[],
+    );
+    assert_eq!(snapshot.digest(), expected.digest());
+  }
+
+  #[test]
+  fn from_roots_overlapping() {
+    let (store, dir, posix_fs, digester) = setup();
+
+    make_file(&dir.path().join("roland"), STR.as_bytes(), 0o600);
+    let path_stats = expand_all_sorted(posix_fs);
+
+    Snapshot::from_roots(
+      store,
+      digester,
+      vec![
+        (PathBuf::from("cats"), path_stats.clone()),
+        (PathBuf::from("cats"), path_stats),
+      ],
+    ).wait()
+      .expect_err("Want error");
+  }
+
+  #[test]
+  fn from_path_stats_with_policy_skips_unreadable_files() {
     let (store, dir, posix_fs, digester) = setup();
 
     let cats = PathBuf::from("cats");
-    let roland = cats.join("roland");
-    let dogs = PathBuf::from("dogs");
-    let llamas = PathBuf::from("llamas");
-    std::fs::create_dir_all(&dir.path().join(&cats)).unwrap();
-    std::fs::create_dir_all(&dir.path().join(&dogs)).unwrap();
-    std::fs::create_dir_all(&dir.path().join(&llamas)).unwrap();
-    make_file(&dir.path().join(&roland), STR.as_bytes(), 0o600);
+    write_tree(
+      dir.path(),
+      &[("cats/roland", STR.as_bytes(), 0o600), ("treats", STR.as_bytes(), 0o600)],
+      &[],
+    );
+    let path_stats = expand_all_sorted(posix_fs);
+    // Removing a file after expanding makes it unreadable when it is digested.
+    std::fs::remove_file(dir.path().join(cats.join("roland"))).unwrap();
 
-    let sorted_path_stats = expand_all_sorted(posix_fs);
-    let mut unsorted_path_stats = sorted_path_stats.clone();
-    unsorted_path_stats.reverse();
-    // TODO: Inline when only used once
-    let fingerprint = Fingerprint::from_hex_string(
-      "fbff703bdaac62accf2ea5083bcfed89292073bf710ef9ad14d9298c637e777b",
-    ).unwrap();
+    let (snapshot, failures) = Snapshot::from_path_stats_with_policy(
+      store.clone(),
+      digester,
+      path_stats.clone(),
+      UnreadableFilePolicy::Skip,
+    ).wait()
+      .unwrap();
+    assert_eq!(
+      failures.iter().map(|&(ref path, _)| path.clone()).collect::<Vec<_>>(),
+      vec![cats.join("roland")]
+    );
     assert_eq!(
-      Snapshot::from_path_stats(store, digester, unsorted_path_stats)
+      snapshot.path_stats(),
+      &path_stats
+        .into_iter()
+        .filter(|path_stat| path_stat.path() != cats.join("roland").as_path())
+        .collect::<Vec<_>>()[..]
+    );
+
+    let destination = TempDir::new("destination").unwrap();
+    snapshot
+      .materialize(store, destination.path().to_owned())
+      .wait()
+      .unwrap();
+    assert_eq!(read_file(&destination.path().join("treats")), STR.as_bytes().to_vec());
+    assert!(destination.path().join("cats").is_dir());
+    assert!(!destination.path().join(cats.join("roland")).exists());
+  }
+
+  #[test]
+  fn from_path_stats_with_policy_reports_all_unreadable_files() {
+    let (store, dir, posix_fs, digester) = setup();
+
+    make_file(&dir.path().join("roland"), STR.as_bytes(), 0o600);
+    make_file(&dir.path().join("treats"), STR.as_bytes(), 0o600);
+    make_file(&dir.path().join("toys"), STR.as_bytes(), 0o600);
+    let path_stats = expand_all_sorted(posix_fs);
+    std::fs::remove_file(dir.path().join("roland")).unwrap();
+    std::fs::remove_file(dir.path().join("treats")).unwrap();
+
+    let err = Snapshot::from_path_stats_with_policy(
+      store,
+      digester,
+      path_stats,
+      UnreadableFilePolicy::ErrorWithAll,
+    ).wait()
+      .expect_err("Want error");
+    assert!(err.contains("Failed to digest 2 files"), "{}", err);
+    assert!(err.contains("\"roland\""), "{}", err);
+    assert!(err.contains("\"treats\""), "{}", err);
+    assert!(!err.contains("\"toys\""), "{}", err);
+  }
+
+  #[test]
+  fn from_path_stats_rejects_unnormalized_paths() {
+    let (store, _dir, _, digester) = setup();
+
+    for path in &["../roland", "/roland", "cats/../roland", "./roland", ""] {
+      let path_stats = vec![
+        PathStat::file(
+          PathBuf::from(path),
+          File {
+            path: PathBuf::from("roland"),
+            is_executable: false,
+          },
+        ),
+      ];
+      let err = Snapshot::from_path_stats(store.clone(), digester.clone(), path_stats)
         .wait()
-        .unwrap(),
-      Snapshot {
-        fingerprint: fingerprint,
-        digest: Some(Digest(fingerprint, 232)),
-        path_stats: sorted_path_stats,
-      }
+        .expect_err("Want error");
+      assert!(err.contains(&format!("{:?}", PathBuf::from(path))), err);
+    }
+  }
+
+  #[test]
+  fn from_path_stats_with_name_transform_renames_components() {
+    let (store, dir, posix_fs, digester) = setup();
+
+    std::fs::create_dir_all(dir.path().join("vendor").join("cats-1.0")).unwrap();
+    make_file(
+      &dir.path().join("vendor").join("cats-1.0").join("roland"),
+      STR.as_bytes(),
+      0o600,
+    );
+    let snapshot = Snapshot::from_path_stats_with_name_transform(
+      store.clone(),
+      digester,
+      expand_all_sorted(posix_fs),
+      strip_version,
+    ).wait()
+      .unwrap();
+    assert_eq!(
+      snapshot.path_stats().iter().map(|s| s.path()).collect::<Vec<_>>(),
+      vec![
+        Path::new("vendor"),
+        Path::new("vendor/cats"),
+        Path::new("vendor/cats/roland"),
+      ]
+    );
+
+    let destination = TempDir::new("destination").unwrap();
+    snapshot
+      .materialize(store, destination.path().to_owned())
+      .wait()
+      .unwrap();
+    assert_eq!(
+      read_file(&destination.path().join("vendor/cats/roland")),
+      STR.as_bytes().to_vec()
     );
   }
 
+  #[test]
+  fn from_path_stats_with_name_transform_rejects_collisions() {
+    let (store, dir, posix_fs, digester) = setup();
+
+    for version in &["cats-1.0", "cats-2.0"] {
+      std::fs::create_dir_all(dir.path().join(version)).unwrap();
+      make_file(&dir.path().join(version).join("roland"), STR.as_bytes(), 0o600);
+    }
+    let err = Snapshot::from_path_stats_with_name_transform(
+      store,
+      digester,
+      expand_all_sorted(posix_fs),
+      strip_version,
+    ).wait()
+      .expect_err("Want error");
+    assert!(err.contains("\"cats\""), err);
+  }
+
+  fn strip_version(_parent: &Path, name: &OsStr) -> OsString {
+    OsString::from(name.to_str().unwrap().split('-').next().unwrap())
+  }
+
+  ///
+  /// Asserts that two Snapshots (whose Directories are both in the given Store) are equal. If their
+  /// digests differ, panics with a diff of their trees rather than of their PathStats.
+  ///
+  fn assert_snapshot_eq(store: &Arc<Store>, actual: &Snapshot, expected: &Snapshot) {
+    if actual.digest() != expected.digest() {
+      assert_lines_eq(
+        &format!(
+          "Snapshot digests differed: actual {:?}, expected {:?}",
+          actual.digest(),
+          expected.digest()
+        ),
+        &tree_lines(store, actual),
+        &tree_lines(store, expected),
+      );
+    }
+    assert_eq!(actual, expected);
+  }
+
+  ///
+  /// Renders a line for each directory and file in a Snapshot, sorted by path.
+  ///
+  fn tree_lines(store: &Arc<Store>, snapshot: &Snapshot) -> Vec<String> {
+    let root = match snapshot.digest() {
+      Some(digest) => digest.0,
+      None => return vec!["<no digest>".to_owned()],
+    };
+    store
+      .read_batch(move |reader| {
+        let mut lines = Vec::new();
+        walk_directories(reader, root, PathBuf::new(), &mut |path, directory| {
+          for directory_node in directory.get_directories() {
+            lines.push(format!("{}/", path.join(directory_node.get_name()).display()));
+          }
+          for file_node in directory.get_files() {
+            lines.push(format!(
+              "{} {} {}{}",
+              path.join(file_node.get_name()).display(),
+              file_node.get_digest().get_hash(),
+              file_node.get_digest().get_size_bytes(),
+              if file_node.get_is_executable() {
+                " (executable)"
+              } else {
+                ""
+              }
+            ));
+          }
+          Ok(())
+        })?;
+        lines.sort();
+        Ok(lines)
+      })
+      .wait()
+      .unwrap_or_else(|err| vec![format!("<error: {}>", err)])
+  }
+
   struct FileSaver(Arc<Store>, Arc<PosixFS>);
 
   impl GetFileDigest<String> for FileSaver {
@@ -295,6 +5225,22 @@ mod tests {
     }
   }
 
+  fn append_tar_entry(
+    builder: &mut tar::Builder<Vec<u8>>,
+    path: &str,
+    entry_type: tar::EntryType,
+    mode: u32,
+    content: &[u8],
+  ) {
+    let mut header = tar::Header::new_gnu();
+    header.set_path(path).unwrap();
+    header.set_entry_type(entry_type);
+    header.set_mode(mode);
+    header.set_size(content.len() as u64);
+    header.set_cksum();
+    builder.append(&header, content).unwrap();
+  }
+
   fn expand_all_sorted(posix_fs: Arc<PosixFS>) -> Vec<PathStat> {
     let mut v = posix_fs
       .expand(PathGlobs::create(&["**".to_owned()], &vec![]).unwrap())
@@ -303,4 +5249,25 @@ mod tests {
     v.sort_by(|a, b| a.path().cmp(b.path()));
     v
   }
+
+  fn read_file(path: &Path) -> Vec<u8> {
+    let mut contents = Vec::new();
+    std::fs::File::open(path)
+      .unwrap()
+      .read_to_end(&mut contents)
+      .unwrap();
+    contents
+  }
+
+  fn set_mode(path: &Path, mode: u32) {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+  }
+
+  fn mode_of(path: &Path) -> u32 {
+    std::fs::metadata(path).unwrap().permissions().mode() & 0o7777
+  }
+
+  fn is_executable(path: &Path) -> bool {
+    std::fs::metadata(path).unwrap().permissions().mode() & 0o100 == 0o100
+  }
 }
@@ -0,0 +1,200 @@
+// Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::cmp::min;
+
+// Runs of zeros shorter than this are left as literals: encoding them would save little, and would
+// fragment the literal segments around them.
+pub const MIN_ZERO_RUN: usize = 4096;
+
+const LITERAL: u8 = 0;
+const ZEROS: u8 = 1;
+
+///
+/// A compact encoding for file contents which contain long runs of zero bytes (such as sparse disk
+/// images). Returns None if the bytes contain no runs of zeros long enough to be worth encoding.
+///
+/// The encoding is the length of the decoded bytes, followed by a series of segments, each of which
+/// is a tag byte and a length. Literal segments are followed by that many bytes of content, while
+/// zero segments represent that many zero bytes. All lengths are big-endian u64s.
+///
+pub fn encode_zero_runs(bytes: &[u8]) -> Option<Vec<u8>> {
+  let mut encoded = Vec::new();
+  push_u64(&mut encoded, bytes.len() as u64);
+
+  let mut found_run = false;
+  let mut literal_start = 0;
+  let mut i = 0;
+  while i < bytes.len() {
+    if bytes[i] != 0 {
+      i += 1;
+      continue;
+    }
+    let run_start = i;
+    while i < bytes.len() && bytes[i] == 0 {
+      i += 1;
+    }
+    if i - run_start >= MIN_ZERO_RUN {
+      push_literal(&mut encoded, &bytes[literal_start..run_start]);
+      encoded.push(ZEROS);
+      push_u64(&mut encoded, (i - run_start) as u64);
+      literal_start = i;
+      found_run = true;
+    }
+  }
+  if !found_run {
+    return None;
+  }
+  push_literal(&mut encoded, &bytes[literal_start..]);
+  Some(encoded)
+}
+
+///
+/// Reconstructs the bytes that were passed to `encode_zero_runs`.
+///
+pub fn decode_zero_runs(encoded: &[u8]) -> Result<Vec<u8>, String> {
+  let (len, mut rest) = read_u64(encoded)?;
+  // The recorded length is untrusted, so reserve no more than the literals could need, which is at
+  // most the length of the encoding: zero runs grow the Vec as they are decoded.
+  let mut decoded = Vec::with_capacity(min(len, encoded.len() as u64) as usize);
+  while !rest.is_empty() {
+    let tag = rest[0];
+    let (segment_len, remainder) = read_u64(&rest[1..])?;
+    let segment_len = segment_len as usize;
+    rest = match tag {
+      LITERAL => {
+        if remainder.len() < segment_len {
+          return Err(format!(
+            "Truncated literal segment: wanted {} bytes but had {}",
+            segment_len,
+            remainder.len()
+          ));
+        }
+        decoded.extend_from_slice(&remainder[..segment_len]);
+        &remainder[segment_len..]
+      }
+      ZEROS => {
+        let new_len = match decoded.len().checked_add(segment_len) {
+          Some(new_len) if new_len as u64 <= len => new_len,
+          _ => {
+            return Err(format!(
+              "Zero segment of {} bytes exceeds decoded length {}",
+              segment_len,
+              len
+            ))
+          }
+        };
+        decoded.resize(new_len, 0);
+        remainder
+      }
+      other => return Err(format!("Unknown segment tag: {}", other)),
+    };
+  }
+  if decoded.len() as u64 != len {
+    return Err(format!(
+      "Decoded {} bytes, but expected {}",
+      decoded.len(),
+      len
+    ));
+  }
+  Ok(decoded)
+}
+
+///
+/// Returns the length of the bytes which `decode_zero_runs` would reconstruct, without decoding
+/// them: it is recorded at the start of the encoding.
+///
+pub fn decoded_len(encoded: &[u8]) -> Result<usize, String> {
+  read_u64(encoded).map(|(len, _)| len as usize)
+}
+
+fn push_literal(encoded: &mut Vec<u8>, literal: &[u8]) {
+  if !literal.is_empty() {
+    encoded.push(LITERAL);
+    push_u64(encoded, literal.len() as u64);
+    encoded.extend_from_slice(literal);
+  }
+}
+
+fn push_u64(encoded: &mut Vec<u8>, value: u64) {
+  for shift in (0..8).rev() {
+    encoded.push((value >> (shift * 8)) as u8);
+  }
+}
+
+fn read_u64(bytes: &[u8]) -> Result<(u64, &[u8]), String> {
+  if bytes.len() < 8 {
+    return Err(format!(
+      "Truncated length: wanted 8 bytes but had {}",
+      bytes.len()
+    ));
+  }
+  let value = bytes[..8].iter().fold(0, |acc, &b| (acc << 8) | b as u64);
+  Ok((value, &bytes[8..]))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{MIN_ZERO_RUN, decode_zero_runs, decoded_len, encode_zero_runs};
+
+  #[test]
+  fn no_long_runs() {
+    let mut bytes = vec![1, 2, 3];
+    bytes.extend(vec![0; MIN_ZERO_RUN - 1]);
+    bytes.push(4);
+    assert_eq!(encode_zero_runs(&bytes), None);
+  }
+
+  #[test]
+  fn roundtrip_only_zeros() {
+    let bytes = vec![0; MIN_ZERO_RUN * 3];
+    let encoded = encode_zero_runs(&bytes).unwrap();
+    assert!(encoded.len() < 32);
+    assert_eq!(decode_zero_runs(&encoded), Ok(bytes));
+  }
+
+  #[test]
+  fn roundtrip_mixed() {
+    let mut bytes = b"leading".to_vec();
+    bytes.extend(vec![0; MIN_ZERO_RUN]);
+    bytes.extend(b"middle".iter().cloned());
+    bytes.extend(vec![0; 10]);
+    bytes.extend(vec![0; MIN_ZERO_RUN * 2]);
+    bytes.extend(b"trailing".iter().cloned());
+    let encoded = encode_zero_runs(&bytes).unwrap();
+    assert!(encoded.len() < 100);
+    assert_eq!(decode_zero_runs(&encoded), Ok(bytes));
+  }
+
+  #[test]
+  fn decoded_len_without_decoding() {
+    let mut bytes = b"leading".to_vec();
+    bytes.extend(vec![0; MIN_ZERO_RUN * 2]);
+    let encoded = encode_zero_runs(&bytes).unwrap();
+    assert_eq!(decoded_len(&encoded), Ok(bytes.len()));
+    decoded_len(&encoded[..7]).expect_err("Want error");
+  }
+
+  #[test]
+  fn decode_rejects_misstated_lengths() {
+    // Claims u64::MAX bytes, but holds a single literal byte.
+    let mut overstated = vec![0xff; 8];
+    overstated.push(0);
+    overstated.extend(vec![0, 0, 0, 0, 0, 0, 0, 1]);
+    overstated.push(7);
+    decode_zero_runs(&overstated).expect_err("Want error");
+
+    // Claims 1 byte, but holds a run of zeros.
+    let mut understated = encode_zero_runs(&vec![0; MIN_ZERO_RUN]).unwrap();
+    understated[6] = 0;
+    understated[7] = 1;
+    decode_zero_runs(&understated).expect_err("Want error");
+  }
+
+  #[test]
+  fn decode_truncated() {
+    let mut encoded = encode_zero_runs(&vec![0; MIN_ZERO_RUN]).unwrap();
+    encoded.pop();
+    decode_zero_runs(&encoded).expect_err("Want error");
+  }
+}
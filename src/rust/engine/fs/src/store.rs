@@ -1,18 +1,37 @@
 use bazel_protos;
-use boxfuture::{Boxable, BoxFuture};
+use boxfuture::{Boxable, BoxFuture, BoxStream};
 use digest::{Digest as DigestTrait, FixedOutput};
-use futures::{future, Future};
-use futures_cpupool::CpuFuture;
-use lmdb::{Database, DatabaseFlags, Environment, NO_OVERWRITE, Transaction};
+use filetime::FileTime;
+use futures::{future, stream, Future, Stream};
+use futures::future::{Either, Shared};
+use lmdb::{self, Cursor as LmdbCursor, Database, DatabaseFlags, Environment, EnvironmentFlags,
+           NO_META_SYNC, NO_OVERWRITE, NO_SYNC, RoTransaction, RwTransaction, Transaction,
+           WriteFlags};
 use lmdb::Error::{KeyExist, NotFound};
+use libc;
+use lmdb_sys as ffi;
 use protobuf::core::Message;
 use sha2::Sha256;
+use tempdir::TempDir;
+use std::cmp::max;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
-use std::path::Path;
-use std::sync::Arc;
+use std::ffi::{CStr, OsStr};
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use hash::Fingerprint;
+use hash::{Fingerprint, WriterHasher};
+use lru::LruCache;
 use pool::ResettablePool;
+use sparse::{decode_zero_runs, decoded_len, encode_zero_runs};
+use timer;
 
 ///
 /// A content-addressed store of file contents, and Directories.
@@ -25,375 +44,3400 @@ pub struct Store {
   inner: Arc<InnerStore>,
 }
 
+///
+/// A function to call with the Digest of each blob which a Store removes. See `Store::on_evict`.
+///
+pub type EvictCallback = Arc<Fn(Digest) + Send + Sync>;
+
+///
+/// Optional behaviours of a Store. The defaults match those of `Store::new`.
+///
+#[derive(Clone, Debug, Default)]
+pub struct StoreOptions {
+  ///
+  /// Store files which contain long runs of zero bytes (such as sparse disk images) in a compact
+  /// encoding, rather than densely. Files are always addressed by the Digest of their dense bytes,
+  /// and are transparently reconstructed when loaded, so this only affects the space they occupy.
+  ///
+  /// Files stored compactly can be loaded by any Store, regardless of this option.
+  ///
+  pub compact_zero_runs: bool,
+
+  ///
+  /// The number of parsed Directory protos to keep in memory, evicting the least recently used
+  /// beyond that. Directories are immutable, so this only saves re-reading and re-parsing them
+  /// when the same trees are walked repeatedly. Zero (the default) disables the cache.
+  ///
+  pub directory_cache_size: usize,
+
+  ///
+  /// The number of Snapshot manifests (see `Snapshot::manifest`) to keep in memory, keyed by the
+  /// Digest of their root Directory, evicting the least recently used beyond that. A manifest holds
+  /// only paths and Digests, so this saves walking large trees repeatedly without holding any file
+  /// contents. Zero (the default) disables the cache.
+  ///
+  pub manifest_cache_size: usize,
+
+  ///
+  /// If set, reads and writes which take longer than this to complete fail with a Timeout error,
+  /// rather than blocking their callers indefinitely (for example, on a hung network mount).
+  ///
+  pub operation_timeout: Option<Duration>,
+
+  ///
+  /// How durably writes are committed to disk.
+  ///
+  pub durability: Durability,
+
+  ///
+  /// When storing a file or Directory whose fingerprint is already present, verify that the stored
+  /// bytes are identical to the new bytes, and fail if they are not.
+  ///
+  /// With SHA-256 a collision is infeasible, so this only guards the content-addressing invariant
+  /// while developing alternative hashing; it costs an extra read for every duplicate store.
+  ///
+  pub check_collisions: bool,
+
+  ///
+  /// The deepest nesting of directories which a Snapshot may have, or None for the default of
+  /// `DEFAULT_MAX_DIRECTORY_DEPTH`. Snapshots are built and walked recursively, so deeper trees are
+  /// rejected with an error rather than risking overflowing the stack.
+  ///
+  pub max_directory_depth: Option<usize>,
+
+  ///
+  /// The number of LMDB reader slots, or None for LMDB's default of 126. Each thread which reads
+  /// from the Store occupies a slot for as long as it lives, so this should be at least the number
+  /// of threads in the pool (plus any other threads which read). Reads beyond the limit fail
+  /// rather than waiting for a slot.
+  ///
+  /// The number of slots is fixed by the first process to open the Store's directory, so this has
+  /// no effect while another process has it open.
+  ///
+  pub max_readers: Option<u32>,
+}
+
+///
+/// The deepest nesting of directories which a Snapshot may have, unless configured otherwise (see
+/// `StoreOptions::max_directory_depth`). This is much deeper than real source trees, but shallow
+/// enough to walk on the smallest of the pool's thread stacks.
+///
+pub const DEFAULT_MAX_DIRECTORY_DEPTH: usize = 512;
+
+///
+/// How durably a Store commits its writes, trading safety for speed.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Durability {
+  ///
+  /// Every commit is flushed to disk before it completes.
+  ///
+  Safe,
+  ///
+  /// Data is flushed on commit, but metadata is not, so a system crash may undo the last
+  /// committed write (but will not corrupt the Store).
+  ///
+  NoMetaSync,
+  ///
+  /// Nothing is flushed on commit, and the operating system decides when to write to disk. This
+  /// is much faster, but a system crash may corrupt the Store, so it is only suitable for
+  /// throwaway stores (such as ephemeral CI caches).
+  ///
+  NoSync,
+}
+
+impl Default for Durability {
+  fn default() -> Durability {
+    Durability::Safe
+  }
+}
+
+///
+/// Statistics about the LMDB environment underlying a Store, as returned by `Store::env_info`.
+///
+/// Pages are freed when entries are deleted (for example by `expire`), but the file never shrinks:
+/// freed pages are only reused by later writes. A large number of free pages relative to the last
+/// used page indicates that copying the Store to a new environment would reclaim space.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EnvInfo {
+  ///
+  /// The maximum size of the environment, in bytes.
+  ///
+  pub map_size: usize,
+  ///
+  /// The size of a page, in bytes.
+  ///
+  pub page_size: u32,
+  ///
+  /// The number of the last page in use, which bounds the size of the data file.
+  ///
+  pub last_page: usize,
+  ///
+  /// The number of pages before the last page which are free to be reused.
+  ///
+  pub free_pages: usize,
+  ///
+  /// The number of entries in each of this Store's databases, by name.
+  ///
+  pub entries: BTreeMap<String, usize>,
+}
+
+///
+/// The largest file which `Store::store_file_from_path` reads into memory before storing it.
+///
+pub const MAX_BUFFERED_STORE_SIZE: usize = 1024 * 1024;
+
+// Backups (see `Store::backup`) begin with this, followed by the format version as a u64.
+const BACKUP_MAGIC: &[u8] = b"pants-store-backup\n";
+const BACKUP_FORMAT_VERSION: u64 = 2;
+const BACKUP_FILE_ENTRY: u8 = 0;
+const BACKUP_DIRECTORY_ENTRY: u8 = 1;
+// Added in version 2.
+const BACKUP_RAW_NAME_ENTRY: u8 = 2;
+
+// The kinds of blob recorded in the additions log (see `Store::stream_entries`).
+const ADDED_FILE: u8 = 0;
+const ADDED_DIRECTORY: u8 = 1;
+
+///
+/// The number of LMDB databases used by each Store (or namespace). An Environment passed to
+/// `Store::from_env` must have room for at least this many named databases.
+///
+pub const DATABASES_PER_STORE: u32 = 9;
+
+///
+/// A position in the log of blobs added to a Store, which a follower replicating the Store can
+/// persist between syncs (as the u64 it wraps). See `Store::stream_entries`.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Cursor(pub u64);
+
+///
+/// An on-disk environment which can hold several independent, namespaced Stores (see
+/// `Store::new_namespaced`). Sharing one environment between Stores saves the file handles and
+/// memory mappings that each would otherwise need.
+///
+#[derive(Clone)]
+pub struct StoreEnvironment {
+  env: Arc<Environment>,
+}
+
+impl StoreEnvironment {
+  ///
+  /// Opens (creating if necessary) an environment at `path` with room for `max_namespaces`
+  /// namespaced Stores.
+  ///
+  pub fn new<P: AsRef<Path>>(
+    path: P,
+    max_namespaces: u32,
+    durability: Durability,
+  ) -> Result<StoreEnvironment, String> {
+    let env = open_environment(
+      path.as_ref(),
+      durability,
+      max_namespaces * DATABASES_PER_STORE,
+      None,
+    )?;
+    Ok(StoreEnvironment { env: Arc::new(env) })
+  }
+}
+
 struct InnerStore {
-  env: Environment,
+  env: Arc<Environment>,
   pool: Arc<ResettablePool>,
+  options: StoreOptions,
   file_store: Database,
+  // Files stored in the encoding of `sparse::encode_zero_runs`. A file is stored in at most one of
+  // this and file_store.
+  sparse_file_store: Database,
+  // Expiry times (in seconds since the epoch) of files stored with a TTL. Files without an entry
+  // never expire.
+  expiry_store: Database,
+  // Modification times of files, keyed by their fingerprint followed by a path at which they were
+  // captured. These are not part of any Digest, and are only recorded on request.
+  mtime_store: Database,
+  // Permission bits of directories, keyed like mtime_store by the Directory's fingerprint followed
+  // by a path at which they were captured. As with mtimes, these are only recorded on request.
+  directory_mode_store: Database,
+  // The exact bytes of names which are not valid UTF8, keyed by the fingerprint of the Directory
+  // containing them followed by the UTF8-lossy form of the name which the Directory records.
+  raw_name_store: Database,
+  // Classifications of file contents, keyed by the files' fingerprints followed by the name of the
+  // classifier which produced them. See `Snapshot::classify`.
+  content_type_store: Database,
+  // The fingerprints of newly added files and Directories, each prefixed by its kind (ADDED_FILE
+  // or ADDED_DIRECTORY), keyed by a big-endian sequence number starting from 1.
+  addition_store: Database,
   // Store directories separately from files because:
   //  1. They may have different lifetimes.
   //  2. It's nice to know whether we should be able to parse something as a proto.
   directory_store: Database,
+  directory_cache: Mutex<LruCache<Fingerprint, bazel_protos::remote_execution::Directory>>,
+  // The parsed Digests of the child Directories of cached Directories, so that walks of the same
+  // trees do not repeatedly decode their hex. Bounded by the same size as directory_cache.
+  child_digest_cache: Mutex<LruCache<Fingerprint, Arc<Vec<Digest>>>>,
+  manifest_cache: Mutex<LruCache<Fingerprint, Arc<Vec<(PathBuf, Digest, bool)>>>>,
+  // Writes of files which are currently in progress, so that concurrent stores of the same file
+  // (common for empty files, or license headers) can wait for a single write.
+  in_flight_file_stores: Mutex<HashMap<Fingerprint, Shared<BoxFuture<(), String>>>>,
+  evict_callbacks: Mutex<Vec<EvictCallback>>,
+}
+
+impl InnerStore {
+  fn max_directory_depth(&self) -> usize {
+    self.options.max_directory_depth.unwrap_or(
+      DEFAULT_MAX_DIRECTORY_DEPTH,
+    )
+  }
+
+  fn cached_directory(
+    &self,
+    fingerprint: Fingerprint,
+  ) -> Option<bazel_protos::remote_execution::Directory> {
+    self
+      .directory_cache
+      .lock()
+      .unwrap()
+      .get(&fingerprint)
+      .cloned()
+  }
+
+  fn cache_directory(
+    &self,
+    fingerprint: Fingerprint,
+    directory: &bazel_protos::remote_execution::Directory,
+  ) {
+    if self.options.directory_cache_size > 0 {
+      self.directory_cache.lock().unwrap().insert(
+        fingerprint,
+        directory.clone(),
+      );
+    }
+  }
+
+  fn child_directory_digests(
+    &self,
+    fingerprint: Fingerprint,
+    directory: &bazel_protos::remote_execution::Directory,
+  ) -> Result<Arc<Vec<Digest>>, String> {
+    if let Some(digests) = self.child_digest_cache.lock().unwrap().get(&fingerprint) {
+      return Ok(digests.clone());
+    }
+    let digests = Arc::new(
+      directory
+        .get_directories()
+        .iter()
+        .map(|directory_node| {
+          Digest::from_bazel_digest(directory_node.get_digest())
+        })
+        .collect::<Result<Vec<_>, String>>()?,
+    );
+    if self.options.directory_cache_size > 0 {
+      self.child_digest_cache.lock().unwrap().insert(
+        fingerprint,
+        digests.clone(),
+      );
+    }
+    Ok(digests)
+  }
 }
 
 impl Store {
   pub fn new<P: AsRef<Path>>(path: P, pool: Arc<ResettablePool>) -> Result<Store, String> {
-    // 2 DBs; one for file contents, one for directories.
-    let env = Environment::new()
-      .set_max_dbs(2)
-      .set_map_size(16 * 1024 * 1024 * 1024)
-      .open(path.as_ref())
-      .map_err(|e| format!("Error making env: {}", e.description()))?;
-    let file_database = env
-      .create_db(Some("files"), DatabaseFlags::empty())
-      .map_err(|e| {
-        format!("Error creating/opening files database: {}", e.description())
-      })?;
-    let directory_database = env
-      .create_db(Some("directories"), DatabaseFlags::empty())
-      .map_err(|e| {
-        format!(
-          "Error creating/opening directories database: {}",
-          e.description()
-        )
-      })?;
+    Store::new_with_options(path, pool, StoreOptions::default())
+  }
+
+  pub fn new_with_options<P: AsRef<Path>>(
+    path: P,
+    pool: Arc<ResettablePool>,
+    options: StoreOptions,
+  ) -> Result<Store, String> {
+    let env = open_environment(
+      path.as_ref(),
+      options.durability,
+      DATABASES_PER_STORE,
+      options.max_readers,
+    )?;
+    Store::open(Arc::new(env), "", pool, options)
+  }
+
+  ///
+  /// Opens a Store with default options in an LMDB Environment which was opened elsewhere (with room
+  /// for at least `DATABASES_PER_STORE` named databases), so that a process embedding several
+  /// components need not open the same environment once per component.
+  ///
+  /// Stores opened from the same Environment in this way use the same databases, so they share
+  /// their contents; use `new_namespaced` for independent Stores. Either way, sharing has costs:
+  /// every Store's read transactions occupy the Environment's reader slots (so concurrent readers
+  /// across all of them must fit within its max_readers), and everything stored counts against its
+  /// single map size, which should be set large enough for all of them.
+  ///
+  pub fn from_env(env: Arc<Environment>, pool: Arc<ResettablePool>) -> Result<Store, String> {
+    Store::open(env, "", pool, StoreOptions::default())
+  }
+
+  ///
+  /// Opens the Store named `namespace` within a StoreEnvironment, which may be shared with other
+  /// namespaces. Each namespace has its own databases, so its contents (and operations like
+  /// `expire`) are independent of the others'.
+  ///
+  /// Durability is a property of the environment, so the durability in `options` is ignored in
+  /// favour of the one that the StoreEnvironment was opened with.
+  ///
+  pub fn new_namespaced(
+    env: &StoreEnvironment,
+    namespace: &str,
+    pool: Arc<ResettablePool>,
+    options: StoreOptions,
+  ) -> Result<Store, String> {
+    if namespace.is_empty() || namespace.contains('/') {
+      return Err(format!(
+        "Store namespace must be non-empty and not contain '/', but was {:?}",
+        namespace
+      ));
+    }
+    Store::open(
+      env.env.clone(),
+      &format!("{}/", namespace),
+      pool,
+      options,
+    )
+  }
+
+  fn open(
+    env: Arc<Environment>,
+    prefix: &str,
+    pool: Arc<ResettablePool>,
+    options: StoreOptions,
+  ) -> Result<Store, String> {
+    // One DB for file contents, one for compactly encoded file contents, one for file expiry
+    // times, one for file modification times, one for directory modes, one for raw names, one for
+    // content types, one for the log of additions, and one for directories.
+    let file_database = create_db(&env, prefix, "files")?;
+    let sparse_file_database = create_db(&env, prefix, "sparse_files")?;
+    let expiry_database = create_db(&env, prefix, "expiries")?;
+    let mtime_database = create_db(&env, prefix, "mtimes")?;
+    let directory_mode_database = create_db(&env, prefix, "directory_modes")?;
+    let raw_name_database = create_db(&env, prefix, "raw_names")?;
+    let content_type_database = create_db(&env, prefix, "content_types")?;
+    let addition_database = create_db(&env, prefix, "additions")?;
+    let directory_database = create_db(&env, prefix, "directories")?;
+    let directory_cache = Mutex::new(LruCache::new(options.directory_cache_size));
+    let child_digest_cache = Mutex::new(LruCache::new(options.directory_cache_size));
+    let manifest_cache = Mutex::new(LruCache::new(options.manifest_cache_size));
     Ok(Store {
       inner: Arc::new(InnerStore {
         env: env,
         pool: pool,
+        options: options,
         file_store: file_database,
+        sparse_file_store: sparse_file_database,
+        expiry_store: expiry_database,
+        mtime_store: mtime_database,
+        directory_mode_store: directory_mode_database,
+        raw_name_store: raw_name_database,
+        content_type_store: content_type_database,
+        addition_store: addition_database,
         directory_store: directory_database,
+        directory_cache: directory_cache,
+        child_digest_cache: child_digest_cache,
+        manifest_cache: manifest_cache,
+        in_flight_file_stores: Mutex::new(HashMap::new()),
+        evict_callbacks: Mutex::new(Vec::new()),
       }),
     })
   }
 
+  ///
+  /// The deepest nesting of directories which Snapshots using this Store may have.
+  ///
+  pub fn max_directory_depth(&self) -> usize {
+    self.inner.max_directory_depth()
+  }
+
+  ///
+  /// The pool on which this Store does its work, for related work such as reading a PosixFS.
+  ///
+  pub fn pool(&self) -> Arc<ResettablePool> {
+    self.inner.pool.clone()
+  }
+
   pub fn store_file_bytes(&self, bytes: Vec<u8>) -> BoxFuture<Digest, String> {
     let len = bytes.len();
+    let store = self.clone();
     self
-      .store_bytes(bytes, self.inner.file_store.clone())
-      .map(move |fingerprint| Digest(fingerprint, len))
+      .spawn(move || Ok((fingerprint_of(&bytes), bytes)))
+      .and_then(move |(fingerprint, bytes)| {
+        store
+          .put_file_bytes_coalesced(fingerprint, bytes)
+          .map(move |()| Digest(fingerprint, len))
+      })
       .to_boxed()
   }
 
-  fn store_bytes(&self, bytes: Vec<u8>, db: Database) -> CpuFuture<Fingerprint, String> {
-    let store = self.clone();
-    self.inner.pool.spawn_fn(move || {
-      let fingerprint = {
-        let mut hasher = Sha256::default();
-        hasher.input(&bytes);
-        Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice())
-      };
-
-      let put_res = store.inner.env.begin_rw_txn().and_then(|mut txn| {
-        txn.put(db, &fingerprint, &bytes, NO_OVERWRITE).and_then(
-              |()| txn.commit(),
-          )
-      });
-
-      match put_res {
-        Ok(()) => Ok(fingerprint),
-        Err(KeyExist) => Ok(fingerprint),
-        Err(err) => Err(format!(
-          "Error storing fingerprint {}: {}",
-          fingerprint,
-          err.description()
-        )),
-      }
-    })
+  ///
+  /// Store the given file bytes under a Digest which the caller has already computed (for example,
+  /// while streaming the bytes from disk), to avoid hashing them a second time.
+  ///
+  /// The length of the bytes is always checked against the Digest. When debug assertions are
+  /// enabled, the fingerprint is verified as well.
+  ///
+  pub fn store_file_bytes_with_digest(
+    &self,
+    bytes: Vec<u8>,
+    expected: Digest,
+  ) -> BoxFuture<Digest, String> {
+    if bytes.len() != expected.1 {
+      return future::err(format!(
+        "Expected {} bytes for {:?}, but got {}",
+        expected.1,
+        expected,
+        bytes.len()
+      )).to_boxed();
+    }
+    if cfg!(debug_assertions) {
+      let store = self.clone();
+      return self
+        .spawn(move || {
+          let actual = fingerprint_of(&bytes);
+          if actual != expected.0 {
+            return Err(format!(
+              "Expected bytes to have fingerprint {}, but they had fingerprint {}",
+              expected.0,
+              actual
+            ));
+          }
+          Ok(bytes)
+        })
+        .and_then(move |bytes| {
+          store
+            .put_file_bytes_coalesced(expected.0, bytes)
+            .map(|()| expected)
+        })
+        .to_boxed();
+    }
+    self
+      .put_file_bytes_coalesced(expected.0, bytes)
+      .map(|()| expected)
+      .to_boxed()
   }
 
-  pub fn load_file_bytes(&self, fingerprint: Fingerprint) -> CpuFuture<Option<Vec<u8>>, String> {
-    self.load_bytes(fingerprint, self.inner.file_store.clone())
+  ///
+  /// Stores the contents of the file at the given path permanently. The file is read once to
+  /// compute its Digest, and again to store it. Fails if the file changed between the two reads.
+  ///
+  /// Files of up to `MAX_BUFFERED_STORE_SIZE` bytes are read into memory for the second read, so
+  /// that they are copied into the database quickly. Larger files are not read into memory, but
+  /// directly into the space reserved for them in the database, which means that the second read
+  /// happens inside a write transaction: since LMDB allows one writer at a time, every other write
+  /// to the Store waits for it.
+  ///
+  /// Files stored this way are stored densely, even if `compact_zero_runs` is set.
+  ///
+  pub fn store_file_from_path(&self, path: &Path) -> BoxFuture<Digest, String> {
+    self.store_file_from_path_impl(path.to_owned(), false)
   }
 
-  pub fn load_file_bytes_with<T: Send + 'static, F: FnOnce(&[u8]) -> T + Send + 'static>(
-    &self,
-    fingerprint: Fingerprint,
-    f: F,
-  ) -> CpuFuture<Option<T>, String> {
-    self.load_bytes_with(fingerprint, self.inner.file_store.clone(), f)
+  ///
+  /// As for `store_file_from_path`, but removes the file once it has been stored. This is the
+  /// natural way for a tool which writes its output to a temporary file to move that output into
+  /// the Store. If storing fails, the file is left in place.
+  ///
+  pub fn promote_file(&self, path: &Path) -> BoxFuture<Digest, String> {
+    self.store_file_from_path_impl(path.to_owned(), true)
   }
 
-  pub fn load_directory_proto_bytes(
-    &self,
-    fingerprint: Fingerprint,
-  ) -> CpuFuture<Option<Vec<u8>>, String> {
-    self.load_bytes(fingerprint, self.inner.directory_store.clone())
+  ///
+  /// Returns a FileWriter, which stores a file whose contents arrive in chunks, reading each chunk
+  /// only once. See `FileWriter`.
+  ///
+  pub fn file_writer(&self) -> FileWriter {
+    FileWriter {
+      store: self.clone(),
+      hasher: Sha256::default(),
+      len: 0,
+      spool: Spool::Memory(Vec::new()),
+    }
   }
 
-  pub fn load_directory_proto(
-    &self,
-    fingerprint: Fingerprint,
-  ) -> BoxFuture<Option<bazel_protos::remote_execution::Directory>, String> {
-    self
-      .load_directory_proto_bytes(fingerprint)
-      .and_then(move |res| match res {
-        Some(bytes) => {
-          let mut proto = bazel_protos::remote_execution::Directory::new();
-          proto
-            .merge_from_bytes(&bytes)
-            .map_err(|e| {
-              format!("Error deserializing proto {}: {}", fingerprint, e)
-            })
-            .and(Ok(Some(proto)))
+  fn store_file_from_path_impl(&self, path: PathBuf, remove: bool) -> BoxFuture<Digest, String> {
+    let store = self.inner.clone();
+    self.spawn(move || {
+      let open = || {
+        fs::File::open(&path).map_err(|e| format!("Error opening {:?}: {}", path, e))
+      };
+      let mut hasher = WriterHasher::new(io::sink());
+      let len = io::copy(&mut BufReader::new(open()?), &mut hasher)
+        .map_err(|e| format!("Error reading {:?}: {}", path, e))? as usize;
+      let fingerprint = hasher.finish();
+      let changed_err = || format!("File {:?} changed while it was being stored", path);
+
+      // Read small files again before taking the write lock, unless they are already stored.
+      let contents = if len <= MAX_BUFFERED_STORE_SIZE &&
+        StoreReader::new(&store)?
+          .load_file_bytes_with(fingerprint, |_| ())?
+          .is_none()
+      {
+        let mut bytes = Vec::with_capacity(len);
+        open()?.read_to_end(&mut bytes).map_err(|e| {
+          format!("Error reading {:?}: {}", path, e)
+        })?;
+        if bytes.len() != len || fingerprint_of(&bytes) != fingerprint {
+          return Err(changed_err());
         }
-        None => Ok(None),
-      })
-      .to_boxed()
-  }
+        Some(bytes)
+      } else {
+        None
+      };
 
-  fn load_bytes(
-    &self,
-    fingerprint: Fingerprint,
-    db: Database,
-  ) -> CpuFuture<Option<Vec<u8>>, String> {
-    self.load_bytes_with(fingerprint, db, |bytes| Vec::from(bytes))
-  }
+      put_file_reserved(&store, fingerprint, len, |buffer| match contents {
+        Some(ref bytes) => {
+          buffer.copy_from_slice(bytes);
+          Ok(())
+        }
+        None => {
+          // Keep this critical section to the read itself: everything else is done outside it.
+          let mut file = open()?;
+          let mut trailing = [0; 1];
+          let unchanged = file.read_exact(buffer).is_ok() &&
+            file.read(&mut trailing).ok() == Some(0) &&
+            fingerprint_of(buffer) == fingerprint;
+          if unchanged {
+            Ok(())
+          } else {
+            Err(changed_err())
+          }
+        }
+      })?;
 
-  fn load_bytes_with<T: Send + 'static, F: FnOnce(&[u8]) -> T + Send + 'static>(
-    &self,
-    fingerprint: Fingerprint,
-    db: Database,
-    f: F,
-  ) -> CpuFuture<Option<T>, String> {
-    let store = self.inner.clone();
-    self.inner.pool.spawn_fn(move || {
-      let ro_txn = store.env.begin_ro_txn().map_err(|err| {
-        format!(
-          "Failed to begin read transaction: {}",
-          err.description().to_string()
-        )
-      });
-      ro_txn.and_then(|txn| match txn.get(db, &fingerprint) {
-        Ok(bytes) => Ok(Some(f(bytes))),
-        Err(NotFound) => Ok(None),
-        Err(err) => Err(format!(
-          "Error loading fingerprint {}: {}",
-          fingerprint,
-          err.description().to_string()
-        )),
-      })
+      if remove {
+        fs::remove_file(&path).map_err(|e| {
+          format!("Stored {:?}, but failed to remove it: {}", path, e)
+        })?;
+      }
+      Ok(Digest(fingerprint, len))
     })
   }
 
   ///
-  /// Store the Directory proto. Does not do anything about the files or directories claimed to be
-  /// contained therein.
-  ///
-  /// Assumes that the directory has been properly canonicalized.
+  /// Store the given file bytes such that they will be removed by the first call to `expire` after
+  /// the TTL has elapsed. See `put_file_bytes` for how this interacts with existing copies.
   ///
-  pub fn record_directory(
+  pub fn store_file_bytes_with_ttl(
     &self,
-    directory: &bazel_protos::remote_execution::Directory,
+    bytes: Vec<u8>,
+    ttl: Duration,
   ) -> BoxFuture<Digest, String> {
+    let len = bytes.len();
+    let expiry = seconds_since_epoch(SystemTime::now() + ttl);
     let store = self.clone();
-    future::result(directory.write_to_bytes().map_err(|e| {
-      format!(
-        "Error serializing directory proto {:?}: {}",
-        directory,
-        e.description()
-      )
-    })).and_then(move |bytes| {
-      let len = bytes.len();
-
+    self.spawn(move || {
+      let fingerprint = fingerprint_of(&bytes);
       store
-        .store_bytes(bytes, store.inner.directory_store.clone())
-        .map(move |fingerprint| Digest(fingerprint, len))
+        .put_file_bytes(fingerprint, &bytes, Some(expiry))
+        .map(|()| Digest(fingerprint, len))
     })
-      .to_boxed()
   }
-}
 
-///
-/// A Digest is a fingerprint, as well as the size in bytes of the plaintext for which that is the
-/// fingerprint.
-///
-/// It is equivalent to a Bazel Remote Execution Digest, but without the overhead (and awkward API)
-/// of needing to create an entire protobuf to pass around the two fields.
-///
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub struct Digest(pub Fingerprint, pub usize);
+  ///
+  /// Removes all files whose expiry is at or before `now`, as well as any Directories which
+  /// (transitively) reference them, since those could no longer be materialized. Returns the total
+  /// number of files and Directories removed.
+  ///
+  /// Finding the affected Directories requires scanning every stored Directory, so this is
+  /// intended to be run periodically rather than frequently.
+  ///
+  pub fn expire(&self, now: SystemTime) -> BoxFuture<usize, String> {
+    let now = seconds_since_epoch(now);
+    let store = self.inner.clone();
+    self.spawn(move || {
+      let store_err = |err: lmdb::Error| format!("Error expiring files: {}", err.description());
+      let mut txn = store.env.begin_rw_txn().map_err(&store_err)?;
+      let expired = {
+        let mut cursor = txn.open_ro_cursor(store.expiry_store).map_err(&store_err)?;
+        cursor
+          .iter_start()
+          .filter(|&(_, expiry)| u64_from_bytes(expiry) <= now)
+          .map(|(key, _)| Fingerprint::from_bytes_unsafe(key))
+          .collect::<Vec<_>>()
+      };
+      if expired.is_empty() {
+        return Ok(0);
+      }
+      // The Digests of the blobs which were actually removed, for the eviction callbacks.
+      let mut evicted = Vec::new();
+      for fingerprint in &expired {
+        let size = match txn.get(store.file_store, fingerprint) {
+          Ok(bytes) => Some(bytes.len()),
+          Err(NotFound) => {
+            match txn.get(store.sparse_file_store, fingerprint) {
+              Ok(bytes) => Some(decoded_len(bytes)?),
+              Err(NotFound) => None,
+              Err(err) => return Err(store_err(err)),
+            }
+          }
+          Err(err) => return Err(store_err(err)),
+        };
+        if let Some(size) = size {
+          evicted.push(Digest(*fingerprint, size));
+        }
+        for &db in &[store.file_store, store.sparse_file_store, store.expiry_store] {
+          match txn.del(db, fingerprint, None) {
+            Ok(()) | Err(NotFound) => {}
+            Err(err) => return Err(store_err(err)),
+          }
+        }
+      }
 
-impl Into<bazel_protos::remote_execution::Digest> for Digest {
-  fn into(self) -> bazel_protos::remote_execution::Digest {
-    let mut digest = bazel_protos::remote_execution::Digest::new();
-    digest.set_hash(self.0.to_hex());
-    digest.set_size_bytes(self.1 as i64);
-    digest
+      // Repeatedly remove Directories which reference removed blobs, until none remain.
+      let mut removed = expired.into_iter().collect::<HashSet<_>>();
+      let mut expired_count = removed.len();
+      let mut remaining = {
+        let mut cursor = txn.open_ro_cursor(store.directory_store).map_err(&store_err)?;
+        cursor
+          .iter_start()
+          .map(|(key, bytes)| {
+            let fingerprint = Fingerprint::from_bytes_unsafe(key);
+            (
+              fingerprint,
+              bytes.len(),
+              referenced_fingerprints(fingerprint, bytes),
+            )
+          })
+          .collect::<Vec<_>>()
+      };
+      loop {
+        let (invalid, valid): (Vec<_>, Vec<_>) =
+          remaining.into_iter().partition(|&(_, _, ref references)| {
+            references.iter().any(|r| removed.contains(r))
+          });
+        if invalid.is_empty() {
+          break;
+        }
+        for (fingerprint, size, _) in invalid {
+          txn.del(store.directory_store, &fingerprint, None).map_err(
+            &store_err,
+          )?;
+          removed.insert(fingerprint);
+          evicted.push(Digest(fingerprint, size));
+          expired_count += 1;
+        }
+        remaining = valid;
+      }
+
+      // Prune the additions of everything removed, so that the log only grows with what is
+      // stored. The last addition is kept regardless, since its sequence number is the one that
+      // the next addition follows: reusing it could hide that addition from followers (see
+      // `stream_entries`).
+      let pruned = {
+        let mut cursor = txn.open_ro_cursor(store.addition_store).map_err(&store_err)?;
+        let mut additions = cursor
+          .iter_start()
+          .map(|(key, value)| (key.to_vec(), Fingerprint::from_bytes_unsafe(&value[1..])))
+          .collect::<Vec<_>>();
+        additions.pop();
+        additions
+          .into_iter()
+          .filter(|&(_, ref fingerprint)| removed.contains(fingerprint))
+          .map(|(key, _)| key)
+          .collect::<Vec<_>>()
+      };
+      for key in pruned {
+        txn.del(store.addition_store, &key, None).map_err(&store_err)?;
+      }
+      txn.commit().map_err(&store_err)?;
+
+      // Only invalidate caches and notify once the removals are durable, so that neither a failed
+      // commit nor a callback ever observes an eviction which was rolled back.
+      for digest in &evicted {
+        store.directory_cache.lock().unwrap().remove(&digest.0);
+        store.child_digest_cache.lock().unwrap().remove(&digest.0);
+        store.manifest_cache.lock().unwrap().remove(&digest.0);
+      }
+      let callbacks = store.evict_callbacks.lock().unwrap().clone();
+      for digest in evicted {
+        for callback in &callbacks {
+          callback(digest.clone());
+        }
+      }
+      Ok(expired_count)
+    })
+  }
+
+  ///
+  /// Registers a callback to be called with the Digest of each file or Directory which this Store
+  /// removes (currently, by `expire`), so that an external index of the Store's contents can be
+  /// kept in sync. Callbacks are called on the Store's pool, after the removal has been committed.
+  ///
+  pub fn on_evict(&self, callback: EvictCallback) {
+    self.inner.evict_callbacks.lock().unwrap().push(callback);
+  }
+
+  ///
+  /// Returns the Digests of the files and Directories which a garbage collection keeping only what
+  /// is reachable from `roots` would remove, sorted, without removing anything. Roots may be the
+  /// Digests of files or of Directories, whose contents are (transitively) reachable.
+  ///
+  /// Each Digest's size is that of the (decoded) blob, so their sum is the number of bytes which
+  /// the collection would free.
+  ///
+  pub fn garbage_collect_dry_run(&self, roots: Vec<Digest>) -> BoxFuture<Vec<Digest>, String> {
+    let store = self.inner.clone();
+    self.spawn(move || {
+      let reader = StoreReader::new(&store)?;
+
+      // Mark everything reachable from the roots.
+      let mut reachable = HashSet::new();
+      let mut todo = roots.into_iter().map(|digest| digest.0).collect::<Vec<_>>();
+      while let Some(fingerprint) = todo.pop() {
+        if !reachable.insert(fingerprint) {
+          continue;
+        }
+        if let Some(bytes) = reader.get(fingerprint, store.directory_store)? {
+          todo.extend(referenced_fingerprints(fingerprint, bytes));
+        }
+      }
+
+      // Find everything else, without sweeping it.
+      let mut unreachable = Vec::new();
+      for &(name, db) in &[
+        ("files", store.file_store),
+        ("sparse_files", store.sparse_file_store),
+        ("directories", store.directory_store),
+      ]
+      {
+        let mut cursor = reader.txn.open_ro_cursor(db).map_err(|err| {
+          format!("Error listing {}: {}", name, err.description())
+        })?;
+        for (key, bytes) in cursor.iter_start() {
+          let fingerprint = Fingerprint::from_bytes_unsafe(key);
+          if reachable.contains(&fingerprint) {
+            continue;
+          }
+          let size = if name == "sparse_files" {
+            decoded_len(bytes)?
+          } else {
+            bytes.len()
+          };
+          unreachable.push(Digest(fingerprint, size));
+        }
+      }
+      unreachable.sort();
+      Ok(unreachable)
+    })
+  }
+
+  ///
+  /// Runs the future returned by `f` while holding an exclusive advisory lock on the Store's
+  /// directory, waiting (on the Store's pool) until no other holder of the lock, shared or
+  /// exclusive, remains in this or any other process using the directory.
+  ///
+  /// LMDB keeps each transaction isolated, but some operations depend on what an earlier
+  /// transaction observed, and so must not interleave with removals made by another process:
+  ///  * Removals, namely `expire` and any sweep of what `garbage_collect_dry_run` reports, should
+  ///    hold this lock, so that concurrent collections do not act on each other's stale view of
+  ///    which blobs are referenced.
+  ///  * Writers which skip storing a blob because it is already present (as `store_file_bytes`
+  ///    does for concurrent stores of the same content), or which record Directories referencing
+  ///    blobs stored earlier, should hold `with_shared_gc_lock` until everything referencing those
+  ///    blobs has been recorded.
+  ///
+  /// Reads need no lock. The lock is released when the future completes, successfully or not.
+  ///
+  pub fn with_exclusive_gc_lock<T, F>(&self, f: F) -> BoxFuture<T, String>
+  where
+    T: Send + 'static,
+    F: FnOnce() -> BoxFuture<T, String> + Send + 'static,
+  {
+    self.with_gc_lock(true, f)
+  }
+
+  ///
+  /// As for `with_exclusive_gc_lock`, but with a shared lock, which may be held by many writers at
+  /// once, but excludes removals. See `with_exclusive_gc_lock` for which operations need it.
+  ///
+  pub fn with_shared_gc_lock<T, F>(&self, f: F) -> BoxFuture<T, String>
+  where
+    T: Send + 'static,
+    F: FnOnce() -> BoxFuture<T, String> + Send + 'static,
+  {
+    self.with_gc_lock(false, f)
+  }
+
+  fn with_gc_lock<T, F>(&self, exclusive: bool, f: F) -> BoxFuture<T, String>
+  where
+    T: Send + 'static,
+    F: FnOnce() -> BoxFuture<T, String> + Send + 'static,
+  {
+    let store = self.inner.clone();
+    self
+      .spawn(move || lock_environment(&store.env, exclusive))
+      .and_then(move |lock| {
+        f().then(move |result| {
+          // Closing the lock file releases the lock.
+          mem::drop(lock);
+          result
+        })
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Stores the files of a git-like object directory, in which each file is named by the hex of its
+  /// Fingerprint, split after the first two characters (`ab/cdef...`). Each file's content is
+  /// verified against its name before it is stored, permanently. Returns the number of files
+  /// stored, and the invalid entries which were skipped, sorted by path, with why they were
+  /// invalid.
+  ///
+  /// Unless `skip_invalid` is set, fails on the first entry which is not a correctly named file
+  /// with matching content, although files stored before it remain stored.
+  ///
+  pub fn import_cas(
+    &self,
+    dir: PathBuf,
+    skip_invalid: bool,
+  ) -> BoxFuture<(usize, Vec<(PathBuf, String)>), String> {
+    let store = self.clone();
+    self.spawn(move || {
+      let sorted_entries = |dir: &Path| -> Result<Vec<PathBuf>, String> {
+        let mut paths = fs::read_dir(dir)
+          .and_then(|entries| {
+            entries
+              .map(|entry| entry.map(|entry| entry.path()))
+              .collect::<Result<Vec<_>, _>>()
+          })
+          .map_err(|e| format!("Error listing {:?}: {}", dir, e))?;
+        paths.sort();
+        Ok(paths)
+      };
+
+      let mut imported = 0;
+      let mut invalid = Vec::new();
+      {
+        let mut import = |path: &Path, hex: Option<String>| -> Result<(), String> {
+          let res = match hex {
+            Some(hex) => store.import_cas_object(path, &hex),
+            None => Err("Not named like a CAS object".to_owned()),
+          };
+          match res {
+            Ok(()) => {
+              imported += 1;
+              Ok(())
+            }
+            Err(err) => {
+              if !skip_invalid {
+                return Err(format!("Invalid CAS object {:?}: {}", path, err));
+              }
+              invalid.push((path.to_owned(), err));
+              Ok(())
+            }
+          }
+        };
+        for shard in sorted_entries(&dir)? {
+          let shard_name = match shard.file_name().and_then(|name| name.to_str()) {
+            Some(name) if name.len() == 2 && shard.is_dir() => name.to_owned(),
+            _ => {
+              import(&shard, None)?;
+              continue;
+            }
+          };
+          for path in sorted_entries(&shard)? {
+            let hex = path.file_name().and_then(|name| name.to_str()).map(
+              |name| {
+                format!("{}{}", shard_name, name)
+              },
+            );
+            import(&path, hex)?;
+          }
+        }
+      }
+      invalid.sort();
+      Ok((imported, invalid))
+    })
+  }
+
+  fn import_cas_object(&self, path: &Path, hex: &str) -> Result<(), String> {
+    let fingerprint = Fingerprint::from_hex_string(hex)?;
+    if fingerprint.to_hex() != hex {
+      return Err(format!("{} is not a lowercase hex fingerprint", hex));
+    }
+    let mut bytes = Vec::new();
+    fs::File::open(path)
+      .and_then(|mut file| file.read_to_end(&mut bytes))
+      .map_err(|e| format!("Error reading: {}", e))?;
+    let actual = fingerprint_of(&bytes);
+    if actual != fingerprint {
+      return Err(format!(
+        "Content has fingerprint {}, which does not match its name",
+        actual
+      ));
+    }
+    self.put_file_bytes(fingerprint, &bytes, None)
+  }
+
+  ///
+  /// Runs the given function on the Store's pool, failing if it does not complete within the
+  /// configured operation timeout (if any).
+  ///
+  /// A timed out operation is abandoned rather than interrupted: if it eventually completes, its
+  /// effects (such as a write) still take place, but its result is discarded.
+  ///
+  fn spawn<T, F>(&self, f: F) -> BoxFuture<T, String>
+  where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, String> + Send + 'static,
+  {
+    let operation = self.inner.pool.spawn_fn(f);
+    let timeout = match self.inner.options.operation_timeout {
+      Some(timeout) => timeout,
+      None => return operation.to_boxed(),
+    };
+
+    operation
+      .select2(timer::timeout(timeout))
+      .then(move |res| match res {
+        Ok(Either::A((value, _))) => Ok(value),
+        Err(Either::A((err, _))) => Err(err),
+        Ok(Either::B(_)) | Err(Either::B(_)) => Err(format!(
+          "Timeout: Store operation did not complete within {:?}",
+          timeout
+        )),
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Records the modification times of files with the given fingerprints at the given paths, so
+  /// that they can later be restored (see `Snapshot::materialize_with_mtimes`). Existing records
+  /// for the same path and fingerprint are replaced.
+  ///
+  pub fn record_mtimes(
+    &self,
+    mtimes: Vec<(PathBuf, Fingerprint, FileTime)>,
+  ) -> BoxFuture<(), String> {
+    let store = self.inner.clone();
+    self.spawn(move || {
+      let mut keyed = Vec::with_capacity(mtimes.len());
+      for (path, fingerprint, mtime) in mtimes {
+        keyed.push((path_key(&path, fingerprint)?, mtime));
+      }
+      store
+        .env
+        .begin_rw_txn()
+        .and_then(|mut txn| {
+          for &(ref key, ref mtime) in &keyed {
+            let mut value = u64_to_bytes(mtime.seconds_relative_to_1970()).to_vec();
+            value.extend_from_slice(&u64_to_bytes(mtime.nanoseconds() as u64));
+            txn.put(store.mtime_store, key, &value, WriteFlags::empty())?;
+          }
+          txn.commit()
+        })
+        .map_err(|err| format!("Error recording mtimes: {}", err.description()))
+    })
+  }
+
+  ///
+  /// Records the permission bits of Directories with the given fingerprints at the given paths, so
+  /// that they can later be restored (see `Snapshot::materialize_with_directory_modes`). Existing
+  /// records for the same path and fingerprint are replaced.
+  ///
+  pub fn record_directory_modes(
+    &self,
+    modes: Vec<(PathBuf, Fingerprint, u32)>,
+  ) -> BoxFuture<(), String> {
+    let store = self.inner.clone();
+    self.spawn(move || {
+      let mut keyed = Vec::with_capacity(modes.len());
+      for (path, fingerprint, mode) in modes {
+        keyed.push((path_key(&path, fingerprint)?, mode));
+      }
+      store
+        .env
+        .begin_rw_txn()
+        .and_then(|mut txn| {
+          for &(ref key, mode) in &keyed {
+            txn.put(
+              store.directory_mode_store,
+              key,
+              &u64_to_bytes(mode as u64),
+              WriteFlags::empty(),
+            )?;
+          }
+          txn.commit()
+        })
+        .map_err(|err| {
+          format!("Error recording directory modes: {}", err.description())
+        })
+    })
+  }
+
+  ///
+  /// Records the exact bytes of names which are not valid UTF8, each keyed by the fingerprint of
+  /// the Directory containing it and the (UTF8-lossy) name which that Directory records, so that
+  /// they can later be restored (see `Snapshot::materialize_with_raw_names`).
+  ///
+  /// Fails without recording anything if a different name has already been recorded for the same
+  /// Directory and lossy name, which happens when Directories differ only in their raw names.
+  ///
+  pub fn record_raw_names(
+    &self,
+    names: Vec<(Fingerprint, String, Vec<u8>)>,
+  ) -> BoxFuture<(), String> {
+    let store = self.inner.clone();
+    self.spawn(move || {
+      let mut keyed = Vec::with_capacity(names.len());
+      for (fingerprint, name, raw) in names {
+        keyed.push((path_key(Path::new(&name), fingerprint)?, fingerprint, name, raw));
+      }
+      let mut txn = store.env.begin_rw_txn().map_err(|err| {
+        format!("Error recording raw names: {}", err.description())
+      })?;
+      for &(ref key, fingerprint, ref name, ref raw) in &keyed {
+        let conflicts = match txn.get(store.raw_name_store, key) {
+          Ok(existing) => Some(existing != &raw[..]),
+          Err(NotFound) => None,
+          Err(err) => {
+            return Err(format!(
+              "Error recording raw names: {}",
+              err.description()
+            ))
+          }
+        };
+        match conflicts {
+          Some(true) => {
+            return Err(format!(
+              "A different raw name has already been recorded for {:?} in Directory {}",
+              name,
+              fingerprint
+            ))
+          }
+          Some(false) => {}
+          None => {
+            txn
+              .put(store.raw_name_store, key, raw, WriteFlags::empty())
+              .map_err(|err| {
+                format!("Error recording raw names: {}", err.description())
+              })?
+          }
+        }
+      }
+      txn.commit().map_err(|err| {
+        format!("Error recording raw names: {}", err.description())
+      })
+    })
+  }
+
+  ///
+  /// Records the content types of files with the given fingerprints, as computed by the classifier
+  /// with the given name, so that they need not be recomputed (see `Snapshot::classify`). Existing
+  /// records for the same fingerprint and classifier are replaced.
+  ///
+  pub fn record_content_types(
+    &self,
+    classifier: String,
+    content_types: Vec<(Fingerprint, String)>,
+  ) -> BoxFuture<(), String> {
+    let store = self.inner.clone();
+    self.spawn(move || {
+      let mut keyed = Vec::with_capacity(content_types.len());
+      for (fingerprint, content_type) in content_types {
+        keyed.push((path_key(Path::new(&classifier), fingerprint)?, content_type));
+      }
+      store
+        .env
+        .begin_rw_txn()
+        .and_then(|mut txn| {
+          for &(ref key, ref content_type) in &keyed {
+            txn.put(
+              store.content_type_store,
+              key,
+              content_type,
+              WriteFlags::empty(),
+            )?;
+          }
+          txn.commit()
+        })
+        .map_err(|err| {
+          format!("Error recording content types: {}", err.description())
+        })
+    })
+  }
+
+  fn store_directory_bytes(&self, bytes: Vec<u8>) -> BoxFuture<Fingerprint, String> {
+    let store = self.clone();
+    self.spawn(move || {
+      let fingerprint = fingerprint_of(&bytes);
+      store.put_directory_bytes(fingerprint, &bytes).map(|()| fingerprint)
+    })
+  }
+
+  ///
+  /// Stores file bytes permanently, or if a store of the same file is already in progress, waits
+  /// for that store to complete instead.
+  ///
+  fn put_file_bytes_coalesced(
+    &self,
+    fingerprint: Fingerprint,
+    bytes: Vec<u8>,
+  ) -> BoxFuture<(), String> {
+    if self.inner.options.check_collisions {
+      // Coalescing would skip checking the bytes of all but one of the concurrent stores.
+      let store = self.clone();
+      return self.spawn(move || store.put_file_bytes(fingerprint, &bytes, None));
+    }
+    let mut in_flight = self.inner.in_flight_file_stores.lock().unwrap();
+    let existing = in_flight.get(&fingerprint).cloned();
+    let write = match existing {
+      Some(write) => write,
+      None => {
+        // The write removes itself from the in-flight map once it has completed, which it cannot
+        // do before it is inserted below, because we hold the lock until then. It is removed when
+        // the future completes rather than when the pool runs the write, because if the write
+        // times out it might still be running, or might never run at all.
+        let store = self.clone();
+        let inner = self.inner.clone();
+        let write = self
+          .spawn(move || store.put_file_bytes(fingerprint, &bytes, None))
+          .then(move |res| {
+            inner.in_flight_file_stores.lock().unwrap().remove(
+              &fingerprint,
+            );
+            res
+          })
+          .to_boxed()
+          .shared();
+        in_flight.insert(fingerprint, write.clone());
+        write
+      }
+    };
+    write
+      .map(|_| ())
+      .map_err(|err| (*err).clone())
+      .to_boxed()
+  }
+
+  ///
+  /// Stores file bytes, and updates their expiry: an expiry of None makes the file permanent.
+  ///
+  /// A file which was already stored permanently stays permanent, and an existing expiry is only
+  /// ever extended, so that storing a file with a TTL never causes a copy which someone else stored
+  /// to expire sooner than they asked for.
+  ///
+  fn put_file_bytes(
+    &self,
+    fingerprint: Fingerprint,
+    bytes: &[u8],
+    expiry: Option<u64>,
+  ) -> Result<(), String> {
+    let encoded = if self.inner.options.compact_zero_runs {
+      encode_zero_runs(bytes)
+    } else {
+      None
+    };
+    let file_store = self.inner.file_store;
+    let sparse_file_store = self.inner.sparse_file_store;
+    let expiry_store = self.inner.expiry_store;
+    let addition_store = self.inner.addition_store;
+    let put_res = self.inner.env.begin_rw_txn().and_then(|mut txn| {
+      let existed = match encoded {
+        Some(ref encoded) => {
+          // If the file was already stored densely, don't store it again.
+          let dense_exists = match txn.get(file_store, &fingerprint) {
+            Ok(_) => true,
+            Err(NotFound) => false,
+            Err(err) => return Err(err),
+          };
+          dense_exists || put_if_absent(&mut txn, sparse_file_store, &fingerprint, encoded)?
+        }
+        None => put_if_absent(&mut txn, file_store, &fingerprint, bytes)?,
+      };
+      if !existed {
+        record_addition(&mut txn, addition_store, ADDED_FILE, &fingerprint)?;
+      }
+
+      match expiry {
+        None => {
+          match txn.del(expiry_store, &fingerprint, None) {
+            Ok(()) | Err(NotFound) => {}
+            Err(err) => return Err(err),
+          }
+        }
+        Some(expiry) => {
+          let current = match txn.get(expiry_store, &fingerprint) {
+            Ok(bytes) => Some(u64_from_bytes(bytes)),
+            Err(NotFound) => None,
+            Err(err) => return Err(err),
+          };
+          let updated = match current {
+            None if existed => None,
+            None => Some(expiry),
+            Some(current) => Some(max(current, expiry)),
+          };
+          if let Some(updated) = updated {
+            txn.put(
+              expiry_store,
+              &fingerprint,
+              &u64_to_bytes(updated),
+              WriteFlags::empty(),
+            )?;
+          }
+        }
+      }
+      txn.commit().map(|()| existed)
+    });
+
+    let existed = put_res.map_err(|err| {
+      format!(
+        "Error storing fingerprint {}: {}",
+        fingerprint,
+        err.description()
+      )
+    })?;
+    if existed && self.inner.options.check_collisions {
+      let matches = StoreReader::new(&self.inner)?.load_file_bytes_with(
+        fingerprint,
+        |stored| stored == bytes,
+      )?;
+      check_collision(fingerprint, matches)?;
+    }
+    Ok(())
+  }
+
+  fn put_raw_name(&self, fingerprint: Fingerprint, name: &str, raw: &[u8]) -> Result<(), String> {
+    let key = path_key(Path::new(name), fingerprint)?;
+    let raw_name_store = self.inner.raw_name_store;
+    self
+      .inner
+      .env
+      .begin_rw_txn()
+      .and_then(|mut txn| {
+        txn.put(raw_name_store, &key, &raw, WriteFlags::empty())?;
+        txn.commit()
+      })
+      .map_err(|err| {
+        format!(
+          "Error recording raw name for {:?} in Directory {}: {}",
+          name,
+          fingerprint,
+          err.description()
+        )
+      })
+  }
+
+  fn put_directory_bytes(&self, fingerprint: Fingerprint, bytes: &[u8]) -> Result<(), String> {
+    let db = self.inner.directory_store;
+    let addition_store = self.inner.addition_store;
+    let put_res = self.inner.env.begin_rw_txn().and_then(|mut txn| {
+      txn.put(db, &fingerprint, &bytes, NO_OVERWRITE)?;
+      record_addition(&mut txn, addition_store, ADDED_DIRECTORY, &fingerprint)?;
+      txn.commit()
+    });
+
+    match put_res {
+      Ok(()) => Ok(()),
+      Err(KeyExist) if self.inner.options.check_collisions => {
+        let matches = StoreReader::new(&self.inner)?.load_bytes_with(
+          fingerprint,
+          db,
+          |stored| stored == bytes,
+        )?;
+        check_collision(fingerprint, matches)
+      }
+      Err(KeyExist) => Ok(()),
+      Err(err) => Err(format!(
+        "Error storing fingerprint {}: {}",
+        fingerprint,
+        err.description()
+      )),
+    }
+  }
+
+  pub fn load_file_bytes(&self, fingerprint: Fingerprint) -> BoxFuture<Option<Vec<u8>>, String> {
+    self.load_file_bytes_with(fingerprint, |bytes| Vec::from(bytes))
+  }
+
+  pub fn load_file_bytes_with<T: Send + 'static, F: FnOnce(&[u8]) -> T + Send + 'static>(
+    &self,
+    fingerprint: Fingerprint,
+    f: F,
+  ) -> BoxFuture<Option<T>, String> {
+    self.read_batch(move |reader| reader.load_file_bytes_with(fingerprint, f))
+  }
+
+  pub fn load_directory_proto_bytes(
+    &self,
+    fingerprint: Fingerprint,
+  ) -> BoxFuture<Option<Vec<u8>>, String> {
+    self.load_bytes(fingerprint, self.inner.directory_store.clone())
+  }
+
+  pub fn load_directory_proto(
+    &self,
+    fingerprint: Fingerprint,
+  ) -> BoxFuture<Option<bazel_protos::remote_execution::Directory>, String> {
+    if let Some(directory) = self.inner.cached_directory(fingerprint) {
+      return future::ok(Some(directory)).to_boxed();
+    }
+    self.read_batch(move |reader| reader.load_directory_proto(fingerprint))
+  }
+
+  ///
+  /// Returns the cached manifest of the tree whose root Directory has the given Fingerprint, if
+  /// there is one. See `StoreOptions::manifest_cache_size`.
+  ///
+  pub fn cached_manifest(&self, root: Fingerprint) -> Option<Arc<Vec<(PathBuf, Digest, bool)>>> {
+    self.inner.manifest_cache.lock().unwrap().get(&root).cloned()
+  }
+
+  ///
+  /// Caches (a copy of) the manifest of the tree whose root Directory has the given Fingerprint, if
+  /// the manifest cache is enabled.
+  ///
+  pub fn cache_manifest(&self, root: Fingerprint, manifest: &[(PathBuf, Digest, bool)]) {
+    if self.inner.options.manifest_cache_size > 0 {
+      self.inner.manifest_cache.lock().unwrap().insert(
+        root,
+        Arc::new(manifest.to_vec()),
+      );
+    }
+  }
+
+  ///
+  /// Empties the manifest cache, for example to release its memory after a burst of work.
+  ///
+  pub fn clear_manifest_cache(&self) {
+    let mut cache = self.inner.manifest_cache.lock().unwrap();
+    *cache = LruCache::new(self.inner.options.manifest_cache_size);
+  }
+
+  ///
+  /// Runs the given function with a StoreReader which shares a single read transaction between
+  /// all of the loads it performs.
+  ///
+  /// Each of the load_* methods on Store begins its own transaction, which dominates the cost of
+  /// small reads when many of them are made in a row. Callers which know that they will make many
+  /// reads (for example, when walking a tree of Directories) should batch them here instead.
+  ///
+  pub fn read_batch<T, F>(&self, f: F) -> BoxFuture<T, String>
+  where
+    T: Send + 'static,
+    F: FnOnce(&StoreReader) -> Result<T, String> + Send + 'static,
+  {
+    let store = self.inner.clone();
+    self.spawn(move || {
+      let reader = StoreReader::new(&store)?;
+      f(&reader)
+    })
+  }
+
+  fn load_bytes(
+    &self,
+    fingerprint: Fingerprint,
+    db: Database,
+  ) -> BoxFuture<Option<Vec<u8>>, String> {
+    self.load_bytes_with(fingerprint, db, |bytes| Vec::from(bytes))
+  }
+
+  fn load_bytes_with<T: Send + 'static, F: FnOnce(&[u8]) -> T + Send + 'static>(
+    &self,
+    fingerprint: Fingerprint,
+    db: Database,
+    f: F,
+  ) -> BoxFuture<Option<T>, String> {
+    let store = self.inner.clone();
+    self.spawn(move || {
+      let reader = StoreReader::new(&store)?;
+      reader.load_bytes_with(fingerprint, db, f)
+    })
+  }
+
+  ///
+  /// Returns the Cursor of the last blob added to this Store so far, for use with
+  /// `stream_entries`.
+  ///
+  pub fn cursor(&self) -> BoxFuture<Cursor, String> {
+    let store = self.inner.clone();
+    self.spawn(move || {
+      let reader = StoreReader::new(&store)?;
+      let cursor = reader.txn.open_ro_cursor(store.addition_store).map_err(
+        |err| {
+          format!("Error reading additions: {}", err.description())
+        },
+      )?;
+      match cursor.get(None, None, ffi::MDB_LAST) {
+        Ok((Some(key), _)) => Ok(Cursor(u64_from_bytes(key))),
+        Ok((None, _)) | Err(NotFound) => Ok(Cursor(0)),
+        Err(err) => Err(format!("Error reading additions: {}", err.description())),
+      }
+    })
+  }
+
+  ///
+  /// Streams the Digest and contents of each file and Directory in this Store, for replication to
+  /// a follower. With a Cursor, only the blobs added after it are streamed, in the order they were
+  /// added, so that a follower can sync incrementally: it should take the `cursor` before
+  /// streaming, and persist it once everything streamed has been ingested. Since blobs are
+  /// immutable, a blob which is streamed again (for example, because it was added while the
+  /// previous sync was streaming) is harmless.
+  ///
+  /// Without a Cursor, everything currently stored is streamed, including blobs stored before
+  /// additions were logged. Blobs which are removed (by `expire`) before they are streamed are
+  /// skipped. Files are streamed densely, as they are loaded.
+  ///
+  pub fn stream_entries(&self, since: Option<Cursor>) -> BoxStream<(Digest, Vec<u8>), String> {
+    let store = self.inner.clone();
+    let loader = self.clone();
+    let entries = self.spawn(move || {
+      let list_err = |err: lmdb::Error| format!("Error listing entries: {}", err.description());
+      let reader = StoreReader::new(&store)?;
+      let mut entries = Vec::new();
+      match since {
+        None => {
+          for &(kind, db) in &[
+            (ADDED_FILE, store.file_store),
+            (ADDED_FILE, store.sparse_file_store),
+            (ADDED_DIRECTORY, store.directory_store),
+          ]
+          {
+            let mut cursor = reader.txn.open_ro_cursor(db).map_err(&list_err)?;
+            for (key, _) in cursor.iter_start() {
+              entries.push((kind, Fingerprint::from_bytes_unsafe(key)));
+            }
+          }
+        }
+        Some(Cursor(since)) => {
+          let cursor = reader.txn.open_ro_cursor(store.addition_store).map_err(
+            &list_err,
+          )?;
+          let mut next = cursor.get(Some(&u64_to_bytes(since + 1)), None, ffi::MDB_SET_RANGE);
+          loop {
+            match next {
+              Ok((_, value)) => {
+                entries.push((value[0], Fingerprint::from_bytes_unsafe(&value[1..])))
+              }
+              Err(NotFound) => break,
+              Err(err) => return Err(list_err(err)),
+            }
+            next = cursor.get(None, None, ffi::MDB_NEXT);
+          }
+        }
+      }
+      Ok(entries)
+    });
+    Box::new(
+      entries
+        .map(|entries| stream::iter_ok::<_, String>(entries))
+        .flatten_stream()
+        .and_then(move |(kind, fingerprint)| {
+          let load = if kind == ADDED_DIRECTORY {
+            loader.load_directory_proto_bytes(fingerprint)
+          } else {
+            loader.load_file_bytes(fingerprint)
+          };
+          load.map(move |maybe_bytes| {
+            maybe_bytes.map(|bytes| (Digest(fingerprint, bytes.len()), bytes))
+          })
+        })
+        .filter_map(|entry| entry),
+    )
+  }
+
+  ///
+  /// Writes every file and Directory in this Store to a single file at `out`, which
+  /// `Store::restore` can turn back into a Store (on any machine, regardless of LMDB's version or
+  /// page size). Returns the number of entries written.
+  ///
+  /// The backup contains a header, followed by one entry per file or Directory: a tag byte, the
+  /// fingerprint, the length of the contents as a big-endian u64, and then the contents. Files are
+  /// always written densely. These are followed by one entry per raw name recorded by
+  /// `Snapshot::from_path_stats_preserving_names`: a tag byte, the fingerprint of the Directory
+  /// containing the name, and then the lossy name and the raw name, each preceded by its length as
+  /// a big-endian u64.
+  ///
+  /// Expiry times, modification times, directory modes and content classifications (see
+  /// `Snapshot::classify`) are not included, so restored files never expire, and are classified
+  /// again when next asked for.
+  ///
+  pub fn backup(&self, out: PathBuf) -> BoxFuture<usize, String> {
+    let store = self.inner.clone();
+    self.spawn(move || {
+      let reader = StoreReader::new(&store)?;
+      let write_err = |e: io::Error| format!("Error writing backup {:?}: {}", out, e);
+      let mut writer = BufWriter::new(fs::File::create(&out).map_err(&write_err)?);
+      writer.write_all(BACKUP_MAGIC).map_err(&write_err)?;
+      writer
+        .write_all(&u64_to_bytes(BACKUP_FORMAT_VERSION))
+        .map_err(&write_err)?;
+
+      let mut count = 0;
+      let databases = [
+        (BACKUP_FILE_ENTRY, store.file_store, false),
+        (BACKUP_FILE_ENTRY, store.sparse_file_store, true),
+        (BACKUP_DIRECTORY_ENTRY, store.directory_store, false),
+      ];
+      for &(tag, db, is_sparse) in &databases {
+        let mut cursor = reader.txn.open_ro_cursor(db).map_err(|e| {
+          format!("Error reading store for backup: {}", e.description())
+        })?;
+        for (key, bytes) in cursor.iter_start() {
+          let fingerprint = Fingerprint::from_bytes_unsafe(key);
+          let decoded;
+          let bytes = if is_sparse {
+            decoded = decode_zero_runs(bytes).map_err(|e| {
+              format!("Error decoding fingerprint {}: {}", fingerprint, e)
+            })?;
+            &decoded[..]
+          } else {
+            bytes
+          };
+          writer.write_all(&[tag]).map_err(&write_err)?;
+          writer.write_all(fingerprint.as_bytes()).map_err(&write_err)?;
+          writer
+            .write_all(&u64_to_bytes(bytes.len() as u64))
+            .map_err(&write_err)?;
+          writer.write_all(bytes).map_err(&write_err)?;
+          count += 1;
+        }
+      }
+
+      let mut cursor = reader.txn.open_ro_cursor(store.raw_name_store).map_err(|e| {
+        format!("Error reading store for backup: {}", e.description())
+      })?;
+      for (key, raw) in cursor.iter_start() {
+        // Keyed by the Directory's fingerprint followed by the lossy name (see `path_key`).
+        let (fingerprint, name) = key.split_at(32);
+        writer.write_all(&[BACKUP_RAW_NAME_ENTRY]).map_err(&write_err)?;
+        writer.write_all(fingerprint).map_err(&write_err)?;
+        for bytes in &[name, raw] {
+          writer
+            .write_all(&u64_to_bytes(bytes.len() as u64))
+            .map_err(&write_err)?;
+          writer.write_all(bytes).map_err(&write_err)?;
+        }
+        count += 1;
+      }
+      writer.flush().map_err(&write_err)?;
+      Ok(count)
+    })
+  }
+
+  ///
+  /// Creates a Store at `path` containing the entries of a backup written by `Store::backup`. Each
+  /// entry is verified against its fingerprint as it is restored.
+  ///
+  pub fn restore<P: AsRef<Path>>(
+    backup: PathBuf,
+    path: P,
+    pool: Arc<ResettablePool>,
+    options: StoreOptions,
+  ) -> BoxFuture<Store, String> {
+    let store = match Store::new_with_options(path, pool, options) {
+      Ok(store) => store,
+      Err(err) => return future::err(err).to_boxed(),
+    };
+    let restoring = store.clone();
+    store
+      .spawn(move || {
+        let read_err = |e: io::Error| format!("Error reading backup {:?}: {}", backup, e);
+        let mut reader = BufReader::new(fs::File::open(&backup).map_err(&read_err)?);
+        let mut magic = vec![0; BACKUP_MAGIC.len()];
+        reader.read_exact(&mut magic).map_err(&read_err)?;
+        if magic != BACKUP_MAGIC {
+          return Err(format!("{:?} is not a store backup", backup));
+        }
+        let mut version = [0; 8];
+        reader.read_exact(&mut version).map_err(&read_err)?;
+        let version = u64_from_bytes(&version);
+        if version > BACKUP_FORMAT_VERSION {
+          return Err(format!(
+            "Backup {:?} has format version {}, but only versions up to {} are supported",
+            backup,
+            version,
+            BACKUP_FORMAT_VERSION
+          ));
+        }
+
+        loop {
+          let mut tag = [0; 1];
+          if reader.read(&mut tag).map_err(&read_err)? == 0 {
+            return Ok(());
+          }
+          let mut fingerprint = [0; 32];
+          reader.read_exact(&mut fingerprint).map_err(&read_err)?;
+          let fingerprint = Fingerprint(fingerprint);
+          if tag[0] == BACKUP_RAW_NAME_ENTRY {
+            let name = read_length_prefixed(&mut reader).map_err(&read_err)?;
+            let raw = read_length_prefixed(&mut reader).map_err(&read_err)?;
+            let name = String::from_utf8(name).map_err(|_| {
+              format!(
+                "Backup {:?} is corrupt: a name in Directory {} is not valid UTF8",
+                backup,
+                fingerprint
+              )
+            })?;
+            restoring.put_raw_name(fingerprint, &name, &raw)?;
+            continue;
+          }
+          let mut len = [0; 8];
+          reader.read_exact(&mut len).map_err(&read_err)?;
+          let mut bytes = Vec::new();
+          reader
+            .by_ref()
+            .take(u64_from_bytes(&len))
+            .read_to_end(&mut bytes)
+            .map_err(&read_err)?;
+          if bytes.len() as u64 != u64_from_bytes(&len) || fingerprint_of(&bytes) != fingerprint {
+            return Err(format!(
+              "Backup {:?} is corrupt: entry for {} does not match its fingerprint",
+              backup,
+              fingerprint
+            ));
+          }
+          match tag[0] {
+            BACKUP_FILE_ENTRY => restoring.put_file_bytes(fingerprint, &bytes, None)?,
+            BACKUP_DIRECTORY_ENTRY => restoring.put_directory_bytes(fingerprint, &bytes)?,
+            other => {
+              return Err(format!(
+                "Backup {:?} is corrupt: unknown entry tag {}",
+                backup,
+                other
+              ))
+            }
+          }
+        }
+      })
+      .map(move |()| store)
+      .to_boxed()
+  }
+
+  ///
+  /// Returns statistics about the underlying LMDB environment, to help decide whether compacting it
+  /// would be worthwhile. If the environment is shared by several namespaced Stores, the page
+  /// statistics cover all of them, but the entry counts only cover this Store.
+  ///
+  pub fn env_info(&self) -> BoxFuture<EnvInfo, String> {
+    let store = self.inner.clone();
+    self.spawn(move || {
+      let reader = StoreReader::new(&store)?;
+      let env = store.env.env();
+      let txn = reader.txn.txn();
+      let (info, env_stat) = unsafe {
+        let mut info: ffi::MDB_envinfo = mem::zeroed();
+        let mut env_stat: ffi::MDB_stat = mem::zeroed();
+        lmdb_result(ffi::mdb_env_info(env, &mut info))
+          .and_then(|()| lmdb_result(ffi::mdb_env_stat(env, &mut env_stat)))
+          .map(|()| (info, env_stat))
+      }.map_err(|err| {
+        format!("Error reading environment info: {}", err.description())
+      })?;
+
+      let databases = [
+        ("files", store.file_store),
+        ("sparse_files", store.sparse_file_store),
+        ("expiries", store.expiry_store),
+        ("mtimes", store.mtime_store),
+        ("directory_modes", store.directory_mode_store),
+        ("raw_names", store.raw_name_store),
+        ("content_types", store.content_type_store),
+        ("additions", store.addition_store),
+        ("directories", store.directory_store),
+      ];
+      let mut entries = BTreeMap::new();
+      for &(name, db) in &databases {
+        let stat = unsafe {
+          let mut stat: ffi::MDB_stat = mem::zeroed();
+          lmdb_result(ffi::mdb_stat(txn, db.dbi(), &mut stat)).map(|()| stat)
+        }.map_err(|err| {
+          format!("Error reading {} database info: {}", name, err.description())
+        })?;
+        entries.insert(name.to_owned(), stat.ms_entries);
+      }
+
+      let free_pages = unsafe { count_free_pages(txn) }.map_err(|err| {
+        format!("Error reading free pages: {}", err.description())
+      })?;
+      Ok(EnvInfo {
+        map_size: info.me_mapsize,
+        page_size: env_stat.ms_psize,
+        last_page: info.me_last_pgno,
+        free_pages: free_pages,
+        entries: entries,
+      })
+    })
+  }
+
+  ///
+  /// Store the Directory proto. Does not do anything about the files or directories claimed to be
+  /// contained therein.
+  ///
+  /// Assumes that the directory has been properly canonicalized.
+  ///
+  pub fn record_directory(
+    &self,
+    directory: &bazel_protos::remote_execution::Directory,
+  ) -> BoxFuture<Digest, String> {
+    let store = self.clone();
+    future::result(directory.write_to_bytes().map_err(|e| {
+      format!(
+        "Error serializing directory proto {:?}: {}",
+        directory,
+        e.description()
+      )
+    })).and_then(move |bytes| {
+      let len = bytes.len();
+
+      store
+        .store_directory_bytes(bytes)
+        .map(move |fingerprint| Digest(fingerprint, len))
+    })
+      .to_boxed()
+  }
+}
+
+///
+/// Stores a file whose contents are written to it in chunks, hashing them as they arrive. Since a
+/// file's key is only known once all of it has been written, its contents are held in memory
+/// until they exceed `MAX_BUFFERED_STORE_SIZE` bytes, and are spooled to a temporary file after
+/// that, from which they are copied into the Store by `finish`.
+///
+/// Files stored this way are stored densely, even if `compact_zero_runs` is set.
+///
+pub struct FileWriter {
+  store: Store,
+  hasher: Sha256,
+  len: usize,
+  spool: Spool,
+}
+
+enum Spool {
+  Memory(Vec<u8>),
+  // The TempDir is held so that the spool file is deleted once the FileWriter is done with it.
+  Disk(TempDir, PathBuf, BufWriter<fs::File>),
+}
+
+impl FileWriter {
+  pub fn write(&mut self, chunk: &[u8]) -> Result<(), String> {
+    self.hasher.input(chunk);
+    self.len += chunk.len();
+    let spilled = match self.spool {
+      Spool::Memory(ref bytes) if self.len > MAX_BUFFERED_STORE_SIZE => {
+        let dir = TempDir::new("spool").map_err(spool_err)?;
+        let path = dir.path().join("contents");
+        let mut file = BufWriter::new(fs::File::create(&path).map_err(spool_err)?);
+        file.write_all(bytes).map_err(spool_err)?;
+        Some(Spool::Disk(dir, path, file))
+      }
+      _ => None,
+    };
+    if let Some(spool) = spilled {
+      self.spool = spool;
+    }
+    match self.spool {
+      Spool::Memory(ref mut bytes) => bytes.extend_from_slice(chunk),
+      Spool::Disk(_, _, ref mut file) => file.write_all(chunk).map_err(spool_err)?,
+    }
+    Ok(())
+  }
+
+  ///
+  /// Stores everything written so far permanently, as store_file_bytes does.
+  ///
+  pub fn finish(self) -> BoxFuture<Digest, String> {
+    let fingerprint = Fingerprint::from_bytes_unsafe(&self.hasher.fixed_result());
+    let len = self.len;
+    match self.spool {
+      Spool::Memory(bytes) => {
+        self
+          .store
+          .put_file_bytes_coalesced(fingerprint, bytes)
+          .map(move |()| Digest(fingerprint, len))
+          .to_boxed()
+      }
+      Spool::Disk(dir, path, file) => {
+        let store = self.store.inner.clone();
+        self.store.spawn(move || {
+          file.into_inner().map_err(|e| spool_err(e.into()))?;
+          let mut spooled = fs::File::open(&path).map_err(spool_err)?;
+          put_file_reserved(&store, fingerprint, len, |buffer| {
+            spooled.read_exact(buffer).map_err(spool_err)
+          })?;
+          mem::drop(dir);
+          Ok(Digest(fingerprint, len))
+        })
+      }
+    }
+  }
+}
+
+fn spool_err(err: io::Error) -> String {
+  format!("Error spooling file contents: {}", err)
+}
+
+///
+/// A handle on a single read transaction against a Store; see `Store::read_batch`.
+///
+/// All loads made through a StoreReader observe the same consistent view of the Store.
+///
+pub struct StoreReader<'a> {
+  store: &'a InnerStore,
+  txn: RoTransaction<'a>,
+}
+
+impl<'a> StoreReader<'a> {
+  fn new(store: &'a InnerStore) -> Result<StoreReader<'a>, String> {
+    let txn = store.env.begin_ro_txn().map_err(|err| match err {
+      lmdb::Error::ReadersFull => {
+        "Failed to begin read transaction: all of the Store's reader slots are in use by other \
+         threads; raise StoreOptions::max_readers to at least the number of threads which read \
+         from the Store"
+          .to_owned()
+      }
+      err => {
+        format!(
+          "Failed to begin read transaction: {}",
+          err.description().to_string()
+        )
+      }
+    })?;
+    Ok(StoreReader {
+      store: store,
+      txn: txn,
+    })
+  }
+
+  ///
+  /// The deepest nesting of directories which Snapshots using this Store may have.
+  ///
+  pub fn max_directory_depth(&self) -> usize {
+    self.store.max_directory_depth()
+  }
+
+  pub fn load_file_bytes(&self, fingerprint: Fingerprint) -> Result<Option<Vec<u8>>, String> {
+    self.load_file_bytes_with(fingerprint, |bytes| Vec::from(bytes))
+  }
+
+  pub fn load_file_bytes_with<T, F: FnOnce(&[u8]) -> T>(
+    &self,
+    fingerprint: Fingerprint,
+    f: F,
+  ) -> Result<Option<T>, String> {
+    if let Some(bytes) = self.get(fingerprint, self.store.file_store)? {
+      return Ok(Some(f(bytes)));
+    }
+    match self.get(fingerprint, self.store.sparse_file_store)? {
+      Some(encoded) => {
+        let bytes = decode_zero_runs(encoded).map_err(|e| {
+          format!("Error decoding fingerprint {}: {}", fingerprint, e)
+        })?;
+        Ok(Some(f(&bytes)))
+      }
+      None => Ok(None),
+    }
+  }
+
+  ///
+  /// Loads the modification time recorded by `Store::record_mtimes` for the file with the given
+  /// fingerprint at the given path, if any.
+  ///
+  pub fn load_mtime(
+    &self,
+    path: &Path,
+    fingerprint: Fingerprint,
+  ) -> Result<Option<FileTime>, String> {
+    let key = path_key(path, fingerprint)?;
+    match self.txn.get(self.store.mtime_store, &key) {
+      Ok(value) if value.len() == 16 => Ok(Some(FileTime::from_seconds_since_1970(
+        u64_from_bytes(&value[..8]),
+        u64_from_bytes(&value[8..]) as u32,
+      ))),
+      Ok(value) => Err(format!(
+        "Malformed mtime for {:?} with fingerprint {}: {:?}",
+        path,
+        fingerprint,
+        value
+      )),
+      Err(NotFound) => Ok(None),
+      Err(err) => Err(format!(
+        "Error loading mtime for {:?} with fingerprint {}: {}",
+        path,
+        fingerprint,
+        err.description()
+      )),
+    }
+  }
+
+  ///
+  /// Loads the permission bits recorded by `Store::record_directory_modes` for the Directory with
+  /// the given fingerprint at the given path, if any.
+  ///
+  pub fn load_directory_mode(
+    &self,
+    path: &Path,
+    fingerprint: Fingerprint,
+  ) -> Result<Option<u32>, String> {
+    let key = path_key(path, fingerprint)?;
+    match self.txn.get(self.store.directory_mode_store, &key) {
+      Ok(value) if value.len() == 8 => Ok(Some(u64_from_bytes(value) as u32)),
+      Ok(value) => Err(format!(
+        "Malformed directory mode for {:?} with fingerprint {}: {:?}",
+        path,
+        fingerprint,
+        value
+      )),
+      Err(NotFound) => Ok(None),
+      Err(err) => Err(format!(
+        "Error loading directory mode for {:?} with fingerprint {}: {}",
+        path,
+        fingerprint,
+        err.description()
+      )),
+    }
+  }
+
+  ///
+  /// Loads the exact bytes recorded by `Store::record_raw_names` for the given (UTF8-lossy) name in
+  /// the Directory with the given fingerprint, if any.
+  ///
+  pub fn load_raw_name(
+    &self,
+    fingerprint: Fingerprint,
+    name: &str,
+  ) -> Result<Option<Vec<u8>>, String> {
+    let key = path_key(Path::new(name), fingerprint)?;
+    match self.txn.get(self.store.raw_name_store, &key) {
+      Ok(value) => Ok(Some(value.to_vec())),
+      Err(NotFound) => Ok(None),
+      Err(err) => Err(format!(
+        "Error loading raw name for {:?} in Directory {}: {}",
+        name,
+        fingerprint,
+        err.description()
+      )),
+    }
+  }
+
+  ///
+  /// Loads the content type recorded by `Store::record_content_types` for the file with the given
+  /// fingerprint by the classifier with the given name, if any.
+  ///
+  pub fn load_content_type(
+    &self,
+    fingerprint: Fingerprint,
+    classifier: &str,
+  ) -> Result<Option<String>, String> {
+    let key = path_key(Path::new(classifier), fingerprint)?;
+    match self.txn.get(self.store.content_type_store, &key) {
+      Ok(value) => Ok(Some(String::from_utf8_lossy(value).into_owned())),
+      Err(NotFound) => Ok(None),
+      Err(err) => Err(format!(
+        "Error loading content type of {} by {:?}: {}",
+        fingerprint,
+        classifier,
+        err.description()
+      )),
+    }
+  }
+
+  pub fn load_directory_proto_bytes(
+    &self,
+    fingerprint: Fingerprint,
+  ) -> Result<Option<Vec<u8>>, String> {
+    self.load_bytes_with(fingerprint, self.store.directory_store, |bytes| {
+      Vec::from(bytes)
+    })
+  }
+
+  pub fn load_directory_proto(
+    &self,
+    fingerprint: Fingerprint,
+  ) -> Result<Option<bazel_protos::remote_execution::Directory>, String> {
+    if let Some(directory) = self.store.cached_directory(fingerprint) {
+      return Ok(Some(directory));
+    }
+    match self.get(fingerprint, self.store.directory_store)? {
+      Some(bytes) => {
+        let directory = parse_directory_proto(fingerprint, bytes)?;
+        self.store.cache_directory(fingerprint, &directory);
+        Ok(Some(directory))
+      }
+      None => Ok(None),
+    }
+  }
+
+  ///
+  /// Returns the parsed Digests of the child Directories of `directory` (which must be the
+  /// Directory with the given Fingerprint), in the order they are listed. Like Directories
+  /// themselves, these are cached if `StoreOptions::directory_cache_size` is set, so that walks of
+  /// the same trees skip decoding their children's hex fingerprints.
+  ///
+  pub fn child_directory_digests(
+    &self,
+    fingerprint: Fingerprint,
+    directory: &bazel_protos::remote_execution::Directory,
+  ) -> Result<Arc<Vec<Digest>>, String> {
+    self.store.child_directory_digests(fingerprint, directory)
+  }
+
+  fn load_bytes_with<T, F: FnOnce(&[u8]) -> T>(
+    &self,
+    fingerprint: Fingerprint,
+    db: Database,
+    f: F,
+  ) -> Result<Option<T>, String> {
+    self.get(fingerprint, db).map(|maybe_bytes| maybe_bytes.map(f))
+  }
+
+  fn get(&self, fingerprint: Fingerprint, db: Database) -> Result<Option<&[u8]>, String> {
+    match self.txn.get(db, &fingerprint) {
+      Ok(bytes) => Ok(Some(bytes)),
+      Err(NotFound) => Ok(None),
+      Err(err) => Err(format!(
+        "Error loading fingerprint {}: {}",
+        fingerprint,
+        err.description().to_string()
+      )),
+    }
+  }
+}
+
+fn lmdb_result(code: i32) -> Result<(), lmdb::Error> {
+  if code == 0 {
+    Ok(())
+  } else {
+    Err(lmdb::Error::from_err_code(code))
+  }
+}
+
+///
+/// Counts the pages on the environment's free list, which LMDB keeps in database 0: each of its
+/// values is a list of page numbers, preceded by the length of the list.
+///
+/// Unsafe because the transaction must be live.
+///
+unsafe fn count_free_pages(txn: *mut ffi::MDB_txn) -> Result<usize, lmdb::Error> {
+  let mut cursor: *mut ffi::MDB_cursor = ptr::null_mut();
+  lmdb_result(ffi::mdb_cursor_open(txn, 0, &mut cursor))?;
+  let mut key = ffi::MDB_val {
+    mv_size: 0,
+    mv_data: ptr::null_mut(),
+  };
+  let mut data = ffi::MDB_val {
+    mv_size: 0,
+    mv_data: ptr::null_mut(),
+  };
+  let mut free_pages = 0;
+  let result = loop {
+    match ffi::mdb_cursor_get(cursor, &mut key, &mut data, ffi::MDB_NEXT) {
+      0 => {
+        if data.mv_size >= mem::size_of::<usize>() {
+          free_pages += ptr::read_unaligned(data.mv_data as *const usize);
+        }
+      }
+      ffi::MDB_NOTFOUND => break Ok(free_pages),
+      code => break Err(lmdb::Error::from_err_code(code)),
+    }
+  };
+  ffi::mdb_cursor_close(cursor);
+  result
+}
+
+fn open_environment(
+  path: &Path,
+  durability: Durability,
+  max_dbs: u32,
+  max_readers: Option<u32>,
+) -> Result<Environment, String> {
+  let env_flags = match durability {
+    Durability::Safe => EnvironmentFlags::empty(),
+    Durability::NoMetaSync => NO_META_SYNC,
+    Durability::NoSync => NO_SYNC,
+  };
+  let mut builder = Environment::new();
+  builder
+    .set_flags(env_flags)
+    .set_max_dbs(max_dbs)
+    .set_map_size(16 * 1024 * 1024 * 1024);
+  if let Some(max_readers) = max_readers {
+    builder.set_max_readers(max_readers);
+  }
+  builder
+    .open(path)
+    .map_err(|e| format!("Error making env: {}", e.description()))
+}
+
+///
+/// Opens (creating if necessary) the lock file in the directory of the given Environment, and
+/// waits to take an advisory `flock` on it, exclusively or shared. The lock is held until the
+/// returned File is closed.
+///
+fn lock_environment(env: &Environment, exclusive: bool) -> Result<fs::File, String> {
+  let dir = unsafe {
+    let mut path: *const libc::c_char = ptr::null();
+    lmdb_result(ffi::mdb_env_get_path(env.env(), &mut path))
+      .map_err(|e| format!("Error finding store directory: {}", e.description()))?;
+    PathBuf::from(OsStr::from_bytes(CStr::from_ptr(path).to_bytes()))
+  };
+  let lock_path = dir.join("gc.lock");
+  let file = fs::OpenOptions::new()
+    .read(true)
+    .write(true)
+    .create(true)
+    .open(&lock_path)
+    .map_err(|e| format!("Error opening lock file {:?}: {}", lock_path, e))?;
+  let operation = if exclusive {
+    libc::LOCK_EX
+  } else {
+    libc::LOCK_SH
+  };
+  loop {
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+      return Ok(file);
+    }
+    let err = io::Error::last_os_error();
+    if err.kind() != io::ErrorKind::Interrupted {
+      return Err(format!("Error locking {:?}: {}", lock_path, err));
+    }
+  }
+}
+
+fn create_db(env: &Environment, prefix: &str, name: &str) -> Result<Database, String> {
+  let name = format!("{}{}", prefix, name);
+  env.create_db(Some(&name), DatabaseFlags::empty()).map_err(
+    |e| {
+      format!(
+        "Error creating/opening {} database: {}",
+        name,
+        e.description()
+      )
+    },
+  )
+}
+
+///
+/// Fails if bytes which were already stored under the given fingerprint did not match the bytes
+/// being stored. None indicates that the bytes were concurrently removed, which is not a collision.
+///
+fn check_collision(fingerprint: Fingerprint, matches: Option<bool>) -> Result<(), String> {
+  match matches {
+    Some(false) => Err(format!(
+      "Fingerprint collision detected for {}",
+      fingerprint
+    )),
+    _ => Ok(()),
+  }
+}
+
+///
+/// Stores the file with the given fingerprint and length permanently (as store_file_bytes does),
+/// unless it is already stored, by calling `fill` to write its contents directly into the space
+/// reserved for it. `fill` runs inside the write transaction, so every other write to the Store
+/// waits for it; if it fails, nothing is stored.
+///
+fn put_file_reserved<F: FnOnce(&mut [u8]) -> Result<(), String>>(
+  store: &InnerStore,
+  fingerprint: Fingerprint,
+  len: usize,
+  fill: F,
+) -> Result<(), String> {
+  let store_err = |err: lmdb::Error| {
+    format!(
+      "Error storing fingerprint {}: {}",
+      fingerprint,
+      err.description()
+    )
+  };
+  let mut txn = store.env.begin_rw_txn().map_err(&store_err)?;
+  let mut exists = false;
+  for &db in &[store.file_store, store.sparse_file_store] {
+    match txn.get(db, &fingerprint) {
+      Ok(_) => exists = true,
+      Err(NotFound) => {}
+      Err(err) => return Err(store_err(err)),
+    }
+  }
+  if !exists {
+    let buffer = txn
+      .reserve(store.file_store, &fingerprint, len, WriteFlags::empty())
+      .map_err(&store_err)?;
+    // Dropping the transaction aborts it.
+    fill(buffer)?;
+  }
+  if !exists {
+    record_addition(&mut txn, store.addition_store, ADDED_FILE, &fingerprint)
+      .map_err(&store_err)?;
+  }
+  match txn.del(store.expiry_store, &fingerprint, None) {
+    Ok(()) | Err(NotFound) => {}
+    Err(err) => return Err(store_err(err)),
+  }
+  txn.commit().map_err(&store_err)
+}
+
+///
+/// Appends the blob of the given kind with the given fingerprint to the additions log, under the
+/// sequence number after the last one used.
+///
+fn record_addition(
+  txn: &mut RwTransaction,
+  addition_store: Database,
+  kind: u8,
+  fingerprint: &Fingerprint,
+) -> Result<(), lmdb::Error> {
+  let next = {
+    let cursor = txn.open_ro_cursor(addition_store)?;
+    match cursor.get(None, None, ffi::MDB_LAST) {
+      Ok((Some(key), _)) => u64_from_bytes(key) + 1,
+      Ok((None, _)) | Err(NotFound) => 1,
+      Err(err) => return Err(err),
+    }
+  };
+  let mut value = vec![kind];
+  value.extend_from_slice(fingerprint.as_bytes());
+  txn.put(
+    addition_store,
+    &u64_to_bytes(next),
+    &value,
+    WriteFlags::empty(),
+  )
+}
+
+fn put_if_absent(
+  txn: &mut RwTransaction,
+  db: Database,
+  fingerprint: &Fingerprint,
+  bytes: &[u8],
+) -> Result<bool, lmdb::Error> {
+  match txn.put(db, fingerprint, &bytes, NO_OVERWRITE) {
+    Ok(()) => Ok(false),
+    Err(KeyExist) => Ok(true),
+    Err(err) => Err(err),
+  }
+}
+
+///
+/// The Fingerprints of the files and Directories referenced by the given serialized Directory.
+/// Unparseable Directories and Digests are treated as referencing nothing.
+///
+fn referenced_fingerprints(fingerprint: Fingerprint, bytes: &[u8]) -> Vec<Fingerprint> {
+  let directory = match parse_directory_proto(fingerprint, bytes) {
+    Ok(directory) => directory,
+    Err(_) => return vec![],
+  };
+  directory
+    .get_files()
+    .iter()
+    .map(|file_node| file_node.get_digest())
+    .chain(directory.get_directories().iter().map(|directory_node| {
+      directory_node.get_digest()
+    }))
+    .filter_map(|digest| Digest::from_bazel_digest(digest).ok())
+    .map(|digest| digest.0)
+    .collect()
+}
+
+// The key of metadata about the file or Directory with the given fingerprint at the given path.
+///
+/// Reads a big-endian u64 length followed by that many bytes, as written by `Store::backup`.
+///
+fn read_length_prefixed<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+  let mut len = [0; 8];
+  reader.read_exact(&mut len)?;
+  let len = u64_from_bytes(&len);
+  let mut bytes = Vec::new();
+  reader.by_ref().take(len).read_to_end(&mut bytes)?;
+  if bytes.len() as u64 != len {
+    return Err(io::Error::new(
+      io::ErrorKind::UnexpectedEof,
+      "backup entry is truncated",
+    ));
+  }
+  Ok(bytes)
+}
+
+fn path_key(path: &Path, fingerprint: Fingerprint) -> Result<Vec<u8>, String> {
+  let path = path.to_str().ok_or_else(|| {
+    format!("Path {:?} is not valid UTF8", path)
+  })?;
+  let mut key = fingerprint.as_bytes().to_vec();
+  key.extend_from_slice(path.as_bytes());
+  Ok(key)
+}
+
+fn seconds_since_epoch(time: SystemTime) -> u64 {
+  time
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_secs())
+    .unwrap_or(0)
+}
+
+fn u64_to_bytes(value: u64) -> [u8; 8] {
+  let mut bytes = [0; 8];
+  for i in 0..8 {
+    bytes[i] = (value >> ((7 - i) * 8)) as u8;
+  }
+  bytes
+}
+
+fn u64_from_bytes(bytes: &[u8]) -> u64 {
+  bytes.iter().fold(0, |acc, &b| (acc << 8) | b as u64)
+}
+
+fn fingerprint_of(bytes: &[u8]) -> Fingerprint {
+  let mut hasher = Sha256::default();
+  hasher.input(bytes);
+  Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice())
+}
+
+fn parse_directory_proto(
+  fingerprint: Fingerprint,
+  bytes: &[u8],
+) -> Result<bazel_protos::remote_execution::Directory, String> {
+  let mut proto = bazel_protos::remote_execution::Directory::new();
+  proto
+    .merge_from_bytes(bytes)
+    .map_err(|e| format!("Error deserializing proto {}: {}", fingerprint, e))?;
+  Ok(proto)
+}
+
+///
+/// A Digest is a fingerprint, as well as the size in bytes of the plaintext for which that is the
+/// fingerprint.
+///
+/// It is equivalent to a Bazel Remote Execution Digest, but without the overhead (and awkward API)
+/// of needing to create an entire protobuf to pass around the two fields.
+///
+/// Digests are ordered by their Fingerprint, and then by their size.
+///
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Digest(pub Fingerprint, pub usize);
+
+impl Digest {
+  ///
+  /// Converts a Bazel Remote Execution Digest, failing if its hash is not a valid hex Fingerprint
+  /// or its size is negative.
+  ///
+  /// This would be a TryFrom impl, but TryFrom is not yet stable on our toolchain.
+  ///
+  pub fn from_bazel_digest(
+    digest: &bazel_protos::remote_execution::Digest,
+  ) -> Result<Digest, String> {
+    let fingerprint = Fingerprint::from_hex_string(digest.get_hash()).map_err(|err| {
+      format!("Invalid hash in Digest {:?}: {}", digest, err)
+    })?;
+    let size_bytes = digest.get_size_bytes();
+    if size_bytes < 0 {
+      return Err(format!("Negative size in Digest {:?}", digest));
+    }
+    Ok(Digest(fingerprint, size_bytes as usize))
+  }
+}
+
+impl Into<bazel_protos::remote_execution::Digest> for Digest {
+  fn into(self) -> bazel_protos::remote_execution::Digest {
+    let mut digest = bazel_protos::remote_execution::Digest::new();
+    digest.set_hash(self.0.to_hex());
+    digest.set_size_bytes(self.1 as i64);
+    digest
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempdir;
+
+  use bazel_protos;
+  use boxfuture::Boxable;
+  use futures::{future, Future, Stream};
+  use futures::sync::oneshot;
+  use super::{Cursor, Digest, Durability, Fingerprint, ResettablePool, Store, StoreEnvironment,
+              StoreOptions, StoreReader, DATABASES_PER_STORE, MAX_BUFFERED_STORE_SIZE,
+              u64_to_bytes};
+  use sparse::MIN_ZERO_RUN;
+  use lmdb::{Cursor as LmdbCursor, DatabaseFlags, Environment, Transaction, WriteFlags};
+  use protobuf::Message;
+  use std;
+  use std::io::{Read, Write};
+  use std::path::Path;
+  use std::sync::{mpsc, Arc, Mutex};
+  use std::thread;
+  use std::time::{Duration, SystemTime};
+  use tempdir::TempDir;
+
+
+  const STR: &str = "European Burmese";
+  const HASH: &str = "693d8db7b05e99c6b7a7c0616456039d89c555029026936248085193559a0b5d";
+
+  fn digest() -> Digest {
+    Digest(Fingerprint::from_hex_string(HASH).unwrap(), STR.len())
+  }
+
+  fn str_bytes() -> Vec<u8> {
+    STR.as_bytes().to_owned()
+  }
+
+  #[test]
+  fn save_file() {
+    let dir = TempDir::new("store").unwrap();
+
+    assert_eq!(
+      new_store(dir.path()).store_file_bytes(str_bytes()).wait(),
+      Ok(digest())
+    );
+  }
+
+  #[test]
+  fn store_file_from_path() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+    let source = TempDir::new("source").unwrap();
+    let path = source.path().join("roland");
+    std::fs::File::create(&path)
+      .unwrap()
+      .write_all(&str_bytes())
+      .unwrap();
+
+    assert_eq!(store.store_file_from_path(&path).wait(), Ok(digest()));
+    assert!(path.exists());
+    assert_eq!(
+      store.load_file_bytes(digest().0).wait(),
+      Ok(Some(str_bytes()))
+    );
+    // Storing it again is a no-op.
+    assert_eq!(store.store_file_from_path(&path).wait(), Ok(digest()));
+
+    assert_eq!(store.promote_file(&path).wait(), Ok(digest()));
+    assert!(!path.exists());
+    store.promote_file(&path).wait().expect_err("Want error");
+  }
+
+  #[test]
+  fn store_large_file_from_path() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+    let source = TempDir::new("source").unwrap();
+    let path = source.path().join("roland");
+    let bytes = (0..MAX_BUFFERED_STORE_SIZE + 1)
+      .map(|i| i as u8)
+      .collect::<Vec<_>>();
+    std::fs::File::create(&path)
+      .unwrap()
+      .write_all(&bytes)
+      .unwrap();
+
+    let digest = store.store_file_from_path(&path).wait().unwrap();
+    assert_eq!(digest.1, bytes.len());
+    assert_eq!(store.load_file_bytes(digest.0).wait(), Ok(Some(bytes)));
+  }
+
+  #[test]
+  fn file_writer_stores_chunks() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+    for &len in &[str_bytes().len(), MAX_BUFFERED_STORE_SIZE + 1] {
+      let bytes = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+      let mut writer = store.file_writer();
+      for chunk in bytes.chunks(1000) {
+        writer.write(chunk).unwrap();
+      }
+      let digest = writer.finish().wait().unwrap();
+      assert_eq!(digest.1, len);
+      assert_eq!(store.load_file_bytes(digest.0).wait(), Ok(Some(bytes)));
+    }
+  }
+
+  #[test]
+  fn save_file_is_idempotent() {
+    let dir = TempDir::new("store").unwrap();
+
+    new_store(dir.path())
+      .store_file_bytes(str_bytes())
+      .wait()
+      .unwrap();
+    assert_eq!(
+      new_store(dir.path()).store_file_bytes(str_bytes()).wait(),
+      Ok(digest())
+    );
+  }
+
+  #[test]
+  fn save_file_collision_preserves_first() {
+    let dir = TempDir::new("store").unwrap();
+
+    let fingerprint = Fingerprint::from_hex_string(HASH).unwrap();
+    let bogus_value: Vec<u8> = vec![];
+
+    let env = Environment::new().set_max_dbs(1).open(dir.path()).unwrap();
+    let database = env.create_db(Some("files"), DatabaseFlags::empty());
+    env
+      .begin_rw_txn()
+      .and_then(|mut txn| {
+        txn.put(database.unwrap(), &fingerprint, &bogus_value, WriteFlags::empty())
+            .and_then(|()| txn.commit())
+      })
+      .unwrap();
+
+    assert_eq!(
+      new_store(dir.path()).load_file_bytes(fingerprint).wait(),
+      Ok(Some(bogus_value.clone()))
+    );
+
+    assert_eq!(
+      new_store(dir.path()).store_file_bytes(str_bytes()).wait(),
+      Ok(digest())
+    );
+
+    assert_eq!(
+      new_store(dir.path()).load_file_bytes(fingerprint).wait(),
+      Ok(Some(bogus_value))
+    );
+  }
+
+  #[test]
+  fn concurrent_saves_of_same_file() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+
+    let saves = (0..10)
+      .map(|_| store.store_file_bytes(str_bytes()))
+      .collect::<Vec<_>>();
+    assert_eq!(future::join_all(saves).wait(), Ok(vec![digest(); 10]));
+    assert_eq!(
+      store.load_file_bytes(digest().0).wait(),
+      Ok(Some(str_bytes()))
+    );
+  }
+
+  #[test]
+  fn roundtrip_file_without_sync() {
+    let dir = TempDir::new("store").unwrap();
+    let store = Store::new_with_options(
+      dir.path(),
+      Arc::new(ResettablePool::new("test-pool-".to_string())),
+      StoreOptions {
+        durability: Durability::NoSync,
+        ..StoreOptions::default()
+      },
+    ).unwrap();
+    let digest = store.store_file_bytes(str_bytes()).wait().unwrap();
+    assert_eq!(store.load_file_bytes(digest.0).wait(), Ok(Some(str_bytes())));
+  }
+
+  #[test]
+  fn max_readers_limits_concurrent_readers() {
+    let dir = TempDir::new("store").unwrap();
+    let store = Store::new_with_options(
+      dir.path(),
+      Arc::new(ResettablePool::new("test-pool-".to_string())),
+      StoreOptions {
+        max_readers: Some(1),
+        ..StoreOptions::default()
+      },
+    ).unwrap();
+
+    // Occupy the only reader slot from another thread.
+    let inner = store.inner.clone();
+    let (held_sender, held_receiver) = mpsc::channel();
+    let (release_sender, release_receiver) = mpsc::channel::<()>();
+    let holder = thread::spawn(move || {
+      let _reader = StoreReader::new(&inner).unwrap();
+      held_sender.send(()).unwrap();
+      release_receiver.recv().unwrap();
+    });
+    held_receiver.recv().unwrap();
+
+    match StoreReader::new(&store.inner) {
+      Ok(_) => panic!("Want error"),
+      Err(err) => assert!(err.contains("StoreOptions::max_readers"), err),
+    }
+    release_sender.send(()).unwrap();
+    holder.join().unwrap();
+  }
+
+  #[test]
+  fn save_file_collision_detected() {
+    let dir = TempDir::new("store").unwrap();
+    let store = Store::new_with_options(
+      dir.path(),
+      Arc::new(ResettablePool::new("test-pool-".to_string())),
+      StoreOptions {
+        check_collisions: true,
+        ..StoreOptions::default()
+      },
+    ).unwrap();
+
+    // Store different bytes under the same fingerprint, as a broken hash might.
+    let fingerprint = Fingerprint::from_hex_string(HASH).unwrap();
+    store
+      .put_file_bytes(fingerprint, STR.as_bytes(), None)
+      .unwrap();
+    assert_eq!(
+      store.put_file_bytes(fingerprint, b"American Burmese", None),
+      Err(format!("Fingerprint collision detected for {}", HASH))
+    );
+
+    // Storing identical bytes again is fine.
+    assert_eq!(store.store_file_bytes(str_bytes()).wait(), Ok(digest()));
+  }
+
+  #[test]
+  fn save_file_with_digest() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+
+    assert_eq!(
+      store.store_file_bytes_with_digest(str_bytes(), digest()).wait(),
+      Ok(digest())
+    );
+    assert_eq!(store.load_file_bytes(digest().0).wait(), Ok(Some(str_bytes())));
+  }
+
+  #[test]
+  fn save_file_with_digest_wrong_length() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+
+    store
+      .store_file_bytes_with_digest(str_bytes(), Digest(digest().0, STR.len() + 1))
+      .wait()
+      .expect_err("Want error");
+    assert_eq!(store.load_file_bytes(digest().0).wait(), Ok(None));
+  }
+
+  #[cfg(debug_assertions)]
+  #[test]
+  fn save_file_with_digest_wrong_fingerprint() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+
+    let wrong = Fingerprint::from_hex_string(
+      "0000000000000000000000000000000000000000000000000000000000000000",
+    ).unwrap();
+    store
+      .store_file_bytes_with_digest(str_bytes(), Digest(wrong, STR.len()))
+      .wait()
+      .expect_err("Want error");
+    assert_eq!(store.load_file_bytes(wrong).wait(), Ok(None));
+  }
+
+  #[test]
+  fn roundtrip_file_with_compact_zero_runs() {
+    let mut data = str_bytes();
+    data.extend(vec![0; MIN_ZERO_RUN * 4]);
+    data.extend(str_bytes());
+
+    let dense_dir = TempDir::new("store").unwrap();
+    let dense_digest = new_store(dense_dir.path())
+      .store_file_bytes(data.clone())
+      .wait()
+      .unwrap();
+
+    let dir = TempDir::new("store").unwrap();
+    let digest = new_compacting_store(dir.path())
+      .store_file_bytes(data.clone())
+      .wait()
+      .unwrap();
+    assert_eq!(digest, dense_digest);
+    assert_eq!(
+      new_compacting_store(dir.path())
+        .load_file_bytes(digest.0)
+        .wait(),
+      Ok(Some(data.clone()))
+    );
+    // Stores which don't compact files should still be able to load them.
+    assert_eq!(
+      new_store(dir.path()).load_file_bytes(digest.0).wait(),
+      Ok(Some(data))
+    );
+  }
+
+  #[test]
+  fn roundtrip_file() {
+    let data = str_bytes();
+    let dir = TempDir::new("store").unwrap();
+
+    let store = new_store(dir.path());
+    let hash = store.store_file_bytes(data.clone()).wait().unwrap();
+    assert_eq!(store.load_file_bytes(hash.0).wait(), Ok(Some(data)));
+  }
+
+  #[test]
+  fn namespaced_stores_are_independent() {
+    let dir = TempDir::new("store").unwrap();
+    let env = StoreEnvironment::new(dir.path(), 2, Durability::Safe).unwrap();
+    let pool = Arc::new(ResettablePool::new("test-pool-".to_string()));
+    let sources =
+      Store::new_namespaced(&env, "sources", pool.clone(), StoreOptions::default()).unwrap();
+    let outputs =
+      Store::new_namespaced(&env, "outputs", pool.clone(), StoreOptions::default()).unwrap();
+
+    let digest = sources
+      .store_file_bytes_with_ttl(str_bytes(), Duration::from_secs(60))
+      .wait()
+      .unwrap();
+    assert_eq!(outputs.load_file_bytes(digest.0).wait(), Ok(None));
+    outputs.store_file_bytes(str_bytes()).wait().unwrap();
+
+    let later = SystemTime::now() + Duration::from_secs(120);
+    assert_eq!(sources.expire(later).wait(), Ok(1));
+    assert_eq!(sources.load_file_bytes(digest.0).wait(), Ok(None));
+    assert_eq!(
+      outputs.load_file_bytes(digest.0).wait(),
+      Ok(Some(str_bytes()))
+    );
+
+    // Reopening a namespace sees its existing contents.
+    let reopened = Store::new_namespaced(&env, "outputs", pool, StoreOptions::default()).unwrap();
+    assert_eq!(
+      reopened.load_file_bytes(digest.0).wait(),
+      Ok(Some(str_bytes()))
+    );
+  }
+
+  #[test]
+  fn stores_from_env_share_contents() {
+    let dir = TempDir::new("store").unwrap();
+    let env = Arc::new(
+      Environment::new()
+        .set_max_dbs(DATABASES_PER_STORE)
+        .open(dir.path())
+        .unwrap(),
+    );
+    let pool = Arc::new(ResettablePool::new("test-pool-".to_string()));
+    let writer = Store::from_env(env.clone(), pool.clone()).unwrap();
+    let reader = Store::from_env(env, pool).unwrap();
+
+    let digest = writer.store_file_bytes(str_bytes()).wait().unwrap();
+    assert_eq!(
+      reader.load_file_bytes(digest.0).wait(),
+      Ok(Some(str_bytes()))
+    );
+  }
+
+  #[test]
+  fn namespace_must_not_contain_separator() {
+    let dir = TempDir::new("store").unwrap();
+    let env = StoreEnvironment::new(dir.path(), 1, Durability::Safe).unwrap();
+    let pool = Arc::new(ResettablePool::new("test-pool-".to_string()));
+    Store::new_namespaced(&env, "sources/files", pool.clone(), StoreOptions::default())
+      .err()
+      .expect("Want error");
+    Store::new_namespaced(&env, "", pool, StoreOptions::default())
+      .err()
+      .expect("Want error");
+  }
+
+  #[test]
+  fn missing_file() {
+    let dir = TempDir::new("store").unwrap();
+    assert_eq!(
+      new_store(dir.path())
+        .load_file_bytes(Fingerprint::from_hex_string(HASH).unwrap())
+        .wait(),
+      Ok(None)
+    );
+  }
+
+  #[test]
+  fn record_and_load_directory_proto() {
+    let mut directory = bazel_protos::remote_execution::Directory::new();
+    directory.mut_files().push({
+      let mut file = bazel_protos::remote_execution::FileNode::new();
+      file.set_name("roland".to_string());
+      file.set_digest({
+        let mut digest = bazel_protos::remote_execution::Digest::new();
+        digest.set_hash(HASH.to_string());
+        digest.set_size_bytes(STR.len() as i64);
+        digest
+      });
+      file.set_is_executable(false);
+      file
+    });
+
+    let dir = TempDir::new("store").unwrap();
+
+    let hash = "63949aa823baf765eff07b946050d76ec0033144c785a94d3ebd82baa931cd16";
+
+    assert_eq!(
+      &new_store(dir.path())
+        .record_directory(&directory)
+        .wait()
+        .unwrap()
+        .0
+        .to_hex(),
+      hash
+    );
+
+    assert_eq!(
+      new_store(dir.path())
+        .load_directory_proto(Fingerprint::from_hex_string(hash).unwrap())
+        .wait(),
+      Ok(Some(directory.clone()))
+    );
+
+    assert_eq!(
+      new_store(dir.path())
+        .load_directory_proto_bytes(Fingerprint::from_hex_string(hash).unwrap())
+        .wait(),
+      Ok(Some(directory.write_to_bytes().unwrap()))
+    );
+  }
+
+  #[test]
+  fn file_is_not_directory_proto() {
+    let dir = TempDir::new("store").unwrap();
+
+    new_store(dir.path())
+      .store_file_bytes(str_bytes())
+      .wait()
+      .unwrap();
+
+    assert_eq!(
+      new_store(dir.path())
+        .load_directory_proto(Fingerprint::from_hex_string(HASH).unwrap())
+        .wait(),
+      Ok(None)
+    );
+
+    assert_eq!(
+      new_store(dir.path())
+        .load_directory_proto_bytes(Fingerprint::from_hex_string(HASH).unwrap())
+        .wait(),
+      Ok(None)
+    );
+  }
+
+  #[test]
+  fn read_batch_shares_transaction_between_loads() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+    let data = str_bytes();
+    let file_digest = store.store_file_bytes(data.clone()).wait().unwrap();
+    let directory = bazel_protos::remote_execution::Directory::new();
+    let directory_digest = store.record_directory(&directory).wait().unwrap();
+
+    let missing = Fingerprint::from_hex_string(
+      "0000000000000000000000000000000000000000000000000000000000000000",
+    ).unwrap();
+    assert_eq!(
+      store
+        .read_batch(move |reader| {
+          Ok((
+            reader.load_file_bytes(file_digest.0)?,
+            reader.load_directory_proto(directory_digest.0)?,
+            reader.load_file_bytes(missing)?,
+          ))
+        })
+        .wait(),
+      Ok((Some(data), Some(directory), None))
+    );
+  }
+
+  #[test]
+  fn expire_removes_files_after_ttl() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+    let digest = store
+      .store_file_bytes_with_ttl(str_bytes(), Duration::from_secs(60))
+      .wait()
+      .unwrap();
+
+    assert_eq!(store.expire(SystemTime::now()).wait(), Ok(0));
+    assert_eq!(
+      store.load_file_bytes(digest.0).wait(),
+      Ok(Some(str_bytes()))
+    );
+
+    let later = SystemTime::now() + Duration::from_secs(120);
+    assert_eq!(store.expire(later).wait(), Ok(1));
+    assert_eq!(store.load_file_bytes(digest.0).wait(), Ok(None));
+  }
+
+  #[test]
+  fn expire_calls_evict_callbacks_after_removal() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+    let evicted = Arc::new(Mutex::new(Vec::new()));
+    {
+      let evicted = evicted.clone();
+      store.on_evict(Arc::new(
+        move |digest: Digest| evicted.lock().unwrap().push(digest),
+      ));
+    }
+    let file_digest = store
+      .store_file_bytes_with_ttl(str_bytes(), Duration::from_secs(60))
+      .wait()
+      .unwrap();
+    let permanent_digest = store.store_file_bytes(b"permanent".to_vec()).wait().unwrap();
+    let mut directory = bazel_protos::remote_execution::Directory::new();
+    directory.mut_files().push({
+      let mut file = bazel_protos::remote_execution::FileNode::new();
+      file.set_name("roland".to_string());
+      file.set_digest(file_digest.clone().into());
+      file
+    });
+    let directory_digest = store.record_directory(&directory).wait().unwrap();
+
+    assert_eq!(store.expire(SystemTime::now()).wait(), Ok(0));
+    assert_eq!(*evicted.lock().unwrap(), vec![]);
+
+    let later = SystemTime::now() + Duration::from_secs(120);
+    assert_eq!(store.expire(later).wait(), Ok(2));
+    assert_eq!(
+      *evicted.lock().unwrap(),
+      vec![file_digest, directory_digest]
+    );
+    assert_eq!(
+      store.load_file_bytes(permanent_digest.0).wait(),
+      Ok(Some(b"permanent".to_vec()))
+    );
   }
-}
 
-#[cfg(test)]
-mod tests {
-  extern crate tempdir;
+  #[test]
+  fn exclusive_gc_lock_excludes_shared_holders() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+    let (acquired_sender, acquired_receiver) = mpsc::channel();
+    let (release_sender, release_receiver) = oneshot::channel::<()>();
+    let exclusive = store.with_exclusive_gc_lock(move || {
+      acquired_sender.send(()).unwrap();
+      release_receiver.map_err(|e| format!("{:?}", e)).to_boxed()
+    });
+    let exclusive_thread = thread::spawn(move || exclusive.wait());
+    acquired_receiver.recv().unwrap();
 
-  use bazel_protos;
-  use futures::Future;
-  use super::{Digest, Fingerprint, ResettablePool, Store};
-  use lmdb::{DatabaseFlags, Environment, Transaction, WriteFlags};
-  use protobuf::Message;
-  use std::path::Path;
-  use std::sync::Arc;
-  use tempdir::TempDir;
+    let (shared_sender, shared_receiver) = mpsc::channel();
+    let shared = store.with_shared_gc_lock(|| future::ok(()).to_boxed());
+    let shared_thread = thread::spawn(move || {
+      let result = shared.wait();
+      shared_sender.send(()).unwrap();
+      result
+    });
+    assert_eq!(
+      shared_receiver.recv_timeout(Duration::from_millis(200)),
+      Err(mpsc::RecvTimeoutError::Timeout)
+    );
 
+    release_sender.send(()).unwrap();
+    assert_eq!(exclusive_thread.join().unwrap(), Ok(()));
+    assert_eq!(shared_thread.join().unwrap(), Ok(()));
+    assert!(dir.path().join("gc.lock").exists());
+  }
 
-  const STR: &str = "European Burmese";
-  const HASH: &str = "693d8db7b05e99c6b7a7c0616456039d89c555029026936248085193559a0b5d";
+  #[test]
+  fn stream_entries_since_cursor() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+    let stream = |since| {
+      let mut entries = store.stream_entries(since).collect().wait().unwrap();
+      entries.sort();
+      entries
+    };
+    assert_eq!(store.cursor().wait(), Ok(Cursor(0)));
 
-  fn digest() -> Digest {
-    Digest(Fingerprint::from_hex_string(HASH).unwrap(), STR.len())
-  }
+    let file_digest = store.store_file_bytes(str_bytes()).wait().unwrap();
+    let directory = bazel_protos::remote_execution::Directory::new();
+    let directory_digest = store.record_directory(&directory).wait().unwrap();
+    // Storing something which is already stored does not add it again.
+    store.store_file_bytes(str_bytes()).wait().unwrap();
+    let cursor = store.cursor().wait().unwrap();
+    assert_eq!(cursor, Cursor(2));
+    let mut expected = vec![
+      (file_digest, str_bytes()),
+      (directory_digest, directory.write_to_bytes().unwrap()),
+    ];
+    expected.sort();
+    assert_eq!(stream(None), expected);
+    assert_eq!(stream(Some(Cursor(0))), expected);
+    assert_eq!(stream(Some(cursor)), vec![]);
 
-  fn str_bytes() -> Vec<u8> {
-    STR.as_bytes().to_owned()
+    let later_digest = store.store_file_bytes(b"later".to_vec()).wait().unwrap();
+    assert_eq!(
+      stream(Some(cursor)),
+      vec![(later_digest, b"later".to_vec())]
+    );
   }
 
   #[test]
-  fn save_file() {
+  fn expire_prunes_additions_of_removed_blobs() {
     let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+    let ttl = Duration::from_secs(60);
+    store
+      .store_file_bytes_with_ttl(b"first".to_vec(), ttl)
+      .wait()
+      .unwrap();
+    let kept_digest = store.store_file_bytes(str_bytes()).wait().unwrap();
+    store
+      .store_file_bytes_with_ttl(b"last".to_vec(), ttl)
+      .wait()
+      .unwrap();
+    let additions = || {
+      let txn = store.inner.env.begin_ro_txn().unwrap();
+      let mut cursor = txn.open_ro_cursor(store.inner.addition_store).unwrap();
+      let mut keys = Vec::new();
+      for (key, _) in cursor.iter_start() {
+        keys.push(key.to_vec());
+      }
+      keys
+    };
+    assert_eq!(additions().len(), 3);
 
+    let later = SystemTime::now() + Duration::from_secs(120);
+    assert_eq!(store.expire(later).wait(), Ok(2));
+    // The last addition is kept, so that its sequence number is not reused.
+    assert_eq!(additions(), vec![u64_to_bytes(2).to_vec(), u64_to_bytes(3).to_vec()]);
+    assert_eq!(store.cursor().wait(), Ok(Cursor(3)));
     assert_eq!(
-      new_store(dir.path()).store_file_bytes(str_bytes()).wait(),
-      Ok(digest())
+      store.stream_entries(Some(Cursor(0))).collect().wait(),
+      Ok(vec![(kept_digest, str_bytes())])
     );
+
+    store.store_file_bytes(b"next".to_vec()).wait().unwrap();
+    assert_eq!(store.cursor().wait(), Ok(Cursor(4)));
   }
 
   #[test]
-  fn save_file_is_idempotent() {
+  fn import_cas_verifies_objects() {
+    let cas = TempDir::new("cas").unwrap();
+    let write = |path: &Path, bytes: &[u8]| {
+      std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+      std::fs::File::create(path)
+        .unwrap()
+        .write_all(bytes)
+        .unwrap();
+    };
+    write(&cas.path().join(&HASH[..2]).join(&HASH[2..]), STR.as_bytes());
+    let mismatched = cas.path().join("00").join(
+      (0..62).map(|_| "0").collect::<String>(),
+    );
+    write(&mismatched, b"meow");
+    let stray = cas.path().join("README");
+    write(&stray, b"Not an object");
+
     let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+    let err = store
+      .import_cas(cas.path().to_owned(), false)
+      .wait()
+      .expect_err("Want error");
+    assert!(err.contains("Invalid CAS object"), "Unexpected error: {}", err);
 
-    new_store(dir.path())
-      .store_file_bytes(str_bytes())
+    let (imported, invalid) = store
+      .import_cas(cas.path().to_owned(), true)
       .wait()
       .unwrap();
+    assert_eq!(imported, 1);
     assert_eq!(
-      new_store(dir.path()).store_file_bytes(str_bytes()).wait(),
-      Ok(digest())
+      invalid
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect::<Vec<_>>(),
+      vec![mismatched, stray]
+    );
+    assert_eq!(
+      store
+        .load_file_bytes(Fingerprint::from_hex_string(HASH).unwrap())
+        .wait(),
+      Ok(Some(str_bytes()))
     );
   }
 
   #[test]
-  fn save_file_collision_preserves_first() {
+  fn garbage_collect_dry_run_lists_unreachable_blobs() {
     let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+    let file_digest = store.store_file_bytes(str_bytes()).wait().unwrap();
+    let loose_digest = store.store_file_bytes(b"loose".to_vec()).wait().unwrap();
+    let zeros_digest = store.store_file_bytes(vec![0; 8192]).wait().unwrap();
 
-    let fingerprint = Fingerprint::from_hex_string(HASH).unwrap();
-    let bogus_value: Vec<u8> = vec![];
-
-    let env = Environment::new().set_max_dbs(1).open(dir.path()).unwrap();
-    let database = env.create_db(Some("files"), DatabaseFlags::empty());
-    env
-      .begin_rw_txn()
-      .and_then(|mut txn| {
-        txn.put(database.unwrap(), &fingerprint, &bogus_value, WriteFlags::empty())
-            .and_then(|()| txn.commit())
-      })
-      .unwrap();
+    let mut directory = bazel_protos::remote_execution::Directory::new();
+    directory.mut_files().push({
+      let mut file = bazel_protos::remote_execution::FileNode::new();
+      file.set_name("roland".to_string());
+      file.set_digest(file_digest.clone().into());
+      file
+    });
+    let directory_digest = store.record_directory(&directory).wait().unwrap();
+    let unrelated = bazel_protos::remote_execution::Directory::new();
+    let unrelated_digest = store.record_directory(&unrelated).wait().unwrap();
 
+    let mut expected = vec![loose_digest.clone(), zeros_digest, unrelated_digest];
+    expected.sort();
     assert_eq!(
-      new_store(dir.path()).load_file_bytes(fingerprint).wait(),
-      Ok(Some(bogus_value.clone()))
+      store
+        .garbage_collect_dry_run(vec![directory_digest.clone()])
+        .wait(),
+      Ok(expected)
     );
 
+    // Nothing was removed.
     assert_eq!(
-      new_store(dir.path()).store_file_bytes(str_bytes()).wait(),
-      Ok(digest())
+      store.load_directory_proto(directory_digest.0).wait(),
+      Ok(Some(directory))
     );
-
     assert_eq!(
-      new_store(dir.path()).load_file_bytes(fingerprint).wait(),
-      Ok(Some(bogus_value))
+      store.load_file_bytes(loose_digest.0).wait(),
+      Ok(Some(b"loose".to_vec()))
     );
   }
 
   #[test]
-  fn roundtrip_file() {
-    let data = str_bytes();
+  fn expire_ignores_files_stored_permanently() {
     let dir = TempDir::new("store").unwrap();
-
     let store = new_store(dir.path());
-    let hash = store.store_file_bytes(data.clone()).wait().unwrap();
-    assert_eq!(store.load_file_bytes(hash.0).wait(), Ok(Some(data)));
-  }
+    store
+      .store_file_bytes_with_ttl(str_bytes(), Duration::from_secs(60))
+      .wait()
+      .unwrap();
+    let digest = store.store_file_bytes(str_bytes()).wait().unwrap();
+    store
+      .store_file_bytes_with_ttl(str_bytes(), Duration::from_secs(60))
+      .wait()
+      .unwrap();
 
-  #[test]
-  fn missing_file() {
-    let dir = TempDir::new("store").unwrap();
+    let later = SystemTime::now() + Duration::from_secs(120);
+    assert_eq!(store.expire(later).wait(), Ok(0));
     assert_eq!(
-      new_store(dir.path())
-        .load_file_bytes(Fingerprint::from_hex_string(HASH).unwrap())
-        .wait(),
-      Ok(None)
+      store.load_file_bytes(digest.0).wait(),
+      Ok(Some(str_bytes()))
     );
   }
 
   #[test]
-  fn record_and_load_directory_proto() {
+  fn expire_removes_directories_referencing_expired_files() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+    let file_digest = store
+      .store_file_bytes_with_ttl(str_bytes(), Duration::from_secs(60))
+      .wait()
+      .unwrap();
+
     let mut directory = bazel_protos::remote_execution::Directory::new();
     directory.mut_files().push({
       let mut file = bazel_protos::remote_execution::FileNode::new();
       file.set_name("roland".to_string());
-      file.set_digest({
-        let mut digest = bazel_protos::remote_execution::Digest::new();
-        digest.set_hash(HASH.to_string());
-        digest.set_size_bytes(STR.len() as i64);
-        digest
-      });
-      file.set_is_executable(false);
+      file.set_digest(file_digest.clone().into());
       file
     });
+    let directory_digest = store.record_directory(&directory).wait().unwrap();
 
-    let dir = TempDir::new("store").unwrap();
+    let mut parent = bazel_protos::remote_execution::Directory::new();
+    parent.mut_directories().push({
+      let mut child = bazel_protos::remote_execution::DirectoryNode::new();
+      child.set_name("cats".to_string());
+      child.set_digest(directory_digest.clone().into());
+      child
+    });
+    let parent_digest = store.record_directory(&parent).wait().unwrap();
 
-    let hash = "63949aa823baf765eff07b946050d76ec0033144c785a94d3ebd82baa931cd16";
+    let unrelated = bazel_protos::remote_execution::Directory::new();
+    let unrelated_digest = store.record_directory(&unrelated).wait().unwrap();
 
+    let later = SystemTime::now() + Duration::from_secs(120);
+    assert_eq!(store.expire(later).wait(), Ok(3));
     assert_eq!(
-      &new_store(dir.path())
-        .record_directory(&directory)
-        .wait()
-        .unwrap()
-        .0
-        .to_hex(),
-      hash
+      store.load_directory_proto(directory_digest.0).wait(),
+      Ok(None)
     );
-
+    assert_eq!(store.load_directory_proto(parent_digest.0).wait(), Ok(None));
     assert_eq!(
-      new_store(dir.path())
-        .load_directory_proto(Fingerprint::from_hex_string(hash).unwrap())
-        .wait(),
-      Ok(Some(directory.clone()))
+      store.load_directory_proto(unrelated_digest.0).wait(),
+      Ok(Some(unrelated))
     );
+  }
+
+  #[test]
+  fn manifest_cache_is_bounded() {
+    let dir = TempDir::new("store").unwrap();
+    let store = Store::new_with_options(
+      dir.path(),
+      Arc::new(ResettablePool::new("test-pool-".to_string())),
+      StoreOptions {
+        manifest_cache_size: 1,
+        ..StoreOptions::default()
+      },
+    ).unwrap();
+    let file_digest = store.store_file_bytes(str_bytes()).wait().unwrap();
+    let manifest = vec![(Path::new("roland").to_owned(), file_digest, false)];
+    let first = Fingerprint::from_hex_string(HASH).unwrap();
+    let second = Fingerprint([0; 32]);
+
+    store.cache_manifest(first, &manifest);
+    assert_eq!(store.cached_manifest(first), Some(Arc::new(manifest.clone())));
+    store.cache_manifest(second, &manifest);
+    assert_eq!(store.cached_manifest(first), None);
+    assert_eq!(store.cached_manifest(second), Some(Arc::new(manifest)));
+    store.clear_manifest_cache();
+    assert_eq!(store.cached_manifest(second), None);
+  }
+
+  #[test]
+  fn cached_directory_is_invalidated_by_expire() {
+    let dir = TempDir::new("store").unwrap();
+    let store = Store::new_with_options(
+      dir.path(),
+      Arc::new(ResettablePool::new("test-pool-".to_string())),
+      StoreOptions {
+        directory_cache_size: 1,
+        ..StoreOptions::default()
+      },
+    ).unwrap();
+    let file_digest = store
+      .store_file_bytes_with_ttl(str_bytes(), Duration::from_secs(60))
+      .wait()
+      .unwrap();
+    let mut directory = bazel_protos::remote_execution::Directory::new();
+    directory.mut_files().push({
+      let mut file = bazel_protos::remote_execution::FileNode::new();
+      file.set_name("roland".to_string());
+      file.set_digest(file_digest.into());
+      file
+    });
+    let directory_digest = store.record_directory(&directory).wait().unwrap();
+
+    for _ in 0..2 {
+      assert_eq!(
+        store.load_directory_proto(directory_digest.0).wait(),
+        Ok(Some(directory.clone()))
+      );
+    }
 
+    let later = SystemTime::now() + Duration::from_secs(120);
+    assert_eq!(store.expire(later).wait(), Ok(2));
     assert_eq!(
-      new_store(dir.path())
-        .load_directory_proto_bytes(Fingerprint::from_hex_string(hash).unwrap())
-        .wait(),
-      Ok(Some(directory.write_to_bytes().unwrap()))
+      store.load_directory_proto(directory_digest.0).wait(),
+      Ok(None)
     );
   }
 
   #[test]
-  fn file_is_not_directory_proto() {
+  fn child_directory_digests_are_cached() {
     let dir = TempDir::new("store").unwrap();
+    let store = Store::new_with_options(
+      dir.path(),
+      Arc::new(ResettablePool::new("test-pool-".to_string())),
+      StoreOptions {
+        directory_cache_size: 1,
+        ..StoreOptions::default()
+      },
+    ).unwrap();
+    let child_digest = store
+      .record_directory(&bazel_protos::remote_execution::Directory::new())
+      .wait()
+      .unwrap();
+    let mut directory = bazel_protos::remote_execution::Directory::new();
+    directory.mut_directories().push({
+      let mut directory_node = bazel_protos::remote_execution::DirectoryNode::new();
+      directory_node.set_name("cats".to_string());
+      directory_node.set_digest(child_digest.clone().into());
+      directory_node
+    });
+    let directory_digest = store.record_directory(&directory).wait().unwrap();
 
-    new_store(dir.path())
-      .store_file_bytes(str_bytes())
+    let (first, second) = store
+      .read_batch(move |reader| {
+        let directory = reader.load_directory_proto(directory_digest.0)?.unwrap();
+        let first = reader.child_directory_digests(directory_digest.0, &directory)?;
+        let second = reader.child_directory_digests(directory_digest.0, &directory)?;
+        Ok((first, second))
+      })
       .wait()
       .unwrap();
+    assert_eq!(*first, vec![child_digest]);
+    assert!(Arc::ptr_eq(&first, &second));
+  }
 
+  #[test]
+  fn operations_exceeding_timeout_fail() {
+    let dir = TempDir::new("store").unwrap();
+    let store = Store::new_with_options(
+      dir.path(),
+      Arc::new(ResettablePool::new("test-pool-".to_string())),
+      StoreOptions {
+        operation_timeout: Some(Duration::from_millis(50)),
+        ..StoreOptions::default()
+      },
+    ).unwrap();
+
+    assert_eq!(store.store_file_bytes(str_bytes()).wait(), Ok(digest()));
+
+    let err = store
+      .read_batch(|_| {
+        thread::sleep(Duration::from_millis(500));
+        Ok(())
+      })
+      .wait()
+      .expect_err("Want error");
+    assert!(err.starts_with("Timeout"), err);
+  }
+
+  #[test]
+  fn coalesced_store_is_retried_after_timeout() {
+    let dir = TempDir::new("store").unwrap();
+    let store = Store::new_with_options(
+      dir.path(),
+      Arc::new(ResettablePool::new("test-pool-".to_string())),
+      StoreOptions {
+        operation_timeout: Some(Duration::from_millis(50)),
+        ..StoreOptions::default()
+      },
+    ).unwrap();
+
+    // Holding the write transaction blocks the store until after it has timed out.
+    let txn = store.inner.env.begin_rw_txn().unwrap();
+    let err = store
+      .put_file_bytes_coalesced(digest().0, str_bytes())
+      .wait()
+      .expect_err("Want error");
+    assert!(err.starts_with("Timeout"), err);
+    assert!(
+      store
+        .inner
+        .in_flight_file_stores
+        .lock()
+        .unwrap()
+        .get(&digest().0)
+        .is_none()
+    );
+    std::mem::drop(txn);
+
+    assert_eq!(store.store_file_bytes(str_bytes()).wait(), Ok(digest()));
     assert_eq!(
-      new_store(dir.path())
-        .load_directory_proto(Fingerprint::from_hex_string(HASH).unwrap())
-        .wait(),
-      Ok(None)
+      store.load_file_bytes(digest().0).wait(),
+      Ok(Some(str_bytes()))
     );
+  }
 
+  #[test]
+  fn digest_from_bazel_digest() {
+    let mut bazel_digest = bazel_protos::remote_execution::Digest::new();
+    bazel_digest.set_hash(HASH.to_string());
+    bazel_digest.set_size_bytes(16);
     assert_eq!(
-      new_store(dir.path())
-        .load_directory_proto_bytes(Fingerprint::from_hex_string(HASH).unwrap())
-        .wait(),
-      Ok(None)
+      Digest::from_bazel_digest(&bazel_digest),
+      Ok(Digest(Fingerprint::from_hex_string(HASH).unwrap(), 16))
     );
   }
 
+  #[test]
+  fn digest_from_bazel_digest_invalid_hash() {
+    let mut bazel_digest = bazel_protos::remote_execution::Digest::new();
+    bazel_digest.set_hash("not hex".to_string());
+    bazel_digest.set_size_bytes(16);
+    let err = Digest::from_bazel_digest(&bazel_digest).expect_err("Want error");
+    assert!(err.contains("Invalid hash"), err);
+  }
+
+  #[test]
+  fn digest_from_bazel_digest_negative_size() {
+    let mut bazel_digest = bazel_protos::remote_execution::Digest::new();
+    bazel_digest.set_hash(HASH.to_string());
+    bazel_digest.set_size_bytes(-1);
+    let err = Digest::from_bazel_digest(&bazel_digest).expect_err("Want error");
+    assert!(err.contains("Negative size"), err);
+  }
+
   #[test]
   fn digest_to_bazel_digest() {
     let digest = Digest(Fingerprint::from_hex_string(HASH).unwrap(), 16);
@@ -403,7 +3447,165 @@ mod tests {
     assert_eq!(bazel_digest, digest.into());
   }
 
+  #[test]
+  fn digest_ordering() {
+    let small = Fingerprint::from_hex_string(HASH).unwrap();
+    let large = Fingerprint::from_hex_string(
+      "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+    ).unwrap();
+    let mut digests = vec![Digest(large, 1), Digest(small, 16), Digest(small, 2)];
+    digests.sort();
+    assert_eq!(
+      digests,
+      vec![Digest(small, 2), Digest(small, 16), Digest(large, 1)]
+    );
+  }
+
+  #[test]
+  fn backup_and_restore() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_compacting_store(dir.path());
+    let file = store.store_file_bytes(str_bytes()).wait().unwrap();
+    let zeros = store
+      .store_file_bytes(vec![0; MIN_ZERO_RUN * 2])
+      .wait()
+      .unwrap();
+    let mut directory = bazel_protos::remote_execution::Directory::new();
+    directory.mut_files().push({
+      let mut file_node = bazel_protos::remote_execution::FileNode::new();
+      file_node.set_name("roland".to_string());
+      file_node.set_digest(file.clone().into());
+      file_node
+    });
+    let directory_digest = store.record_directory(&directory).wait().unwrap();
+
+    let backup = TempDir::new("backup").unwrap();
+    let backup_file = backup.path().join("store.backup");
+    assert_eq!(store.backup(backup_file.clone()).wait(), Ok(3));
+
+    let restored_dir = TempDir::new("restored").unwrap();
+    let restored = Store::restore(
+      backup_file,
+      restored_dir.path(),
+      Arc::new(ResettablePool::new("test-pool-".to_string())),
+      StoreOptions::default(),
+    ).wait()
+      .unwrap();
+    assert_eq!(
+      restored.load_file_bytes(file.0).wait(),
+      Ok(Some(str_bytes()))
+    );
+    assert_eq!(
+      restored.load_file_bytes(zeros.0).wait(),
+      Ok(Some(vec![0; MIN_ZERO_RUN * 2]))
+    );
+    assert_eq!(
+      restored.load_directory_proto(directory_digest.0).wait(),
+      Ok(Some(directory))
+    );
+  }
+
+  #[test]
+  fn backup_and_restore_raw_names() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+    let directory = Fingerprint::from_hex_string(HASH).unwrap();
+    store
+      .record_raw_names(vec![
+        (directory, "caf\u{FFFD}".to_string(), b"caf\xe9".to_vec()),
+      ])
+      .wait()
+      .unwrap();
+
+    let backup = TempDir::new("backup").unwrap();
+    let backup_file = backup.path().join("store.backup");
+    assert_eq!(store.backup(backup_file.clone()).wait(), Ok(1));
+
+    let restored_dir = TempDir::new("restored").unwrap();
+    let restored = Store::restore(
+      backup_file,
+      restored_dir.path(),
+      Arc::new(ResettablePool::new("test-pool-".to_string())),
+      StoreOptions::default(),
+    ).wait()
+      .unwrap();
+    assert_eq!(
+      restored
+        .read_batch(move |reader| reader.load_raw_name(directory, "caf\u{FFFD}"))
+        .wait(),
+      Ok(Some(b"caf\xe9".to_vec()))
+    );
+  }
+
+  #[test]
+  fn restore_rejects_corrupt_backup() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+    store.store_file_bytes(str_bytes()).wait().unwrap();
+    let backup = TempDir::new("backup").unwrap();
+    let backup_file = backup.path().join("store.backup");
+    store.backup(backup_file.clone()).wait().unwrap();
+
+    let mut bytes = Vec::new();
+    std::fs::File::open(&backup_file)
+      .unwrap()
+      .read_to_end(&mut bytes)
+      .unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 1;
+    std::fs::File::create(&backup_file)
+      .unwrap()
+      .write_all(&bytes)
+      .unwrap();
+
+    let restored_dir = TempDir::new("restored").unwrap();
+    let err = Store::restore(
+      backup_file,
+      restored_dir.path(),
+      Arc::new(ResettablePool::new("test-pool-".to_string())),
+      StoreOptions::default(),
+    ).wait()
+      .err()
+      .expect("Want error");
+    assert!(err.contains("corrupt"), err);
+  }
+
+  #[test]
+  fn env_info_counts_entries() {
+    let dir = TempDir::new("store").unwrap();
+    let store = new_store(dir.path());
+    store.store_file_bytes(str_bytes()).wait().unwrap();
+    store
+      .store_file_bytes_with_ttl(vec![], Duration::from_secs(60))
+      .wait()
+      .unwrap();
+    store
+      .record_directory(&bazel_protos::remote_execution::Directory::new())
+      .wait()
+      .unwrap();
+
+    let info = store.env_info().wait().unwrap();
+    assert_eq!(info.map_size, 16 * 1024 * 1024 * 1024);
+    assert!(info.page_size > 0);
+    assert!(info.free_pages <= info.last_page);
+    assert_eq!(info.entries["files"], 2);
+    assert_eq!(info.entries["expiries"], 1);
+    assert_eq!(info.entries["directories"], 1);
+    assert_eq!(info.entries["sparse_files"], 0);
+  }
+
   fn new_store<P: AsRef<Path>>(dir: P) -> Store {
     Store::new(dir, Arc::new(ResettablePool::new("test-pool-".to_string()))).unwrap()
   }
+
+  fn new_compacting_store<P: AsRef<Path>>(dir: P) -> Store {
+    Store::new_with_options(
+      dir,
+      Arc::new(ResettablePool::new("test-pool-".to_string())),
+      StoreOptions {
+        compact_zero_runs: true,
+        ..StoreOptions::default()
+      },
+    ).unwrap()
+  }
 }
@@ -0,0 +1,119 @@
+// Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use boxfuture::{Boxable, BoxFuture};
+use futures::{Future, Stream};
+use std::sync::Arc;
+
+use {Digest, File, FileContentStream, GetFileDigest, PosixFS, Store};
+
+///
+/// A GetFileDigest which stores files read from a PosixFS as it digests them, reading each file
+/// once: small files are read whole and stored with `Store::store_file_bytes`, and larger files are
+/// streamed from `PosixFS::read_file_stream` into a `Store::file_writer`, so that they are never
+/// held in memory whole. This is the recommended GetFileDigest for snapshotting a PosixFS,
+/// particularly one containing large files.
+///
+/// A file which changes while it is being read is stored as it was read; combine this with a
+/// ChangeDetectingDigester to detect that.
+///
+pub struct StoringDigester {
+  store: Arc<Store>,
+  posix_fs: Arc<PosixFS>,
+}
+
+impl StoringDigester {
+  pub fn new(store: Arc<Store>, posix_fs: Arc<PosixFS>) -> StoringDigester {
+    StoringDigester {
+      store: store,
+      posix_fs: posix_fs,
+    }
+  }
+}
+
+impl GetFileDigest<String> for StoringDigester {
+  fn digest(&self, file: &File) -> BoxFuture<Digest, String> {
+    let store = self.store.clone();
+    let path = file.path.clone();
+    self
+      .posix_fs
+      .read_file_stream(file)
+      .map_err(move |e| format!("Error reading file {:?}: {}", path, e))
+      .and_then(move |contents| -> BoxFuture<Digest, String> {
+        match contents {
+          FileContentStream::Buffered(content) => store.store_file_bytes(content.content),
+          FileContentStream::Chunked { path, chunks, .. } => {
+            chunks
+              .map_err(move |e| format!("Error reading file {:?}: {}", path, e))
+              .fold(store.file_writer(), |mut writer, chunk| -> Result<_, String> {
+                writer.write(&chunk)?;
+                Ok(writer)
+              })
+              .and_then(|writer| writer.finish())
+              .to_boxed()
+          }
+        }
+      })
+      .to_boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempdir;
+  extern crate testutil;
+
+  use futures::Future;
+  use self::testutil::make_file;
+  use std::path::PathBuf;
+  use std::sync::Arc;
+  use super::StoringDigester;
+  use super::super::{File, GetFileDigest, PosixFS, ResettablePool, Store,
+                     MAX_BUFFERED_STORE_SIZE};
+
+  #[test]
+  fn stores_files_from_posix_fs() {
+    let dir = tempdir::TempDir::new("root").unwrap();
+    let store_dir = tempdir::TempDir::new("store").unwrap();
+    let pool = Arc::new(ResettablePool::new("test-pool-".to_string()));
+    let store = Arc::new(Store::new(store_dir.path(), pool.clone()).unwrap());
+    let posix_fs = Arc::new(PosixFS::new(dir.path(), pool, vec![]).unwrap());
+    make_file(&dir.path().join("roland"), b"European Burmese", 0o600);
+
+    let digest = StoringDigester::new(store.clone(), posix_fs)
+      .digest(&File {
+        path: PathBuf::from("roland"),
+        is_executable: false,
+      })
+      .wait()
+      .unwrap();
+    assert_eq!(digest.1, 16);
+    assert_eq!(
+      store.load_file_bytes(digest.0).wait(),
+      Ok(Some(b"European Burmese".to_vec()))
+    );
+  }
+
+  #[test]
+  fn stores_large_files_from_posix_fs() {
+    let dir = tempdir::TempDir::new("root").unwrap();
+    let store_dir = tempdir::TempDir::new("store").unwrap();
+    let pool = Arc::new(ResettablePool::new("test-pool-".to_string()));
+    let store = Arc::new(Store::new(store_dir.path(), pool.clone()).unwrap());
+    let posix_fs = Arc::new(PosixFS::new(dir.path(), pool, vec![]).unwrap());
+    let bytes = (0..MAX_BUFFERED_STORE_SIZE * 3)
+      .map(|i| i as u8)
+      .collect::<Vec<_>>();
+    make_file(&dir.path().join("roland"), &bytes, 0o600);
+
+    let digest = StoringDigester::new(store.clone(), posix_fs)
+      .digest(&File {
+        path: PathBuf::from("roland"),
+        is_executable: false,
+      })
+      .wait()
+      .unwrap();
+    assert_eq!(digest.1, bytes.len());
+    assert_eq!(store.load_file_bytes(digest.0).wait(), Ok(Some(bytes)));
+  }
+}
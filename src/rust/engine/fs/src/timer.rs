@@ -0,0 +1,125 @@
+// Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use futures::sync::oneshot;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+  static ref TIMER: Timer = Timer::new();
+}
+
+///
+/// Returns a receiver which completes once the given duration has elapsed.
+///
+/// All timeouts are completed by a single shared thread, which sleeps until the earliest pending
+/// deadline. Dropping the receiver does not cancel its timeout, which is discarded once it is due.
+///
+pub fn timeout(duration: Duration) -> oneshot::Receiver<()> {
+  TIMER.timeout(duration)
+}
+
+struct Deadline {
+  when: Instant,
+  sender: oneshot::Sender<()>,
+}
+
+// Ordered so that the earliest deadline is the greatest, and so is at the top of a BinaryHeap.
+impl Ord for Deadline {
+  fn cmp(&self, other: &Deadline) -> Ordering {
+    other.when.cmp(&self.when)
+  }
+}
+
+impl PartialOrd for Deadline {
+  fn partial_cmp(&self, other: &Deadline) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl PartialEq for Deadline {
+  fn eq(&self, other: &Deadline) -> bool {
+    self.when == other.when
+  }
+}
+
+impl Eq for Deadline {}
+
+struct Timer {
+  deadlines: Arc<(Mutex<BinaryHeap<Deadline>>, Condvar)>,
+}
+
+impl Timer {
+  fn new() -> Timer {
+    let deadlines = Arc::new((Mutex::new(BinaryHeap::new()), Condvar::new()));
+    let shared = deadlines.clone();
+    thread::Builder::new()
+      .name("fs-timer".to_owned())
+      .spawn(move || Timer::run(&shared))
+      .expect("Failed to start timer thread");
+    Timer { deadlines: deadlines }
+  }
+
+  fn timeout(&self, duration: Duration) -> oneshot::Receiver<()> {
+    let (sender, receiver) = oneshot::channel();
+    let &(ref lock, ref condvar) = &*self.deadlines;
+    lock.lock().unwrap().push(Deadline {
+      when: Instant::now() + duration,
+      sender: sender,
+    });
+    // The new deadline may be earlier than the one the timer thread is waiting for.
+    condvar.notify_one();
+    receiver
+  }
+
+  fn run(deadlines: &Arc<(Mutex<BinaryHeap<Deadline>>, Condvar)>) {
+    let &(ref lock, ref condvar) = &**deadlines;
+    let mut pending = lock.lock().unwrap();
+    loop {
+      let now = Instant::now();
+      let mut next = None;
+      loop {
+        let when = match pending.peek() {
+          Some(deadline) => deadline.when,
+          None => break,
+        };
+        if when > now {
+          next = Some(when);
+          break;
+        }
+        // The receiver may have been dropped, if what it was timing completed first.
+        let _ = pending.pop().unwrap().sender.send(());
+      }
+      pending = match next {
+        Some(when) => condvar.wait_timeout(pending, when - now).unwrap().0,
+        None => condvar.wait(pending).unwrap(),
+      };
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use futures::Future;
+  use std::time::{Duration, Instant};
+  use super::timeout;
+
+  #[test]
+  fn completes_timeouts_in_deadline_order() {
+    let start = Instant::now();
+    let long = timeout(Duration::from_millis(200));
+    let short = timeout(Duration::from_millis(50));
+
+    short.wait().unwrap();
+    let short_elapsed = start.elapsed();
+    long.wait().unwrap();
+    let long_elapsed = start.elapsed();
+
+    assert!(short_elapsed >= Duration::from_millis(50));
+    assert!(short_elapsed < Duration::from_millis(200));
+    assert!(long_elapsed >= Duration::from_millis(200));
+  }
+}
@@ -0,0 +1,147 @@
+// Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use boxfuture::{Boxable, BoxFuture};
+use futures::Future;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use {Digest, File, GetFileDigest, PosixFS};
+
+///
+/// A GetFileDigest which wraps another, and fails with "File X changed during snapshot" if a file's
+/// size or modification time differ between just before and just after the wrapped digester reads
+/// it, or if its size differs from that of its Digest.
+///
+/// A Snapshot built from a long-running scan can otherwise be inconsistent with any single state
+/// of the filesystem, when files are edited concurrently. Stats are taken relative to the PosixFS'
+/// root, so the wrapped digester should read files from the same PosixFS.
+///
+pub struct ChangeDetectingDigester<GFD> {
+  inner: Arc<GFD>,
+  posix_fs: Arc<PosixFS>,
+}
+
+impl<GFD> ChangeDetectingDigester<GFD> {
+  pub fn new(inner: Arc<GFD>, posix_fs: Arc<PosixFS>) -> ChangeDetectingDigester<GFD> {
+    ChangeDetectingDigester {
+      inner: inner,
+      posix_fs: posix_fs,
+    }
+  }
+}
+
+impl<GFD: GetFileDigest<String> + Send + Sync + 'static> GetFileDigest<String>
+  for ChangeDetectingDigester<GFD> {
+  fn digest(&self, file: &File) -> BoxFuture<Digest, String> {
+    let path_abs = self.posix_fs.root.0.join(&file.path);
+    let file = file.clone();
+    let inner = self.inner.clone();
+    let pool = self.posix_fs.pool.clone();
+    self
+      .posix_fs
+      .pool
+      .spawn_fn(move || file_stamp(&path_abs).map(|before| (path_abs, before)))
+      .and_then(move |(path_abs, before)| {
+        inner.digest(&file).map(
+          move |digest| (file, path_abs, before, digest),
+        )
+      })
+      .and_then(move |(file, path_abs, before, digest)| {
+        pool.spawn_fn(move || {
+          let after = file_stamp(&path_abs)?;
+          if after != before || digest.1 as u64 != before.0 {
+            return Err(format!("File {:?} changed during snapshot", file.path));
+          }
+          Ok(digest)
+        })
+      })
+      .to_boxed()
+  }
+}
+
+// The size and modification time of the file at the given path.
+fn file_stamp(path: &Path) -> Result<(u64, SystemTime), String> {
+  fs::metadata(path)
+    .and_then(|metadata| {
+      metadata.modified().map(|modified| (metadata.len(), modified))
+    })
+    .map_err(|e| format!("Error statting {:?}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempdir;
+  extern crate testutil;
+
+  use boxfuture::BoxFuture;
+  use futures::Future;
+  use self::testutil::make_file;
+  use std::fs::OpenOptions;
+  use std::io::Write;
+  use std::path::PathBuf;
+  use std::sync::Arc;
+  use super::ChangeDetectingDigester;
+  use super::super::{Digest, File, GetFileDigest, PosixFS, ResettablePool, Store};
+
+  // Stores the given bytes as the content of any file, optionally appending to the file on disk
+  // first, as a concurrent edit might.
+  struct Digester {
+    store: Arc<Store>,
+    root: PathBuf,
+    bytes: Vec<u8>,
+    append: bool,
+  }
+
+  impl GetFileDigest<String> for Digester {
+    fn digest(&self, file: &File) -> BoxFuture<Digest, String> {
+      if self.append {
+        OpenOptions::new()
+          .append(true)
+          .open(self.root.join(&file.path))
+          .unwrap()
+          .write_all(b" edited")
+          .unwrap();
+      }
+      self.store.store_file_bytes(self.bytes.clone())
+    }
+  }
+
+  #[test]
+  fn detects_files_changed_while_digesting() {
+    let dir = tempdir::TempDir::new("root").unwrap();
+    let store_dir = tempdir::TempDir::new("store").unwrap();
+    let pool = Arc::new(ResettablePool::new("test-pool-".to_string()));
+    let store = Arc::new(Store::new(store_dir.path(), pool.clone()).unwrap());
+    let posix_fs = Arc::new(PosixFS::new(dir.path(), pool, vec![]).unwrap());
+    make_file(&dir.path().join("roland"), b"European Burmese", 0o600);
+    let file = File {
+      path: PathBuf::from("roland"),
+      is_executable: false,
+    };
+    let digester = |append: bool| {
+      ChangeDetectingDigester::new(
+        Arc::new(Digester {
+          store: store.clone(),
+          root: dir.path().to_owned(),
+          bytes: b"European Burmese".to_vec(),
+          append: append,
+        }),
+        posix_fs.clone(),
+      )
+    };
+
+    assert_eq!(
+      digester(false).digest(&file).wait(),
+      store
+        .store_file_bytes(b"European Burmese".to_vec())
+        .wait()
+    );
+    assert_eq!(
+      digester(true).digest(&file).wait(),
+      Err("File \"roland\" changed during snapshot".to_owned())
+    );
+  }
+}
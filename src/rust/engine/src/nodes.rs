@@ -917,7 +917,7 @@ impl Snapshot {
 
   fn store_snapshot(context: &Context, item: &fs::Snapshot) -> Value {
     let path_stats: Vec<_> = item
-      .path_stats
+      .path_stats()
       .iter()
       .map(|ps| Self::store_path_stat(context, ps))
       .collect();
@@ -1,6 +1,7 @@
 use std::io::Write;
 use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::cmp::{max, min};
+use std::path::{Path, PathBuf};
 
 pub fn owned_string_vec(args: &[&str]) -> Vec<String> {
   args.into_iter().map(|s| s.to_string()).collect()
@@ -17,3 +18,121 @@ pub fn make_file(path: &Path, contents: &[u8], mode: u32) {
   permissions.set_mode(mode);
   file.set_permissions(permissions).unwrap();
 }
+
+///
+/// Asserts that the actual (path, content) pairs (for example, those of a `Vec<FileContent>`) are
+/// equal to the expected ones, in order. On mismatch, panics with a description of the first pair
+/// which differs, rather than a dump of every byte of every file.
+///
+pub fn assert_contents_eq<P: AsRef<Path>, C: AsRef<[u8]>>(
+  actual: &[(P, C)],
+  expected: &[(PathBuf, &[u8])],
+) {
+  let actual_paths: Vec<&Path> = actual.iter().map(|&(ref p, _)| p.as_ref()).collect();
+  let expected_paths: Vec<&Path> = expected.iter().map(|&(ref p, _)| p.as_path()).collect();
+  if actual_paths != expected_paths {
+    panic!(
+      "Paths differed:\n  actual:   {:?}\n  expected: {:?}",
+      actual_paths,
+      expected_paths
+    );
+  }
+  for (&(ref path, ref actual_content), &(_, expected_content)) in actual.iter().zip(expected) {
+    let actual_content = actual_content.as_ref();
+    if actual_content != expected_content {
+      panic!(
+        "Contents of {:?} differed:\n  actual:   {} bytes {}\n  expected: {} bytes {}",
+        path.as_ref(),
+        actual_content.len(),
+        preview(actual_content),
+        expected_content.len(),
+        preview(expected_content)
+      );
+    }
+  }
+}
+
+///
+/// Asserts that two lists of lines (for example, renderings of two trees) are equal. On mismatch,
+/// panics with the given description followed by a line diff, in which lines only in `expected`
+/// are marked with `-` and lines only in `actual` are marked with `+`.
+///
+pub fn assert_lines_eq(description: &str, actual: &[String], expected: &[String]) {
+  if actual != expected {
+    panic!(
+      "{} (- expected, + actual):\n{}",
+      description,
+      diff_lines(expected, actual)
+    );
+  }
+}
+
+fn diff_lines(expected: &[String], actual: &[String]) -> String {
+  // common[i][j] is the length of the longest common subsequence of expected[i..] and actual[j..].
+  let mut common = vec![vec![0; actual.len() + 1]; expected.len() + 1];
+  for i in (0..expected.len()).rev() {
+    for j in (0..actual.len()).rev() {
+      common[i][j] = if expected[i] == actual[j] {
+        common[i + 1][j + 1] + 1
+      } else {
+        max(common[i + 1][j], common[i][j + 1])
+      };
+    }
+  }
+
+  let mut diff = Vec::new();
+  let (mut i, mut j) = (0, 0);
+  while i < expected.len() || j < actual.len() {
+    if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+      diff.push(format!("  {}", expected[i]));
+      i += 1;
+      j += 1;
+    } else if j == actual.len() || (i < expected.len() && common[i + 1][j] >= common[i][j + 1]) {
+      diff.push(format!("- {}", expected[i]));
+      i += 1;
+    } else {
+      diff.push(format!("+ {}", actual[j]));
+      j += 1;
+    }
+  }
+  diff.join("\n")
+}
+
+fn preview(bytes: &[u8]) -> String {
+  let len = min(bytes.len(), 32);
+  let describer = if len < bytes.len() { "starting " } else { "" };
+  format!("{}{:?}", describer, String::from_utf8_lossy(&bytes[..len]))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+  use super::assert_contents_eq;
+
+  #[test]
+  fn assert_contents_eq_accepts_equal_contents() {
+    assert_contents_eq(
+      &[(PathBuf::from("roland"), b"meow".to_vec())],
+      &[(PathBuf::from("roland"), &b"meow"[..])],
+    );
+  }
+
+  #[test]
+  #[should_panic(expected = "Paths differed:\n  actual:   [\"roland\"]\n  expected: [\"fido\"]")]
+  fn assert_contents_eq_describes_differing_paths() {
+    assert_contents_eq(
+      &[(PathBuf::from("roland"), b"meow".to_vec())],
+      &[(PathBuf::from("fido"), &b"meow"[..])],
+    );
+  }
+
+  #[test]
+  #[should_panic(expected = "Contents of \"roland\" differed:\n  actual:   4 bytes \"meow\"\n  \
+                             expected: 34 bytes starting \"European Burmese, who likes to n\"")]
+  fn assert_contents_eq_describes_differing_contents() {
+    assert_contents_eq(
+      &[(PathBuf::from("roland"), b"meow".to_vec())],
+      &[(PathBuf::from("roland"), &b"European Burmese, who likes to nap"[..])],
+    );
+  }
+}